@@ -0,0 +1,456 @@
+//! Agent mode: lets another machine list, bind and unbind this host's devices.
+//!
+//! The protocol is one JSON object per line over TCP. Every request carries
+//! the shared token from `[agent]` or a personal one from `[[agent.users]]`;
+//! binds are checked against reservations under the personal token's user.
+//! Every reply carries the device list as it is after the request, so a bind
+//! and the refresh that follows take one round trip. See [`crate::fleet`] for
+//! the side that talks to many agents.
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::device_list::{self, UsbipDevice};
+use crate::reservations::{self, Reservations};
+use crate::runner::{CommandError, CommandRunner};
+use crate::wsl::{self, Attachments};
+use crate::{linux_client, mdns};
+
+pub const AGENT_PORT: u16 = 3242;
+
+/// Longest request line accepted, so a stray client can't exhaust memory.
+const MAX_LINE: u64 = 64 * 1024;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Request {
+    List,
+    Bind {
+        busid: String,
+        #[serde(default)]
+        force: bool,
+    },
+    Unbind {
+        busid: String,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    token: String,
+    #[serde(flatten)]
+    request: Request,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Response {
+    Ok {
+        /// The agent's `[agent] name`.
+        host: String,
+        devices: Vec<UsbipDevice>,
+    },
+    Error {
+        message: String,
+    },
+    Unauthorized,
+}
+
+#[derive(Debug)]
+pub enum AgentError {
+    /// The agent couldn't be reached or the connection broke.
+    Io(io::Error),
+    Unauthorized,
+    /// The agent ran the request and usbipd failed.
+    Failed(String),
+    /// The reply wasn't a protocol message.
+    Protocol(String),
+}
+
+impl fmt::Display for AgentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AgentError::Io(e) => write!(f, "{}", e),
+            AgentError::Unauthorized => f.write_str("the agent rejected the token"),
+            AgentError::Failed(message) => f.write_str(message),
+            AgentError::Protocol(message) => write!(f, "unexpected reply: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for AgentError {}
+
+impl From<io::Error> for AgentError {
+    fn from(e: io::Error) -> Self {
+        AgentError::Io(e)
+    }
+}
+
+/// One `[[agent.users]]` or `[[api.users]]` entry: a token that stands for
+/// one person, so what it binds counts as theirs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserToken {
+    pub user: String,
+    pub token: String,
+}
+
+/// Compares tokens without stopping at the first difference.
+pub(crate) fn same_token(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Who presented `token`: the user of a matching personal token, or an
+/// empty name for the shared one, which may only bind unreserved devices.
+/// `None` if neither matches.
+pub(crate) fn identify(token: &str, shared: &str, users: &[UserToken]) -> Option<String> {
+    if let Some(entry) = users.iter().find(|u| !u.token.is_empty() && same_token(token, &u.token)) {
+        return Some(entry.user.clone());
+    }
+    (!shared.is_empty() && same_token(token, shared)).then(String::new)
+}
+
+/// Carries out one request for `user` against the local usbipd and lists the
+/// devices afterwards. Binds are checked against `reservations`.
+pub fn apply(runner: &dyn CommandRunner, reservations: &Reservations, request: &Request, user: &str) -> Result<Vec<UsbipDevice>, CommandError> {
+    match request {
+        Request::List => {}
+        Request::Bind { busid, force } => {
+            reservations.check(busid, user).map_err(|e| CommandError::Failed(e.to_string()))?;
+            device_list::bind_device(runner, busid, *force)?
+        }
+        Request::Unbind { busid } => device_list::unbind_device(runner, busid)?,
+    }
+    device_list::list_devices(runner)
+}
+
+fn handle(runner: &dyn CommandRunner, settings: &AgentSettings, request: &Request, user: &str) -> Response {
+    match apply(runner, &Reservations::at(settings.reservations.clone()), request, user) {
+        Ok(mut devices) => {
+            Attachments::at(settings.wsl_attachments.clone()).annotate(&mut devices);
+            Response::Ok { host: settings.name.clone(), devices }
+        }
+        Err(e) => Response::Error { message: e.to_string() },
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AgentSettings {
+    pub name: String,
+    pub token: String,
+    pub users: Vec<UserToken>,
+    /// The reservations file binds are checked against.
+    pub reservations: PathBuf,
+    /// Names the WSL distribution of devices attached from this machine.
+    pub wsl_attachments: PathBuf,
+}
+
+impl AgentSettings {
+    pub fn from_config() -> Self {
+        let agent = config::current().agent;
+        let name = if agent.name.is_empty() { mdns::host_name() } else { agent.name };
+        AgentSettings {
+            name,
+            token: agent.token,
+            users: agent.users,
+            reservations: reservations::default_path(),
+            wsl_attachments: wsl::default_attachments_path(),
+        }
+    }
+}
+
+/// Answers requests on `listener`, one thread per connection. Doesn't return
+/// unless accepting fails.
+pub fn run<R>(listener: TcpListener, settings: AgentSettings, runner: Arc<R>) -> io::Result<()>
+where
+    R: CommandRunner + Send + Sync + 'static,
+{
+    let settings = Arc::new(settings);
+    loop {
+        let (stream, _) = listener.accept()?;
+        let settings = settings.clone();
+        let runner = runner.clone();
+        thread::spawn(move || {
+            let _ = serve_connection(stream, &settings, runner.as_ref());
+        });
+    }
+}
+
+/// [`run`] on a background thread.
+pub fn serve<R>(listener: TcpListener, settings: AgentSettings, runner: Arc<R>)
+where
+    R: CommandRunner + Send + Sync + 'static,
+{
+    thread::spawn(move || run(listener, settings, runner));
+}
+
+/// Starts the agent if `agent.listen` is set in the config file.
+pub fn serve_configured<R>(runner: Arc<R>) -> io::Result<()>
+where
+    R: CommandRunner + Send + Sync + 'static,
+{
+    let listen = config::current().agent.listen;
+    if listen.is_empty() {
+        return Ok(());
+    }
+    serve(TcpListener::bind(&listen)?, AgentSettings::from_config(), runner);
+    Ok(())
+}
+
+fn serve_connection(stream: TcpStream, settings: &AgentSettings, runner: &dyn CommandRunner) -> io::Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut line = String::new();
+        if (&mut reader).take(MAX_LINE).read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let response = match serde_json::from_str::<Envelope>(&line) {
+            Ok(envelope) => match identify(&envelope.token, &settings.token, &settings.users) {
+                Some(user) => handle(runner, settings, &envelope.request, &user),
+                None => Response::Unauthorized,
+            },
+            Err(e) => Response::Error { message: format!("bad request: {}", e) },
+        };
+        let mut reply = serde_json::to_string(&response).unwrap_or_default();
+        reply.push('\n');
+        writer.write_all(reply.as_bytes())?;
+        if matches!(response, Response::Unauthorized) {
+            return Ok(());
+        }
+    }
+}
+
+/// Talks to one agent. Each request opens its own connection.
+#[derive(Debug, Clone)]
+pub struct AgentClient {
+    pub address: String,
+    token: String,
+    timeout: Duration,
+}
+
+impl AgentClient {
+    /// `address` is `host[:port]`; the port defaults to [`AGENT_PORT`].
+    pub fn new(address: &str, token: &str, timeout: Duration) -> Self {
+        AgentClient { address: address.to_string(), token: token.to_string(), timeout }
+    }
+
+    /// Sends `request` and returns the agent's name and device list.
+    pub fn request(&self, request: Request) -> Result<(String, Vec<UsbipDevice>), AgentError> {
+        let (host, port) = linux_client::split_host(&self.address, AGENT_PORT);
+        let addr = linux_client::resolve(&host, port)?;
+        let mut stream = TcpStream::connect_timeout(&addr, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        let envelope = Envelope { token: self.token.clone(), request };
+        let mut line = serde_json::to_string(&envelope).map_err(|e| AgentError::Protocol(e.to_string()))?;
+        line.push('\n');
+        stream.write_all(line.as_bytes())?;
+
+        let mut reply = String::new();
+        BufReader::new(stream).take(MAX_LINE * 64).read_line(&mut reply)?;
+        if reply.is_empty() {
+            return Err(AgentError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "the agent closed the connection")));
+        }
+        match serde_json::from_str(&reply).map_err(|e| AgentError::Protocol(e.to_string()))? {
+            Response::Ok { host, devices } => Ok((host, devices)),
+            Response::Error { message } => Err(AgentError::Failed(message)),
+            Response::Unauthorized => Err(AgentError::Unauthorized),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_list::DeviceState;
+    use crate::reservations::RESERVATIONS_FILE;
+    use crate::testing::{self, FakeRunner, TempDir};
+
+    const TOKEN: &str = "s3cret-token";
+
+    fn runner() -> FakeRunner {
+        let devices = [testing::device("1-1", "0483:374b", DeviceState::Shared), testing::device("1-2", "046d:c52b", DeviceState::NotShared)];
+        FakeRunner::new().ok("usbipd state", &testing::state_json(&devices)).ok("usbipd unbind", "")
+    }
+
+    fn settings(name: &str, users: Vec<UserToken>, dir: &TempDir) -> AgentSettings {
+        AgentSettings {
+            name: name.to_string(),
+            token: TOKEN.to_string(),
+            users,
+            reservations: dir.join(RESERVATIONS_FILE),
+            wsl_attachments: dir.join(wsl::ATTACHMENTS_FILE),
+        }
+    }
+
+    /// An agent on a loopback port.
+    fn start(settings: AgentSettings, runner: Arc<FakeRunner>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        serve(listener, settings, runner);
+        address
+    }
+
+    /// An agent named `name` with only the shared token. Keep the directory
+    /// alive for as long as the agent is used.
+    fn agent(name: &str, runner: Arc<FakeRunner>) -> (String, TempDir) {
+        let dir = TempDir::new("agent");
+        (start(settings(name, Vec::new(), &dir), runner), dir)
+    }
+
+    fn client(address: &str, token: &str) -> AgentClient {
+        AgentClient::new(address, token, Duration::from_secs(5))
+    }
+
+    #[test]
+    fn same_token_compares_whole_strings() {
+        assert!(same_token(TOKEN, TOKEN));
+        assert!(!same_token(TOKEN, "s3cret-tokeN"));
+        assert!(!same_token(TOKEN, "s3cret"));
+        assert!(!same_token(TOKEN, ""));
+    }
+
+    #[test]
+    fn requests_are_one_json_line_with_the_token() {
+        let envelope = Envelope { token: TOKEN.to_string(), request: Request::Unbind { busid: "1-1".into() } };
+        assert_eq!(serde_json::to_string(&envelope).unwrap(), r#"{"token":"s3cret-token","op":"unbind","busid":"1-1"}"#);
+        let parsed: Envelope = serde_json::from_str(r#"{"token":"t","op":"bind","busid":"1-1"}"#).unwrap();
+        assert_eq!(parsed.request, Request::Bind { busid: "1-1".into(), force: false });
+        // Older clients named the user themselves; that is ignored now.
+        let parsed: Envelope = serde_json::from_str(r#"{"token":"t","op":"bind","busid":"1-1","user":"ana"}"#).unwrap();
+        assert_eq!(parsed.request, Request::Bind { busid: "1-1".into(), force: false });
+    }
+
+    #[test]
+    fn identify_names_the_owner_of_a_personal_token() {
+        let users = [
+            UserToken { user: "ana".into(), token: "ana-personal-token".into() },
+            UserToken { user: "bob".into(), token: String::new() },
+        ];
+        assert_eq!(identify("ana-personal-token", TOKEN, &users).as_deref(), Some("ana"));
+        assert_eq!(identify(TOKEN, TOKEN, &users).as_deref(), Some(""));
+        assert_eq!(identify("guess", TOKEN, &users), None);
+        // Empty tokens never match, personal or shared.
+        assert_eq!(identify("", TOKEN, &users), None);
+        assert_eq!(identify("", "", &[]), None);
+    }
+
+    #[test]
+    fn personal_tokens_are_accepted() {
+        let dir = TempDir::new("agent");
+        let users = vec![UserToken { user: "ana".into(), token: "ana-personal-token".into() }];
+        let address = start(settings("bench-1", users, &dir), Arc::new(runner()));
+        assert!(client(&address, "ana-personal-token").request(Request::List).is_ok());
+        assert!(matches!(client(&address, "bob-personal-token").request(Request::List), Err(AgentError::Unauthorized)));
+    }
+
+    #[test]
+    fn reserved_devices_are_only_bound_for_their_holder() {
+        let dir = TempDir::new("agent");
+        Reservations::at(dir.join(RESERVATIONS_FILE)).reserve("1-2", "ana", Duration::from_secs(3600)).unwrap();
+        let users = vec![
+            UserToken { user: "ana".into(), token: "ana-personal-token".into() },
+            UserToken { user: "bob".into(), token: "bob-personal-token".into() },
+        ];
+        let runner = Arc::new(runner().ok("usbipd bind", ""));
+        let address = start(settings("bench-1", users, &dir), runner.clone());
+        let bind = Request::Bind { busid: "1-2".into(), force: false };
+
+        for token in [TOKEN, "bob-personal-token"] {
+            let error = client(&address, token).request(bind.clone()).unwrap_err();
+            assert!(matches!(&error, AgentError::Failed(message) if message.contains("reserved by ana")), "{:?}", error);
+        }
+        assert!(runner.calls_to("usbipd bind").is_empty());
+        client(&address, "ana-personal-token").request(bind).unwrap();
+        assert_eq!(runner.calls_to("usbipd bind"), ["usbipd bind --busid 1-2"]);
+    }
+
+    #[test]
+    fn list_returns_the_agent_name_and_devices() {
+        let runner = Arc::new(runner());
+        let (address, _dir) = agent("bench-1", runner.clone());
+        let (host, devices) = client(&address, TOKEN).request(Request::List).unwrap();
+        assert_eq!(host, "bench-1");
+        assert_eq!(devices.iter().map(|d| d.busid.as_str()).collect::<Vec<_>>(), ["1-1", "1-2"]);
+        assert_eq!(devices[0].state, DeviceState::Shared);
+    }
+
+    #[test]
+    fn unbind_runs_usbipd_and_lists_again() {
+        let runner = Arc::new(runner());
+        let (address, _dir) = agent("bench-1", runner.clone());
+        let (_, devices) = client(&address, TOKEN).request(Request::Unbind { busid: "1-1".into() }).unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(runner.calls_to("usbipd unbind"), ["usbipd unbind --busid 1-1"]);
+    }
+
+    #[test]
+    fn a_wrong_token_is_rejected_before_anything_runs() {
+        let runner = Arc::new(runner());
+        let (address, _dir) = agent("bench-1", runner.clone());
+        let error = client(&address, "guess").request(Request::Unbind { busid: "1-1".into() }).unwrap_err();
+        assert!(matches!(error, AgentError::Unauthorized), "{:?}", error);
+        assert!(runner.calls().is_empty());
+    }
+
+    #[test]
+    fn the_connection_is_closed_after_a_rejected_token() {
+        let (address, _dir) = agent("bench-1", Arc::new(runner()));
+        let mut stream = TcpStream::connect(&address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"{\"token\":\"guess\",\"op\":\"list\"}\n").unwrap();
+        // Reading to the end only returns once the agent hangs up.
+        let mut replies = String::new();
+        stream.read_to_string(&mut replies).unwrap();
+        assert_eq!(replies, "{\"status\":\"unauthorized\"}\n");
+    }
+
+    #[test]
+    fn one_connection_serves_several_requests() {
+        let (address, _dir) = agent("bench-1", Arc::new(runner()));
+        let stream = TcpStream::connect(&address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        for _ in 0..2 {
+            writer.write_all(b"{\"token\":\"s3cret-token\",\"op\":\"list\"}\n").unwrap();
+            let mut reply = String::new();
+            reader.read_line(&mut reply).unwrap();
+            assert!(matches!(serde_json::from_str(&reply).unwrap(), Response::Ok { .. }), "{}", reply);
+        }
+    }
+
+    #[test]
+    fn bad_requests_and_usbipd_failures_are_errors() {
+        let runner = Arc::new(runner().fail("usbipd unbind", 1, "", "usbipd: error: Device 9-9 not found"));
+        let (address, _dir) = agent("bench-1", runner);
+
+        let error = client(&address, TOKEN).request(Request::Unbind { busid: "9-9".into() }).unwrap_err();
+        assert!(matches!(&error, AgentError::Failed(message) if message.contains("not found")), "{:?}", error);
+
+        let mut stream = TcpStream::connect(&address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"{\"token\":\"s3cret-token\",\"op\":\"reboot\"}\n").unwrap();
+        let mut reply = String::new();
+        BufReader::new(stream).read_line(&mut reply).unwrap();
+        assert!(reply.starts_with("{\"status\":\"error\",\"message\":\"bad request: "), "{}", reply);
+    }
+
+    #[test]
+    fn an_unreachable_agent_is_an_io_error() {
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let error = client(&address, TOKEN).request(Request::List).unwrap_err();
+        assert!(matches!(error, AgentError::Io(_)), "{:?}", error);
+    }
+}
@@ -0,0 +1,286 @@
+//! Friendly names and notes for devices.
+//!
+//! An alias is tied to what identifies the device itself, VID:PID plus serial
+//! number or the GUID usbipd gives a persisted device, so it follows the
+//! device when it is plugged into another port.
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::device_list::UsbipDevice;
+
+/// One `[[aliases]]` entry in the config file. At least one of `vidpid` or
+/// `guid` must be set.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceAlias {
+    pub alias: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vidpid: Option<String>,
+    /// Narrows `vidpid` down to one device when several of the same model exist.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guid: Option<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub note: String,
+}
+
+impl DeviceAlias {
+    /// Creates an alias for `device` from every identity it has.
+    pub fn for_device(alias: &str, device: &UsbipDevice) -> Self {
+        DeviceAlias {
+            alias: alias.to_string(),
+            vidpid: Some(device.vidpid.clone()).filter(|v| !v.is_empty()),
+            serial: device.serial().map(str::to_string),
+            guid: device.guid.clone(),
+            note: String::new(),
+        }
+    }
+
+    pub fn matches(&self, device: &UsbipDevice) -> bool {
+        if let (Some(guid), Some(device_guid)) = (&self.guid, &device.guid)
+            && guid.eq_ignore_ascii_case(device_guid)
+        {
+            return true;
+        }
+        match &self.vidpid {
+            Some(vidpid) => {
+                vidpid.eq_ignore_ascii_case(&device.vidpid)
+                    && self.serial.as_ref().is_none_or(|s| device.serial() == Some(s.as_str()))
+            }
+            None => false,
+        }
+    }
+
+    /// True if this alias and `other` point at the same device.
+    fn same_identity(&self, other: &DeviceAlias) -> bool {
+        let guid = self.guid.is_some() && eq_opt(&self.guid, &other.guid);
+        let hardware = self.vidpid.is_some() && eq_opt(&self.vidpid, &other.vidpid) && eq_opt(&self.serial, &other.serial);
+        guid || hardware
+    }
+}
+
+fn eq_opt(a: &Option<String>, b: &Option<String>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// A config entry that can't be used, with its position in `aliases`.
+#[derive(Debug)]
+pub struct InvalidAlias {
+    pub index: usize,
+    pub field: &'static str,
+    pub message: String,
+}
+
+pub fn validate(aliases: &[DeviceAlias]) -> Result<(), InvalidAlias> {
+    for (index, entry) in aliases.iter().enumerate() {
+        let invalid = |field, message: String| Err(InvalidAlias { index, field, message });
+        if entry.alias.trim().is_empty() || entry.alias.contains(char::is_whitespace) {
+            return invalid("alias", String::from("must be a single word"));
+        }
+        if looks_like_busid(&entry.alias) {
+            return invalid("alias", format!("'{}' would be mistaken for a busid", entry.alias));
+        }
+        if aliases[..index].iter().any(|a| a.alias.eq_ignore_ascii_case(&entry.alias)) {
+            return invalid("alias", format!("'{}' is used more than once", entry.alias));
+        }
+        if entry.vidpid.is_none() && entry.guid.is_none() {
+            return invalid("vidpid", String::from("needs vidpid or guid"));
+        }
+        if entry.serial.is_some() && entry.vidpid.is_none() {
+            return invalid("serial", String::from("needs vidpid"));
+        }
+    }
+    Ok(())
+}
+
+/// `1-2`, `2-1.3`: what usbipd uses as a busid.
+pub fn looks_like_busid(value: &str) -> bool {
+    match value.split_once('-') {
+        Some((bus, port)) => {
+            !bus.is_empty()
+                && bus.chars().all(|c| c.is_ascii_digit())
+                && !port.is_empty()
+                && port.split('.').all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit()))
+        }
+        None => false,
+    }
+}
+
+/// The alias of `device`, if it has one.
+pub fn lookup<'a>(aliases: &'a [DeviceAlias], device: &UsbipDevice) -> Option<&'a DeviceAlias> {
+    aliases.iter().find(|a| a.matches(device))
+}
+
+/// Adds `entry`, replacing any alias with the same name or for the same device.
+pub fn upsert(aliases: &mut Vec<DeviceAlias>, entry: DeviceAlias) {
+    aliases.retain(|a| !a.alias.eq_ignore_ascii_case(&entry.alias) && !a.same_identity(&entry));
+    aliases.push(entry);
+}
+
+/// What a busid argument refers to once aliases are resolved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Busid(String),
+    /// A persisted device that is not plugged in.
+    Guid(String),
+}
+
+#[derive(Debug)]
+pub enum ResolveError {
+    Unknown(String),
+    /// The alias exists but its device isn't plugged in or persisted.
+    NotPresent(String),
+    /// Several connected devices match an alias without a serial number.
+    Ambiguous { alias: String, busids: Vec<String> },
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResolveError::Unknown(name) => write!(f, "'{}' is neither a busid nor a known alias", name),
+            ResolveError::NotPresent(alias) => write!(f, "the device aliased '{}' is not connected", alias),
+            ResolveError::Ambiguous { alias, busids } => {
+                write!(f, "alias '{}' matches more than one device: {}", alias, busids.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+/// Resolves `name`, a busid or an alias, against the current `devices`.
+///
+/// A busid is passed through unchecked so usbipd reports on unknown ones.
+pub fn resolve(aliases: &[DeviceAlias], devices: &[UsbipDevice], name: &str) -> Result<Target, ResolveError> {
+    if looks_like_busid(name) {
+        return Ok(Target::Busid(name.to_string()));
+    }
+    let entry = aliases
+        .iter()
+        .find(|a| a.alias.eq_ignore_ascii_case(name))
+        .ok_or_else(|| ResolveError::Unknown(name.to_string()))?;
+
+    let matching: Vec<&UsbipDevice> = devices.iter().filter(|d| entry.matches(d)).collect();
+    let connected: Vec<&UsbipDevice> = matching.iter().copied().filter(|d| !d.busid.is_empty()).collect();
+    match connected.as_slice() {
+        [device] => Ok(Target::Busid(device.busid.clone())),
+        [] => match matching.iter().find_map(|d| d.guid.clone()) {
+            Some(guid) => Ok(Target::Guid(guid)),
+            None => Err(ResolveError::NotPresent(entry.alias.clone())),
+        },
+        _ => Err(ResolveError::Ambiguous {
+            alias: entry.alias.clone(),
+            busids: connected.iter().map(|d| d.busid.clone()).collect(),
+        }),
+    }
+}
+
+/// The aliases from the current config.
+pub fn current() -> Vec<DeviceAlias> {
+    config::current().aliases
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_list::DeviceState;
+    use crate::testing;
+
+    const GUID: &str = "5d1f1b2a-0000-0000-0000-000000000000";
+
+    fn alias(name: &str, vidpid: Option<&str>, serial: Option<&str>, guid: Option<&str>) -> DeviceAlias {
+        DeviceAlias {
+            alias: name.to_string(),
+            vidpid: vidpid.map(str::to_string),
+            serial: serial.map(str::to_string),
+            guid: guid.map(str::to_string),
+            note: String::new(),
+        }
+    }
+
+    fn probe(busid: &str, serial: &str) -> UsbipDevice {
+        let mut device = testing::device(busid, "0483:374b", DeviceState::NotShared);
+        device.instance_id = Some(format!("USB\\VID_0483&PID_374B\\{}", serial));
+        device
+    }
+
+    fn persisted() -> UsbipDevice {
+        let mut device = testing::device("", "0483:374b", DeviceState::Persisted);
+        device.guid = Some(String::from(GUID));
+        device
+    }
+
+    #[test]
+    fn busids_are_told_apart_from_aliases() {
+        for busid in ["1-2", "12-1.3", "2-1.3.4"] {
+            assert!(looks_like_busid(busid), "{}", busid);
+        }
+        for name in ["probe", "1-", "-2", "1-2.", "a-1", "1-2x", "1.2"] {
+            assert!(!looks_like_busid(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn an_alias_follows_the_device_to_another_port() {
+        let aliases = [alias("stlink", Some("0483:374B"), Some("AAA"), None), alias("other", Some("0483:374b"), Some("BBB"), None)];
+        assert_eq!(resolve(&aliases, &[probe("1-1", "AAA"), probe("2-4", "BBB")], "STLINK").unwrap(), Target::Busid(String::from("1-1")));
+        assert_eq!(resolve(&aliases, &[probe("2-4", "BBB"), probe("3-1", "AAA")], "stlink").unwrap(), Target::Busid(String::from("3-1")));
+        assert_eq!(lookup(&aliases, &probe("3-1", "BBB")).map(|a| a.alias.as_str()), Some("other"));
+    }
+
+    #[test]
+    fn busids_pass_through_unchecked() {
+        assert_eq!(resolve(&[], &[], "9-9").unwrap(), Target::Busid(String::from("9-9")));
+    }
+
+    #[test]
+    fn a_persisted_device_resolves_to_its_guid() {
+        let aliases = [alias("stlink", Some("0483:374b"), None, Some(GUID))];
+        assert_eq!(resolve(&aliases, &[persisted()], "stlink").unwrap(), Target::Guid(String::from(GUID)));
+    }
+
+    #[test]
+    fn resolve_errors() {
+        let aliases = [alias("stlink", Some("0483:374b"), None, None), alias("mouse", Some("046d:c52b"), None, None)];
+        let devices = [probe("1-1", "AAA"), probe("1-2", "BBB")];
+
+        let message = resolve(&aliases, &devices, "stlink").unwrap_err().to_string();
+        assert_eq!(message, "alias 'stlink' matches more than one device: 1-1, 1-2");
+        assert!(matches!(resolve(&aliases, &devices, "mouse"), Err(ResolveError::NotPresent(a)) if a == "mouse"));
+        assert!(matches!(resolve(&aliases, &devices, "keyboard"), Err(ResolveError::Unknown(_))));
+    }
+
+    #[test]
+    fn upsert_replaces_the_same_name_or_device() {
+        let mut aliases = vec![alias("stlink", Some("0483:374b"), Some("AAA"), None), alias("mouse", Some("046d:c52b"), None, None)];
+        upsert(&mut aliases, DeviceAlias::for_device("probe", &probe("1-1", "AAA")));
+        upsert(&mut aliases, alias("MOUSE", Some("046d:c077"), None, None));
+        let names: Vec<&str> = aliases.iter().map(|a| a.alias.as_str()).collect();
+        assert_eq!(names, ["probe", "MOUSE"]);
+        assert_eq!(aliases[0].serial.as_deref(), Some("AAA"));
+    }
+
+    #[test]
+    fn validate_rejects_unusable_entries() {
+        let cases = [
+            (alias("two words", Some("0483:374b"), None, None), "alias", "single word"),
+            (alias("1-4", Some("0483:374b"), None, None), "alias", "mistaken for a busid"),
+            (alias("Probe", Some("1366:0105"), None, None), "alias", "more than once"),
+            (alias("nothing", None, None, None), "vidpid", "needs vidpid or guid"),
+            (alias("serial", None, Some("AAA"), Some(GUID)), "serial", "needs vidpid"),
+        ];
+        for (entry, field, message) in cases {
+            let error = validate(&[alias("probe", Some("0483:374b"), None, None), entry]).unwrap_err();
+            assert_eq!((error.index, error.field), (1, field));
+            assert!(error.message.contains(message), "{}", error.message);
+        }
+        assert!(validate(&[alias("probe", None, None, Some(GUID))]).is_ok());
+    }
+}
@@ -0,0 +1,541 @@
+//! Local control API for scripts and test harnesses.
+//!
+//! Plain HTTP/1.1 with JSON bodies, one request per connection. Every route
+//! except `/openapi.json` needs `Authorization: Bearer <api.token>`, or a
+//! personal token from `[[api.users]]` to bind reserved devices. The
+//! routes are described in `openapi.json` next to this file, which is also
+//! served as-is.
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::agent::{self, Request, UserToken};
+use crate::aliases;
+use crate::config;
+use crate::device_list::{self, UsbipDevice};
+use crate::reservations::{self, Reservations};
+use crate::runner::{CommandError, CommandRunner};
+use crate::version;
+use crate::wsl::{self, Attachments};
+use crate::watcher::{DeviceEvent, Watcher};
+
+pub const API_PORT: u16 = 3243;
+pub const OPENAPI: &str = include_str!("openapi.json");
+
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest request head accepted; requests carry no body worth reading.
+const MAX_HEAD: u64 = 16 * 1024;
+/// Comment lines sent on a quiet event stream, so a client that went away is noticed.
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone)]
+pub struct ApiSettings {
+    pub token: String,
+    pub users: Vec<UserToken>,
+    /// How often `/events` polls usbipd.
+    pub poll_interval: Duration,
+    /// The reservations file binds are checked against.
+    pub reservations: PathBuf,
+    /// Names the WSL distribution of devices attached from this machine.
+    pub wsl_attachments: PathBuf,
+}
+
+impl ApiSettings {
+    pub fn from_config() -> Self {
+        let config = config::current();
+        ApiSettings {
+            token: config.api.token,
+            users: config.api.users,
+            poll_interval: config.watcher.poll_interval(),
+            reservations: reservations::default_path(),
+            wsl_attachments: wsl::default_attachments_path(),
+        }
+    }
+}
+
+/// The parts of a request the routes look at.
+#[derive(Debug, Default)]
+struct HttpRequest {
+    method: String,
+    path: String,
+    query: String,
+    bearer: Option<String>,
+}
+
+fn read_request(stream: &TcpStream) -> io::Result<HttpRequest> {
+    let mut reader = BufReader::new(stream.try_clone()?.take(MAX_HEAD));
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or("").to_string();
+    let target = parts.next().unwrap_or("");
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut request = HttpRequest { method, path: path.to_string(), query: query.to_string(), bearer: None };
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':')
+            && name.trim().eq_ignore_ascii_case("authorization")
+            && let Some((scheme, token)) = value.trim().split_once(' ')
+            && scheme.eq_ignore_ascii_case("bearer")
+        {
+            request.bearer = Some(token.trim().to_string());
+        }
+    }
+    Ok(request)
+}
+
+struct Reply {
+    status: &'static str,
+    body: String,
+}
+
+impl Reply {
+    fn json<T: Serialize>(status: &'static str, value: &T) -> Self {
+        Reply { status, body: serde_json::to_string_pretty(value).unwrap_or_default() }
+    }
+
+    fn error(status: &'static str, message: impl Into<String>) -> Self {
+        Reply::json(status, &json!({ "error": message.into() }))
+    }
+}
+
+fn command_error(e: CommandError) -> Reply {
+    match e {
+        CommandError::Failed(message) => Reply::error("409 Conflict", message),
+        e => Reply::error("503 Service Unavailable", e.to_string()),
+    }
+}
+
+fn write_reply(stream: &mut TcpStream, reply: &Reply, extra_headers: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        reply.status,
+        reply.body.len(),
+        extra_headers,
+        reply.body
+    )?;
+    stream.flush()
+}
+
+fn version_info(runner: &dyn CommandRunner) -> Reply {
+    let usbipd = version::capabilities(runner).version.map(|v| v.to_string());
+    Reply::json("200 OK", &json!({ "app": env!("CARGO_PKG_VERSION"), "usbipd": usbipd }))
+}
+
+/// Binds or unbinds `busid` for `user` and answers with the device as it is afterwards.
+fn change(runner: &dyn CommandRunner, stores: (&Reservations, &Attachments), busid: &str, request: Request, user: &str) -> Reply {
+    let (reservations, attachments) = stores;
+    if !aliases::looks_like_busid(busid) {
+        return Reply::error("400 Bad Request", format!("'{}' is not a busid", busid));
+    }
+    match device_list::list_devices(runner) {
+        Ok(devices) if !devices.iter().any(|d| d.busid == busid) => {
+            return Reply::error("404 Not Found", format!("no device at {}", busid));
+        }
+        Ok(_) => {}
+        Err(e) => return command_error(e),
+    }
+    match agent::apply(runner, reservations, &request, user) {
+        Ok(mut devices) => {
+            attachments.annotate(&mut devices);
+            match devices.into_iter().find(|d| d.busid == busid) {
+                Some(device) => Reply::json("200 OK", &device),
+                None => Reply::error("404 Not Found", format!("{} went away", busid)),
+            }
+        }
+        Err(e) => command_error(e),
+    }
+}
+
+fn query_value<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query.split('&').find_map(|pair| match pair.split_once('=') {
+        Some((key, value)) if key == name => Some(value),
+        None if pair == name => Some(""),
+        _ => None,
+    })
+}
+
+fn query_flag(query: &str, name: &str) -> bool {
+    query_value(query, name).is_some_and(|value| matches!(value, "" | "1" | "true"))
+}
+
+/// Answers `request` for `user`, the owner of the bearer token.
+fn route(runner: &dyn CommandRunner, stores: (&Reservations, &Attachments), request: &HttpRequest, user: &str) -> Reply {
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["version"]) => version_info(runner),
+        ("GET", ["devices"]) => match device_list::list_devices(runner) {
+            Ok(mut devices) => {
+                stores.1.annotate(&mut devices);
+                Reply::json("200 OK", &devices)
+            }
+            Err(e) => command_error(e),
+        },
+        ("POST", ["devices", busid, "bind"]) => {
+            let force = query_flag(&request.query, "force");
+            change(runner, stores, busid, Request::Bind { busid: busid.to_string(), force }, user)
+        }
+        ("POST", ["devices", busid, "unbind"]) => change(runner, stores, busid, Request::Unbind { busid: busid.to_string() }, user),
+        (_, ["version"] | ["devices"] | ["devices", _, "bind" | "unbind"] | ["events"]) => {
+            Reply::error("405 Method Not Allowed", "method not allowed")
+        }
+        _ => Reply::error("404 Not Found", "not found"),
+    }
+}
+
+/// What one `/events` message carries.
+#[derive(Serialize)]
+struct EventData<'a> {
+    device: &'a UsbipDevice,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    old_state: Option<&'static str>,
+}
+
+/// Renders a watcher event as a server-sent event.
+pub fn format_event(event: &DeviceEvent) -> String {
+    let (name, old_state) = match event {
+        DeviceEvent::Added(_) => ("added", None),
+        DeviceEvent::Removed(_) => ("removed", None),
+        DeviceEvent::StateChanged { old, .. } => ("state_changed", Some(old.as_str())),
+    };
+    let data = serde_json::to_string(&EventData { device: event.device(), old_state }).unwrap_or_default();
+    format!("event: {}\ndata: {}\n\n", name, data)
+}
+
+/// What every connection of one [`run`] shares.
+struct Server<R> {
+    settings: ApiSettings,
+    runner: Arc<R>,
+    /// Polls usbipd for all `/events` streams. Started by the first stream
+    /// and kept polling from then on.
+    watcher: OnceLock<Watcher>,
+}
+
+impl<R> Server<R>
+where
+    R: CommandRunner + Send + Sync + 'static,
+{
+    fn watcher(&self) -> &Watcher {
+        self.watcher.get_or_init(|| {
+            let runner = self.runner.clone();
+            Watcher::start(self.settings.poll_interval, move || device_list::list_devices(runner.as_ref()))
+        })
+    }
+}
+
+/// Streams watcher events until the client goes away. The watcher replays
+/// its snapshot to new subscribers, so every stream starts with an `added`
+/// event for each current device.
+fn stream_events<R>(mut stream: TcpStream, server: &Server<R>) -> io::Result<()>
+where
+    R: CommandRunner + Send + Sync + 'static,
+{
+    stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n")?;
+    stream.flush()?;
+    let events = server.watcher().subscribe();
+    loop {
+        let message = match events.recv_timeout(KEEPALIVE) {
+            Ok(event) => format_event(&event),
+            Err(RecvTimeoutError::Timeout) => String::from(": keepalive\n\n"),
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        stream.write_all(message.as_bytes())?;
+        stream.flush()?;
+    }
+}
+
+fn serve_connection<R>(mut stream: TcpStream, server: &Server<R>) -> io::Result<()>
+where
+    R: CommandRunner + Send + Sync + 'static,
+{
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let request = read_request(&stream)?;
+    if request.method == "GET" && request.path == "/openapi.json" {
+        let reply = Reply { status: "200 OK", body: OPENAPI.to_string() };
+        return write_reply(&mut stream, &reply, "");
+    }
+    let settings = &server.settings;
+    let Some(user) = request.bearer.as_deref().and_then(|bearer| agent::identify(bearer, &settings.token, &settings.users)) else {
+        let reply = Reply::error("401 Unauthorized", "missing or wrong bearer token");
+        return write_reply(&mut stream, &reply, "WWW-Authenticate: Bearer\r\n");
+    };
+    if request.method == "GET" && request.path.trim_end_matches('/') == "/events" {
+        return stream_events(stream, server);
+    }
+    let reservations = Reservations::at(settings.reservations.clone());
+    let attachments = Attachments::at(settings.wsl_attachments.clone());
+    let reply = route(server.runner.as_ref(), (&reservations, &attachments), &request, &user);
+    write_reply(&mut stream, &reply, "")
+}
+
+/// Answers requests on `listener`, one thread per connection. Doesn't return
+/// unless accepting fails.
+pub fn run<R>(listener: TcpListener, settings: ApiSettings, runner: Arc<R>) -> io::Result<()>
+where
+    R: CommandRunner + Send + Sync + 'static,
+{
+    let server = Arc::new(Server { settings, runner, watcher: OnceLock::new() });
+    loop {
+        let (stream, _) = listener.accept()?;
+        let server = server.clone();
+        thread::spawn(move || {
+            let _ = serve_connection(stream, &server);
+        });
+    }
+}
+
+/// [`run`] on a background thread.
+pub fn serve<R>(listener: TcpListener, settings: ApiSettings, runner: Arc<R>)
+where
+    R: CommandRunner + Send + Sync + 'static,
+{
+    thread::spawn(move || run(listener, settings, runner));
+}
+
+/// Starts the API if `api.enabled` is set in the config file.
+pub fn serve_configured<R>(runner: Arc<R>) -> io::Result<()>
+where
+    R: CommandRunner + Send + Sync + 'static,
+{
+    let api = config::current().api;
+    if !api.enabled {
+        return Ok(());
+    }
+    serve(TcpListener::bind(&api.listen)?, ApiSettings::from_config(), runner);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_list::DeviceState;
+    use crate::agent::UserToken;
+    use crate::reservations::RESERVATIONS_FILE;
+    use crate::testing::{self, FakeRunner, TempDir};
+
+    const TOKEN: &str = "api-token";
+
+    fn runner() -> FakeRunner {
+        let devices = [testing::device("1-1", "0483:374b", DeviceState::Shared), testing::device("1-2", "046d:c52b", DeviceState::NotShared)];
+        FakeRunner::new().ok("usbipd state", &testing::state_json(&devices)).ok("usbipd bind", "").ok("usbipd unbind", "")
+    }
+
+    /// The API on a loopback port, with a reservations file in the returned
+    /// directory. Its `/events` watcher polls once a minute, so a test sees
+    /// only the first poll.
+    fn api_with_users(token: &str, users: Vec<UserToken>, runner: Arc<FakeRunner>) -> (String, TempDir) {
+        let dir = TempDir::new("api");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let settings = ApiSettings {
+            token: token.to_string(),
+            users,
+            poll_interval: Duration::from_secs(60),
+            reservations: dir.join(RESERVATIONS_FILE),
+            wsl_attachments: dir.join(wsl::ATTACHMENTS_FILE),
+        };
+        serve(listener, settings, runner);
+        (address, dir)
+    }
+
+    fn api(token: &str, runner: Arc<FakeRunner>) -> (String, TempDir) {
+        api_with_users(token, Vec::new(), runner)
+    }
+
+    fn connect(address: &str, head: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        write!(stream, "{}\r\n\r\n", head).unwrap();
+        stream
+    }
+
+    /// Sends a request and returns the status line, the headers and the body.
+    fn send(address: &str, head: &str) -> (String, String, String) {
+        let mut response = String::new();
+        connect(address, head).read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let (status, headers) = head.split_once("\r\n").unwrap();
+        (status.to_string(), headers.to_string(), body.to_string())
+    }
+
+    fn authorized(address: &str, request: &str) -> (String, serde_json::Value) {
+        let (status, _, body) = send(address, &format!("{} HTTP/1.1\r\nAuthorization: Bearer {}", request, TOKEN));
+        (status, serde_json::from_str(&body).unwrap())
+    }
+
+    /// Reads one server-sent event, skipping keepalives.
+    fn next_event(reader: &mut BufReader<TcpStream>) -> String {
+        let mut event = String::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "\n" && !event.is_empty() {
+                return event;
+            }
+            if !line.starts_with(':') && line != "\n" {
+                event.push_str(&line);
+            }
+        }
+    }
+
+    fn events(address: &str) -> BufReader<TcpStream> {
+        let mut reader = BufReader::new(connect(address, &format!("GET /events HTTP/1.1\r\nAuthorization: Bearer {}", TOKEN)));
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "HTTP/1.1 200 OK\r\n");
+        while line != "\r\n" {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+        }
+        reader
+    }
+
+    #[test]
+    fn requests_without_the_right_token_are_unauthorized() {
+        let runner = Arc::new(runner());
+        let (address, _dir) = api(TOKEN, runner.clone());
+        for head in [
+            "GET /devices HTTP/1.1",
+            "GET /devices HTTP/1.1\r\nAuthorization: Bearer guess",
+            "GET /devices HTTP/1.1\r\nAuthorization: Basic YXBpLXRva2Vu",
+            "POST /devices/1-1/unbind HTTP/1.1\r\nAuthorization: Bearer api-token-2",
+            "GET /events HTTP/1.1",
+        ] {
+            let (status, headers, body) = send(&address, head);
+            assert_eq!(status, "HTTP/1.1 401 Unauthorized", "{}", head);
+            assert!(headers.contains("WWW-Authenticate: Bearer\r\n"));
+            assert!(body.contains("missing or wrong bearer token"));
+        }
+        assert!(runner.calls_to("usbipd").is_empty());
+    }
+
+    #[test]
+    fn an_empty_token_locks_the_api() {
+        let (address, _dir) = api("", Arc::new(runner()));
+        let (status, _, _) = send(&address, "GET /devices HTTP/1.1\r\nAuthorization: Bearer ");
+        assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+    }
+
+    #[test]
+    fn openapi_needs_no_token() {
+        let (address, _dir) = api(TOKEN, Arc::new(runner()));
+        let (status, _, body) = send(&address, "GET /openapi.json HTTP/1.1");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body, OPENAPI);
+    }
+
+    #[test]
+    fn devices_lists_usbipd_state() {
+        let (address, _dir) = api(TOKEN, Arc::new(runner()));
+        let (status, devices) = authorized(&address, "GET /devices");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(devices[0]["busid"], "1-1");
+        assert_eq!(devices[1]["state"], "not_shared");
+    }
+
+    #[test]
+    fn bind_and_unbind_answer_with_the_device() {
+        let runner = Arc::new(runner());
+        let (address, _dir) = api(TOKEN, runner.clone());
+        let (status, device) = authorized(&address, "POST /devices/1-2/bind?force=1");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(device["busid"], "1-2");
+        let (status, _) = authorized(&address, "POST /devices/1-1/unbind");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(runner.calls_to("usbipd bind"), ["usbipd bind --force --busid 1-2"]);
+        assert_eq!(runner.calls_to("usbipd unbind"), ["usbipd unbind --busid 1-1"]);
+    }
+
+    #[test]
+    fn reserved_devices_are_only_bound_for_their_holder() {
+        let runner = Arc::new(runner());
+        let users = vec![UserToken { user: "ana".into(), token: "ana-personal-token".into() }];
+        let (address, dir) = api_with_users(TOKEN, users, runner.clone());
+        Reservations::at(dir.join(RESERVATIONS_FILE)).reserve("1-2", "ana", Duration::from_secs(3600)).unwrap();
+
+        let (status, body) = authorized(&address, "POST /devices/1-2/bind");
+        assert_eq!(status, "HTTP/1.1 409 Conflict");
+        assert!(body["error"].as_str().unwrap().contains("reserved by ana"), "{}", body);
+        assert!(runner.calls_to("usbipd bind").is_empty());
+
+        let (status, _, _) = send(&address, "POST /devices/1-2/bind HTTP/1.1\r\nAuthorization: Bearer ana-personal-token");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(runner.calls_to("usbipd bind"), ["usbipd bind --busid 1-2"]);
+    }
+
+    #[test]
+    fn bind_checks_the_busid_and_the_method() {
+        let runner = Arc::new(runner().fail("usbipd unbind", 1, "", "usbipd: error: Access denied."));
+        let (address, _dir) = api(TOKEN, runner.clone());
+        let (status, body) = authorized(&address, "POST /devices/probe/bind");
+        assert_eq!(status, "HTTP/1.1 400 Bad Request");
+        assert_eq!(body["error"], "'probe' is not a busid");
+        let (status, _) = authorized(&address, "POST /devices/9-9/bind");
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+        let (status, _) = authorized(&address, "GET /devices/1-1/bind");
+        assert_eq!(status, "HTTP/1.1 405 Method Not Allowed");
+        let (status, _) = authorized(&address, "GET /nothing");
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+        let (status, _) = authorized(&address, "POST /devices/1-1/unbind");
+        assert_eq!(status, "HTTP/1.1 409 Conflict");
+        assert!(runner.calls_to("usbipd bind").is_empty());
+    }
+
+    #[test]
+    fn query_values_and_flags() {
+        assert_eq!(query_value("force&busid=1-1", "busid"), Some("1-1"));
+        assert_eq!(query_value("force&busid=1-1", "force"), Some(""));
+        assert_eq!(query_value("force=0", "busid"), None);
+        assert!(query_flag("force", "force"));
+        assert!(query_flag("force=true", "force"));
+        assert!(!query_flag("force=0", "force"));
+        assert!(!query_flag("", "force"));
+    }
+
+    #[test]
+    fn format_event_frames_each_kind() {
+        let device = testing::device("1-1", "0483:374b", DeviceState::Shared);
+        let added = format_event(&DeviceEvent::Added(device.clone()));
+        assert!(added.starts_with("event: added\ndata: {\"device\":{\"busid\":\"1-1\","), "{}", added);
+        assert!(added.ends_with("}}\n\n"));
+        assert!(!added.contains("old_state"));
+        assert_eq!(added.matches('\n').count(), 3);
+
+        let removed = format_event(&DeviceEvent::Removed(device.clone()));
+        assert!(removed.starts_with("event: removed\ndata: "));
+
+        let changed = format_event(&DeviceEvent::StateChanged { device, old: DeviceState::NotShared });
+        assert!(changed.starts_with("event: state_changed\ndata: "));
+        assert!(changed.ends_with(",\"old_state\":\"not_shared\"}\n\n"), "{}", changed);
+    }
+
+    #[test]
+    fn event_streams_share_one_watcher() {
+        let runner = Arc::new(runner());
+        let (address, _dir) = api(TOKEN, runner.clone());
+
+        let mut first = events(&address);
+        let mut second = events(&address);
+        for reader in [&mut first, &mut second] {
+            let added: Vec<String> = (0..2).map(|_| next_event(reader)).collect();
+            assert!(added[0].starts_with("event: added\ndata: {\"device\":{\"busid\":\"1-1\""), "{}", added[0]);
+            assert!(added[1].starts_with("event: added\ndata: {\"device\":{\"busid\":\"1-2\""), "{}", added[1]);
+        }
+        // A third stream after the first poll gets the same devices replayed.
+        let mut third = events(&address);
+        assert!(next_event(&mut third).contains("\"busid\":\"1-1\""));
+        assert_eq!(runner.calls_to("usbipd state").len(), 1);
+    }
+}
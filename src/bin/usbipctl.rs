@@ -0,0 +1,15 @@
+//! Command-line interface for scripting what the GUI does.
+use std::io;
+
+use usb_ip_host::runner::SystemRunner;
+use usb_ip_host::{cli, config};
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    // Keep going on the defaults so `usbipctl config check` can still report the problem.
+    if let Err(e) = config::init() {
+        eprintln!("warning: {}: {}", config::config_path().display(), e);
+    }
+    let code = cli::run(&args, &SystemRunner, &cli::Context::current(), &mut io::stdout(), &mut io::stderr());
+    std::process::exit(code);
+}
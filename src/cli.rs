@@ -0,0 +1,1472 @@
+//! Headless front-end used by `usbipctl`. Every subcommand calls the same core
+//! functions as the GUI menu handlers.
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::aliases::{self, DeviceAlias, Target};
+use crate::device_list::{self, UsbipDevice};
+use crate::installer::InstallError;
+use crate::linux_client::{Companion, PortRecord, VhciPort};
+use crate::package_manager::PackageState;
+use crate::runner::{CommandError, CommandRunner, SystemRunner};
+use crate::version::{Capability, Version};
+use crate::updates::{Product, UpdateChecker, UpdateError};
+use crate::agent::AgentSettings;
+use crate::api::ApiSettings;
+use crate::client_scripts::{self, ClientTarget, ScriptKind};
+use crate::fleet::{Fleet, FleetError};
+use crate::reservations::{Outcome, ReservationError, Reservations};
+use crate::scanner::ScanCache;
+use crate::{agent, api, config, firewall, leases, linux_firewall, mdns, policy, reservations, reverse, rules, scanner, service, stats, updates, version, wsl};
+
+pub const EXIT_OK: i32 = 0;
+/// The operation ran but failed.
+pub const EXIT_FAILURE: i32 = 1;
+/// Bad arguments.
+pub const EXIT_USAGE: i32 = 2;
+/// A required program (usbipd, netsh) or every package manager is missing.
+pub const EXIT_NOT_FOUND: i32 = 3;
+/// The operation needs administrator rights.
+pub const EXIT_NOT_ELEVATED: i32 = 4;
+
+pub const USAGE: &str = "\
+Usage: usbipctl <command> [options]
+
+Commands:
+  list [--json|--csv]                 List connected and persisted devices
+  bind <busid> [--force]              Share a device
+  unbind <busid> | --guid <guid>      Stop sharing a device
+  persisted [--json|--csv]            List persisted devices only
+  version [--json]                    Show application and usbipd versions
+  updates [--json] [--refresh]        Check the release feeds for newer versions
+  firewall ensure|remove|status [--json]
+                                      Manage the USB/IP firewall rule
+  service install|upgrade|uninstall   Manage the usbipd-win package
+  service status [--json]             Show whether usbipd-win is installed or outdated
+  scan <cidr> [--json]                Probe a subnet for USB/IP servers
+  browse [--json]                     List hosts advertised over mDNS
+  reverse host <rendezvous>           Dial out to a rendezvous listener
+  reverse rendezvous [<host-listen>]  Accept reverse connections from hosts
+  rules dry-run [--json]              Show which devices each auto-share rule matches
+  config path|show|check              Locate, print or validate the config file
+  alias list [--json]                 List device aliases and notes
+  alias set <device> <name> [--note <text>]
+                                      Name a device by busid, GUID or alias
+  alias note <name> <text>            Attach a note to an aliased device
+  alias remove <name>                 Forget an alias
+  policy list [--json]                List usbipd policy rules and the devices they cover
+  policy add <busid> [--hardware-id] [--deny]
+                                      Allow (or deny) auto-binding a device
+  policy remove <guid> | --all        Remove policy rules
+  wsl list [--json]                   List WSL distributions
+  wsl attach <busid> [--distribution <name>]
+                                      Attach a shared device to WSL
+  wsl detach <busid>                  Detach a device from WSL
+  client <busid> [--format shell|systemd|powershell] [--host <address>]
+                                      Print the commands a client runs to attach a device
+  client <busid> --export <dir> [--host <address>]
+                                      Write all client scripts into a directory
+  companion run                       Keep the [client] targets attached (Linux)
+  companion once [--json]             Attach the [client] targets once and report
+  companion ports [--json]            List attached vhci ports and their remote devices
+  agent [<listen>]                    Serve this host's devices to other machines
+  fleet list [--json]                 List devices of this machine and every [[hosts]] agent
+  fleet health [--json]               Show whether each host's agent answers
+  fleet bind <host> <busid> [--force]
+                                      Share a device on a host; \"local\" is this machine
+  fleet unbind <host> <busid>         Stop sharing a device on a host
+  reserve <busid> [--for <minutes>] [--user <name>]
+                                      Reserve a device, or queue for it if someone else has it;
+                                      only administrators can reserve for another --user
+  release <busid>                     Give up your reservation or place in its queue
+  release <busid> --force             End a reservation whoever holds it
+  reservations [--json]               List reservations and who is waiting
+  leases run [--dry-run] [--json]     Detach or unbind devices past their [leases] limits
+  api [<listen>]                      Serve the local control API (see api.token)
+  api openapi                         Print the control API's OpenAPI description
+
+Wherever a <busid> is expected, an alias can be given instead.
+";
+
+#[derive(Debug)]
+pub struct CliError {
+    pub code: i32,
+    pub message: String,
+}
+
+impl CliError {
+    fn usage(message: impl Into<String>) -> Self {
+        CliError { code: EXIT_USAGE, message: message.into() }
+    }
+
+    fn failure(message: impl Into<String>) -> Self {
+        CliError { code: EXIT_FAILURE, message: message.into() }
+    }
+}
+
+impl From<CommandError> for CliError {
+    fn from(e: CommandError) -> Self {
+        let code = match e {
+            CommandError::NotFound(_) => EXIT_NOT_FOUND,
+            _ => EXIT_FAILURE,
+        };
+        CliError { code, message: e.to_string() }
+    }
+}
+
+impl From<InstallError> for CliError {
+    fn from(e: InstallError) -> Self {
+        match e {
+            InstallError::Command(e) => e.into(),
+            InstallError::NoPackageManager(_) => CliError { code: EXIT_NOT_FOUND, message: e.to_string() },
+            e => CliError::failure(e.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Text,
+    Json,
+    Csv,
+}
+
+/// Who runs the commands and where they keep local state.
+pub struct Context {
+    /// The reservations file binds are checked against.
+    pub reservations: PathBuf,
+    /// Where WSL attachments made here are remembered.
+    pub wsl_attachments: PathBuf,
+    /// The account reservations are made, released and checked for.
+    pub user: String,
+    /// Whether the caller may act for other users with `reserve --user`.
+    pub admin: bool,
+}
+
+impl Context {
+    /// The config directory's files, for the account running this process.
+    pub fn current() -> Self {
+        Context {
+            reservations: reservations::default_path(),
+            wsl_attachments: wsl::default_attachments_path(),
+            user: reservations::current_user(),
+            admin: is_admin(),
+        }
+    }
+}
+
+/// Administrator rights on Windows, root elsewhere.
+fn is_admin() -> bool {
+    #[cfg(windows)]
+    return crate::windows::is_app_elevated();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        // /proc/self belongs to the process's effective user.
+        std::fs::metadata("/proc/self").is_ok_and(|m| m.uid() == 0)
+    }
+    #[cfg(not(any(windows, unix)))]
+    false
+}
+
+/// Runs one command line (without the program name) and returns the exit code.
+pub fn run(args: &[String], runner: &dyn CommandRunner, context: &Context, out: &mut dyn Write, err: &mut dyn Write) -> i32 {
+    match execute(args, runner, context, out) {
+        Ok(()) => EXIT_OK,
+        Err(e) => {
+            let _ = writeln!(err, "error: {}", e.message);
+            if e.code == EXIT_USAGE {
+                let _ = write!(err, "\n{}", USAGE);
+            }
+            e.code
+        }
+    }
+}
+
+fn execute(args: &[String], runner: &dyn CommandRunner, context: &Context, out: &mut dyn Write) -> Result<(), CliError> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let (command, rest) = match args.split_first() {
+        Some((command, rest)) => (*command, rest),
+        None => return Err(CliError::usage("missing command")),
+    };
+
+    match command {
+        "list" => list(runner, context, out, format(rest)?, false),
+        "persisted" => list(runner, context, out, format(rest)?, true),
+        "bind" => bind(runner, context, rest),
+        "unbind" => unbind(runner, rest),
+        "version" => version(runner, out, format(rest)?),
+        "updates" => updates(runner, out, rest),
+        "firewall" => firewall(runner, out, rest),
+        "service" => service(runner, out, rest),
+        "scan" => scan(out, rest),
+        "browse" => browse(out, format(rest)?),
+        "reverse" => reverse(rest),
+        "rules" => rules(runner, out, rest),
+        "config" => config(out, rest),
+        "alias" => alias(runner, out, rest),
+        "policy" => policy(runner, out, rest),
+        "wsl" => wsl(runner, context, out, rest),
+        "client" => client(runner, out, rest),
+        "companion" => companion(runner, out, rest),
+        "agent" => agent(context, rest),
+        "fleet" => fleet(runner, context, out, rest),
+        "api" => api(context, out, rest),
+        "reserve" => reserve(runner, context, out, rest),
+        "release" => release(runner, context, out, rest),
+        "reservations" => list_reservations(context, out, format(rest)?),
+        "leases" => leases(runner, context, out, rest),
+        "help" | "--help" | "-h" => write(out, USAGE),
+        other => Err(CliError::usage(format!("unknown command '{}'", other))),
+    }
+}
+
+fn format(args: &[&str]) -> Result<Format, CliError> {
+    match args {
+        [] => Ok(Format::Text),
+        ["--json"] => Ok(Format::Json),
+        ["--csv"] => Ok(Format::Csv),
+        _ => Err(CliError::usage(format!("unexpected arguments: {}", args.join(" ")))),
+    }
+}
+
+fn write(out: &mut dyn Write, text: &str) -> Result<(), CliError> {
+    out.write_all(text.as_bytes()).map_err(|e| CliError::failure(e.to_string()))
+}
+
+fn write_json<T: Serialize + ?Sized>(out: &mut dyn Write, value: &T) -> Result<(), CliError> {
+    let json = serde_json::to_string_pretty(value).map_err(|e| CliError::failure(e.to_string()))?;
+    write(out, &format!("{}\n", json))
+}
+
+/// Fails with [`EXIT_NOT_ELEVATED`] if a mutating command runs without admin rights.
+fn require_elevation() -> Result<(), CliError> {
+    #[cfg(windows)]
+    if !crate::windows::is_app_elevated() {
+        return Err(CliError {
+            code: EXIT_NOT_ELEVATED,
+            message: String::from("Administrator rights needed!"),
+        });
+    }
+    Ok(())
+}
+
+/// Resolves a busid or alias argument.
+///
+/// Only lists devices when it has to, so plain busids cost no extra usbipd call.
+fn resolve_device(runner: &dyn CommandRunner, name: &str) -> Result<Target, CliError> {
+    if aliases::looks_like_busid(name) {
+        return Ok(Target::Busid(name.to_string()));
+    }
+    let devices = device_list::list_devices(runner)?;
+    aliases::resolve(&aliases::current(), &devices, name).map_err(|e| CliError::failure(e.to_string()))
+}
+
+#[derive(Serialize)]
+struct ListedDevice<'a> {
+    #[serde(flatten)]
+    device: &'a UsbipDevice,
+    alias: Option<&'a str>,
+    note: Option<&'a str>,
+}
+
+fn list(runner: &dyn CommandRunner, context: &Context, out: &mut dyn Write, format: Format, persisted_only: bool) -> Result<(), CliError> {
+    let known = aliases::current();
+    let mut devices: Vec<UsbipDevice> = device_list::list_devices(runner)?
+        .into_iter()
+        .filter(|d| !persisted_only || d.persisted)
+        .collect();
+    wsl::Attachments::at(context.wsl_attachments.clone()).annotate(&mut devices);
+    let devices: Vec<ListedDevice> = devices
+        .iter()
+        .map(|device| {
+            let alias = aliases::lookup(&known, device);
+            ListedDevice {
+                device,
+                alias: alias.map(|a| a.alias.as_str()),
+                note: alias.map(|a| a.note.as_str()).filter(|n| !n.is_empty()),
+            }
+        })
+        .collect();
+
+    match format {
+        Format::Json => write_json(out, &devices),
+        Format::Csv => {
+            let mut text = String::from("busid,vidpid,device,state,persisted,guid,alias,note,wsl_distribution\n");
+            for listed in &devices {
+                let d = listed.device;
+                let row = [
+                    d.busid.as_str(),
+                    d.vidpid.as_str(),
+                    d.device.as_str(),
+                    d.state.as_str(),
+                    if d.persisted { "true" } else { "false" },
+                    d.guid.as_deref().unwrap_or(""),
+                    listed.alias.unwrap_or(""),
+                    listed.note.unwrap_or(""),
+                    d.wsl_distribution.as_deref().unwrap_or(""),
+                ];
+                let row: Vec<String> = row.iter().map(|field| csv_field(field)).collect();
+                text.push_str(&row.join(","));
+                text.push('\n');
+            }
+            write(out, &text)
+        }
+        Format::Text => {
+            let mut text = format!("{:<12} {:<16} {:<10} {:<50} {}\n", "BUSID", "ALIAS", "VID:PID", "DEVICE", "STATE");
+            for listed in &devices {
+                let d = listed.device;
+                let alias = listed.alias.unwrap_or("");
+                text.push_str(&format!(
+                    "{:<12} {:<16} {:<10} {:<50} {}\n",
+                    d.display_id(),
+                    alias,
+                    d.vidpid,
+                    d.device,
+                    d.state_label()
+                ));
+            }
+            write(out, &text)
+        }
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn bind(runner: &dyn CommandRunner, context: &Context, args: &[&str]) -> Result<(), CliError> {
+    let (busid, force) = match args {
+        [busid] => (*busid, false),
+        [busid, "--force"] | ["--force", busid] => (*busid, true),
+        _ => return Err(CliError::usage("usage: bind <busid> [--force]")),
+    };
+    require_elevation()?;
+    match resolve_device(runner, busid)? {
+        Target::Busid(busid) => {
+            Reservations::at(context.reservations.clone()).check(&busid, &context.user)?;
+            device_list::bind_device(runner, &busid, force)?
+        }
+        Target::Guid(_) => return Err(CliError::failure(format!("'{}' is not connected", busid))),
+    }
+    Ok(())
+}
+
+fn unbind(runner: &dyn CommandRunner, args: &[&str]) -> Result<(), CliError> {
+    match args {
+        ["--guid", guid] => {
+            require_elevation()?;
+            device_list::unbind_guid(runner, guid)?;
+        }
+        [busid] if !busid.starts_with('-') => {
+            require_elevation()?;
+            match resolve_device(runner, busid)? {
+                Target::Busid(busid) => device_list::unbind_device(runner, &busid)?,
+                Target::Guid(guid) => device_list::unbind_guid(runner, &guid)?,
+            }
+        }
+        _ => return Err(CliError::usage("usage: unbind <busid> | --guid <guid>")),
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Versions {
+    application: &'static str,
+    usbipd: Version,
+    capabilities: Vec<Capability>,
+    warning: Option<String>,
+}
+
+fn version(runner: &dyn CommandRunner, out: &mut dyn Write, format: Format) -> Result<(), CliError> {
+    let usbipd = service::usbipd_version(runner)?;
+    let versions = Versions {
+        application: env!("CARGO_PKG_VERSION"),
+        capabilities: version::capabilities(runner).supported(),
+        warning: version::minimum_warning(&usbipd),
+        usbipd,
+    };
+    if format == Format::Json {
+        return write_json(out, &versions);
+    }
+    let mut text = format!("usbipctl {}\nusbipd-win {}\n", versions.application, versions.usbipd.full());
+    for (capability, _) in version::CAPABILITIES {
+        let mark = if versions.capabilities.contains(&capability) { "yes" } else { "no" };
+        text.push_str(&format!("  {:<24} {}\n", capability.command(), mark));
+    }
+    if let Some(warning) = &versions.warning {
+        text.push_str(&format!("warning: {}\n", warning));
+    }
+    write(out, &text)
+}
+
+fn updates(runner: &dyn CommandRunner, out: &mut dyn Write, args: &[&str]) -> Result<(), CliError> {
+    let refresh = args.contains(&"--refresh");
+    let rest: Vec<&str> = args.iter().copied().filter(|a| *a != "--refresh").collect();
+    let format = format(&rest)?;
+    let checker = UpdateChecker::from_config(runner).refresh(refresh);
+    let mut results = Vec::new();
+    for product in [Product::Usbipd, Product::App] {
+        match checker.check(product) {
+            Ok(info) => results.push(info),
+            Err(UpdateError::NoFeed(_)) => {}
+            Err(UpdateError::Version(e)) => return Err(e.into()),
+            Err(e) => return Err(CliError::failure(e.to_string())),
+        }
+    }
+    if format == Format::Json {
+        return write_json(out, &results);
+    }
+    let mut text = String::new();
+    for info in &results {
+        match &info.latest {
+            Some(release) if info.available() => {
+                text.push_str(&format!("{}: {} -> {}  {}\n", info.product, info.current, release.version, release.url));
+                for line in updates::changelog_excerpt(&release.notes, 12).lines() {
+                    text.push_str(&format!("    {}\n", line));
+                }
+            }
+            _ => text.push_str(&format!("{}: {} (up to date)\n", info.product, info.current)),
+        }
+        if info.stale {
+            text.push_str("    (feed unreachable, showing cached result)\n");
+        }
+    }
+    write(out, &text)
+}
+
+fn firewall(runner: &dyn CommandRunner, out: &mut dyn Write, args: &[&str]) -> Result<(), CliError> {
+    if !cfg!(windows) {
+        return linux_firewall(runner, out, args);
+    }
+    let manager = firewall::FirewallManager::from_config(runner);
+    match args {
+        ["status", rest @ ..] => {
+            let status = manager.status()?;
+            if format(rest)? == Format::Json {
+                return write_json(out, &status);
+            }
+            match status {
+                firewall::RuleStatus::Missing => write(out, "absent\n"),
+                firewall::RuleStatus::Ok { .. } => write(out, "present\n"),
+                firewall::RuleStatus::Unverified { .. } => write(out, "present (settings not checked: netsh output is not in English)\n"),
+                firewall::RuleStatus::Drifted { drift, .. } => {
+                    let mut text = String::from("drifted\n");
+                    for d in &drift {
+                        text.push_str(&format!("  {}\n", d));
+                    }
+                    write(out, &text)
+                }
+            }
+        }
+        ["ensure"] => {
+            require_elevation()?;
+            let text = match manager.ensure()? {
+                firewall::EnsureOutcome::Unchanged => "unchanged\n",
+                firewall::EnsureOutcome::Added => "added\n",
+                firewall::EnsureOutcome::Repaired => "repaired\n",
+            };
+            write(out, text)
+        }
+        ["remove"] => {
+            require_elevation()?;
+            let removed = manager.remove()?;
+            write(out, if removed { "removed\n" } else { "unchanged\n" })
+        }
+        _ => Err(CliError::usage("usage: firewall ensure|remove|status [--json]")),
+    }
+}
+
+fn linux_firewall(runner: &dyn CommandRunner, out: &mut dyn Write, args: &[&str]) -> Result<(), CliError> {
+    let manager = linux_firewall::LinuxFirewall::from_config(runner);
+    match args {
+        ["status", rest @ ..] => {
+            let status = manager.status()?;
+            if format(rest)? == Format::Json {
+                return write_json(out, &status);
+            }
+            let mut text = match status.state {
+                linux_firewall::RuleState::NotNeeded => String::from("not needed (no input filtering)\n"),
+                linux_firewall::RuleState::Missing => String::from("absent\n"),
+                linux_firewall::RuleState::Ok => String::from("present\n"),
+                linux_firewall::RuleState::Drifted => String::from("drifted\n"),
+            };
+            for d in &status.drift {
+                text.push_str(&format!("  {}\n", d));
+            }
+            text.push_str(&format!("backend: {}\n", status.backend));
+            write(out, &text)
+        }
+        ["ensure"] => {
+            let text = match manager.ensure()? {
+                firewall::EnsureOutcome::Unchanged => "unchanged\n",
+                firewall::EnsureOutcome::Added => "added\n",
+                firewall::EnsureOutcome::Repaired => "repaired\n",
+            };
+            write(out, text)
+        }
+        ["remove"] => {
+            let removed = manager.remove()?;
+            write(out, if removed { "removed\n" } else { "unchanged\n" })
+        }
+        _ => Err(CliError::usage("usage: firewall ensure|remove|status [--json]")),
+    }
+}
+
+fn service(runner: &dyn CommandRunner, out: &mut dyn Write, args: &[&str]) -> Result<(), CliError> {
+    let action = match args {
+        ["status", rest @ ..] => {
+            let state = service::package_state(runner)?;
+            if format(rest)? == Format::Json {
+                return write_json(out, &state);
+            }
+            let mut text = format!("{}\n", state);
+            if state == PackageState::NotInstalled
+                && let Some(available) = service::available_version(runner)?
+            {
+                text.push_str(&format!("winget offers version {}.\n", available));
+            }
+            return write(out, &text);
+        }
+        [action @ ("install" | "upgrade" | "uninstall")] => *action,
+        _ => return Err(CliError::usage("usage: service install|upgrade|uninstall|status [--json]")),
+    };
+    require_elevation()?;
+
+    let transition = match action {
+        "install" => service::install_usbipd(runner)?,
+        "upgrade" => service::upgrade_usbipd(runner)?,
+        _ => service::uninstall_usbipd(runner)?,
+    };
+    write(out, &format!("{}\n", transition.message()))
+}
+
+#[derive(Serialize)]
+struct ScannedServer {
+    address: SocketAddr,
+    /// False for a server only known from an earlier scan.
+    answered: bool,
+    /// Seconds since the Unix epoch.
+    last_seen: u64,
+    devices: Vec<ScannedDevice>,
+}
+
+#[derive(Serialize)]
+struct ScannedDevice {
+    busid: String,
+    vidpid: String,
+}
+
+fn scan(out: &mut dyn Write, args: &[&str]) -> Result<(), CliError> {
+    let (cidr, format) = match args {
+        [cidr] => (*cidr, Format::Text),
+        [cidr, "--json"] => (*cidr, Format::Json),
+        _ => return Err(CliError::usage("usage: scan <cidr> [--json]")),
+    };
+    let settings = scanner::ScanConfig::default();
+    let targets = scanner::targets(cidr, settings.port).map_err(CliError::usage)?;
+    let found = scanner::scan_targets(&targets, &settings);
+
+    let path = config::config_dir().join(scanner::CACHE_FILE);
+    let mut cache = ScanCache::load(&path);
+    cache.update(found.clone());
+    cache.prune(scanner::CACHE_MAX_AGE);
+    // Not being able to cache only means servers that stop answering aren't listed next time.
+    let _ = cache.save(&path);
+
+    let servers: Vec<ScannedServer> = cache
+        .servers()
+        .into_iter()
+        .filter(|info| targets.contains(&info.addr))
+        .map(|info| ScannedServer {
+            address: info.addr,
+            answered: found.iter().any(|f| f.addr == info.addr),
+            last_seen: info.last_seen.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            devices: info
+                .devices
+                .iter()
+                .map(|d| ScannedDevice { busid: d.busid.clone(), vidpid: d.vidpid() })
+                .collect(),
+        })
+        .collect();
+
+    if format == Format::Json {
+        return write_json(out, &servers);
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let mut text = String::new();
+    for server in &servers {
+        if server.answered {
+            text.push_str(&format!("{}\n", server.address));
+        } else {
+            let age = Duration::from_secs(now.saturating_sub(server.last_seen));
+            text.push_str(&format!("{} (not answering, last seen {} ago)\n", server.address, scanner::format_age(age)));
+        }
+        for d in &server.devices {
+            text.push_str(&format!("  {:<12} {}\n", d.busid, d.vidpid));
+        }
+    }
+    write(out, &text)
+}
+
+#[derive(Serialize)]
+struct BrowsedHost {
+    instance: String,
+    hostname: String,
+    addresses: Vec<String>,
+    port: u16,
+    devices: Vec<BrowsedDevice>,
+}
+
+#[derive(Serialize)]
+struct BrowsedDevice {
+    busid: String,
+    vidpid: String,
+    description: String,
+}
+
+fn browse(out: &mut dyn Write, format: Format) -> Result<(), CliError> {
+    let hosts = mdns::browse(&mdns::MdnsConfig::default(), Duration::from_secs(2))
+        .map_err(|e| CliError::failure(e.to_string()))?;
+    let hosts: Vec<BrowsedHost> = hosts
+        .into_iter()
+        .map(|h| BrowsedHost {
+            instance: h.instance,
+            hostname: h.hostname,
+            addresses: h.addresses.iter().map(|a| a.to_string()).collect(),
+            port: h.port,
+            devices: h
+                .devices
+                .into_iter()
+                .map(|d| BrowsedDevice { busid: d.busid, vidpid: d.vidpid, description: d.description })
+                .collect(),
+        })
+        .collect();
+
+    if format == Format::Json {
+        return write_json(out, &hosts);
+    }
+    let mut text = String::new();
+    for host in &hosts {
+        text.push_str(&format!("{} ({}:{})\n", host.instance, host.addresses.join(", "), host.port));
+        for d in &host.devices {
+            text.push_str(&format!("  {:<12} {:<10} {}\n", d.busid, d.vidpid, d.description));
+        }
+    }
+    write(out, &text)
+}
+
+fn reverse(args: &[&str]) -> Result<(), CliError> {
+    enum Side {
+        Host(reverse::ReverseHostConfig),
+        Rendezvous(reverse::RendezvousConfig),
+    }
+    let side = match args {
+        ["host", rendezvous] => Side::Host(reverse::ReverseHostConfig { rendezvous: rendezvous.to_string(), ..Default::default() }),
+        ["rendezvous", listen @ ..] if listen.len() <= 1 => {
+            let mut config = reverse::RendezvousConfig::default();
+            if let Some(addr) = listen.first() {
+                config.host_listen = addr.to_string();
+            }
+            Side::Rendezvous(config)
+        }
+        _ => return Err(CliError::usage("usage: reverse host <rendezvous> | reverse rendezvous [<host-listen>]")),
+    };
+    if config::current().reverse.secret.is_empty() {
+        return Err(CliError::failure("reverse.secret must be set on both ends before connecting them"));
+    }
+    let stop = AtomicBool::new(false);
+    // Lets the GUI, the metrics endpoint and leases see the traffic proxied here.
+    let _publisher = stats::Publisher::start(stats::global().clone(), &stats::shared_dir(), stats::PUBLISH_INTERVAL);
+    match side {
+        Side::Host(config) => reverse::run_host(&config, &stop),
+        Side::Rendezvous(config) => reverse::run_rendezvous(&config, &stop),
+    }
+    .map_err(|e| CliError::failure(e.to_string()))
+}
+
+fn rules(runner: &dyn CommandRunner, out: &mut dyn Write, args: &[&str]) -> Result<(), CliError> {
+    let format = match args {
+        ["dry-run", rest @ ..] => format(rest)?,
+        _ => return Err(CliError::usage("usage: rules dry-run [--json]")),
+    };
+    let rule_set = rules::RuleSet::load().map_err(|e| CliError::failure(e.to_string()))?;
+    let devices = device_list::list_devices(runner)?;
+    let entries = rule_set.dry_run(&devices);
+
+    if format == Format::Json {
+        return write_json(out, &entries);
+    }
+    let mut text = String::new();
+    for entry in &entries {
+        let devices = if entry.devices.is_empty() { String::from("-") } else { entry.devices.join(", ") };
+        text.push_str(&format!("{:<30} {:<14} {}\n", entry.rule, entry.action.to_string(), devices));
+    }
+    write(out, &text)
+}
+
+fn config(out: &mut dyn Write, args: &[&str]) -> Result<(), CliError> {
+    let path = config::config_path();
+    match args {
+        ["path"] => write(out, &format!("{}\n", path.display())),
+        ["show"] => write(out, &config::current().to_toml()),
+        ["check"] => {
+            let text = std::fs::read_to_string(&path)
+                .map_err(|e| CliError::failure(format!("{}: {}", path.display(), e)))?;
+            config::AppConfig::parse(&text).map_err(|e| CliError::failure(format!("{}: {}", path.display(), e)))?;
+            write(out, "ok\n")
+        }
+        _ => Err(CliError::usage("usage: config path|show|check")),
+    }
+}
+
+fn alias(runner: &dyn CommandRunner, out: &mut dyn Write, args: &[&str]) -> Result<(), CliError> {
+    let config_error = |e: config::ConfigError| CliError::failure(e.to_string());
+    match args {
+        ["list", rest @ ..] => {
+            let known = aliases::current();
+            if format(rest)? == Format::Json {
+                return write_json(out, &known);
+            }
+            let mut text = String::new();
+            for a in &known {
+                let identity = match (&a.vidpid, &a.serial, &a.guid) {
+                    (Some(vidpid), Some(serial), _) => format!("{} #{}", vidpid, serial),
+                    (Some(vidpid), None, _) => vidpid.clone(),
+                    (None, _, Some(guid)) => guid.clone(),
+                    (None, _, None) => String::new(),
+                };
+                text.push_str(&format!("{:<16} {:<30} {}\n", a.alias, identity, a.note));
+            }
+            write(out, &text)
+        }
+        ["set", device, name, rest @ ..] => {
+            let note = match rest {
+                [] => None,
+                ["--note", note] => Some(note.to_string()),
+                _ => return Err(CliError::usage("usage: alias set <device> <name> [--note <text>]")),
+            };
+            let devices = device_list::list_devices(runner)?;
+            let known = aliases::current();
+            let target = aliases::resolve(&known, &devices, device).ok();
+            let found = devices.iter().find(|d| match &target {
+                Some(Target::Busid(busid)) => d.busid == *busid,
+                Some(Target::Guid(guid)) => d.guid.as_ref() == Some(guid),
+                None => d.guid.as_deref().is_some_and(|g| g.eq_ignore_ascii_case(device)),
+            });
+            let Some(found) = found else {
+                return Err(CliError::failure(format!("no device '{}'", device)));
+            };
+            let mut entry = DeviceAlias::for_device(name, found);
+            // Renaming keeps the note unless a new one is given.
+            entry.note = note.or_else(|| aliases::lookup(&known, found).map(|a| a.note.clone())).unwrap_or_default();
+            config::update(|c| aliases::upsert(&mut c.aliases, entry)).map_err(config_error)?;
+            Ok(())
+        }
+        ["note", name, note] => {
+            if !aliases::current().iter().any(|a| a.alias.eq_ignore_ascii_case(name)) {
+                return Err(CliError::failure(format!("no alias '{}'", name)));
+            }
+            config::update(|c| {
+                for a in c.aliases.iter_mut().filter(|a| a.alias.eq_ignore_ascii_case(name)) {
+                    a.note = note.to_string();
+                }
+            })
+            .map_err(config_error)?;
+            Ok(())
+        }
+        ["remove", name] => {
+            let mut removed = false;
+            config::update(|c| {
+                let before = c.aliases.len();
+                c.aliases.retain(|a| !a.alias.eq_ignore_ascii_case(name));
+                removed = c.aliases.len() != before;
+            })
+            .map_err(config_error)?;
+            if !removed {
+                return Err(CliError::failure(format!("no alias '{}'", name)));
+            }
+            Ok(())
+        }
+        _ => Err(CliError::usage("usage: alias list|set|note|remove")),
+    }
+}
+
+fn policy(runner: &dyn CommandRunner, out: &mut dyn Write, args: &[&str]) -> Result<(), CliError> {
+    match args {
+        ["list", rest @ ..] => {
+            let format = format(rest)?;
+            let rules = policy::list_policies(runner)?;
+            let rows = policy::policy_rows(&rules, &device_list::list_devices(runner)?);
+            if format == Format::Json {
+                return write_json(out, &rows);
+            }
+            let mut text = String::new();
+            for row in &rows {
+                let rule = &row.rule;
+                text.push_str(&format!(
+                    "{}  {:<5} {:<9} {:<8} {:<9} {}\n",
+                    rule.guid,
+                    rule.effect,
+                    rule.operation,
+                    rule.busid.as_deref().unwrap_or("-"),
+                    rule.hardware_id.as_deref().unwrap_or("-"),
+                    row.devices.join(",")
+                ));
+            }
+            write(out, &text)
+        }
+        ["add", device, options @ ..] => {
+            let mut by = policy::MatchBy::Busid;
+            let mut effect = policy::Effect::Allow;
+            for option in options {
+                match *option {
+                    "--hardware-id" => by = policy::MatchBy::HardwareId,
+                    "--deny" => effect = policy::Effect::Deny,
+                    _ => return Err(CliError::usage("usage: policy add <busid> [--hardware-id] [--deny]")),
+                }
+            }
+            require_elevation()?;
+            let Target::Busid(busid) = resolve_device(runner, device)? else {
+                return Err(CliError::failure(format!("'{}' is not connected", device)));
+            };
+            let devices = device_list::list_devices(runner)?;
+            let Some(found) = devices.iter().find(|d| d.busid == busid) else {
+                return Err(CliError::failure(format!("no device at {}", busid)));
+            };
+            let rule = policy::NewRule { effect, ..policy::NewRule::allow_auto_bind(found, by) };
+            policy::add_policy(runner, &rule)?;
+            Ok(())
+        }
+        ["remove", "--all"] => {
+            require_elevation()?;
+            policy::remove_all_policies(runner)?;
+            Ok(())
+        }
+        ["remove", guid] => {
+            require_elevation()?;
+            policy::remove_policy(runner, guid)?;
+            Ok(())
+        }
+        _ => Err(CliError::usage("usage: policy list [--json] | add <busid> [--hardware-id] [--deny] | remove <guid>|--all")),
+    }
+}
+
+fn wsl(runner: &dyn CommandRunner, context: &Context, out: &mut dyn Write, args: &[&str]) -> Result<(), CliError> {
+    match args {
+        ["list", rest @ ..] => {
+            let format = format(rest)?;
+            let distributions = wsl::list_distributions(runner)?;
+            if format == Format::Json {
+                return write_json(out, &distributions);
+            }
+            let mut text = format!("  {:<24} {:<10} {}\n", "NAME", "STATE", "VERSION");
+            for d in &distributions {
+                let marker = if d.default { '*' } else { ' ' };
+                text.push_str(&format!("{} {:<24} {:<10} {}\n", marker, d.name, d.state, d.version));
+            }
+            write(out, &text)
+        }
+        ["attach", device, options @ ..] => {
+            let distribution = match options {
+                [] => None,
+                ["--distribution" | "-d", name] => Some(*name),
+                _ => return Err(CliError::usage("usage: wsl attach <busid> [--distribution <name>]")),
+            };
+            let Target::Busid(busid) = resolve_device(runner, device)? else {
+                return Err(CliError::failure(format!("'{}' is not connected", device)));
+            };
+            Reservations::at(context.reservations.clone()).check(&busid, &context.user)?;
+            wsl::attach(runner, &wsl::Attachments::at(context.wsl_attachments.clone()), &busid, distribution)?;
+            Ok(())
+        }
+        ["detach", device] => {
+            let Target::Busid(busid) = resolve_device(runner, device)? else {
+                return Err(CliError::failure(format!("'{}' is not connected", device)));
+            };
+            wsl::detach(runner, &wsl::Attachments::at(context.wsl_attachments.clone()), &busid)?;
+            Ok(())
+        }
+        _ => Err(CliError::usage("usage: wsl list [--json] | attach <busid> [--distribution <name>] | detach <busid>")),
+    }
+}
+
+fn client(runner: &dyn CommandRunner, out: &mut dyn Write, args: &[&str]) -> Result<(), CliError> {
+    const USAGE: &str = "usage: client <busid> [--format shell|systemd|powershell | --export <dir>] [--host <address>]";
+    let [device, options @ ..] = args else { return Err(CliError::usage(USAGE)) };
+    let mut kind = ScriptKind::Shell;
+    let mut export = None;
+    let mut host = None;
+    let mut options = options;
+    while let [option, value, rest @ ..] = options {
+        match *option {
+            "--format" => kind = value.parse().map_err(|_| CliError::usage(USAGE))?,
+            "--export" => export = Some(value),
+            "--host" => host = Some(value.to_string()),
+            _ => return Err(CliError::usage(USAGE)),
+        }
+        options = rest;
+    }
+    if !options.is_empty() {
+        return Err(CliError::usage(USAGE));
+    }
+
+    let Target::Busid(busid) = resolve_device(runner, device)? else {
+        return Err(CliError::failure(format!("'{}' is not connected", device)));
+    };
+    let devices = device_list::list_devices(runner)?;
+    let Some(found) = devices.iter().find(|d| d.busid == busid) else {
+        return Err(CliError::failure(format!("no device at {}", busid)));
+    };
+    let hosts = match host {
+        Some(host) => vec![host],
+        None => client_scripts::detected_hosts(),
+    };
+    let target = ClientTarget::new(found, &hosts, config::current().server.port)
+        .map_err(|e| CliError::failure(e.to_string()))?;
+    match export {
+        Some(dir) => {
+            let written = client_scripts::export(&target, std::path::Path::new(dir))
+                .map_err(|e| CliError::failure(format!("{}: {}", dir, e)))?;
+            let text: String = written.iter().map(|path| format!("{}\n", path.display())).collect();
+            write(out, &text)
+        }
+        None => write(out, &target.render(kind)),
+    }
+}
+
+#[derive(Serialize)]
+struct AttachedPort {
+    #[serde(flatten)]
+    port: VhciPort,
+    remote: Option<PortRecord>,
+}
+
+fn companion(runner: &dyn CommandRunner, out: &mut dyn Write, args: &[&str]) -> Result<(), CliError> {
+    let mut companion = Companion::from_config(runner);
+    match args {
+        ["run"] => {
+            if companion.statuses().is_empty() {
+                return Err(CliError::failure("no [[client.targets]] in the config file"));
+            }
+            companion.run(&AtomicBool::new(false));
+            Ok(())
+        }
+        ["once", rest @ ..] => {
+            let format = format(rest)?;
+            companion.tick(std::time::Instant::now());
+            if format == Format::Json {
+                return write_json(out, companion.statuses());
+            }
+            let mut text = String::new();
+            for status in companion.statuses() {
+                let outcome = match (status.port, &status.last_error) {
+                    (Some(port), _) => format!("attached on port {}", port),
+                    (None, Some(error)) => error.clone(),
+                    (None, None) => String::from("not attached"),
+                };
+                text.push_str(&format!("{} {}: {}\n", status.target.host, status.target.device, outcome));
+            }
+            write(out, &text)
+        }
+        ["ports", rest @ ..] => {
+            let format = format(rest)?;
+            let vhci = companion.vhci();
+            let ports = vhci.ports().map_err(|e| CliError::failure(format!("vhci_hcd: {}", e)))?;
+            let ports: Vec<AttachedPort> = ports
+                .into_iter()
+                .filter(VhciPort::is_used)
+                .map(|port| AttachedPort { remote: vhci.record(port.port), port })
+                .collect();
+            if format == Format::Json {
+                return write_json(out, &ports);
+            }
+            let mut text = String::new();
+            for p in &ports {
+                let remote = match &p.remote {
+                    Some(r) => format!("{}:{}/{}", r.host, r.port, r.busid),
+                    None => String::from("?"),
+                };
+                text.push_str(&format!("{:<4} {:<12} {}\n", p.port.port, p.port.local_busid, remote));
+            }
+            write(out, &text)
+        }
+        _ => Err(CliError::usage("usage: companion run | once [--json] | ports [--json]")),
+    }
+}
+
+fn agent(context: &Context, args: &[&str]) -> Result<(), CliError> {
+    let listen = match args {
+        [] => config::current().agent.listen,
+        [listen] => listen.to_string(),
+        _ => return Err(CliError::usage("usage: agent [<listen>]")),
+    };
+    if listen.is_empty() {
+        return Err(CliError::usage("no address to listen on; set agent.listen or pass one"));
+    }
+    let settings = AgentSettings {
+        reservations: context.reservations.clone(),
+        wsl_attachments: context.wsl_attachments.clone(),
+        ..AgentSettings::from_config()
+    };
+    if settings.token.is_empty() {
+        return Err(CliError::failure("agent.token must be set before serving devices to other machines"));
+    }
+    let listener = std::net::TcpListener::bind(&listen).map_err(|e| CliError::failure(format!("{}: {}", listen, e)))?;
+    // The agent always acts on this machine, even when the CLI was handed another runner.
+    agent::run(listener, settings, std::sync::Arc::new(SystemRunner)).map_err(|e| CliError::failure(e.to_string()))
+}
+
+fn api(context: &Context, out: &mut dyn Write, args: &[&str]) -> Result<(), CliError> {
+    let listen = match args {
+        ["openapi"] => return write(out, api::OPENAPI),
+        [] => config::current().api.listen,
+        [listen] => listen.to_string(),
+        _ => return Err(CliError::usage("usage: api [<listen> | openapi]")),
+    };
+    let settings = ApiSettings {
+        reservations: context.reservations.clone(),
+        wsl_attachments: context.wsl_attachments.clone(),
+        ..ApiSettings::from_config()
+    };
+    if settings.token.is_empty() {
+        return Err(CliError::failure("api.token must be set before serving the control API"));
+    }
+    let listener = std::net::TcpListener::bind(&listen).map_err(|e| CliError::failure(format!("{}: {}", listen, e)))?;
+    api::run(listener, settings, std::sync::Arc::new(SystemRunner)).map_err(|e| CliError::failure(e.to_string()))
+}
+
+impl From<ReservationError> for CliError {
+    fn from(e: ReservationError) -> Self {
+        CliError::failure(e.to_string())
+    }
+}
+
+fn connected_busid(runner: &dyn CommandRunner, device: &str) -> Result<String, CliError> {
+    match resolve_device(runner, device)? {
+        Target::Busid(busid) => Ok(busid),
+        Target::Guid(_) => Err(CliError::failure(format!("'{}' is not connected", device))),
+    }
+}
+
+fn reserve(runner: &dyn CommandRunner, context: &Context, out: &mut dyn Write, args: &[&str]) -> Result<(), CliError> {
+    const USAGE: &str = "usage: reserve <busid> [--for <minutes>] [--user <name>]";
+    let [device, options @ ..] = args else { return Err(CliError::usage(USAGE)) };
+    let mut duration = config::current().reservations.default_duration();
+    let mut user = context.user.clone();
+    let mut options = options;
+    while let [option, value, rest @ ..] = options {
+        match *option {
+            "--for" => {
+                let minutes: u64 = value.parse().map_err(|_| CliError::usage(format!("'{}' is not a number of minutes", value)))?;
+                // Anything that overflows is far past the longest reservation.
+                duration = minutes.checked_mul(60).map_or(Duration::MAX, Duration::from_secs);
+            }
+            "--user" => user = value.to_string(),
+            _ => return Err(CliError::usage(USAGE)),
+        }
+        options = rest;
+    }
+    if !options.is_empty() {
+        return Err(CliError::usage(USAGE));
+    }
+    if !context.admin && !user.eq_ignore_ascii_case(&context.user) {
+        return Err(CliError { code: EXIT_NOT_ELEVATED, message: String::from("only an administrator can reserve for someone else") });
+    }
+    let busid = connected_busid(runner, device)?;
+    let store = Reservations::at(context.reservations.clone());
+    let now = store.now();
+    let text = match store.reserve(&busid, &user, duration)? {
+        Outcome::Granted(r) => format!("Reserved {} for {} for {}\n", busid, r.user, reservations::format_remaining(r.remaining(now))),
+        Outcome::Extended(r) => format!("Extended {} for {} to {}\n", busid, r.user, reservations::format_remaining(r.remaining(now))),
+        Outcome::Queued { position, holder } => format!(
+            "{} is reserved by {} for another {}; {} is number {} in the queue\n",
+            busid,
+            holder.user,
+            reservations::format_remaining(holder.remaining(now)),
+            user,
+            position
+        ),
+    };
+    write(out, &text)
+}
+
+fn release(runner: &dyn CommandRunner, context: &Context, out: &mut dyn Write, args: &[&str]) -> Result<(), CliError> {
+    let store = Reservations::at(context.reservations.clone());
+    let (device, next) = match args {
+        [device, "--force"] => (*device, store.force_release(&connected_busid(runner, device)?)?),
+        [device] => (*device, store.release(&connected_busid(runner, device)?, &context.user)?),
+        _ => return Err(CliError::usage("usage: release <busid> [--force]")),
+    };
+    match next {
+        Some(holder) => write(out, &format!("{} is now reserved by {}\n", device, holder.user)),
+        None => write(out, &format!("{} is free\n", device)),
+    }
+}
+
+fn list_reservations(context: &Context, out: &mut dyn Write, format: Format) -> Result<(), CliError> {
+    let store = Reservations::at(context.reservations.clone());
+    let now = store.now();
+    let devices = store.list()?;
+    if format == Format::Json {
+        return write_json(out, &devices);
+    }
+    let mut text = format!("{:<12} {:<20} {:<14} {}\n", "BUSID", "HOLDER", "REMAINING", "QUEUE");
+    for d in &devices {
+        let queue: Vec<&str> = d.queue.iter().map(|w| w.user.as_str()).collect();
+        text.push_str(&format!(
+            "{:<12} {:<20} {:<14} {}\n",
+            d.busid,
+            d.holder.user,
+            reservations::format_remaining(d.holder.remaining(now)),
+            queue.join(", ")
+        ));
+    }
+    write(out, &text)
+}
+
+fn leases(runner: &dyn CommandRunner, context: &Context, out: &mut dyn Write, args: &[&str]) -> Result<(), CliError> {
+    let ["run", options @ ..] = args else { return Err(CliError::usage("usage: leases run [--dry-run] [--json]")) };
+    let mut dry_run = false;
+    let mut json = false;
+    for option in options {
+        match *option {
+            "--dry-run" => dry_run = true,
+            "--json" => json = true,
+            _ => return Err(CliError::usage("usage: leases run [--dry-run] [--json]")),
+        }
+    }
+    let stop = AtomicBool::new(false);
+    let attachments = wsl::Attachments::at(context.wsl_attachments.clone());
+    leases::run(runner, &attachments, &stop, dry_run, |event, result| {
+        let line = if json {
+            let error = result.as_ref().err().map(|e| e.to_string());
+            serde_json::json!({ "lease": event, "error": error }).to_string()
+        } else {
+            match &result {
+                Ok(()) => event.to_string(),
+                Err(e) => format!("{}: failed: {}", event, e),
+            }
+        };
+        let _ = writeln!(out, "{}", line).and_then(|_| out.flush());
+    });
+    Ok(())
+}
+
+impl From<FleetError> for CliError {
+    fn from(e: FleetError) -> Self {
+        match e {
+            FleetError::Local(e) => e.into(),
+            e => CliError::failure(e.to_string()),
+        }
+    }
+}
+
+fn fleet(runner: &dyn CommandRunner, context: &Context, out: &mut dyn Write, args: &[&str]) -> Result<(), CliError> {
+    let fleet = Fleet::from_config()
+        .with_reservations(context.reservations.clone())
+        .with_wsl_attachments(context.wsl_attachments.clone())
+        .with_user(&context.user);
+    match args {
+        ["list", rest @ ..] => {
+            let format = format(rest)?;
+            let snapshot = fleet.poll(Some(runner));
+            if format == Format::Json {
+                return write_json(out, &snapshot.devices);
+            }
+            let mut text = format!("{:<16} {:<12} {:<10} {:<50} {}\n", "HOST", "BUSID", "VID:PID", "DEVICE", "STATE");
+            for d in &snapshot.devices {
+                text.push_str(&format!(
+                    "{:<16} {:<12} {:<10} {:<50} {}\n",
+                    d.host,
+                    d.device.display_id(),
+                    d.device.vidpid,
+                    d.device.device,
+                    d.device.state_label()
+                ));
+            }
+            for h in snapshot.health.iter().filter(|h| h.error.is_some()) {
+                text.push_str(&format!("{:<16} ({}: {})\n", h.host, h.health, h.error.as_deref().unwrap_or_default()));
+            }
+            write(out, &text)
+        }
+        ["health", rest @ ..] => {
+            let format = format(rest)?;
+            let snapshot = fleet.poll(Some(runner));
+            if format == Format::Json {
+                return write_json(out, &snapshot.health);
+            }
+            let mut text = format!("{:<16} {:<24} {:<13} {:>7} {:>8}  {}\n", "HOST", "ADDRESS", "HEALTH", "DEVICES", "LATENCY", "ERROR");
+            for h in &snapshot.health {
+                text.push_str(&format!(
+                    "{:<16} {:<24} {:<13} {:>7} {:>6}ms  {}\n",
+                    h.host,
+                    h.address,
+                    h.health,
+                    h.devices,
+                    h.latency_ms,
+                    h.error.as_deref().unwrap_or_default()
+                ));
+            }
+            write(out, &text)
+        }
+        ["bind", host, busid, options @ ..] => {
+            let force = match options {
+                [] => false,
+                ["--force"] => true,
+                _ => return Err(CliError::usage("usage: fleet bind <host> <busid> [--force]")),
+            };
+            fleet.bind(runner, host, busid, force)?;
+            Ok(())
+        }
+        ["unbind", host, busid] => {
+            fleet.unbind(runner, host, busid)?;
+            Ok(())
+        }
+        _ => Err(CliError::usage("usage: fleet list|health [--json] | bind <host> <busid> [--force] | unbind <host> <busid>")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_list::DeviceState;
+    use crate::reservations::RESERVATIONS_FILE;
+    use crate::testing::{self, FakeRunner, TempDir};
+
+    /// Runs as the non-administrator "ana" with its state files in `dir`.
+    fn context(dir: &TempDir) -> Context {
+        Context {
+            reservations: dir.join(RESERVATIONS_FILE),
+            wsl_attachments: dir.join(wsl::ATTACHMENTS_FILE),
+            user: String::from("ana"),
+            admin: false,
+        }
+    }
+
+    fn run_in(runner: &FakeRunner, context: &Context, args: &[&str]) -> (i32, String, String) {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let code = run(&args, runner, context, &mut out, &mut err);
+        (code, String::from_utf8(out).unwrap(), String::from_utf8(err).unwrap())
+    }
+
+    /// [`run_in`] with fresh state files.
+    fn run_cli(runner: &FakeRunner, args: &[&str]) -> (i32, String, String) {
+        let dir = TempDir::new("cli");
+        run_in(runner, &context(&dir), args)
+    }
+
+    fn runner_with_devices() -> FakeRunner {
+        let mut persisted = testing::device("", "1366:0105", DeviceState::Persisted);
+        persisted.guid = Some(String::from("5d1f1b2a-0000-0000-0000-000000000000"));
+        persisted.device = String::from("Old, \"quoted\" probe");
+        let devices = [
+            testing::device("1-1", "0483:374b", DeviceState::Shared),
+            testing::device("1-2", "046d:c52b", DeviceState::NotShared),
+            persisted,
+        ];
+        FakeRunner::new().ok("usbipd state", &testing::state_json(&devices))
+    }
+
+    #[test]
+    fn missing_and_unknown_commands_are_usage_errors() {
+        let runner = FakeRunner::new();
+        let (code, out, err) = run_cli(&runner, &[]);
+        assert_eq!(code, EXIT_USAGE);
+        assert!(out.is_empty());
+        assert!(err.starts_with("error: missing command\n\nUsage: usbipctl"));
+
+        let (code, _, err) = run_cli(&runner, &["frobnicate"]);
+        assert_eq!(code, EXIT_USAGE);
+        assert!(err.starts_with("error: unknown command 'frobnicate'"));
+    }
+
+    #[test]
+    fn help_prints_usage() {
+        let (code, out, err) = run_cli(&FakeRunner::new(), &["--help"]);
+        assert_eq!(code, EXIT_OK);
+        assert_eq!(out, USAGE);
+        assert!(err.is_empty());
+    }
+
+    #[test]
+    fn format_flags_are_checked() {
+        let runner = runner_with_devices();
+        let (code, _, err) = run_cli(&runner, &["list", "--yaml"]);
+        assert_eq!(code, EXIT_USAGE);
+        assert!(err.starts_with("error: unexpected arguments: --yaml"));
+        assert!(runner.calls_to("usbipd state").is_empty());
+    }
+
+    #[test]
+    fn list_as_json() {
+        let (code, out, _) = run_cli(&runner_with_devices(), &["list", "--json"]);
+        assert_eq!(code, EXIT_OK);
+        let listed: Vec<serde_json::Value> = serde_json::from_str(&out).unwrap();
+        assert_eq!(listed.len(), 3);
+        assert_eq!(listed[0]["busid"], "1-1");
+        assert_eq!(listed[0]["state"], "shared");
+        assert_eq!(listed[0]["vidpid"], "0483:374b");
+        assert_eq!(listed[0]["alias"], serde_json::Value::Null);
+        assert_eq!(listed[2]["state"], "persisted");
+    }
+
+    #[test]
+    fn list_as_csv_quotes_fields() {
+        let (code, out, _) = run_cli(&runner_with_devices(), &["list", "--csv"]);
+        assert_eq!(code, EXIT_OK);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "busid,vidpid,device,state,persisted,guid,alias,note,wsl_distribution");
+        assert_eq!(lines[1], "1-1,0483:374b,Device 0483:374b,shared,false,00000011-0000-0000-0000-000000000000,,,");
+        assert_eq!(lines[3], ",1366:0105,\"Old, \"\"quoted\"\" probe\",persisted,true,5d1f1b2a-0000-0000-0000-000000000000,,,");
+    }
+
+    #[test]
+    fn persisted_lists_only_persisted_devices() {
+        let (_, out, _) = run_cli(&runner_with_devices(), &["persisted"]);
+        let rows: Vec<&str> = out.lines().skip(1).collect();
+        assert_eq!(rows.len(), 1);
+        assert!(rows[0].starts_with("5d1f1b2a-0000-0000-0000-000000000000"));
+    }
+
+    #[test]
+    fn bind_parses_force_on_either_side() {
+        let runner = FakeRunner::new().ok("usbipd bind", "");
+        assert_eq!(run_cli(&runner, &["bind", "1-1", "--force"]).0, EXIT_OK);
+        assert_eq!(run_cli(&runner, &["bind", "--force", "2-1"]).0, EXIT_OK);
+        assert_eq!(run_cli(&runner, &["bind", "3-1"]).0, EXIT_OK);
+        assert_eq!(
+            runner.calls_to("usbipd bind"),
+            ["usbipd bind --force --busid 1-1", "usbipd bind --force --busid 2-1", "usbipd bind --busid 3-1"]
+        );
+        assert_eq!(run_cli(&runner, &["bind"]).0, EXIT_USAGE);
+        assert_eq!(run_cli(&runner, &["bind", "1-1", "--forced"]).0, EXIT_USAGE);
+    }
+
+    #[test]
+    fn unbind_by_busid_or_guid() {
+        let runner = FakeRunner::new().ok("usbipd unbind", "");
+        assert_eq!(run_cli(&runner, &["unbind", "1-1"]).0, EXIT_OK);
+        assert_eq!(run_cli(&runner, &["unbind", "--guid", "5d1f1b2a"]).0, EXIT_OK);
+        assert_eq!(runner.calls_to("usbipd unbind"), ["usbipd unbind --busid 1-1", "usbipd unbind --guid 5d1f1b2a"]);
+        assert_eq!(run_cli(&runner, &["unbind", "--all"]).0, EXIT_USAGE);
+    }
+
+    #[test]
+    fn unknown_alias_is_a_failure() {
+        let (code, _, err) = run_cli(&runner_with_devices(), &["bind", "no-such-probe"]);
+        assert_eq!(code, EXIT_FAILURE);
+        assert!(err.contains("no-such-probe"));
+    }
+
+    #[test]
+    fn usbipd_failures_and_missing_usbipd_have_their_own_codes() {
+        let failing = FakeRunner::new().fail("usbipd bind", 1, "", "usbipd: error: Device is already shared.");
+        let (code, _, err) = run_cli(&failing, &["bind", "1-1"]);
+        assert_eq!(code, EXIT_FAILURE);
+        assert_eq!(err, "error: usbipd: error: Device is already shared.\n");
+
+        let (code, _, _) = run_cli(&FakeRunner::empty(), &["unbind", "1-1"]);
+        assert_eq!(code, EXIT_NOT_FOUND);
+    }
+
+    #[test]
+    fn version_as_json() {
+        let (code, out, _) = run_cli(&FakeRunner::new(), &["version", "--json"]);
+        assert_eq!(code, EXIT_OK);
+        let versions: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(versions["usbipd"], testing::USBIPD_VERSION);
+        assert_eq!(versions["application"], env!("CARGO_PKG_VERSION"));
+        assert!(versions["capabilities"].as_array().unwrap().contains(&serde_json::json!("state_json")));
+    }
+
+    #[test]
+    fn scan_refuses_wide_networks() {
+        let (code, _, err) = run_cli(&FakeRunner::new(), &["scan", "10.0.0.0/8"]);
+        assert_eq!(code, EXIT_USAGE);
+        assert!(err.starts_with("error: Refusing to scan a /8 network"));
+    }
+
+    #[test]
+    fn reserve_checks_its_options() {
+        let runner = FakeRunner::new();
+        assert_eq!(run_cli(&runner, &["reserve"]).0, EXIT_USAGE);
+        assert_eq!(run_cli(&runner, &["reserve", "1-1", "--for"]).0, EXIT_USAGE);
+        let (code, _, err) = run_cli(&runner, &["reserve", "1-1", "--for", "soon"]);
+        assert_eq!(code, EXIT_USAGE);
+        assert!(err.starts_with("error: 'soon' is not a number of minutes"));
+        // Rejected before the state file is touched.
+        let (code, _, err) = run_cli(&runner_with_devices(), &["reserve", "1-1", "--for", "999999999999999999"]);
+        assert_eq!(code, EXIT_FAILURE);
+        assert!(err.contains("a reservation can last at most 30 days"), "{}", err);
+    }
+
+    #[test]
+    fn only_administrators_reserve_for_someone_else() {
+        let dir = TempDir::new("cli");
+        let runner = FakeRunner::new();
+        let (code, _, err) = run_in(&runner, &context(&dir), &["reserve", "1-1", "--user", "bob"]);
+        assert_eq!(code, EXIT_NOT_ELEVATED);
+        assert!(err.starts_with("error: only an administrator can reserve for someone else"), "{}", err);
+        assert!(!dir.join(RESERVATIONS_FILE).exists());
+
+        let (code, out, _) = run_in(&runner, &context(&dir), &["reserve", "1-1", "--user", "ana"]);
+        assert_eq!(code, EXIT_OK);
+        assert!(out.starts_with("Reserved 1-1 for ana for "), "{}", out);
+        let admin = Context { admin: true, ..context(&dir) };
+        let (code, out, _) = run_in(&runner, &admin, &["reserve", "1-2", "--user", "bob"]);
+        assert_eq!(code, EXIT_OK);
+        assert!(out.starts_with("Reserved 1-2 for bob for "), "{}", out);
+    }
+
+    #[test]
+    fn bind_and_attach_respect_reservations() {
+        let dir = TempDir::new("cli");
+        let runner = FakeRunner::new().ok("usbipd bind", "");
+        Reservations::at(dir.join(RESERVATIONS_FILE)).reserve("1-1", "bob", Duration::from_secs(3600)).unwrap();
+
+        for args in [&["bind", "1-1"][..], &["wsl", "attach", "1-1"], &["fleet", "bind", "local", "1-1"]] {
+            let (code, _, err) = run_in(&runner, &context(&dir), args);
+            assert_eq!(code, EXIT_FAILURE, "{:?}", args);
+            assert!(err.contains("1-1 is reserved by bob"), "{}", err);
+        }
+        assert!(runner.calls_to("usbipd bind").is_empty());
+        let bob = Context { user: String::from("bob"), ..context(&dir) };
+        assert_eq!(run_in(&runner, &bob, &["bind", "1-1"]).0, EXIT_OK);
+        assert_eq!(runner.calls_to("usbipd bind"), ["usbipd bind --busid 1-1"]);
+    }
+
+    #[test]
+    fn release_is_only_for_yourself_or_forced() {
+        let runner = FakeRunner::new();
+        let (code, _, err) = run_cli(&runner, &["release", "1-1", "--user", "ana"]);
+        assert_eq!(code, EXIT_USAGE);
+        assert!(err.starts_with("error: usage: release <busid> [--force]"));
+    }
+
+    #[test]
+    fn reverse_checks_its_arguments_before_starting() {
+        let (code, _, err) = run_cli(&FakeRunner::new(), &["reverse", "host"]);
+        assert_eq!(code, EXIT_USAGE);
+        assert!(err.starts_with("error: usage: reverse host <rendezvous>"), "{}", err);
+        let (code, _, _) = run_cli(&FakeRunner::new(), &["reverse", "rendezvous", "a", "b"]);
+        assert_eq!(code, EXIT_USAGE);
+    }
+
+    #[test]
+    fn csv_field_quotes_only_when_needed() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+}
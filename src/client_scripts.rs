@@ -0,0 +1,369 @@
+//! Ready-to-run commands for attaching a shared device from a client.
+//!
+//! The host is given by one of its LAN addresses, found by asking the OS which
+//! source address it would use for a few private destinations; nothing is sent.
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::device_list::UsbipDevice;
+
+/// usbip's default TCP port; `--tcp-port` is only written for other ports.
+pub const DEFAULT_PORT: u16 = 3240;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptKind {
+    /// `usbip attach` for a Linux shell.
+    Shell,
+    /// A oneshot unit that attaches at boot and detaches when stopped.
+    Systemd,
+    /// usbip-win2's `usbip.exe` for Windows clients.
+    PowerShell,
+}
+
+impl ScriptKind {
+    pub const ALL: [ScriptKind; 3] = [ScriptKind::Shell, ScriptKind::Systemd, ScriptKind::PowerShell];
+
+    pub fn file_name(&self, busid: &str) -> String {
+        match self {
+            ScriptKind::Shell => format!("attach-{}.sh", busid),
+            ScriptKind::Systemd => format!("usbip-attach-{}.service", busid),
+            ScriptKind::PowerShell => format!("attach-{}.ps1", busid),
+        }
+    }
+}
+
+impl fmt::Display for ScriptKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ScriptKind::Shell => "shell",
+            ScriptKind::Systemd => "systemd",
+            ScriptKind::PowerShell => "powershell",
+        })
+    }
+}
+
+impl FromStr for ScriptKind {
+    type Err = ();
+
+    fn from_str(text: &str) -> Result<Self, ()> {
+        match text.to_ascii_lowercase().as_str() {
+            "shell" | "sh" => Ok(ScriptKind::Shell),
+            "systemd" => Ok(ScriptKind::Systemd),
+            "powershell" | "ps1" => Ok(ScriptKind::PowerShell),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ScriptError {
+    /// Clients can only attach bound devices.
+    NotShared(String),
+    /// No LAN address was found and none was given.
+    NoAddress,
+    InvalidHost(String),
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptError::NotShared(busid) => write!(f, "{} is not shared; bind it first", busid),
+            ScriptError::NoAddress => f.write_str("could not detect a LAN address for this host; pass one explicitly"),
+            ScriptError::InvalidHost(host) => write!(f, "'{}' is not a host name or address", host),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+/// What a client needs to reach one shared device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientTarget {
+    pub host: String,
+    /// More addresses of this host, mentioned in a comment.
+    pub other_hosts: Vec<String>,
+    pub port: u16,
+    pub busid: String,
+    pub vidpid: String,
+    pub description: String,
+}
+
+/// Host names and addresses end up inside shell and PowerShell commands, so
+/// only the characters they can contain are accepted.
+pub fn is_valid_host(host: &str) -> bool {
+    !host.is_empty()
+        && !host.starts_with('-')
+        && host.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':' | '_'))
+}
+
+impl ClientTarget {
+    /// `hosts` in order of preference; the first one is used in the commands.
+    pub fn new(device: &UsbipDevice, hosts: &[String], port: u16) -> Result<Self, ScriptError> {
+        if device.busid.is_empty() || !device.is_shared() {
+            return Err(ScriptError::NotShared(device.display_id().to_string()));
+        }
+        if let Some(bad) = hosts.iter().find(|h| !is_valid_host(h)) {
+            return Err(ScriptError::InvalidHost(bad.clone()));
+        }
+        let (host, other_hosts) = hosts.split_first().ok_or(ScriptError::NoAddress)?;
+        Ok(ClientTarget {
+            host: host.clone(),
+            other_hosts: other_hosts.to_vec(),
+            port,
+            busid: device.busid.clone(),
+            vidpid: device.vidpid.clone(),
+            description: device.device.clone(),
+        })
+    }
+
+    fn url(&self) -> String {
+        let host = if self.host.contains(':') { format!("[{}]", self.host) } else { self.host.clone() };
+        format!("usbip://{}:{}/{}", host, self.port, self.busid)
+    }
+
+    /// `usbip` global options placed before the subcommand.
+    fn port_option(&self) -> String {
+        if self.port == DEFAULT_PORT { String::new() } else { format!("--tcp-port {} ", self.port) }
+    }
+
+    fn header(&self) -> String {
+        let mut header = format!("# {} ({}) at {}\n", self.description, self.vidpid, self.url());
+        if !self.other_hosts.is_empty() {
+            header.push_str(&format!("# This host is also reachable as {}\n", self.other_hosts.join(", ")));
+        }
+        header
+    }
+
+    pub fn render(&self, kind: ScriptKind) -> String {
+        match kind {
+            ScriptKind::Shell => self.shell(),
+            ScriptKind::Systemd => self.systemd(),
+            ScriptKind::PowerShell => self.powershell(),
+        }
+    }
+
+    fn shell(&self) -> String {
+        format!(
+            "#!/bin/sh\n{}sudo modprobe vhci-hcd\nsudo usbip {}attach -r {} -b {}\n",
+            self.header(),
+            self.port_option(),
+            self.host,
+            self.busid
+        )
+    }
+
+    fn systemd(&self) -> String {
+        // systemd turns `$$` into `$` and `\"` into `"` before sh sees the command.
+        let detach = format!(
+            "port=$$(/usr/bin/usbip port | awk '/^Port/ {{ p = $$2 + 0 }} $$NF == \\\"{}\\\" {{ print p }}'); \
+             [ -n \\\"$$port\\\" ] && exec /usr/bin/usbip detach -p \\\"$$port\\\"",
+            self.url()
+        );
+        format!(
+            "{header}\
+             [Unit]\n\
+             Description=Attach USB/IP device {busid} from {host}\n\
+             Wants=network-online.target\n\
+             After=network-online.target\n\
+             \n\
+             [Service]\n\
+             Type=oneshot\n\
+             RemainAfterExit=yes\n\
+             ExecStartPre=/sbin/modprobe vhci-hcd\n\
+             ExecStart=/usr/bin/usbip {port}attach -r {host} -b {busid}\n\
+             ExecStop=/bin/sh -c \"{detach}\"\n\
+             \n\
+             [Install]\n\
+             WantedBy=multi-user.target\n",
+            header = self.header(),
+            busid = self.busid,
+            host = self.host,
+            port = self.port_option(),
+            detach = detach
+        )
+    }
+
+    fn powershell(&self) -> String {
+        format!(
+            "{}# Needs usbip-win2 (https://github.com/vadimgrn/usbip-win2); run as Administrator.\n\
+             & \"$env:ProgramFiles\\USBip\\usbip.exe\" {}attach -r {} -b {}\n",
+            self.header(),
+            self.port_option(),
+            self.host,
+            self.busid
+        )
+    }
+}
+
+/// Writes every kind of script into `dir` and returns the files written.
+pub fn export(target: &ClientTarget, dir: &Path) -> io::Result<Vec<PathBuf>> {
+    fs::create_dir_all(dir)?;
+    ScriptKind::ALL
+        .iter()
+        .map(|kind| {
+            let path = dir.join(kind.file_name(&target.busid));
+            fs::write(&path, target.render(*kind))?;
+            Ok(path)
+        })
+        .collect()
+}
+
+/// The source address the OS would pick to reach `destination`.
+fn route_address(destination: Ipv4Addr) -> Option<Ipv4Addr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect((destination, 9)).ok()?;
+    match socket.local_addr().ok()? {
+        SocketAddr::V4(addr) => Some(*addr.ip()),
+        SocketAddr::V6(_) => None,
+    }
+}
+
+/// This host's LAN addresses, the one on the default route first.
+pub fn lan_addresses() -> Vec<Ipv4Addr> {
+    let probes = [
+        Ipv4Addr::new(192, 0, 2, 1),
+        Ipv4Addr::new(10, 255, 255, 254),
+        Ipv4Addr::new(172, 31, 255, 254),
+        Ipv4Addr::new(192, 168, 255, 254),
+    ];
+    let mut addresses: Vec<Ipv4Addr> = Vec::new();
+    for address in probes.into_iter().filter_map(route_address) {
+        if !address.is_loopback() && !address.is_unspecified() && !address.is_link_local() && !addresses.contains(&address) {
+            addresses.push(address);
+        }
+    }
+    addresses
+}
+
+/// [`lan_addresses`] as strings, for [`ClientTarget::new`].
+pub fn detected_hosts() -> Vec<String> {
+    lan_addresses().iter().map(Ipv4Addr::to_string).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_list::DeviceState;
+    use crate::testing;
+
+    const SYSTEMD: &str = r#"# ST-Link Debug (0483:374b) at usbip://192.168.1.20:3241/1-4
+# This host is also reachable as 10.0.0.5
+[Unit]
+Description=Attach USB/IP device 1-4 from 192.168.1.20
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+ExecStartPre=/sbin/modprobe vhci-hcd
+ExecStart=/usr/bin/usbip --tcp-port 3241 attach -r 192.168.1.20 -b 1-4
+ExecStop=/bin/sh -c "port=$$(/usr/bin/usbip port | awk '/^Port/ { p = $$2 + 0 } $$NF == \"usbip://192.168.1.20:3241/1-4\" { print p }'); [ -n \"$$port\" ] && exec /usr/bin/usbip detach -p \"$$port\""
+
+[Install]
+WantedBy=multi-user.target
+"#;
+
+    fn device(state: DeviceState) -> UsbipDevice {
+        let mut device = testing::device("1-4", "0483:374b", state);
+        device.device = String::from("ST-Link Debug");
+        device
+    }
+
+    fn target(hosts: &[&str], port: u16) -> ClientTarget {
+        let hosts: Vec<String> = hosts.iter().map(|h| h.to_string()).collect();
+        ClientTarget::new(&device(DeviceState::Shared), &hosts, port).unwrap()
+    }
+
+    /// The command `ExecStop=` hands to sh once systemd has unquoted it.
+    fn exec_stop(unit: &str) -> String {
+        let line = unit.lines().find_map(|l| l.strip_prefix("ExecStop=/bin/sh -c \"")).unwrap();
+        let quoted = line.strip_suffix('"').unwrap();
+        // Every `"` and `$` must be escaped, or systemd would end the
+        // argument early or expand an environment variable.
+        assert!(!quoted.replace("\\\"", "").contains('"'), "{}", quoted);
+        assert!(!quoted.replace("$$", "").contains('$'), "{}", quoted);
+        assert!(!quoted.contains('%'), "{}", quoted);
+        quoted.replace("\\\"", "\"").replace("$$", "$")
+    }
+
+    #[test]
+    fn shell_script() {
+        assert_eq!(
+            target(&["192.168.1.20"], DEFAULT_PORT).render(ScriptKind::Shell),
+            "#!/bin/sh\n# ST-Link Debug (0483:374b) at usbip://192.168.1.20:3240/1-4\nsudo modprobe vhci-hcd\nsudo usbip attach -r 192.168.1.20 -b 1-4\n"
+        );
+    }
+
+    #[test]
+    fn systemd_unit() {
+        assert_eq!(target(&["192.168.1.20", "10.0.0.5"], 3241).render(ScriptKind::Systemd), SYSTEMD);
+        assert_eq!(
+            exec_stop(SYSTEMD),
+            "port=$(/usr/bin/usbip port | awk '/^Port/ { p = $2 + 0 } $NF == \"usbip://192.168.1.20:3241/1-4\" { print p }'); [ -n \"$port\" ] && exec /usr/bin/usbip detach -p \"$port\""
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn systemd_exec_stop_detaches_the_right_port() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::process::Command;
+
+        let dir = std::env::temp_dir().join(format!("usbip_host-client-scripts-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let usbip = dir.join("usbip");
+        let log = dir.join("detach.log");
+        let fake = format!(
+            "#!/bin/sh\nif [ \"$1\" = port ]; then cat <<'EOF'\nImported USB devices\n====================\n\
+             Port 00: <Port in Use> at Full Speed(12Mbps)\n       unknown vendor : unknown product (046d:c52b)\n       3-1 -> usbip://10.0.0.9:3240/1-1\n           -> remote bus/dev 001/001\n\
+             Port 09: <Port in Use> at High Speed(480Mbps)\n       STMicroelectronics : ST-LINK/V2.1 (0483:374b)\n       3-10 -> usbip://192.168.1.20:3241/1-4\n           -> remote bus/dev 001/004\nEOF\nelse echo \"$@\" > {}; fi\n",
+            log.display()
+        );
+        fs::write(&usbip, fake).unwrap();
+        fs::set_permissions(&usbip, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let command = exec_stop(&target(&["192.168.1.20"], 3241).render(ScriptKind::Systemd)).replace("/usr/bin/usbip", &usbip.to_string_lossy());
+        let status = Command::new("/bin/sh").args(["-c", &command]).status().unwrap();
+        let detached = fs::read_to_string(&log);
+        let _ = fs::remove_dir_all(&dir);
+        assert!(status.success());
+        assert_eq!(detached.unwrap(), "detach -p 9\n");
+    }
+
+    #[test]
+    fn powershell_script() {
+        assert_eq!(
+            target(&["fe80::1"], 3241).render(ScriptKind::PowerShell),
+            "# ST-Link Debug (0483:374b) at usbip://[fe80::1]:3241/1-4\n\
+             # Needs usbip-win2 (https://github.com/vadimgrn/usbip-win2); run as Administrator.\n\
+             & \"$env:ProgramFiles\\USBip\\usbip.exe\" --tcp-port 3241 attach -r fe80::1 -b 1-4\n"
+        );
+    }
+
+    #[test]
+    fn targets_need_a_shared_device_and_a_safe_host() {
+        let message = |device: &UsbipDevice, hosts: &[&str]| {
+            let hosts: Vec<String> = hosts.iter().map(|h| h.to_string()).collect();
+            ClientTarget::new(device, &hosts, DEFAULT_PORT).unwrap_err().to_string()
+        };
+        assert_eq!(message(&device(DeviceState::NotShared), &["192.168.1.20"]), "1-4 is not shared; bind it first");
+        assert_eq!(message(&device(DeviceState::Shared), &[]), "could not detect a LAN address for this host; pass one explicitly");
+        for host in ["lab host", "-oProxyCommand=x", "host;reboot", "$(id)", "\"host\""] {
+            assert_eq!(message(&device(DeviceState::Shared), &["192.168.1.20", host]), format!("'{}' is not a host name or address", host));
+        }
+    }
+
+    #[test]
+    fn script_kinds_and_file_names() {
+        assert_eq!("PS1".parse(), Ok(ScriptKind::PowerShell));
+        assert_eq!("sh".parse(), Ok(ScriptKind::Shell));
+        assert_eq!("bat".parse::<ScriptKind>(), Err(()));
+        let names: Vec<String> = ScriptKind::ALL.iter().map(|k| k.file_name("1-4")).collect();
+        assert_eq!(names, ["attach-1-4.sh", "usbip-attach-1-4.service", "attach-1-4.ps1"]);
+    }
+}
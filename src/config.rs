@@ -0,0 +1,941 @@
+//! The per-user configuration file.
+//!
+//! `config.toml` holds every setting that used to be hard-coded. The file
+//! carries a schema `version`; older files are migrated on load and written
+//! back. [`ConfigWatcher`] reloads the file when it changes on disk.
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::agent::UserToken;
+use crate::aliases::{self, DeviceAlias};
+use crate::api;
+use crate::firewall::{self, FirewallProfile};
+use crate::fleet::{self, RemoteHost};
+use crate::installer::{self, InstallMethod};
+use crate::leases::{DeviceLease, LeaseAction};
+use crate::linux_client::{AttachMethod, AttachTarget, Selector};
+use crate::linux_firewall::BackendChoice;
+use crate::rules::{Rule, RuleSet, RulesError};
+use crate::version::Version;
+use crate::wsl::{self, AutoAttach};
+
+const APP_DIR: &str = "usbip_host";
+pub const CONFIG_FILE: &str = "config.toml";
+/// The rules file written before the config file existed (schema version 0).
+pub const LEGACY_RULES_FILE: &str = "auto_share.toml";
+pub const SCHEMA_VERSION: u32 = 1;
+/// Shortest `agent.token`, `api.token`, personal token and `reverse.secret` accepted.
+const MIN_TOKEN_LEN: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppConfig {
+    pub version: u32,
+    pub server: ServerConfig,
+    pub firewall: FirewallConfig,
+    pub service: ServiceConfig,
+    pub window: WindowConfig,
+    pub watcher: WatcherConfig,
+    pub mdns: MdnsSettings,
+    pub metrics: MetricsConfig,
+    pub auto_share: AutoShareConfig,
+    pub aliases: Vec<DeviceAlias>,
+    pub installer: InstallerConfig,
+    pub updates: UpdatesConfig,
+    pub wsl: WslConfig,
+    pub client: ClientConfig,
+    pub agent: AgentConfig,
+    pub hosts: Vec<RemoteHost>,
+    pub api: ApiConfig,
+    pub reservations: ReservationsConfig,
+    pub leases: LeasesConfig,
+    pub reverse: ReverseConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// TCP port usbipd listens on.
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FirewallConfig {
+    pub rule_name: String,
+    /// Profiles the rule applies to. Empty means all of them.
+    pub profiles: Vec<FirewallProfile>,
+    /// Addresses allowed to connect, in any form netsh accepts for
+    /// `remoteip=`, e.g. `LocalSubnet` or `10.0.0.0/8`. Empty means any.
+    pub remote_addresses: Vec<String>,
+    /// `auto`, `nftables` or `iptables`. Only used on Linux.
+    pub linux_backend: BackendChoice,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServiceConfig {
+    pub winget_package_id: String,
+    /// Older usbipd-win releases trigger a warning.
+    pub minimum_usbipd_version: String,
+}
+
+/// Where usbipd-win is installed from. See [`crate::package_manager`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InstallerConfig {
+    pub method: InstallMethod,
+    /// Package managers `auto` tries, first available wins. `msi` only
+    /// counts as available when `msi_url` or `manifest_url` is set.
+    pub order: Vec<InstallMethod>,
+    pub chocolatey_package: String,
+    /// Scoop app, optionally prefixed with its bucket.
+    pub scoop_package: String,
+    /// URL or file share path of the usbipd-win MSI.
+    pub msi_url: String,
+    /// Pinned SHA-256 of the MSI at `msi_url`.
+    pub sha256: String,
+    /// Signed release manifest, used instead of `msi_url` and `sha256`.
+    /// The signature is expected at the same URL plus `.sig`.
+    pub manifest_url: String,
+    /// Hex Ed25519 public key the manifest must be signed with.
+    pub manifest_public_key: String,
+    /// Let winget skip its own hash check. Its global setting is restored afterwards.
+    pub winget_ignore_hash: bool,
+}
+
+/// Release feeds checked by [`crate::updates`], in the GitHub releases API format.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpdatesConfig {
+    pub usbipd_feed: String,
+    /// Releases of this application. Empty disables the check.
+    pub app_feed: String,
+    /// How long a fetched feed is reused before asking again.
+    pub cache_minutes: u64,
+    pub include_prereleases: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WindowConfig {
+    pub width: u32,
+    pub height: u32,
+    pub x: i32,
+    pub y: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatcherConfig {
+    pub poll_interval_ms: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MdnsSettings {
+    pub enabled: bool,
+    /// Advertised instance name. Empty means the computer name.
+    pub instance: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Address for the Prometheus endpoint, e.g. `127.0.0.1:9464`. Empty disables it.
+    pub listen: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AutoShareConfig {
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WslConfig {
+    /// Devices attached to a WSL distribution whenever they are plugged in.
+    pub auto_attach: Vec<AutoAttach>,
+}
+
+/// Agent mode, see [`crate::agent`].
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AgentConfig {
+    /// Address to accept agent requests on, e.g. `0.0.0.0:3242`. Empty disables agent mode.
+    pub listen: String,
+    /// Shared secret every request must carry.
+    pub token: String,
+    /// Shown in other hosts' Host column. Empty means the computer name.
+    pub name: String,
+    /// Personal tokens. Binds made with one are checked against reservations
+    /// as that user; the shared token can only bind unreserved devices.
+    pub users: Vec<UserToken>,
+}
+
+/// The local control API, see [`crate::api`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+    pub enabled: bool,
+    /// Only reachable from this machine unless changed.
+    pub listen: String,
+    /// Bearer token every request must carry.
+    pub token: String,
+    /// Personal bearer tokens, as in `[[agent.users]]`.
+    pub users: Vec<UserToken>,
+}
+
+/// See [`crate::reservations`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReservationsConfig {
+    /// How long a reservation lasts when no duration is given.
+    pub default_minutes: u64,
+}
+
+/// Idle timeouts and fixed leases, see [`crate::leases`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LeasesConfig {
+    /// Minutes without a URB before the action fires. 0 turns it off.
+    pub idle_minutes: u64,
+    /// Minutes after attaching (or sharing, for `unbind`) before the action fires. 0 turns it off.
+    pub max_minutes: u64,
+    pub action: LeaseAction,
+    /// How long before the action a warning is raised. 0 means no warning.
+    pub warn_minutes: u64,
+    /// Busids or aliases leases never apply to.
+    pub exempt: Vec<String>,
+    pub devices: Vec<DeviceLease>,
+}
+
+/// Reverse-connect mode, see [`crate::reverse`].
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReverseConfig {
+    /// Shared by the host and the rendezvous; hosts that can't prove they know it are turned away.
+    pub secret: String,
+}
+
+/// Client mode on Linux, see [`crate::linux_client`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    pub targets: Vec<AttachTarget>,
+    pub method: AttachMethod,
+    pub vhci_path: String,
+    /// Where usbip keeps the `port<N>` records `usbip port` reads.
+    pub record_dir: String,
+    pub poll_interval_secs: u64,
+    pub initial_backoff_secs: u64,
+    pub max_backoff_secs: u64,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        AppConfig {
+            version: SCHEMA_VERSION,
+            server: ServerConfig::default(),
+            firewall: FirewallConfig::default(),
+            service: ServiceConfig::default(),
+            window: WindowConfig::default(),
+            watcher: WatcherConfig::default(),
+            mdns: MdnsSettings::default(),
+            metrics: MetricsConfig::default(),
+            auto_share: AutoShareConfig::default(),
+            aliases: Vec::new(),
+            installer: InstallerConfig::default(),
+            updates: UpdatesConfig::default(),
+            wsl: WslConfig::default(),
+            client: ClientConfig::default(),
+            agent: AgentConfig::default(),
+            hosts: Vec::new(),
+            api: ApiConfig::default(),
+            reservations: ReservationsConfig::default(),
+            leases: LeasesConfig::default(),
+            reverse: ReverseConfig::default(),
+        }
+    }
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        ApiConfig { enabled: false, listen: format!("127.0.0.1:{}", api::API_PORT), token: String::new(), users: Vec::new() }
+    }
+}
+
+impl Default for ReservationsConfig {
+    fn default() -> Self {
+        ReservationsConfig { default_minutes: 60 }
+    }
+}
+
+impl ReservationsConfig {
+    pub fn default_duration(&self) -> Duration {
+        Duration::from_secs(self.default_minutes.saturating_mul(60))
+    }
+}
+
+impl Default for LeasesConfig {
+    fn default() -> Self {
+        LeasesConfig {
+            idle_minutes: 0,
+            max_minutes: 0,
+            action: LeaseAction::Detach,
+            warn_minutes: 5,
+            exempt: Vec::new(),
+            devices: Vec::new(),
+        }
+    }
+}
+
+impl LeasesConfig {
+    /// Whether any device can have a lease at all.
+    pub fn is_enabled(&self) -> bool {
+        self.idle_minutes > 0
+            || self.max_minutes > 0
+            || self.devices.iter().any(|d| d.idle_minutes.unwrap_or(0) > 0 || d.max_minutes.unwrap_or(0) > 0)
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig { port: 3240 }
+    }
+}
+
+impl Default for FirewallConfig {
+    fn default() -> Self {
+        FirewallConfig {
+            rule_name: String::from("_Plex (Port 3240)"),
+            profiles: Vec::new(),
+            remote_addresses: Vec::new(),
+            linux_backend: BackendChoice::Auto,
+        }
+    }
+}
+
+impl Default for ServiceConfig {
+    fn default() -> Self {
+        ServiceConfig {
+            winget_package_id: String::from("dorssel.usbipd-win"),
+            minimum_usbipd_version: String::from("4.0.0"),
+        }
+    }
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        WindowConfig { width: 940, height: 530, x: 300, y: 300 }
+    }
+}
+
+impl Default for InstallerConfig {
+    fn default() -> Self {
+        InstallerConfig {
+            method: InstallMethod::Auto,
+            order: vec![InstallMethod::Msi, InstallMethod::Winget, InstallMethod::Chocolatey, InstallMethod::Scoop],
+            chocolatey_package: String::from("usbipd-win"),
+            scoop_package: String::from("nonportable/usbipd-win-np"),
+            msi_url: String::new(),
+            sha256: String::new(),
+            manifest_url: String::new(),
+            manifest_public_key: String::new(),
+            winget_ignore_hash: false,
+        }
+    }
+}
+
+impl Default for UpdatesConfig {
+    fn default() -> Self {
+        UpdatesConfig {
+            usbipd_feed: String::from("https://api.github.com/repos/dorssel/usbipd-win/releases"),
+            app_feed: String::new(),
+            cache_minutes: 360,
+            include_prereleases: false,
+        }
+    }
+}
+
+impl Default for WatcherConfig {
+    fn default() -> Self {
+        WatcherConfig { poll_interval_ms: 2000 }
+    }
+}
+
+impl Default for MdnsSettings {
+    fn default() -> Self {
+        MdnsSettings { enabled: true, instance: String::new() }
+    }
+}
+
+impl WatcherConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_interval_ms)
+    }
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            targets: Vec::new(),
+            method: AttachMethod::default(),
+            vhci_path: String::from("/sys/devices/platform/vhci_hcd.0"),
+            record_dir: String::from("/var/run/vhci_hcd"),
+            poll_interval_secs: 5,
+            initial_backoff_secs: 1,
+            max_backoff_secs: 60,
+        }
+    }
+}
+
+impl ClientConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_secs)
+    }
+
+    pub fn initial_backoff(&self) -> Duration {
+        Duration::from_secs(self.initial_backoff_secs)
+    }
+
+    pub fn max_backoff(&self) -> Duration {
+        Duration::from_secs(self.max_backoff_secs)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    /// The file isn't valid TOML or doesn't fit the schema. The message
+    /// carries the line and column.
+    Parse(String),
+    /// A value that parsed but makes no sense, with its dotted key.
+    Invalid { key: String, message: String },
+    /// Written by a newer version of this application.
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "{}", e),
+            ConfigError::Parse(message) => f.write_str(message),
+            ConfigError::Invalid { key, message } => write!(f, "{}: {}", key, message),
+            ConfigError::UnsupportedVersion(v) => {
+                write!(f, "version: schema version {} is newer than the supported {}", v, SCHEMA_VERSION)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> Self {
+        ConfigError::Io(e)
+    }
+}
+
+impl AppConfig {
+    /// Parses a config file of any supported schema version.
+    ///
+    /// The second value is true if the text had to be migrated.
+    pub fn parse(text: &str) -> Result<(Self, bool), ConfigError> {
+        let mut table: toml::Table = text.parse().map_err(|e: toml::de::Error| ConfigError::Parse(e.to_string()))?;
+        let migrated = migrate(&mut table)?;
+        // Deserializing the original text keeps line numbers in the error.
+        let config: Result<AppConfig, toml::de::Error> =
+            if migrated { table.try_into() } else { toml::from_str(text) };
+        let config = config.map_err(|e| ConfigError::Parse(e.to_string()))?;
+        config.validate()?;
+        Ok((config, migrated))
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |key: &str, message: &str| {
+            Err(ConfigError::Invalid { key: key.to_string(), message: message.to_string() })
+        };
+        if self.server.port == 0 {
+            return invalid("server.port", "must be between 1 and 65535");
+        }
+        if self.firewall.rule_name.trim().is_empty() {
+            return invalid("firewall.rule_name", "must not be empty");
+        }
+        for (i, address) in self.firewall.remote_addresses.iter().enumerate() {
+            if !firewall::is_valid_remote_address(address) {
+                return Err(ConfigError::Invalid {
+                    key: format!("firewall.remote_addresses[{}]", i),
+                    message: format!("'{}' is not an address, range, subnet or netsh keyword", address),
+                });
+            }
+        }
+        if self.service.winget_package_id.trim().is_empty() {
+            return invalid("service.winget_package_id", "must not be empty");
+        }
+        if let Err(e) = self.service.minimum_usbipd_version.parse::<Version>() {
+            return invalid("service.minimum_usbipd_version", &e.to_string());
+        }
+        let installer = &self.installer;
+        if !installer.sha256.is_empty() && !installer::is_sha256(&installer.sha256) {
+            return invalid("installer.sha256", "must be 64 hex digits");
+        }
+        if !installer.manifest_url.is_empty() && !installer::is_public_key(&installer.manifest_public_key) {
+            return invalid("installer.manifest_public_key", "must be a 32-byte hex Ed25519 key when manifest_url is set");
+        }
+        if installer.method == InstallMethod::Msi && installer.msi_url.is_empty() && installer.manifest_url.is_empty() {
+            return invalid("installer.method", "msi needs msi_url or manifest_url");
+        }
+        for (i, method) in installer.order.iter().enumerate() {
+            let message = if *method == InstallMethod::Auto {
+                "auto is not a package manager"
+            } else if installer.order[..i].contains(method) {
+                "is listed twice"
+            } else {
+                continue;
+            };
+            return Err(ConfigError::Invalid { key: format!("installer.order[{}]", i), message: message.to_string() });
+        }
+        if installer.method == InstallMethod::Auto && installer.order.is_empty() {
+            return invalid("installer.order", "must name at least one package manager when method is auto");
+        }
+        for (key, feed) in [("updates.usbipd_feed", &self.updates.usbipd_feed), ("updates.app_feed", &self.updates.app_feed)] {
+            if !feed.is_empty() && !feed.starts_with("https://") && !feed.starts_with("http://") {
+                return invalid(key, "must be an http(s) URL");
+            }
+        }
+        if self.window.width < 200 {
+            return invalid("window.width", "must be at least 200");
+        }
+        if self.window.height < 150 {
+            return invalid("window.height", "must be at least 150");
+        }
+        if self.watcher.poll_interval_ms < 250 {
+            return invalid("watcher.poll_interval_ms", "must be at least 250");
+        }
+        if !self.metrics.listen.is_empty() && self.metrics.listen.parse::<SocketAddr>().is_err() {
+            return invalid("metrics.listen", "must be an address like 127.0.0.1:9464");
+        }
+        if let Err(RulesError::Invalid { index, message, .. }) = RuleSet::new(self.auto_share.rules.clone()) {
+            return Err(ConfigError::Invalid { key: format!("auto_share.rules[{}]", index), message });
+        }
+        if let Err(e) = aliases::validate(&self.aliases) {
+            return Err(ConfigError::Invalid { key: format!("aliases[{}].{}", e.index, e.field), message: e.message });
+        }
+        for (i, entry) in self.wsl.auto_attach.iter().enumerate() {
+            let (field, message) = if entry.device.trim().is_empty() {
+                ("device", String::from("must not be empty"))
+            } else if !aliases::looks_like_busid(&entry.device)
+                && !self.aliases.iter().any(|a| a.alias.eq_ignore_ascii_case(&entry.device))
+            {
+                ("device", format!("'{}' is neither a busid nor a known alias", entry.device))
+            } else if !entry.distribution.is_empty() && !wsl::is_valid_distribution_name(&entry.distribution) {
+                ("distribution", format!("'{}' is not a valid distribution name", entry.distribution))
+            } else {
+                continue;
+            };
+            return Err(ConfigError::Invalid { key: format!("wsl.auto_attach[{}].{}", i, field), message });
+        }
+        for (i, target) in self.client.targets.iter().enumerate() {
+            let (field, message) = if target.host.trim().is_empty() {
+                ("host", String::from("must not be empty"))
+            } else if Selector::parse(&target.device, &self.aliases).is_none() {
+                ("device", format!("'{}' is not a busid, VID:PID or alias with a vidpid", target.device))
+            } else {
+                continue;
+            };
+            return Err(ConfigError::Invalid { key: format!("client.targets[{}].{}", i, field), message });
+        }
+        if !self.agent.listen.is_empty() {
+            if self.agent.listen.parse::<SocketAddr>().is_err() {
+                return invalid("agent.listen", "must be an address like 0.0.0.0:3242");
+            }
+            if self.agent.token.len() < MIN_TOKEN_LEN {
+                return invalid("agent.token", "must be at least 16 characters when agent.listen is set");
+            }
+        }
+        check_users("agent", &self.agent.users, &self.agent.token)?;
+        if self.api.enabled {
+            if self.api.listen.parse::<SocketAddr>().is_err() {
+                return invalid("api.listen", "must be an address like 127.0.0.1:3243");
+            }
+            if self.api.token.len() < MIN_TOKEN_LEN {
+                return invalid("api.token", "must be at least 16 characters when api.enabled is set");
+            }
+        }
+        check_users("api", &self.api.users, &self.api.token)?;
+        if !self.reverse.secret.is_empty() && self.reverse.secret.len() < MIN_TOKEN_LEN {
+            return invalid("reverse.secret", "must be at least 16 characters");
+        }
+        for (i, host) in self.hosts.iter().enumerate() {
+            let (field, message) = if host.name.trim().is_empty() {
+                ("name", String::from("must not be empty"))
+            } else if host.name.eq_ignore_ascii_case(fleet::LOCAL) {
+                ("name", format!("'{}' is reserved for this machine", fleet::LOCAL))
+            } else if self.hosts[..i].iter().any(|h| h.name.eq_ignore_ascii_case(&host.name)) {
+                ("name", format!("'{}' is listed twice", host.name))
+            } else if host.address.trim().is_empty() {
+                ("address", String::from("must not be empty"))
+            } else if host.token.is_empty() {
+                ("token", String::from("must not be empty"))
+            } else {
+                continue;
+            };
+            return Err(ConfigError::Invalid { key: format!("hosts[{}].{}", i, field), message });
+        }
+        let is_device_name = |name: &str| {
+            aliases::looks_like_busid(name) || self.aliases.iter().any(|a| a.alias.eq_ignore_ascii_case(name))
+        };
+        for (i, name) in self.leases.exempt.iter().enumerate() {
+            if !is_device_name(name) {
+                return Err(ConfigError::Invalid {
+                    key: format!("leases.exempt[{}]", i),
+                    message: format!("'{}' is neither a busid nor a known alias", name),
+                });
+            }
+        }
+        for (i, entry) in self.leases.devices.iter().enumerate() {
+            if !is_device_name(&entry.device) {
+                return Err(ConfigError::Invalid {
+                    key: format!("leases.devices[{}].device", i),
+                    message: format!("'{}' is neither a busid nor a known alias", entry.device),
+                });
+            }
+        }
+        if self.reservations.default_minutes == 0 {
+            return invalid("reservations.default_minutes", "must be at least 1");
+        }
+        if self.client.poll_interval_secs == 0 {
+            return invalid("client.poll_interval_secs", "must be at least 1");
+        }
+        if self.client.initial_backoff_secs == 0 || self.client.max_backoff_secs < self.client.initial_backoff_secs {
+            return invalid("client.max_backoff_secs", "must be at least initial_backoff_secs, which must be at least 1");
+        }
+        Ok(())
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).unwrap_or_default()
+    }
+}
+
+/// Personal tokens must name someone and tell them apart, or a bind could
+/// count as the wrong user's.
+fn check_users(table: &str, users: &[UserToken], shared: &str) -> Result<(), ConfigError> {
+    for (i, entry) in users.iter().enumerate() {
+        let (field, message) = if entry.user.trim().is_empty() {
+            ("user", String::from("must not be empty"))
+        } else if entry.token.len() < MIN_TOKEN_LEN {
+            ("token", format!("must be at least {} characters", MIN_TOKEN_LEN))
+        } else if entry.token == shared || users[..i].iter().any(|u| u.token == entry.token) {
+            ("token", String::from("must differ from the shared token and every other user's"))
+        } else {
+            continue;
+        };
+        return Err(ConfigError::Invalid { key: format!("{}.users[{}].{}", table, i, field), message });
+    }
+    Ok(())
+}
+
+/// Upgrades `table` in place to [`SCHEMA_VERSION`]. Returns whether anything changed.
+fn migrate(table: &mut toml::Table) -> Result<bool, ConfigError> {
+    let version = match table.get("version") {
+        None => 0,
+        Some(toml::Value::Integer(v)) => u32::try_from(*v).map_err(|_| ConfigError::Invalid {
+            key: String::from("version"),
+            message: format!("{} is not a schema version", v),
+        })?,
+        Some(_) => {
+            return Err(ConfigError::Invalid {
+                key: String::from("version"),
+                message: String::from("must be a non-negative integer"),
+            })
+        }
+    };
+    if version > SCHEMA_VERSION {
+        return Err(ConfigError::UnsupportedVersion(version));
+    }
+
+    if version < 1 {
+        // Version 0 is the standalone auto-share file: a top-level [[rule]] array.
+        if let Some(rules) = table.remove("rule") {
+            let mut auto_share = toml::Table::new();
+            auto_share.insert(String::from("rules"), rules);
+            table.insert(String::from("auto_share"), toml::Value::Table(auto_share));
+        }
+    }
+
+    table.insert(String::from("version"), toml::Value::Integer(SCHEMA_VERSION as i64));
+    Ok(version != SCHEMA_VERSION)
+}
+
+/// `%APPDATA%\usbip_host` on Windows, `$XDG_CONFIG_HOME/usbip_host` or
+/// `~/.config/usbip_host` elsewhere.
+pub fn config_dir() -> PathBuf {
+    let base = if cfg!(windows) {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+    };
+    base.unwrap_or_else(|| PathBuf::from(".")).join(APP_DIR)
+}
+
+pub fn config_path() -> PathBuf {
+    config_dir().join(CONFIG_FILE)
+}
+
+/// Loads `path`, migrating and rewriting it if it's from an older schema.
+///
+/// A missing file is created with the defaults, importing the legacy
+/// auto-share rules file if there is one next to it.
+pub fn load_from(path: &Path) -> Result<AppConfig, ConfigError> {
+    let (text, legacy) = match fs::read_to_string(path) {
+        Ok(text) => (text, false),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let legacy_path = path.with_file_name(LEGACY_RULES_FILE);
+            match fs::read_to_string(&legacy_path) {
+                Ok(text) => (text, true),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    let config = AppConfig::default();
+                    save_to(path, &config)?;
+                    return Ok(config);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let (config, migrated) = AppConfig::parse(&text)?;
+    if migrated || legacy {
+        save_to(path, &config)?;
+    }
+    Ok(config)
+}
+
+/// Applies `change` to the file on disk, then saves it and puts it into effect.
+///
+/// Reads the file rather than [`current`] so edits made since start-up survive.
+pub fn update(change: impl FnOnce(&mut AppConfig)) -> Result<AppConfig, ConfigError> {
+    let path = config_path();
+    let mut config = load_from(&path)?;
+    change(&mut config);
+    config.validate()?;
+    save_to(&path, &config)?;
+    set(config.clone());
+    Ok(config)
+}
+
+pub fn save_to(path: &Path, config: &AppConfig) -> Result<(), ConfigError> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Written aside and renamed, so a crash or a watcher never sees half a file.
+    let temp = path.with_extension("tmp");
+    fs::write(&temp, config.to_toml())?;
+    fs::rename(&temp, path)?;
+    Ok(())
+}
+
+fn global() -> &'static RwLock<AppConfig> {
+    static CONFIG: OnceLock<RwLock<AppConfig>> = OnceLock::new();
+    CONFIG.get_or_init(|| RwLock::new(AppConfig::default()))
+}
+
+/// Loads the user's config file into the process-wide settings.
+///
+/// On error the defaults stay in effect and the error is returned so the
+/// caller can tell the user.
+pub fn init() -> Result<(), ConfigError> {
+    let config = load_from(&config_path())?;
+    set(config);
+    Ok(())
+}
+
+/// A copy of the settings currently in effect.
+pub fn current() -> AppConfig {
+    global().read().unwrap().clone()
+}
+
+pub fn set(config: AppConfig) {
+    *global().write().unwrap() = config;
+}
+
+#[derive(Debug, Clone)]
+pub enum ConfigEvent {
+    /// The file changed and the new settings are now in effect.
+    Reloaded(Box<AppConfig>),
+    /// The file changed but is invalid; the previous settings stay in effect.
+    Invalid(String),
+}
+
+/// Reloads the config file when its modification time changes.
+pub struct ConfigWatcher {
+    subscribers: Arc<Mutex<Vec<Sender<ConfigEvent>>>>,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ConfigWatcher {
+    pub fn start(path: PathBuf, interval: Duration) -> Self {
+        let subscribers: Arc<Mutex<Vec<Sender<ConfigEvent>>>> = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let subscribers = subscribers.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut last = modified(&path);
+                while !stop.load(Ordering::Relaxed) {
+                    thread::sleep(interval);
+                    let now = modified(&path);
+                    if now == last {
+                        continue;
+                    }
+                    last = now;
+                    let event = match fs::read_to_string(&path).map_err(ConfigError::from).and_then(|t| AppConfig::parse(&t)) {
+                        Ok((config, _)) => {
+                            set(config.clone());
+                            ConfigEvent::Reloaded(Box::new(config))
+                        }
+                        Err(e) => ConfigEvent::Invalid(e.to_string()),
+                    };
+                    let mut subscribers = subscribers.lock().unwrap();
+                    subscribers.retain(|tx| tx.send(event.clone()).is_ok());
+                }
+            })
+        };
+
+        ConfigWatcher { subscribers, stop, handle: Some(handle) }
+    }
+
+    pub fn subscribe(&self) -> Receiver<ConfigEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+}
+
+impl Drop for ConfigWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// When the file was last written, and its length, since two writes can
+/// land in the same timestamp tick.
+fn modified(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::RuleAction;
+    use crate::testing::TempDir;
+
+    /// A schema version 0 file: the old standalone auto-share rules.
+    const LEGACY: &str = "[[rule]]\nname = \"probe\"\nvidpid = \"0483:374b\"\naction = \"bind\"\n";
+
+    #[test]
+    fn version_0_files_are_migrated() {
+        let (config, migrated) = AppConfig::parse(LEGACY).unwrap();
+        assert!(migrated);
+        assert_eq!(config.version, SCHEMA_VERSION);
+        assert_eq!(config.auto_share.rules.len(), 1);
+        assert_eq!(config.auto_share.rules[0].name, "probe");
+        assert_eq!(config.auto_share.rules[0].action, RuleAction::Bind);
+
+        let (_, migrated) = AppConfig::parse(&config.to_toml()).unwrap();
+        assert!(!migrated);
+    }
+
+    #[test]
+    fn old_files_are_rewritten_on_load() {
+        let dir = TempDir::new("config");
+        let path = dir.join(CONFIG_FILE);
+        fs::write(&path, format!("version = 0\n{}", LEGACY)).unwrap();
+        let config = load_from(&path).unwrap();
+        assert_eq!(config.auto_share.rules[0].name, "probe");
+        let saved = fs::read_to_string(&path).unwrap();
+        assert!(saved.starts_with(&format!("version = {}\n", SCHEMA_VERSION)), "{}", saved);
+        assert!(!path.with_extension("tmp").exists());
+
+        // With no config file yet, the legacy rules file next to it is imported.
+        let path = dir.join("fresh").join(CONFIG_FILE);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path.with_file_name(LEGACY_RULES_FILE), LEGACY).unwrap();
+        assert_eq!(load_from(&path).unwrap().auto_share.rules.len(), 1);
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn bad_versions_name_the_version_key() {
+        for text in ["version = -1", "version = 4294967296", "version = \"1\""] {
+            match AppConfig::parse(text) {
+                Err(ConfigError::Invalid { key, .. }) => assert_eq!(key, "version", "{}", text),
+                other => panic!("{}: {:?}", text, other),
+            }
+        }
+        assert!(matches!(AppConfig::parse("version = 99"), Err(ConfigError::UnsupportedVersion(99))));
+    }
+
+    #[test]
+    fn validation_errors_name_the_bad_key() {
+        let cases = [
+            ("[server]\nport = 0", "server.port"),
+            ("[window]\nwidth = 10", "window.width"),
+            ("[firewall]\nremote_addresses = [\"LocalSubnet\", \"not an address\"]", "firewall.remote_addresses[1]"),
+            ("[api]\ntoken = \"0123456789abcdef\"\n[[api.users]]\nuser = \"ana\"\ntoken = \"0123456789abcdef\"", "api.users[0].token"),
+        ];
+        for (text, expected) in cases {
+            let error = AppConfig::parse(&format!("version = 1\n{}", text)).unwrap_err();
+            assert!(matches!(&error, ConfigError::Invalid { key, .. } if key == expected), "{}: {:?}", text, error);
+            assert!(error.to_string().starts_with(&format!("{}: ", expected)));
+        }
+    }
+
+    #[test]
+    fn the_watcher_reloads_a_changed_file() {
+        let dir = TempDir::new("config");
+        let path = dir.join(CONFIG_FILE);
+        save_to(&path, &AppConfig::default()).unwrap();
+        let watcher = ConfigWatcher::start(path.clone(), Duration::from_millis(20));
+        let events = watcher.subscribe();
+
+        // Rewritten until seen, in case the watcher started after the first write.
+        let invalid = (0..50)
+            .find_map(|_| {
+                fs::write(&path, "[window]\nwidth = 10\n").unwrap();
+                events.recv_timeout(Duration::from_millis(100)).ok()
+            })
+            .expect("no event");
+        assert!(matches!(&invalid, ConfigEvent::Invalid(message) if message.starts_with("window.width: ")), "{:?}", invalid);
+
+        let mut changed = AppConfig::default();
+        changed.window.width += 1;
+        save_to(&path, &changed).unwrap();
+        match events.recv_timeout(Duration::from_secs(5)).unwrap() {
+            ConfigEvent::Reloaded(config) => assert_eq!(config.window.width, changed.window.width),
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -1,34 +1,201 @@
-use std::process::{Command, Stdio};
-use std::os::windows::process::CommandExt;
-use std::io::Read;
+use std::fmt;
 
-const CREATE_NO_WINDOW: u32 = 0x08000000;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-#[derive(Debug, Clone)]
+use crate::runner::{self, CommandError, CommandRunner};
+use crate::version::{self, Capability};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UsbipDevice {
     pub busid: String,
     pub vidpid: String,
     pub device: String,
-    pub state: String,
+    pub state: DeviceState,
     pub persisted: bool,
+    /// GUID of the persisted binding. Persisted devices that aren't
+    /// connected have this instead of a busid.
+    pub guid: Option<String>,
+    /// Windows device instance ID, e.g. `USB\VID_046D&PID_C52B\5&2A5E5C3&0&1`.
+    /// Only known when usbipd supports `usbipd state`.
+    pub instance_id: Option<String>,
+    /// WSL distribution the device was attached to from this machine.
+    pub wsl_distribution: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeviceState {
+    NotShared,
+    Shared,
+    SharedForced,
+    Attached,
+    /// Listed under "Persisted:", i.e. bound but not currently connected.
+    Persisted,
+    Unknown,
+}
+
+/// State column values printed by `usbipd list`, longest first.
+const STATES: [(&str, DeviceState); 4] = [
+    ("Shared (forced)", DeviceState::SharedForced),
+    ("Not shared", DeviceState::NotShared),
+    ("Attached", DeviceState::Attached),
+    ("Shared", DeviceState::Shared),
+];
+
+impl DeviceState {
+    pub const ALL: [DeviceState; 6] = [
+        DeviceState::NotShared,
+        DeviceState::Shared,
+        DeviceState::SharedForced,
+        DeviceState::Attached,
+        DeviceState::Persisted,
+        DeviceState::Unknown,
+    ];
+
+    /// Short lowercase name for metrics and machine-readable output.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeviceState::NotShared => "not_shared",
+            DeviceState::Shared => "shared",
+            DeviceState::SharedForced => "shared_forced",
+            DeviceState::Attached => "attached",
+            DeviceState::Persisted => "persisted",
+            DeviceState::Unknown => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for DeviceState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            DeviceState::Persisted => "Persisted",
+            DeviceState::Unknown => "Unknown",
+            state => STATES.iter().find(|(_, s)| s == state).map(|(t, _)| *t).unwrap_or_default(),
+        };
+        f.write_str(text)
+    }
+}
+
+impl UsbipDevice {
+    /// The busid, or the GUID for persisted devices that aren't connected.
+    pub fn display_id(&self) -> &str {
+        match &self.guid {
+            Some(guid) if self.busid.is_empty() => guid,
+            _ => &self.busid,
+        }
+    }
+
+    /// The USB serial number, taken from the last part of the instance ID.
+    ///
+    /// Windows makes up an ID containing `&` for devices without a serial
+    /// number, so those yield `None`.
+    pub fn serial(&self) -> Option<&str> {
+        let serial = self.instance_id.as_deref()?.rsplit('\\').next()?;
+        if serial.is_empty() || serial.contains('&') { None } else { Some(serial) }
+    }
+
+    /// True if the device is bound and can be attached by a client.
+    pub fn is_shared(&self) -> bool {
+        matches!(self.state, DeviceState::Shared | DeviceState::SharedForced | DeviceState::Attached)
+    }
+
+    /// The state for display, naming the WSL distribution of attached devices.
+    pub fn state_label(&self) -> String {
+        match &self.wsl_distribution {
+            Some(distribution) if self.state == DeviceState::Attached => format!("Attached (WSL: {})", distribution),
+            _ => self.state.to_string(),
+        }
+    }
+}
+
+impl Serialize for DeviceState {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for DeviceState {
+    /// Reads [`DeviceState::as_str`] names; anything else is `Unknown`.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Ok(DeviceState::ALL.into_iter().find(|s| s.as_str() == name).unwrap_or(DeviceState::Unknown))
+    }
+}
+
+/// Lists devices, preferring the machine-readable `usbipd state` and falling
+/// back to the `usbipd list` table on versions that don't have it.
+///
+/// WSL distributions aren't filled in; see [`crate::wsl::Attachments::annotate`].
+pub fn list_devices(runner: &dyn CommandRunner) -> Result<Vec<UsbipDevice>, CommandError> {
+    let devices = if version::capabilities(runner).supports(Capability::StateJson)
+        && let Ok(output) = runner::usbipd(runner, &["state"])
+        && let Some(devices) = parse_state(&output.stdout)
+    {
+        devices
+    } else {
+        parse_list(&runner::usbipd(runner, &["list"])?.stdout)
+    };
+    Ok(devices)
 }
 
-pub fn list_devices() -> Vec<UsbipDevice> {
-    let output = Command::new("usbipd")
-        .args(&["list"])
-        .creation_flags(CREATE_NO_WINDOW)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::null())
-        .spawn()
-        .and_then(|mut child| {
-            let mut out = String::new();
-            if let Some(mut stdout) = child.stdout.take() {
-                let _ = stdout.read_to_string(&mut out);
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct StateOutput {
+    devices: Vec<StateDevice>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct StateDevice {
+    bus_id: Option<String>,
+    #[serde(rename = "ClientIPAddress")]
+    client_ip_address: Option<String>,
+    description: Option<String>,
+    instance_id: Option<String>,
+    #[serde(default)]
+    is_forced: bool,
+    persisted_guid: Option<String>,
+}
+
+/// Parses the JSON printed by `usbipd state`.
+pub fn parse_state(output: &str) -> Option<Vec<UsbipDevice>> {
+    let state: StateOutput = serde_json::from_str(output).ok()?;
+    let devices = state
+        .devices
+        .into_iter()
+        .map(|d| {
+            let state = match (&d.bus_id, &d.persisted_guid, &d.client_ip_address) {
+                (None, _, _) => DeviceState::Persisted,
+                (Some(_), _, Some(_)) => DeviceState::Attached,
+                (Some(_), Some(_), None) if d.is_forced => DeviceState::SharedForced,
+                (Some(_), Some(_), None) => DeviceState::Shared,
+                (Some(_), None, None) => DeviceState::NotShared,
+            };
+            UsbipDevice {
+                busid: d.bus_id.unwrap_or_default(),
+                vidpid: d.instance_id.as_deref().and_then(vidpid_from_instance_id).unwrap_or_default(),
+                device: d.description.unwrap_or_default(),
+                state,
+                persisted: state == DeviceState::Persisted,
+                guid: d.persisted_guid,
+                instance_id: d.instance_id,
+                wsl_distribution: None,
             }
-            let _ = child.wait();
-            Ok(out)
-        }).unwrap_or_default();
+        })
+        .collect();
+    Some(devices)
+}
+
+/// Extracts `vid:pid` from an instance ID like `USB\VID_046D&PID_C52B\...`.
+pub fn vidpid_from_instance_id(instance_id: &str) -> Option<String> {
+    let upper = instance_id.to_ascii_uppercase();
+    let id = |prefix: &str| upper.split(prefix).nth(1)?.get(..4).filter(|id| id.chars().all(|c| c.is_ascii_hexdigit()));
+    let vid = id("VID_")?;
+    let pid = id("PID_")?;
+    Some(format!("{}:{}", vid, pid).to_ascii_lowercase())
+}
 
+/// Parses the human-readable table printed by `usbipd list`.
+pub fn parse_list(output: &str) -> Vec<UsbipDevice> {
     let mut out_devices = Vec::new();
     let mut section = "";
     for line in output.lines() {
@@ -40,35 +207,142 @@ pub fn list_devices() -> Vec<UsbipDevice> {
         if l.starts_with("Persisted:") {
             section = "persisted"; continue;
         }
-        if l.starts_with("BUSID") { continue; }
+        if l.starts_with("BUSID") || l.starts_with("GUID") { continue; }
         let cols: Vec<&str> = l.split_whitespace().collect();
+        if section == "persisted" {
+            // GUID followed by the description, no VID:PID or state.
+            if cols.len() < 2 { continue; }
+            out_devices.push(UsbipDevice {
+                busid: String::new(),
+                vidpid: String::new(),
+                device: cols[1..].join(" "),
+                state: DeviceState::Persisted,
+                persisted: true,
+                guid: Some(cols[0].to_string()),
+                instance_id: None,
+                wsl_distribution: None,
+            });
+            continue;
+        }
         if cols.len() < 4 { continue; }
+        // The state column may span several words ("Not shared", "Shared (forced)").
+        let (state_words, state) = match STATES.iter().find(|(s, _)| l.ends_with(s)) {
+            Some((text, state)) => (text.split_whitespace().count(), *state),
+            None => (1, DeviceState::Unknown),
+        };
+        if cols.len() < 3 + state_words { continue; }
         let dev = UsbipDevice {
             busid: cols[0].to_string(),
             vidpid: cols[1].to_string(),
-            device: cols[2..cols.len()-1].join(" "),
-            state: cols[cols.len()-1].to_string(),
-            persisted: section == "persisted",
+            device: cols[2..cols.len()-state_words].join(" "),
+            state,
+            persisted: false,
+            guid: None,
+            instance_id: None,
+            wsl_distribution: None,
         };
         out_devices.push(dev);
     }
     out_devices
 }
-pub fn bind_device(busid: &str) -> Result<(), String> {
-    let output = Command::new("usbipd")
-        .args(&["bind", "-f", "-b", busid])
-        .creation_flags(CREATE_NO_WINDOW)
-        .output()
-        .map_err(|e| e.to_string())?;
-    if output.status.success() { Ok(()) }
-    else { Err(String::from_utf8_lossy(&output.stderr).into_owned()) }
-}
-pub fn unbind_device(busid: &str) -> Result<(), String> {
-    let output = Command::new("usbipd")
-        .args(&["unbind", "-b", busid])
-        .creation_flags(CREATE_NO_WINDOW)
-        .output()
-        .map_err(|e| e.to_string())?;
-    if output.status.success() { Ok(()) }
-    else { Err(String::from_utf8_lossy(&output.stderr).into_owned()) }
-}
\ No newline at end of file
+
+pub fn bind_device(runner: &dyn CommandRunner, busid: &str, force: bool) -> Result<(), CommandError> {
+    if force {
+        version::capabilities(runner).require(Capability::BindForce)?;
+        runner::usbipd(runner, &["bind", "--force", "--busid", busid])?;
+    } else {
+        runner::usbipd(runner, &["bind", "--busid", busid])?;
+    }
+    Ok(())
+}
+
+pub fn unbind_device(runner: &dyn CommandRunner, busid: &str) -> Result<(), CommandError> {
+    runner::usbipd(runner, &["unbind", "--busid", busid])?;
+    Ok(())
+}
+
+/// Unbinds a persisted device that is not currently connected.
+pub fn unbind_guid(runner: &dyn CommandRunner, guid: &str) -> Result<(), CommandError> {
+    version::capabilities(runner).require(Capability::UnbindGuid)?;
+    runner::usbipd(runner, &["unbind", "--guid", guid])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakeRunner;
+
+    /// One `usbipd state` device with the given fields set.
+    fn state_device(bus_id: Option<&str>, client: Option<&str>, guid: Option<&str>, forced: bool) -> String {
+        let text = |value: Option<&str>| value.map(|v| format!("\"{}\"", v)).unwrap_or_else(|| "null".to_string());
+        format!(
+            r#"{{"BusId":{},"ClientIPAddress":{},"Description":"USB Receiver","InstanceId":"USB\\VID_046D&PID_C52B\\5&1A2B","IsForced":{},"PersistedGuid":{}}}"#,
+            text(bus_id),
+            text(client),
+            forced,
+            text(guid)
+        )
+    }
+
+    #[test]
+    fn parse_state_covers_every_state() {
+        const GUID: &str = "0e8b6f4a-3c1d-4a7e-9b2f-5d6c7e8f9a0b";
+        let cases = [
+            (state_device(Some("1-3"), None, None, false), DeviceState::NotShared),
+            (state_device(Some("1-3"), None, Some(GUID), false), DeviceState::Shared),
+            (state_device(Some("1-3"), None, Some(GUID), true), DeviceState::SharedForced),
+            (state_device(Some("1-3"), Some("172.20.0.2"), Some(GUID), false), DeviceState::Attached),
+            (state_device(Some("1-3"), Some("172.20.0.2"), None, false), DeviceState::Attached),
+            (state_device(None, None, Some(GUID), false), DeviceState::Persisted),
+        ];
+        for (json, state) in cases {
+            let devices = parse_state(&format!(r#"{{"Devices":[{}]}}"#, json)).unwrap();
+            assert_eq!(devices.len(), 1, "{}", json);
+            let device = &devices[0];
+            assert_eq!(device.state, state, "{}", json);
+            assert_eq!(device.persisted, state == DeviceState::Persisted, "{}", json);
+            assert_eq!(device.vidpid, "046d:c52b");
+            assert_eq!(device.device, "USB Receiver");
+            assert_eq!(device.instance_id.as_deref(), Some(r"USB\VID_046D&PID_C52B\5&1A2B"));
+        }
+    }
+
+    #[test]
+    fn parse_state_rejects_what_isnt_state_output() {
+        for output in ["", "not json", "{}", r#"{"Devices":{}}"#, r#"{"Devices":[{"IsForced":"yes"}]}"#] {
+            assert!(parse_state(output).is_none(), "{}", output);
+        }
+        assert!(parse_state(r#"{"Devices":[]}"#).unwrap().is_empty());
+    }
+
+    #[test]
+    fn vidpid_comes_from_the_instance_id() {
+        let cases = [
+            (r"USB\VID_046D&PID_C52B\5&1A2B3C4D&0&3", Some("046d:c52b")),
+            (r"usb\vid_0483&pid_374b&mi_00\6&abc", Some("0483:374b")),
+            (r"USB\PID_C52B&VID_046D\1", Some("046d:c52b")),
+            ("", None),
+            (r"USB\ROOT_HUB30\4&1", None),
+            (r"USB\VID_046D\1", None),
+            (r"USB\PID_C52B\1", None),
+            (r"USB\VID_04&PID_C52B", None),
+            (r"USB\VID_046D&PID_C5", None),
+            (r"USB\VID_ZZZZ&PID_C52B", None),
+            ("USB\\VID_04D\u{e9}&PID_C52B", None),
+        ];
+        for (instance_id, vidpid) in cases {
+            assert_eq!(vidpid_from_instance_id(instance_id).as_deref(), vidpid, "{}", instance_id);
+        }
+    }
+
+    const LIST: &str = "Connected:\nBUSID  VID:PID    DEVICE                STATE\n1-3    046d:c52b  USB Receiver          Not shared\n";
+
+    #[test]
+    fn old_usbipd_is_listed_without_state() {
+        let runner = FakeRunner::empty().ok("usbipd --version", "2.3.0").ok("usbipd list", LIST).ok("usbipd state", "{}");
+        let devices = list_devices(&runner).unwrap();
+        assert_eq!(devices.iter().map(|d| d.busid.as_str()).collect::<Vec<_>>(), ["1-3"]);
+        assert!(runner.calls_to("usbipd state").is_empty());
+    }
+}
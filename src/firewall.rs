@@ -0,0 +1,554 @@
+//! The Windows Firewall rule that opens the USB/IP port.
+//!
+//! [`FirewallManager`] reads the rule back from `netsh advfirewall firewall
+//! show rule` and compares it with what the config asks for, so a rule that
+//! was disabled or edited by hand is repaired rather than reported as fine.
+//! The rule name, profiles and remote addresses come from `[firewall]` in
+//! the config file and the port from `server.port`. netsh localises its
+//! output; on a non-English system the rule is only found by name and its
+//! settings can't be compared.
+use std::fmt;
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::runner::{self, CommandError, CommandRunner};
+use crate::{config, metrics};
+
+/// Printed by netsh, with exit code 1, when no rule has the name.
+const NO_MATCH: &str = "No rules match the specified criteria.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FirewallProfile {
+    Domain,
+    Private,
+    Public,
+}
+
+impl FirewallProfile {
+    pub const ALL: [FirewallProfile; 3] = [FirewallProfile::Domain, FirewallProfile::Private, FirewallProfile::Public];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FirewallProfile::Domain => "domain",
+            FirewallProfile::Private => "private",
+            FirewallProfile::Public => "public",
+        }
+    }
+}
+
+/// The rule as the config file wants it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleSpec {
+    pub name: String,
+    pub port: u16,
+    /// Empty means every profile.
+    pub profiles: Vec<FirewallProfile>,
+    /// Values for netsh's `remoteip=`. Empty means any address.
+    pub remote_addresses: Vec<String>,
+}
+
+impl RuleSpec {
+    pub fn from_config() -> Self {
+        let settings = config::current();
+        RuleSpec {
+            name: settings.firewall.rule_name,
+            port: settings.server.port,
+            profiles: settings.firewall.profiles,
+            remote_addresses: settings.firewall.remote_addresses,
+        }
+    }
+
+    fn profile_arg(&self) -> String {
+        if self.profiles.is_empty() {
+            return String::from("any");
+        }
+        self.profiles.iter().map(FirewallProfile::as_str).collect::<Vec<_>>().join(",")
+    }
+
+    fn remote_arg(&self) -> String {
+        if self.remote_addresses.is_empty() { String::from("any") } else { self.remote_addresses.join(",") }
+    }
+
+    fn expected_profiles(&self) -> Vec<FirewallProfile> {
+        if self.profiles.is_empty() { FirewallProfile::ALL.to_vec() } else { self.profiles.clone() }
+    }
+}
+
+/// One rule from `netsh advfirewall firewall show rule`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct FirewallRule {
+    pub name: String,
+    pub enabled: bool,
+    pub direction: String,
+    pub profiles: String,
+    pub remote_ip: String,
+    pub protocol: String,
+    pub local_port: String,
+    pub action: String,
+}
+
+/// Parses the blocks printed by `netsh advfirewall firewall show rule`.
+///
+/// Each block starts with `Rule Name:` and lists `Key: value` lines. netsh
+/// localises these labels, so only English output is understood; see
+/// [`count_named`] for the rest.
+pub fn parse_rules(output: &str) -> Vec<FirewallRule> {
+    let mut rules: Vec<FirewallRule> = Vec::new();
+    for line in output.lines() {
+        let Some((key, value)) = line.split_once(':') else { continue };
+        let value = value.trim().to_string();
+        if key.trim() == "Rule Name" {
+            rules.push(FirewallRule { name: value, ..Default::default() });
+            continue;
+        }
+        let Some(rule) = rules.last_mut() else { continue };
+        match key.trim() {
+            "Enabled" => rule.enabled = value.eq_ignore_ascii_case("yes"),
+            "Direction" => rule.direction = value,
+            "Profiles" => rule.profiles = value,
+            "RemoteIP" => rule.remote_ip = value,
+            "Protocol" => rule.protocol = value,
+            "LocalPort" => rule.local_port = value,
+            "Action" => rule.action = value,
+            _ => {}
+        }
+    }
+    rules
+}
+
+/// How many blocks in netsh output, in any language, are headed by `name`:
+/// the header is the only line whose value is exactly the rule name.
+pub fn count_named(output: &str, name: &str) -> usize {
+    output.lines().filter(|line| line.split_once(':').is_some_and(|(_, value)| value.trim() == name)).count()
+}
+
+/// A difference between the installed rule and the [`RuleSpec`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Drift {
+    Disabled,
+    /// Several rules share the name; netsh would edit or delete all of them.
+    Duplicate { count: usize },
+    WrongDirection { found: String },
+    WrongAction { found: String },
+    WrongProtocol { found: String },
+    WrongPort { found: String },
+    WrongProfiles { found: String },
+    WrongRemoteAddresses { found: String },
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Drift::Disabled => f.write_str("rule is disabled"),
+            Drift::Duplicate { count } => write!(f, "{} rules share the name", count),
+            Drift::WrongDirection { found } => write!(f, "direction is {}", found),
+            Drift::WrongAction { found } => write!(f, "action is {}", found),
+            Drift::WrongProtocol { found } => write!(f, "protocol is {}", found),
+            Drift::WrongPort { found } => write!(f, "local port is {}", found),
+            Drift::WrongProfiles { found } => write!(f, "profiles are {}", found),
+            Drift::WrongRemoteAddresses { found } => write!(f, "remote addresses are {}", found),
+        }
+    }
+}
+
+/// Lists how `rules`, all carrying the spec's name, differ from `spec`.
+pub fn detect_drift(spec: &RuleSpec, rules: &[FirewallRule]) -> Vec<Drift> {
+    let mut drift = Vec::new();
+    if rules.len() > 1 {
+        drift.push(Drift::Duplicate { count: rules.len() });
+    }
+    let Some(rule) = rules.first() else { return drift };
+
+    if !rule.enabled {
+        drift.push(Drift::Disabled);
+    }
+    if !rule.direction.eq_ignore_ascii_case("in") {
+        drift.push(Drift::WrongDirection { found: rule.direction.clone() });
+    }
+    if !rule.action.eq_ignore_ascii_case("allow") {
+        drift.push(Drift::WrongAction { found: rule.action.clone() });
+    }
+    if !rule.protocol.eq_ignore_ascii_case("tcp") {
+        drift.push(Drift::WrongProtocol { found: rule.protocol.clone() });
+    }
+    if rule.local_port.trim() != spec.port.to_string() {
+        drift.push(Drift::WrongPort { found: rule.local_port.clone() });
+    }
+
+    let mut found: Vec<String> = rule.profiles.split(',').map(|p| p.trim().to_ascii_lowercase()).collect();
+    let mut expected: Vec<String> = spec.expected_profiles().iter().map(|p| p.as_str().to_string()).collect();
+    found.sort();
+    expected.sort();
+    if found != expected {
+        drift.push(Drift::WrongProfiles { found: rule.profiles.clone() });
+    }
+
+    let mut found: Vec<String> = rule.remote_ip.split(',').map(normalize_address).collect();
+    let mut expected: Vec<String> = if spec.remote_addresses.is_empty() {
+        vec![String::from("any")]
+    } else {
+        spec.remote_addresses.iter().map(|a| normalize_address(a)).collect()
+    };
+    found.sort();
+    expected.sort();
+    if found != expected {
+        drift.push(Drift::WrongRemoteAddresses { found: rule.remote_ip.clone() });
+    }
+    drift
+}
+
+/// Brings an address into the form netsh prints: lower case, with a host
+/// address as `/32` and a prefix length as a dotted mask.
+fn normalize_address(address: &str) -> String {
+    let address = address.trim().to_ascii_lowercase();
+    if let Ok(IpAddr::V4(ip)) = address.parse::<IpAddr>() {
+        return format!("{}/255.255.255.255", ip);
+    }
+    if let Some((ip, prefix)) = address.split_once('/')
+        && let (Ok(IpAddr::V4(ip)), Ok(prefix)) = (ip.parse::<IpAddr>(), prefix.parse::<u32>())
+        && prefix <= 32
+    {
+        let mask = if prefix == 0 { 0 } else { u32::MAX << (32 - prefix) };
+        return format!("{}/{}", ip, std::net::Ipv4Addr::from(mask));
+    }
+    address
+}
+
+/// Accepts the keywords and address forms netsh takes for `remoteip=`.
+pub fn is_valid_remote_address(value: &str) -> bool {
+    const KEYWORDS: [&str; 6] = ["any", "localsubnet", "dns", "dhcp", "wins", "defaultgateway"];
+    let value = value.trim();
+    if KEYWORDS.iter().any(|k| value.eq_ignore_ascii_case(k)) || value.parse::<IpAddr>().is_ok() {
+        return true;
+    }
+    if let Some((ip, prefix)) = value.split_once('/') {
+        let max = match ip.parse::<IpAddr>() {
+            Ok(IpAddr::V4(_)) => 32,
+            Ok(IpAddr::V6(_)) => 128,
+            Err(_) => return false,
+        };
+        return prefix.parse::<u32>().is_ok_and(|p| p <= max);
+    }
+    if let Some((start, end)) = value.split_once('-') {
+        return start.parse::<IpAddr>().is_ok() && end.parse::<IpAddr>().is_ok();
+    }
+    false
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum RuleStatus {
+    Missing,
+    Ok { rule: FirewallRule },
+    Drifted { rules: Vec<FirewallRule>, drift: Vec<Drift> },
+    /// The rule exists but netsh answered in a language [`parse_rules`]
+    /// doesn't read, so its settings weren't checked.
+    Unverified { count: usize },
+}
+
+impl RuleStatus {
+    pub fn is_present(&self) -> bool {
+        !matches!(self, RuleStatus::Missing)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnsureOutcome {
+    Unchanged,
+    Added,
+    /// A drifted rule was deleted and added again.
+    Repaired,
+}
+
+/// What `netsh advfirewall firewall show rule` said about the spec's name.
+enum Installed {
+    Parsed(Vec<FirewallRule>),
+    /// This many rules carry the name, in output `parse_rules` can't read.
+    Unreadable(usize),
+}
+
+pub struct FirewallManager<'a> {
+    runner: &'a dyn CommandRunner,
+    spec: RuleSpec,
+}
+
+impl<'a> FirewallManager<'a> {
+    pub fn new(runner: &'a dyn CommandRunner, spec: RuleSpec) -> Self {
+        FirewallManager { runner, spec }
+    }
+
+    /// A manager for the rule described in the config file.
+    pub fn from_config(runner: &'a dyn CommandRunner) -> Self {
+        Self::new(runner, RuleSpec::from_config())
+    }
+
+    pub fn spec(&self) -> &RuleSpec {
+        &self.spec
+    }
+
+    fn installed(&self) -> Result<Installed, CommandError> {
+        let name = format!("name={}", self.spec.name);
+        let output = self
+            .runner
+            .run("netsh", &["advfirewall", "firewall", "show", "rule", &name])
+            .map_err(|e| CommandError::from_io("netsh", e))?;
+        let named = count_named(&output.stdout, &self.spec.name);
+        if !output.success() {
+            // netsh exits 1 when no rule matches and says so on stdout, in
+            // the system language.
+            let no_match = output.stdout.contains(NO_MATCH) || (named == 0 && output.stderr.trim().is_empty());
+            return if no_match { Ok(Installed::Parsed(Vec::new())) } else { Err(CommandError::Failed(output.message())) };
+        }
+        // `name=` matches exactly, but be strict in case of wildcards in the name.
+        let rules: Vec<FirewallRule> = parse_rules(&output.stdout).into_iter().filter(|r| r.name == self.spec.name).collect();
+        if rules.is_empty() && named > 0 {
+            return Ok(Installed::Unreadable(named));
+        }
+        Ok(Installed::Parsed(rules))
+    }
+
+    pub fn status(&self) -> Result<RuleStatus, CommandError> {
+        let rules = match self.installed()? {
+            Installed::Parsed(rules) => rules,
+            Installed::Unreadable(count) => {
+                metrics::set_firewall_rule_present(true);
+                return Ok(RuleStatus::Unverified { count });
+            }
+        };
+        metrics::set_firewall_rule_present(!rules.is_empty());
+        if rules.is_empty() {
+            return Ok(RuleStatus::Missing);
+        }
+        let drift = detect_drift(&self.spec, &rules);
+        if drift.is_empty() {
+            Ok(RuleStatus::Ok { rule: rules.into_iter().next().unwrap_or_default() })
+        } else {
+            Ok(RuleStatus::Drifted { rules, drift })
+        }
+    }
+
+    /// Adds the rule, or replaces it if it drifted from the spec.
+    pub fn ensure(&self) -> Result<EnsureOutcome, CommandError> {
+        let outcome = match self.status()? {
+            // Replacing a rule that can't be read could only make things worse.
+            RuleStatus::Ok { .. } | RuleStatus::Unverified { .. } => return Ok(EnsureOutcome::Unchanged),
+            RuleStatus::Missing => EnsureOutcome::Added,
+            RuleStatus::Drifted { .. } => {
+                self.delete()?;
+                EnsureOutcome::Repaired
+            }
+        };
+        self.add()?;
+        metrics::set_firewall_rule_present(true);
+        Ok(outcome)
+    }
+
+    /// Deletes every rule with the spec's name. Returns whether any existed.
+    pub fn remove(&self) -> Result<bool, CommandError> {
+        if !self.status()?.is_present() {
+            return Ok(false);
+        }
+        self.delete()?;
+        metrics::set_firewall_rule_present(false);
+        Ok(true)
+    }
+
+    fn add(&self) -> Result<(), CommandError> {
+        let name = format!("name={}", self.spec.name);
+        let port = format!("localport={}", self.spec.port);
+        let profile = format!("profile={}", self.spec.profile_arg());
+        let remote = format!("remoteip={}", self.spec.remote_arg());
+        runner::run_checked(
+            self.runner,
+            "netsh",
+            &[
+                "advfirewall",
+                "firewall",
+                "add",
+                "rule",
+                &name,
+                "dir=in",
+                "action=allow",
+                "protocol=TCP",
+                &port,
+                &profile,
+                &remote,
+                "edge=yes",
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn delete(&self) -> Result<(), CommandError> {
+        let name = format!("name={}", self.spec.name);
+        runner::run_checked(self.runner, "netsh", &["advfirewall", "firewall", "delete", "rule", &name])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakeRunner;
+
+    const SHOW: &str = "netsh advfirewall firewall show rule name=USBIP";
+
+    const ENGLISH: &str = "
+Rule Name:                            USBIP
+----------------------------------------------------------------------
+Enabled:                              Yes
+Direction:                            In
+Profiles:                             Domain,Private,Public
+Grouping:
+LocalIP:                              Any
+RemoteIP:                             Any
+Protocol:                             TCP
+LocalPort:                            3240
+RemotePort:                           Any
+Edge traversal:                       Yes
+Action:                               Allow
+Ok.
+";
+
+    const GERMAN: &str = "
+Regelname:                            USBIP
+----------------------------------------------------------------------
+Aktiviert:                            Ja
+Richtung:                             Eingehend
+Profile:                              Domäne,Privat,Öffentlich
+Gruppierung:
+Lokale IP:                            Beliebig
+Remote-IP:                            Beliebig
+Protokoll:                            TCP
+Lokaler Port:                         3240
+Remoteport:                           Beliebig
+Edgeausnahme:                         Ja
+Aktion:                               Zulassen
+OK.
+";
+
+    fn spec() -> RuleSpec {
+        RuleSpec { name: String::from("USBIP"), port: 3240, profiles: Vec::new(), remote_addresses: Vec::new() }
+    }
+
+    fn rule() -> FirewallRule {
+        parse_rules(ENGLISH).remove(0)
+    }
+
+    #[test]
+    fn parses_english_netsh_output() {
+        let rules = parse_rules(&format!("{}{}", ENGLISH, ENGLISH.replace("USBIP", "Other")));
+        assert_eq!(rules.len(), 2);
+        assert_eq!(
+            rules[0],
+            FirewallRule {
+                name: String::from("USBIP"),
+                enabled: true,
+                direction: String::from("In"),
+                profiles: String::from("Domain,Private,Public"),
+                remote_ip: String::from("Any"),
+                protocol: String::from("TCP"),
+                local_port: String::from("3240"),
+                action: String::from("Allow"),
+            }
+        );
+        assert_eq!(rules[1].name, "Other");
+        assert!(parse_rules(GERMAN).is_empty());
+    }
+
+    #[test]
+    fn localised_output_is_found_by_name() {
+        assert_eq!(count_named(GERMAN, "USBIP"), 1);
+        assert_eq!(count_named(&format!("{}{}", GERMAN, GERMAN), "USBIP"), 2);
+        assert_eq!(count_named(GERMAN, "USB"), 0);
+    }
+
+    #[test]
+    fn a_matching_rule_has_no_drift() {
+        assert!(detect_drift(&spec(), &[rule()]).is_empty());
+        let spec = RuleSpec {
+            profiles: vec![FirewallProfile::Private, FirewallProfile::Domain],
+            remote_addresses: vec![String::from("192.168.1.0/24"), String::from("10.0.0.5")],
+            ..spec()
+        };
+        let rule = FirewallRule {
+            profiles: String::from("Domain,Private"),
+            remote_ip: String::from("10.0.0.5/255.255.255.255,192.168.1.0/255.255.255.0"),
+            ..rule()
+        };
+        assert!(detect_drift(&spec, &[rule]).is_empty());
+    }
+
+    #[test]
+    fn every_difference_is_reported() {
+        let edited = FirewallRule {
+            enabled: false,
+            direction: String::from("Out"),
+            profiles: String::from("Public"),
+            remote_ip: String::from("LocalSubnet"),
+            protocol: String::from("UDP"),
+            local_port: String::from("3241"),
+            action: String::from("Block"),
+            ..rule()
+        };
+        let drift = detect_drift(&spec(), &[edited, rule()]);
+        let text: Vec<String> = drift.iter().map(Drift::to_string).collect();
+        assert_eq!(
+            text,
+            [
+                "2 rules share the name",
+                "rule is disabled",
+                "direction is Out",
+                "action is Block",
+                "protocol is UDP",
+                "local port is 3241",
+                "profiles are Public",
+                "remote addresses are LocalSubnet",
+            ]
+        );
+    }
+
+    #[test]
+    fn status_reads_the_installed_rule() {
+        let runner = FakeRunner::empty().ok(SHOW, ENGLISH);
+        assert!(matches!(FirewallManager::new(&runner, spec()).status().unwrap(), RuleStatus::Ok { .. }));
+
+        let runner = FakeRunner::empty().fail(SHOW, 1, &format!("\n{}\n\n", NO_MATCH), "");
+        assert_eq!(FirewallManager::new(&runner, spec()).status().unwrap(), RuleStatus::Missing);
+    }
+
+    #[test]
+    fn a_localised_rule_is_left_alone() {
+        let runner = FakeRunner::empty().ok(SHOW, GERMAN).ok("netsh advfirewall firewall add", "OK.");
+        let manager = FirewallManager::new(&runner, spec());
+        assert_eq!(manager.status().unwrap(), RuleStatus::Unverified { count: 1 });
+        assert_eq!(manager.ensure().unwrap(), EnsureOutcome::Unchanged);
+        assert!(runner.calls_to("netsh advfirewall firewall add").is_empty());
+    }
+
+    #[test]
+    fn a_localised_no_match_is_missing() {
+        let runner = FakeRunner::empty()
+            .fail(SHOW, 1, "\nKeine Regeln entsprechen den angegebenen Kriterien.\n\n", "")
+            .ok("netsh advfirewall firewall add", "OK.");
+        let manager = FirewallManager::new(&runner, spec());
+        assert_eq!(manager.ensure().unwrap(), EnsureOutcome::Added);
+        assert_eq!(
+            runner.calls_to("netsh advfirewall firewall add"),
+            ["netsh advfirewall firewall add rule name=USBIP dir=in action=allow protocol=TCP localport=3240 profile=any remoteip=any edge=yes"]
+        );
+    }
+
+    #[test]
+    fn a_drifted_rule_is_replaced() {
+        let runner = FakeRunner::empty()
+            .ok(SHOW, &ENGLISH.replace("3240", "3241"))
+            .ok("netsh advfirewall firewall delete", "Deleted 1 rule(s).")
+            .ok("netsh advfirewall firewall add", "OK.");
+        assert_eq!(FirewallManager::new(&runner, spec()).ensure().unwrap(), EnsureOutcome::Repaired);
+        assert_eq!(runner.calls_to("netsh advfirewall firewall delete"), ["netsh advfirewall firewall delete rule name=USBIP"]);
+    }
+}
@@ -0,0 +1,441 @@
+//! One device table across this machine and the agents listed in `[[hosts]]`.
+//!
+//! Hosts are asked in parallel; a host that doesn't answer only costs its own
+//! rows, and its [`HostHealth`] says why.
+use std::fmt;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::agent::{self, AgentClient, AgentError, Request};
+use crate::config;
+use crate::device_list::{self, UsbipDevice};
+use crate::reservations::{self, Reservations};
+use crate::wsl::{self, Attachments};
+use crate::runner::{CommandError, CommandRunner};
+
+/// The host name rows of this machine carry.
+pub const LOCAL: &str = "local";
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One `[[hosts]]` entry in the config file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RemoteHost {
+    /// Shown in the Host column and used to pick the host in commands.
+    pub name: String,
+    /// `host[:port]` of the agent; the port defaults to 3242.
+    pub address: String,
+    /// The agent's `[agent] token`, or a personal token from its
+    /// `[[agent.users]]`, so binds there count as that user's.
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HostDevice {
+    pub host: String,
+    #[serde(flatten)]
+    pub device: UsbipDevice,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Health {
+    Online,
+    /// Not reachable, or the connection broke.
+    Offline,
+    /// Reachable, but the token is wrong.
+    Unauthorized,
+    /// Reachable, but listing devices failed there (usbipd missing, ...).
+    Failing,
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Health::Online => "online",
+            Health::Offline => "offline",
+            Health::Unauthorized => "unauthorized",
+            Health::Failing => "failing",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HostHealth {
+    pub host: String,
+    /// Empty for this machine.
+    pub address: String,
+    pub health: Health,
+    pub devices: usize,
+    /// Round trip of the last request.
+    pub latency_ms: u64,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Snapshot {
+    pub devices: Vec<HostDevice>,
+    pub health: Vec<HostHealth>,
+}
+
+impl Snapshot {
+    /// Swaps in `host`'s devices as returned by a bind or unbind, keeping the
+    /// table order, without polling every host again.
+    pub fn replace(&mut self, host: &str, devices: Vec<UsbipDevice>) {
+        let at = self.devices.iter().position(|d| d.host == host).unwrap_or(self.devices.len());
+        self.devices.retain(|d| d.host != host);
+        if let Some(health) = self.health.iter_mut().find(|h| h.host == host) {
+            health.health = Health::Online;
+            health.devices = devices.len();
+            health.error = None;
+        }
+        let rows = devices.into_iter().map(|device| HostDevice { host: host.to_string(), device });
+        // A host's rows are contiguous, so `at` is still in range after the retain.
+        self.devices.splice(at..at, rows);
+    }
+}
+
+#[derive(Debug)]
+pub enum FleetError {
+    UnknownHost(String),
+    Local(CommandError),
+    Agent { host: String, error: AgentError },
+}
+
+impl fmt::Display for FleetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FleetError::UnknownHost(host) => write!(f, "no host named '{}' in [[hosts]]", host),
+            FleetError::Local(e) => write!(f, "{}", e),
+            FleetError::Agent { host, error } => write!(f, "{}: {}", host, error),
+        }
+    }
+}
+
+impl std::error::Error for FleetError {}
+
+fn health_of(error: &AgentError) -> Health {
+    match error {
+        AgentError::Io(_) | AgentError::Protocol(_) => Health::Offline,
+        AgentError::Unauthorized => Health::Unauthorized,
+        AgentError::Failed(_) => Health::Failing,
+    }
+}
+
+pub struct Fleet {
+    hosts: Vec<RemoteHost>,
+    timeout: Duration,
+    /// Local binds are checked against this reservations file, for `user`.
+    reservations: PathBuf,
+    user: String,
+    /// Names the WSL distribution of devices attached from this machine.
+    wsl_attachments: PathBuf,
+}
+
+impl Fleet {
+    /// Binds on this machine go through the default reservations file as
+    /// the OS user.
+    pub fn new(hosts: Vec<RemoteHost>) -> Self {
+        Fleet {
+            hosts,
+            timeout: TIMEOUT,
+            reservations: reservations::default_path(),
+            user: reservations::current_user(),
+            wsl_attachments: wsl::default_attachments_path(),
+        }
+    }
+
+    pub fn from_config() -> Self {
+        Self::new(config::current().hosts)
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_reservations(mut self, path: PathBuf) -> Self {
+        self.reservations = path;
+        self
+    }
+
+    pub fn with_user(mut self, user: &str) -> Self {
+        self.user = user.to_string();
+        self
+    }
+
+    pub fn with_wsl_attachments(mut self, path: PathBuf) -> Self {
+        self.wsl_attachments = path;
+        self
+    }
+
+    pub fn hosts(&self) -> &[RemoteHost] {
+        &self.hosts
+    }
+
+    fn client(&self, host: &RemoteHost) -> AgentClient {
+        AgentClient::new(&host.address, &host.token, self.timeout)
+    }
+
+    fn find(&self, name: &str) -> Result<&RemoteHost, FleetError> {
+        self.hosts.iter().find(|h| h.name.eq_ignore_ascii_case(name)).ok_or_else(|| FleetError::UnknownHost(name.to_string()))
+    }
+
+    /// Lists every host's devices, this machine's first when `local` is given.
+    pub fn poll(&self, local: Option<&dyn CommandRunner>) -> Snapshot {
+        let remote: Vec<(Result<Vec<UsbipDevice>, AgentError>, Duration)> = thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .hosts
+                .iter()
+                .map(|host| {
+                    scope.spawn(move || {
+                        let started = Instant::now();
+                        let result = self.client(host).request(Request::List).map(|(_, devices)| devices);
+                        (result, started.elapsed())
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().expect("agent request panicked")).collect()
+        });
+
+        let mut snapshot = Snapshot::default();
+        if let Some(runner) = local {
+            let started = Instant::now();
+            let result = device_list::list_devices(runner);
+            let elapsed = started.elapsed();
+            let (health, error) = match &result {
+                Ok(_) => (Health::Online, None),
+                Err(e) => (Health::Failing, Some(e.to_string())),
+            };
+            let mut devices = result.unwrap_or_default();
+            Attachments::at(self.wsl_attachments.clone()).annotate(&mut devices);
+            snapshot.health.push(HostHealth {
+                host: LOCAL.to_string(),
+                address: String::new(),
+                health,
+                devices: devices.len(),
+                latency_ms: elapsed.as_millis() as u64,
+                error,
+            });
+            snapshot.devices.extend(devices.into_iter().map(|device| HostDevice { host: LOCAL.to_string(), device }));
+        }
+        for (host, (result, elapsed)) in self.hosts.iter().zip(remote) {
+            let (health, error, devices) = match result {
+                Ok(devices) => (Health::Online, None, devices),
+                Err(e) => (health_of(&e), Some(e.to_string()), Vec::new()),
+            };
+            snapshot.health.push(HostHealth {
+                host: host.name.clone(),
+                address: host.address.clone(),
+                health,
+                devices: devices.len(),
+                latency_ms: elapsed.as_millis() as u64,
+                error,
+            });
+            snapshot.devices.extend(devices.into_iter().map(|device| HostDevice { host: host.name.clone(), device }));
+        }
+        snapshot
+    }
+
+    /// Runs `request` on `host`, [`LOCAL`] meaning this machine, and returns
+    /// that host's devices afterwards.
+    fn run(&self, local: &dyn CommandRunner, host: &str, request: Request) -> Result<Vec<UsbipDevice>, FleetError> {
+        if host.eq_ignore_ascii_case(LOCAL) {
+            let reservations = Reservations::at(self.reservations.clone());
+            let mut devices = agent::apply(local, &reservations, &request, &self.user).map_err(FleetError::Local)?;
+            Attachments::at(self.wsl_attachments.clone()).annotate(&mut devices);
+            return Ok(devices);
+        }
+        let remote = self.find(host)?;
+        self.client(remote)
+            .request(request)
+            .map(|(_, devices)| devices)
+            .map_err(|error| FleetError::Agent { host: remote.name.clone(), error })
+    }
+
+    /// Binds under the host's token, or as [`Fleet::with_user`] on this machine. A
+    /// reserved device is only bound for its holder.
+    pub fn bind(&self, local: &dyn CommandRunner, host: &str, busid: &str, force: bool) -> Result<Vec<UsbipDevice>, FleetError> {
+        self.run(local, host, Request::Bind { busid: busid.to_string(), force })
+    }
+
+    pub fn unbind(&self, local: &dyn CommandRunner, host: &str, busid: &str) -> Result<Vec<UsbipDevice>, FleetError> {
+        self.run(local, host, Request::Unbind { busid: busid.to_string() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::AgentSettings;
+    use crate::device_list::DeviceState;
+    use crate::reservations::RESERVATIONS_FILE;
+    use crate::testing::{self, FakeRunner, TempDir};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+
+    const TOKEN: &str = "fleet-token";
+
+    fn runner(busids: &[&str]) -> FakeRunner {
+        let devices: Vec<UsbipDevice> = busids.iter().map(|b| testing::device(b, "0483:374b", DeviceState::Shared)).collect();
+        FakeRunner::new().ok("usbipd state", &testing::state_json(&devices)).ok("usbipd unbind", "")
+    }
+
+    /// An agent on a loopback port, listed in `[[hosts]]` as `name`, with
+    /// its reservations file in `dir`.
+    fn agent(name: &str, runner: Arc<FakeRunner>, dir: &TempDir) -> RemoteHost {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let settings = AgentSettings {
+            name: name.to_string(),
+            token: TOKEN.to_string(),
+            users: Vec::new(),
+            reservations: dir.join(RESERVATIONS_FILE),
+            wsl_attachments: dir.join(wsl::ATTACHMENTS_FILE),
+        };
+        agent::serve(listener, settings, runner);
+        host(name, &address, TOKEN)
+    }
+
+    /// A fleet whose local binds use a reservations file in `dir`.
+    fn fleet(hosts: Vec<RemoteHost>, dir: &TempDir) -> Fleet {
+        Fleet::new(hosts)
+            .with_reservations(dir.join(RESERVATIONS_FILE))
+            .with_wsl_attachments(dir.join(wsl::ATTACHMENTS_FILE))
+            .with_user("ana")
+    }
+
+    fn host(name: &str, address: &str, token: &str) -> RemoteHost {
+        RemoteHost { name: name.to_string(), address: address.to_string(), token: token.to_string() }
+    }
+
+    /// A loopback address nothing listens on.
+    fn down(name: &str) -> RemoteHost {
+        let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        host(name, &address, TOKEN)
+    }
+
+    fn rows(snapshot: &Snapshot) -> Vec<String> {
+        snapshot.devices.iter().map(|d| format!("{}/{}", d.host, d.device.busid)).collect()
+    }
+
+    fn health(snapshot: &Snapshot) -> Vec<(String, Health)> {
+        snapshot.health.iter().map(|h| (h.host.clone(), h.health)).collect()
+    }
+
+    #[test]
+    fn poll_puts_local_rows_first_then_hosts_in_order() {
+        let dir = TempDir::new("fleet");
+        let hosts = vec![agent("bench-1", Arc::new(runner(&["1-1", "1-2"])), &dir), agent("bench-2", Arc::new(runner(&["2-1"])), &dir)];
+        let fleet = fleet(hosts, &dir);
+        let snapshot = fleet.poll(Some(&runner(&["3-1"])));
+        assert_eq!(rows(&snapshot), ["local/3-1", "bench-1/1-1", "bench-1/1-2", "bench-2/2-1"]);
+        assert_eq!(
+            health(&snapshot),
+            [("local".into(), Health::Online), ("bench-1".into(), Health::Online), ("bench-2".into(), Health::Online)]
+        );
+        assert_eq!(snapshot.health.iter().map(|h| h.devices).collect::<Vec<_>>(), [1, 2, 1]);
+        assert_eq!(snapshot.health[0].address, "");
+        assert_eq!(snapshot.health[1].address, fleet.hosts()[0].address);
+    }
+
+    #[test]
+    fn poll_without_local_lists_only_hosts() {
+        let dir = TempDir::new("fleet");
+        let fleet = fleet(vec![agent("bench-1", Arc::new(runner(&["1-1"])), &dir)], &dir);
+        let snapshot = fleet.poll(None);
+        assert_eq!(rows(&snapshot), ["bench-1/1-1"]);
+        assert_eq!(health(&snapshot), [("bench-1".into(), Health::Online)]);
+    }
+
+    #[test]
+    fn a_host_that_is_down_only_costs_its_own_rows() {
+        let dir = TempDir::new("fleet");
+        let fleet = fleet(vec![down("bench-1"), agent("bench-2", Arc::new(runner(&["2-1"])), &dir)], &dir).with_timeout(Duration::from_secs(2));
+        let snapshot = fleet.poll(None);
+        assert_eq!(rows(&snapshot), ["bench-2/2-1"]);
+        assert_eq!(health(&snapshot), [("bench-1".into(), Health::Offline), ("bench-2".into(), Health::Online)]);
+        assert_eq!(snapshot.health[0].devices, 0);
+        assert!(snapshot.health[0].error.is_some());
+        assert_eq!(snapshot.health[1].error, None);
+    }
+
+    #[test]
+    fn health_tells_a_wrong_token_from_a_failing_usbipd() {
+        let dir = TempDir::new("fleet");
+        let mut wrong_token = agent("bench-1", Arc::new(runner(&["1-1"])), &dir);
+        wrong_token.token = String::from("guess");
+        let failing = agent("bench-2", Arc::new(FakeRunner::new().fail("usbipd", 1, "", "usbipd: error: access denied")), &dir);
+        let snapshot = fleet(vec![wrong_token, failing], &dir).poll(Some(&FakeRunner::empty()));
+        assert!(snapshot.devices.is_empty());
+        assert_eq!(
+            health(&snapshot),
+            [("local".into(), Health::Failing), ("bench-1".into(), Health::Unauthorized), ("bench-2".into(), Health::Failing)]
+        );
+        assert_eq!(snapshot.health[1].error.as_deref(), Some("the agent rejected the token"));
+    }
+
+    #[test]
+    fn unbind_goes_to_the_named_host() {
+        let dir = TempDir::new("fleet");
+        let remote = Arc::new(runner(&["1-1"]));
+        let local = runner(&["3-1"]);
+        let fleet = fleet(vec![agent("bench-1", remote.clone(), &dir)], &dir);
+
+        let devices = fleet.unbind(&local, "BENCH-1", "1-1").unwrap();
+        assert_eq!(devices.iter().map(|d| d.busid.as_str()).collect::<Vec<_>>(), ["1-1"]);
+        assert_eq!(remote.calls_to("usbipd unbind"), ["usbipd unbind --busid 1-1"]);
+        assert!(local.calls_to("usbipd unbind").is_empty());
+
+        fleet.unbind(&local, LOCAL, "3-1").unwrap();
+        assert_eq!(local.calls_to("usbipd unbind"), ["usbipd unbind --busid 3-1"]);
+    }
+
+    #[test]
+    fn local_binds_respect_reservations() {
+        let dir = TempDir::new("fleet");
+        let local = runner(&["3-1"]).ok("usbipd bind", "");
+        Reservations::at(dir.join(RESERVATIONS_FILE)).reserve("3-1", "bob", Duration::from_secs(3600)).unwrap();
+
+        let error = fleet(Vec::new(), &dir).bind(&local, LOCAL, "3-1", false).unwrap_err();
+        assert!(error.to_string().contains("reserved by bob"), "{}", error);
+        assert!(local.calls_to("usbipd bind").is_empty());
+        fleet(Vec::new(), &dir).with_user("bob").bind(&local, LOCAL, "3-1", false).unwrap();
+        assert_eq!(local.calls_to("usbipd bind"), ["usbipd bind --busid 3-1"]);
+    }
+
+    #[test]
+    fn requests_name_the_host_that_failed() {
+        let dir = TempDir::new("fleet");
+        let fleet = fleet(vec![down("bench-1")], &dir).with_timeout(Duration::from_secs(2));
+        let local = FakeRunner::new();
+        let error = fleet.unbind(&local, "bench-2", "1-1").unwrap_err();
+        assert_eq!(error.to_string(), "no host named 'bench-2' in [[hosts]]");
+        let error = fleet.unbind(&local, "bench-1", "1-1").unwrap_err();
+        assert!(matches!(&error, FleetError::Agent { host, error: AgentError::Io(_) } if host == "bench-1"), "{:?}", error);
+        assert!(error.to_string().starts_with("bench-1: "));
+    }
+
+    #[test]
+    fn replace_swaps_one_hosts_rows_in_place() {
+        let dir = TempDir::new("fleet");
+        let fleet = fleet(vec![down("bench-1"), agent("bench-2", Arc::new(runner(&["2-1"])), &dir)], &dir).with_timeout(Duration::from_secs(2));
+        let mut snapshot = fleet.poll(Some(&runner(&["3-1"])));
+        snapshot.replace(
+            "bench-1",
+            vec![testing::device("1-1", "0483:374b", DeviceState::Shared), testing::device("1-2", "0483:374b", DeviceState::NotShared)],
+        );
+        assert_eq!(rows(&snapshot), ["local/3-1", "bench-2/2-1", "bench-1/1-1", "bench-1/1-2"]);
+        assert_eq!(snapshot.health[1].health, Health::Online);
+        assert_eq!(snapshot.health[1].devices, 2);
+        assert_eq!(snapshot.health[1].error, None);
+
+        snapshot.replace("local", Vec::new());
+        snapshot.replace("bench-2", vec![testing::device("2-9", "0483:374b", DeviceState::Shared)]);
+        assert_eq!(rows(&snapshot), ["bench-2/2-9", "bench-1/1-1", "bench-1/1-2"]);
+    }
+}
@@ -0,0 +1,444 @@
+//! Installs usbipd-win from an MSI whose SHA-256 is checked before it runs.
+//! This is the `msi` backend of [`crate::package_manager`].
+//!
+//! The expected hash comes either pinned in the config file next to the MSI
+//! location, or from a release manifest signed with an Ed25519 key that is
+//! pinned in the config file. The MSI can live on a web server or a file
+//! share; downloads go through `curl`, which ships with Windows 10 and later.
+//! The file is verified after it has been copied to a private temporary
+//! directory, so what runs is exactly what was checked.
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config;
+use crate::runner::{self, CommandError, CommandRunner};
+use crate::version::{ParseVersionError, Version};
+
+/// msiexec's "success, reboot required".
+const MSI_REBOOT_REQUIRED: i32 = 3010;
+
+/// `installer.method` and the entries of `installer.order` in the config file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InstallMethod {
+    /// The first available package manager in `installer.order`.
+    #[default]
+    Auto,
+    Msi,
+    Winget,
+    Chocolatey,
+    Scoop,
+}
+
+impl fmt::Display for InstallMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            InstallMethod::Auto => "auto",
+            InstallMethod::Msi => "msi",
+            InstallMethod::Winget => "winget",
+            InstallMethod::Chocolatey => "chocolatey",
+            InstallMethod::Scoop => "scoop",
+        })
+    }
+}
+
+/// A release manifest, signed as a whole by the publisher's key.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub version: String,
+    /// URL or path of the MSI.
+    pub url: String,
+    /// Hex SHA-256 of the MSI.
+    pub sha256: String,
+}
+
+/// What to install and the hash it must have.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Artifact {
+    pub location: String,
+    pub sha256: String,
+}
+
+#[derive(Debug)]
+pub enum InstallError {
+    /// The `[installer]` section doesn't describe a usable source.
+    Config(String),
+    Io(io::Error),
+    /// A program (curl, msiexec, winget) failed or is missing.
+    Command(CommandError),
+    Manifest(String),
+    BadSignature,
+    HashMismatch { expected: String, actual: String },
+    /// None of the package managers that were tried is available.
+    NoPackageManager(Vec<InstallMethod>),
+}
+
+impl fmt::Display for InstallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InstallError::Config(message) => write!(f, "installer: {}", message),
+            InstallError::Io(e) => write!(f, "{}", e),
+            InstallError::Command(e) => write!(f, "{}", e),
+            InstallError::Manifest(message) => write!(f, "release manifest: {}", message),
+            InstallError::BadSignature => f.write_str("release manifest signature does not match the pinned key"),
+            InstallError::HashMismatch { expected, actual } => {
+                write!(f, "installer SHA-256 is {} but {} was expected", actual, expected)
+            }
+            InstallError::NoPackageManager(tried) => {
+                let tried: Vec<String> = tried.iter().map(InstallMethod::to_string).collect();
+                write!(f, "no package manager is available to install usbipd-win (tried {})", tried.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for InstallError {}
+
+impl From<io::Error> for InstallError {
+    fn from(e: io::Error) -> Self {
+        InstallError::Io(e)
+    }
+}
+
+impl From<CommandError> for InstallError {
+    fn from(e: CommandError) -> Self {
+        InstallError::Command(e)
+    }
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim();
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+pub fn is_sha256(value: &str) -> bool {
+    value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+pub fn is_public_key(value: &str) -> bool {
+    unhex(value).is_some_and(|key| key.len() == 32)
+}
+
+/// Checks the detached Ed25519 `signature` (hex) over `manifest` and parses it.
+pub fn verify_manifest(manifest: &[u8], signature: &str, public_key: &str) -> Result<Manifest, InstallError> {
+    let key: [u8; 32] = unhex(public_key)
+        .and_then(|k| k.try_into().ok())
+        .ok_or_else(|| InstallError::Config(String::from("manifest_public_key is not 32 hex bytes")))?;
+    let key = VerifyingKey::from_bytes(&key).map_err(|_| InstallError::Config(String::from("manifest_public_key is not a valid key")))?;
+    let signature: [u8; 64] = unhex(signature).and_then(|s| s.try_into().ok()).ok_or(InstallError::BadSignature)?;
+    key.verify(manifest, &Signature::from_bytes(&signature)).map_err(|_| InstallError::BadSignature)?;
+
+    let text = std::str::from_utf8(manifest).map_err(|e| InstallError::Manifest(e.to_string()))?;
+    let manifest: Manifest = toml::from_str(text).map_err(|e| InstallError::Manifest(e.to_string()))?;
+    if !is_sha256(&manifest.sha256) {
+        return Err(InstallError::Manifest(String::from("sha256 is not 64 hex digits")));
+    }
+    Ok(manifest)
+}
+
+/// Compares the file's SHA-256 with `expected`.
+pub fn verify_file(path: &Path, expected: &str) -> Result<(), InstallError> {
+    let actual = sha256_hex(&fs::read(path)?);
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        return Err(InstallError::HashMismatch { expected: expected.trim().to_ascii_lowercase(), actual });
+    }
+    Ok(())
+}
+
+fn is_url(location: &str) -> bool {
+    location.starts_with("https://") || location.starts_with("http://")
+}
+
+/// A per-run directory under the system temp directory, removed on drop.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> io::Result<Self> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        Self::create(&env::temp_dir(), &NEXT)
+    }
+
+    /// Makes `usbip_host-<pid>-<n>` in `parent`, taking the next `n` until
+    /// one doesn't exist yet.
+    fn create(parent: &Path, next: &AtomicUsize) -> io::Result<Self> {
+        loop {
+            let path = parent.join(format!("usbip_host-{}-{}", process::id(), next.fetch_add(1, Ordering::Relaxed)));
+            match fs::create_dir(&path) {
+                Ok(()) => return Ok(TempDir(path)),
+                // Left behind by an earlier process that had the same pid.
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+pub struct Installer<'a> {
+    runner: &'a dyn CommandRunner,
+    settings: config::InstallerConfig,
+}
+
+impl<'a> Installer<'a> {
+    pub fn new(runner: &'a dyn CommandRunner, settings: config::InstallerConfig) -> Self {
+        Installer { runner, settings }
+    }
+
+    pub fn from_config(runner: &'a dyn CommandRunner) -> Self {
+        Self::new(runner, config::current().installer)
+    }
+
+    /// Whether an MSI source is configured.
+    pub fn configured(&self) -> bool {
+        !self.settings.msi_url.is_empty() || !self.settings.manifest_url.is_empty()
+    }
+
+    /// Installs or upgrades from the verified MSI. Returns whether Windows must be restarted.
+    pub fn install(&self) -> Result<bool, InstallError> {
+        self.msiexec("/i")
+    }
+
+    /// Uninstalls the product the verified MSI describes.
+    pub fn uninstall(&self) -> Result<bool, InstallError> {
+        self.msiexec("/x")
+    }
+
+    /// The version the signed manifest offers, if there is a manifest.
+    pub fn manifest_version(&self) -> Result<Option<Version>, InstallError> {
+        if self.settings.manifest_url.is_empty() {
+            return Ok(None);
+        }
+        let dir = TempDir::new()?;
+        let manifest = self.manifest(&dir.0)?;
+        manifest.version.parse().map(Some).map_err(|e: ParseVersionError| InstallError::Manifest(e.to_string()))
+    }
+
+    fn manifest(&self, dir: &Path) -> Result<Manifest, InstallError> {
+        let settings = &self.settings;
+        let manifest = self.fetch(&settings.manifest_url, &dir.join("manifest.toml"))?;
+        let signature = self.fetch(&format!("{}.sig", settings.manifest_url), &dir.join("manifest.toml.sig"))?;
+        let signature = fs::read_to_string(signature)?;
+        verify_manifest(&fs::read(manifest)?, &signature, &settings.manifest_public_key)
+    }
+
+    /// Works out the MSI location and hash, from the signed manifest if there is one.
+    pub fn artifact(&self, dir: &Path) -> Result<Artifact, InstallError> {
+        let settings = &self.settings;
+        if !settings.manifest_url.is_empty() {
+            let manifest = self.manifest(dir)?;
+            return Ok(Artifact { location: manifest.url, sha256: manifest.sha256 });
+        }
+        if settings.msi_url.is_empty() {
+            return Err(InstallError::Config(String::from("set msi_url and sha256, or manifest_url")));
+        }
+        if settings.sha256.is_empty() {
+            return Err(InstallError::Config(String::from("msi_url needs a pinned sha256")));
+        }
+        Ok(Artifact { location: settings.msi_url.clone(), sha256: settings.sha256.clone() })
+    }
+
+    /// Downloads or copies the MSI into `dir` and verifies it.
+    pub fn fetch_verified(&self, dir: &Path) -> Result<PathBuf, InstallError> {
+        let artifact = self.artifact(dir)?;
+        let path = self.fetch(&artifact.location, &dir.join("usbipd-win.msi"))?;
+        verify_file(&path, &artifact.sha256)?;
+        Ok(path)
+    }
+
+    fn msiexec(&self, operation: &str) -> Result<bool, InstallError> {
+        let dir = TempDir::new()?;
+        let msi = self.fetch_verified(&dir.0)?;
+        let msi = msi.to_string_lossy();
+        let output = self
+            .runner
+            .run("msiexec", &[operation, &msi, "/qn", "/norestart"])
+            .map_err(|e| CommandError::from_io("msiexec", e))?;
+        match output.code {
+            Some(0) => Ok(false),
+            Some(MSI_REBOOT_REQUIRED) => Ok(true),
+            code => Err(CommandError::Failed(format!("msiexec failed with exit code {:?}", code)).into()),
+        }
+    }
+
+    fn fetch(&self, location: &str, target: &Path) -> Result<PathBuf, InstallError> {
+        if is_url(location) {
+            let target_arg = target.to_string_lossy();
+            // -f turns HTTP errors into a non-zero exit code.
+            runner::run_checked(
+                self.runner,
+                "curl",
+                &["--fail", "--silent", "--show-error", "--location", "--proto", "=https,http", "--output", &target_arg, location],
+            )?;
+        } else {
+            fs::copy(location, target)?;
+        }
+        Ok(target.to_path_buf())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakeRunner;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    const MSI: &[u8] = b"not really an msi";
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    fn public_key(key: &SigningKey) -> String {
+        hex(key.verifying_key().as_bytes())
+    }
+
+    fn manifest(sha256: &str) -> Vec<u8> {
+        format!("version = \"4.3.0\"\nurl = \"https://example.com/usbipd-win_4.3.0.msi\"\nsha256 = \"{}\"\n", sha256).into_bytes()
+    }
+
+    fn sign(key: &SigningKey, data: &[u8]) -> String {
+        hex(&key.sign(data).to_bytes())
+    }
+
+    #[test]
+    fn a_signed_manifest_is_accepted() {
+        let data = manifest(&sha256_hex(MSI));
+        let parsed = verify_manifest(&data, &format!("{}\n", sign(&key(1), &data)), &public_key(&key(1))).unwrap();
+        assert_eq!(
+            parsed,
+            Manifest { version: String::from("4.3.0"), url: String::from("https://example.com/usbipd-win_4.3.0.msi"), sha256: sha256_hex(MSI) }
+        );
+    }
+
+    #[test]
+    fn a_bad_signature_is_rejected() {
+        let data = manifest(&sha256_hex(MSI));
+        let signature = sign(&key(1), &data);
+
+        let tampered = manifest(&sha256_hex(b"something else"));
+        assert!(matches!(verify_manifest(&tampered, &signature, &public_key(&key(1))), Err(InstallError::BadSignature)));
+        assert!(matches!(verify_manifest(&data, &signature, &public_key(&key(2))), Err(InstallError::BadSignature)));
+        assert!(matches!(verify_manifest(&data, &signature[..64], &public_key(&key(1))), Err(InstallError::BadSignature)));
+        assert!(matches!(verify_manifest(&data, "zz", &public_key(&key(1))), Err(InstallError::BadSignature)));
+    }
+
+    #[test]
+    fn a_malformed_key_or_manifest_is_reported() {
+        let data = manifest(&sha256_hex(MSI));
+        let signature = sign(&key(1), &data);
+        assert!(matches!(verify_manifest(&data, &signature, "abcd"), Err(InstallError::Config(_))));
+
+        let short_hash = manifest("abc123");
+        let error = verify_manifest(&short_hash, &sign(&key(1), &short_hash), &public_key(&key(1))).unwrap_err();
+        assert_eq!(error.to_string(), "release manifest: sha256 is not 64 hex digits");
+        let extra = b"version = \"4.3.0\"\nurl = \"x\"\nsha256 = \"00\"\nmirror = \"y\"\n";
+        assert!(matches!(verify_manifest(extra, &sign(&key(1), extra), &public_key(&key(1))), Err(InstallError::Manifest(_))));
+    }
+
+    #[test]
+    fn verify_file_compares_hashes() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.0.join("usbipd-win.msi");
+        fs::write(&path, MSI).unwrap();
+        assert!(verify_file(&path, &sha256_hex(MSI).to_ascii_uppercase()).is_ok());
+
+        let wrong = sha256_hex(b"other");
+        match verify_file(&path, &format!(" {} ", wrong.to_ascii_uppercase())) {
+            Err(InstallError::HashMismatch { expected, actual }) => assert_eq!((expected, actual), (wrong, sha256_hex(MSI))),
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(verify_file(&dir.0.join("missing.msi"), &sha256_hex(MSI)), Err(InstallError::Io(_))));
+    }
+
+    #[test]
+    fn temp_dirs_skip_names_already_taken() {
+        let parent = TempDir::new().unwrap();
+        let next = AtomicUsize::new(0);
+        fs::create_dir(parent.0.join(format!("usbip_host-{}-0", process::id()))).unwrap();
+        let first = TempDir::create(&parent.0, &next).unwrap();
+        let second = TempDir::create(&parent.0, &next).unwrap();
+        assert_eq!(first.0, parent.0.join(format!("usbip_host-{}-1", process::id())));
+        assert_eq!(second.0, parent.0.join(format!("usbip_host-{}-2", process::id())));
+
+        let path = first.0.clone();
+        drop(first);
+        assert!(!path.exists());
+        assert!(second.0.is_dir());
+    }
+
+    #[test]
+    fn artifact_needs_a_usable_source() {
+        let runner = FakeRunner::empty();
+        let dir = TempDir::new().unwrap();
+        let artifact = |settings: config::InstallerConfig| Installer::new(&runner, settings).artifact(&dir.0);
+
+        let message = artifact(config::InstallerConfig::default()).unwrap_err().to_string();
+        assert_eq!(message, "installer: set msi_url and sha256, or manifest_url");
+        let unpinned = config::InstallerConfig { msi_url: String::from("https://example.com/usbipd.msi"), ..Default::default() };
+        assert_eq!(artifact(unpinned.clone()).unwrap_err().to_string(), "installer: msi_url needs a pinned sha256");
+
+        let pinned = config::InstallerConfig { sha256: sha256_hex(MSI), ..unpinned };
+        assert_eq!(artifact(pinned).unwrap(), Artifact { location: String::from("https://example.com/usbipd.msi"), sha256: sha256_hex(MSI) });
+    }
+
+    #[test]
+    fn the_manifest_is_read_from_a_file_share() {
+        let share = TempDir::new().unwrap();
+        let data = manifest(&sha256_hex(MSI));
+        fs::write(share.0.join("manifest.toml"), &data).unwrap();
+        fs::write(share.0.join("manifest.toml.sig"), sign(&key(1), &data)).unwrap();
+        let settings = |seed| config::InstallerConfig {
+            manifest_url: share.0.join("manifest.toml").to_string_lossy().into_owned(),
+            manifest_public_key: public_key(&key(seed)),
+            ..Default::default()
+        };
+
+        let runner = FakeRunner::empty();
+        assert_eq!(Installer::new(&runner, settings(1)).manifest_version().unwrap(), Some(Version::new(4, 3, 0)));
+        let dir = TempDir::new().unwrap();
+        assert!(matches!(Installer::new(&runner, settings(2)).artifact(&dir.0), Err(InstallError::BadSignature)));
+        assert!(runner.calls().is_empty());
+    }
+
+    #[test]
+    fn only_a_verified_msi_is_run() {
+        let share = TempDir::new().unwrap();
+        let msi = share.0.join("usbipd-win.msi");
+        fs::write(&msi, MSI).unwrap();
+        let runner = FakeRunner::empty().fail("msiexec /i", MSI_REBOOT_REQUIRED, "", "");
+        let settings = |sha256: String| config::InstallerConfig { msi_url: msi.to_string_lossy().into_owned(), sha256, ..Default::default() };
+
+        assert!(matches!(Installer::new(&runner, settings(sha256_hex(b"other"))).install(), Err(InstallError::HashMismatch { .. })));
+        assert!(runner.calls().is_empty());
+        assert!(Installer::new(&runner, settings(sha256_hex(MSI))).install().unwrap());
+        let calls = runner.calls_to("msiexec /i");
+        assert_eq!(calls.len(), 1);
+        assert!(calls[0].ends_with("usbipd-win.msi /qn /norestart"), "{}", calls[0]);
+    }
+}
@@ -0,0 +1,531 @@
+//! Leases: detach or unbind devices that sit idle or have been in use too long.
+//!
+//! A lease starts when a device is first seen attached (for `detach`) or
+//! shared (for `unbind`) and ends after `max_minutes`, or earlier after
+//! `idle_minutes` without a new URB. URBs come from [`stats::current`], which
+//! merges the counters every usbip_host process publishes, so connections
+//! proxied by `usbipctl reverse` count here too. A device nobody proxies has
+//! no counters and can't go idle; its lease says so once with
+//! [`LeaseEvent::NoCounters`]. Lease times live in memory and start over when
+//! the process restarts.
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::aliases::{self, DeviceAlias};
+use crate::config::{self, LeasesConfig};
+use crate::device_list::{self, DeviceState, UsbipDevice};
+use crate::runner::{CommandError, CommandRunner};
+use crate::stats::{self, TrafficCounters};
+use crate::reservations;
+use crate::wsl::{self, Attachments};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+const STOP_POLL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaseAction {
+    /// Disconnect the client; the device stays shared.
+    #[default]
+    Detach,
+    /// Stop sharing the device, which also disconnects the client.
+    Unbind,
+}
+
+impl LeaseAction {
+    /// Whether a device in `state` holds a lease this action would end.
+    fn applies_to(&self, state: DeviceState) -> bool {
+        match self {
+            LeaseAction::Detach => state == DeviceState::Attached,
+            LeaseAction::Unbind => matches!(state, DeviceState::Shared | DeviceState::SharedForced | DeviceState::Attached),
+        }
+    }
+}
+
+impl fmt::Display for LeaseAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            LeaseAction::Detach => "detach",
+            LeaseAction::Unbind => "unbind",
+        })
+    }
+}
+
+/// One `[[leases.devices]]` entry, overriding the global settings.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceLease {
+    /// A busid or an alias from `[[aliases]]`.
+    pub device: String,
+    /// 0 turns the idle timeout off for this device.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_minutes: Option<u64>,
+    /// 0 turns the fixed lease off for this device.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_minutes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub action: Option<LeaseAction>,
+}
+
+/// Whether `name`, a busid or alias, refers to `device`.
+pub fn names(name: &str, device: &UsbipDevice, known: &[DeviceAlias]) -> bool {
+    if aliases::looks_like_busid(name) {
+        return name == device.busid;
+    }
+    aliases::lookup(known, device).is_some_and(|a| a.alias.eq_ignore_ascii_case(name))
+}
+
+/// The lease terms for one device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Terms {
+    pub idle: Option<Duration>,
+    pub max: Option<Duration>,
+    pub action: LeaseAction,
+}
+
+fn minutes(value: u64) -> Option<Duration> {
+    (value > 0).then(|| Duration::from_secs(value.saturating_mul(60)))
+}
+
+/// `device`'s terms, or `None` if it's exempt or no limit applies to it.
+pub fn terms_for(settings: &LeasesConfig, device: &UsbipDevice, known: &[DeviceAlias]) -> Option<Terms> {
+    if settings.exempt.iter().any(|name| names(name, device, known)) {
+        return None;
+    }
+    let entry = settings.devices.iter().find(|d| names(&d.device, device, known));
+    let terms = Terms {
+        idle: minutes(entry.and_then(|e| e.idle_minutes).unwrap_or(settings.idle_minutes)),
+        max: minutes(entry.and_then(|e| e.max_minutes).unwrap_or(settings.max_minutes)),
+        action: entry.and_then(|e| e.action).unwrap_or(settings.action),
+    };
+    (terms.idle.is_some() || terms.max.is_some()).then_some(terms)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    /// No URB for `idle_minutes`.
+    Idle,
+    /// `max_minutes` since the lease started.
+    Expired,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Reason::Idle => "idle",
+            Reason::Expired => "lease expired",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum LeaseEvent {
+    /// The action fires in `remaining_secs` unless the device gets used.
+    Warning { device: UsbipDevice, action: LeaseAction, reason: Reason, remaining_secs: u64 },
+    /// The action is due now.
+    Fired { device: UsbipDevice, action: LeaseAction, reason: Reason },
+    /// The device has an idle timeout but no traffic counters, so only
+    /// `max_minutes` can end its lease. Reported once per lease.
+    NoCounters { device: UsbipDevice },
+}
+
+impl LeaseEvent {
+    pub fn device(&self) -> &UsbipDevice {
+        match self {
+            LeaseEvent::Warning { device, .. } | LeaseEvent::Fired { device, .. } | LeaseEvent::NoCounters { device } => device,
+        }
+    }
+}
+
+impl fmt::Display for LeaseEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LeaseEvent::Warning { device, action, reason, remaining_secs } => write!(
+                f,
+                "{} ({}): {} in {} ({})",
+                device.busid,
+                device.device,
+                action,
+                reservations::format_remaining(Duration::from_secs(*remaining_secs)),
+                reason
+            ),
+            LeaseEvent::Fired { device, action, reason } => {
+                write!(f, "{} ({}): {} ({})", device.busid, device.device, action, reason)
+            }
+            LeaseEvent::NoCounters { device } => write!(
+                f,
+                "{} ({}): idle timeout not enforced, no traffic counters (only proxied connections are counted)",
+                device.busid, device.device
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Lease {
+    instance_id: Option<String>,
+    action: LeaseAction,
+    started: Instant,
+    last_activity: Instant,
+    /// URB count at `last_activity`; `None` while the device has no counters.
+    urbs: Option<u64>,
+    warned: bool,
+    fired: bool,
+    /// Whether [`LeaseEvent::NoCounters`] went out for this lease.
+    no_counters_reported: bool,
+}
+
+/// Follows every device's lease from one device list to the next.
+#[derive(Debug, Default)]
+pub struct LeaseTracker {
+    leases: HashMap<String, Lease>,
+}
+
+impl LeaseTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Updates the leases from a fresh device list and traffic counters and
+    /// returns the warnings and actions that became due at `now`.
+    pub fn tick(
+        &mut self,
+        now: Instant,
+        settings: &LeasesConfig,
+        known: &[DeviceAlias],
+        devices: &[UsbipDevice],
+        traffic: &HashMap<String, TrafficCounters>,
+    ) -> Vec<LeaseEvent> {
+        let mut events = Vec::new();
+        let mut leases = HashMap::new();
+        let warn = Duration::from_secs(settings.warn_minutes.saturating_mul(60));
+
+        for device in devices.iter().filter(|d| !d.busid.is_empty()) {
+            let Some(terms) = terms_for(settings, device, known) else { continue };
+            if !terms.action.applies_to(device.state) {
+                continue;
+            }
+            let urbs = traffic.get(&device.busid).map(|c| c.urbs);
+            let mut lease = match self.leases.remove(&device.busid) {
+                // A different device on the same port, or different terms, starts over.
+                Some(lease) if lease.instance_id == device.instance_id && lease.action == terms.action => lease,
+                _ => Lease {
+                    instance_id: device.instance_id.clone(),
+                    action: terms.action,
+                    started: now,
+                    last_activity: now,
+                    urbs,
+                    warned: false,
+                    fired: false,
+                    no_counters_reported: false,
+                },
+            };
+            if urbs != lease.urbs {
+                lease.urbs = urbs;
+                lease.last_activity = now;
+            }
+
+            if terms.idle.is_some() && lease.urbs.is_none() && !lease.no_counters_reported {
+                lease.no_counters_reported = true;
+                events.push(LeaseEvent::NoCounters { device: device.clone() });
+            }
+
+            // A deadline past what an Instant can hold never comes.
+            let idle_due = terms
+                .idle
+                .filter(|_| lease.urbs.is_some())
+                .and_then(|idle| lease.last_activity.checked_add(idle))
+                .map(|at| (at, Reason::Idle));
+            let max_due = terms.max.and_then(|max| lease.started.checked_add(max)).map(|at| (at, Reason::Expired));
+            let due = match (idle_due, max_due) {
+                (Some(a), Some(b)) => Some(if b.0 <= a.0 { b } else { a }),
+                (a, b) => a.or(b),
+            };
+            if let Some((at, reason)) = due {
+                let (device, action) = (device.clone(), terms.action);
+                if now >= at {
+                    if !lease.fired {
+                        lease.fired = true;
+                        events.push(LeaseEvent::Fired { device, action, reason });
+                    }
+                } else if now.checked_add(warn).is_none_or(|w| w >= at) {
+                    if !lease.warned && !warn.is_zero() {
+                        lease.warned = true;
+                        events.push(LeaseEvent::Warning { device, action, reason, remaining_secs: (at - now).as_secs() });
+                    }
+                } else {
+                    // Traffic pushed the deadline back out; warn again next time.
+                    lease.warned = false;
+                    lease.fired = false;
+                }
+            }
+            leases.insert(device.busid.clone(), lease);
+        }
+        self.leases = leases;
+        events
+    }
+
+    /// Lets the action for `busid` fire again on the next tick, after
+    /// carrying it out failed.
+    pub fn retry(&mut self, busid: &str) {
+        if let Some(lease) = self.leases.get_mut(busid) {
+            lease.fired = false;
+        }
+    }
+}
+
+/// Detaches or unbinds the device of a [`LeaseEvent::Fired`]; warnings are no-ops.
+pub fn carry_out(runner: &dyn CommandRunner, attachments: &Attachments, event: &LeaseEvent) -> Result<(), CommandError> {
+    let LeaseEvent::Fired { device, action, .. } = event else { return Ok(()) };
+    match action {
+        LeaseAction::Detach => wsl::detach(runner, attachments, &device.busid),
+        LeaseAction::Unbind => device_list::unbind_device(runner, &device.busid),
+    }
+}
+
+/// Polls the device list and enforces `[leases]` until `stop` is set. Each
+/// event is reported with the result of carrying it out; a failed action is
+/// tried and reported again on the next round.
+pub fn run(
+    runner: &dyn CommandRunner,
+    attachments: &Attachments,
+    stop: &AtomicBool,
+    dry_run: bool,
+    mut report: impl FnMut(&LeaseEvent, Result<(), CommandError>),
+) {
+    let mut tracker = LeaseTracker::new();
+    while !stop.load(Ordering::Relaxed) {
+        // Read per round so edits to [leases] apply without a restart.
+        let config = config::current();
+        if config.leases.is_enabled()
+            && let Ok(devices) = device_list::list_devices(runner)
+        {
+            let traffic = stats::current().devices;
+            for event in tracker.tick(Instant::now(), &config.leases, &config.aliases, &devices, &traffic) {
+                let result = if dry_run { Ok(()) } else { carry_out(runner, attachments, &event) };
+                if result.is_err() {
+                    tracker.retry(&event.device().busid);
+                }
+                report(&event, result);
+            }
+        }
+        let deadline = Instant::now() + POLL_INTERVAL;
+        while !stop.load(Ordering::Relaxed) && Instant::now() < deadline {
+            thread::sleep(STOP_POLL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, FakeRunner, TempDir};
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn attached(busid: &str, instance_id: &str) -> UsbipDevice {
+        let mut device = testing::device(busid, "0483:374b", DeviceState::Attached);
+        device.instance_id = Some(instance_id.to_string());
+        device
+    }
+
+    fn traffic(counts: &[(&str, u64)]) -> HashMap<String, TrafficCounters> {
+        counts.iter().map(|(busid, urbs)| (busid.to_string(), TrafficCounters { urbs: *urbs, ..TrafficCounters::default() })).collect()
+    }
+
+    fn settings(idle_minutes: u64, max_minutes: u64) -> LeasesConfig {
+        LeasesConfig { idle_minutes, max_minutes, warn_minutes: 5, ..LeasesConfig::default() }
+    }
+
+    fn summary(events: &[LeaseEvent]) -> Vec<String> {
+        events
+            .iter()
+            .map(|e| match e {
+                LeaseEvent::Warning { device, action, reason, remaining_secs } => {
+                    format!("warn {} {} {} {}s", device.busid, action, reason, remaining_secs)
+                }
+                LeaseEvent::Fired { device, action, reason } => format!("fire {} {} {}", device.busid, action, reason),
+                LeaseEvent::NoCounters { device } => format!("no counters {}", device.busid),
+            })
+            .collect()
+    }
+
+    /// Ticks `tracker` at `start + at` with one attached device and its URB count.
+    fn tick_at(tracker: &mut LeaseTracker, settings: &LeasesConfig, start: Instant, at: Duration, urbs: u64) -> Vec<String> {
+        let devices = [attached("1-1", "USB\\VID_0483&PID_374B\\A")];
+        summary(&tracker.tick(start + at, settings, &[], &devices, &traffic(&[("1-1", urbs)])))
+    }
+
+    #[test]
+    fn an_idle_device_is_warned_then_detached() {
+        let settings = settings(30, 0);
+        let mut tracker = LeaseTracker::new();
+        let start = Instant::now();
+        assert!(tick_at(&mut tracker, &settings, start, Duration::ZERO, 7).is_empty());
+        assert!(tick_at(&mut tracker, &settings, start, 24 * MINUTE, 7).is_empty());
+        assert_eq!(tick_at(&mut tracker, &settings, start, 26 * MINUTE, 7), ["warn 1-1 detach idle 240s"]);
+        // Warned once only.
+        assert!(tick_at(&mut tracker, &settings, start, 28 * MINUTE, 7).is_empty());
+        assert_eq!(tick_at(&mut tracker, &settings, start, 30 * MINUTE, 7), ["fire 1-1 detach idle"]);
+        assert!(tick_at(&mut tracker, &settings, start, 31 * MINUTE, 7).is_empty());
+    }
+
+    #[test]
+    fn traffic_pushes_the_idle_deadline_back() {
+        let settings = settings(30, 0);
+        let mut tracker = LeaseTracker::new();
+        let start = Instant::now();
+        tick_at(&mut tracker, &settings, start, Duration::ZERO, 7);
+        assert_eq!(tick_at(&mut tracker, &settings, start, 26 * MINUTE, 7).len(), 1);
+        // New URBs at minute 27 restart the idle time and re-arm the warning.
+        assert!(tick_at(&mut tracker, &settings, start, 27 * MINUTE, 9).is_empty());
+        assert!(tick_at(&mut tracker, &settings, start, 30 * MINUTE, 9).is_empty());
+        assert_eq!(tick_at(&mut tracker, &settings, start, 53 * MINUTE, 9), ["warn 1-1 detach idle 240s"]);
+        assert_eq!(tick_at(&mut tracker, &settings, start, 57 * MINUTE, 9), ["fire 1-1 detach idle"]);
+    }
+
+    #[test]
+    fn a_lease_expires_whatever_the_traffic() {
+        let settings = LeasesConfig { action: LeaseAction::Unbind, ..settings(0, 60) };
+        let mut tracker = LeaseTracker::new();
+        let start = Instant::now();
+        tick_at(&mut tracker, &settings, start, Duration::ZERO, 1);
+        assert_eq!(tick_at(&mut tracker, &settings, start, 55 * MINUTE, 2), ["warn 1-1 unbind lease expired 300s"]);
+        assert_eq!(tick_at(&mut tracker, &settings, start, 60 * MINUTE, 3), ["fire 1-1 unbind lease expired"]);
+    }
+
+    #[test]
+    fn the_earlier_deadline_wins() {
+        let settings = settings(30, 20);
+        let mut tracker = LeaseTracker::new();
+        let start = Instant::now();
+        tick_at(&mut tracker, &settings, start, Duration::ZERO, 1);
+        assert_eq!(tick_at(&mut tracker, &settings, start, 20 * MINUTE, 1), ["fire 1-1 detach lease expired"]);
+    }
+
+    #[test]
+    fn no_warning_when_warn_minutes_is_zero() {
+        let settings = LeasesConfig { warn_minutes: 0, ..settings(30, 0) };
+        let mut tracker = LeaseTracker::new();
+        let start = Instant::now();
+        tick_at(&mut tracker, &settings, start, Duration::ZERO, 1);
+        assert!(tick_at(&mut tracker, &settings, start, 29 * MINUTE, 1).is_empty());
+        assert_eq!(tick_at(&mut tracker, &settings, start, 30 * MINUTE, 1), ["fire 1-1 detach idle"]);
+    }
+
+    #[test]
+    fn a_new_instance_on_the_port_restarts_the_lease() {
+        let settings = settings(0, 60);
+        let mut tracker = LeaseTracker::new();
+        let start = Instant::now();
+        let first = [attached("1-1", "USB\\VID_0483&PID_374B\\A")];
+        let second = [attached("1-1", "USB\\VID_0483&PID_374B\\B")];
+        tracker.tick(start, &settings, &[], &first, &HashMap::new());
+        assert!(tracker.tick(start + 50 * MINUTE, &settings, &[], &second, &HashMap::new()).is_empty());
+        assert!(tracker.tick(start + 60 * MINUTE, &settings, &[], &second, &HashMap::new()).is_empty());
+        let events = tracker.tick(start + 110 * MINUTE, &settings, &[], &second, &HashMap::new());
+        assert_eq!(summary(&events), ["fire 1-1 detach lease expired"]);
+    }
+
+    #[test]
+    fn a_detached_device_drops_its_lease() {
+        let settings = settings(0, 60);
+        let mut tracker = LeaseTracker::new();
+        let start = Instant::now();
+        let device = attached("1-1", "USB\\VID_0483&PID_374B\\A");
+        let mut shared = device.clone();
+        shared.state = DeviceState::Shared;
+        tracker.tick(start, &settings, &[], std::slice::from_ref(&device), &HashMap::new());
+        tracker.tick(start + 30 * MINUTE, &settings, &[], &[shared], &HashMap::new());
+        // Attached again: a fresh hour.
+        tracker.tick(start + 40 * MINUTE, &settings, &[], std::slice::from_ref(&device), &HashMap::new());
+        assert!(tracker.tick(start + 60 * MINUTE, &settings, &[], std::slice::from_ref(&device), &HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn exempt_devices_and_aliases_never_get_a_lease() {
+        let probe = DeviceAlias { alias: "probe".into(), vidpid: Some("0483:3748".into()), serial: None, guid: None, note: String::new() };
+        let settings = LeasesConfig { exempt: vec!["1-2".into(), "PROBE".into()], ..settings(0, 60) };
+        let mut other = testing::device("1-3", "0483:3748", DeviceState::Attached);
+        other.instance_id = Some(String::from("USB\\VID_0483&PID_3748\\C"));
+        let devices = [attached("1-1", "A"), attached("1-2", "B"), other];
+        let known = [probe];
+
+        let mut tracker = LeaseTracker::new();
+        let start = Instant::now();
+        tracker.tick(start, &settings, &known, &devices, &HashMap::new());
+        let events = tracker.tick(start + 60 * MINUTE, &settings, &known, &devices, &HashMap::new());
+        assert_eq!(summary(&events), ["fire 1-1 detach lease expired"]);
+        assert_eq!(terms_for(&settings, &devices[1], &known), None);
+        assert_eq!(terms_for(&settings, &devices[2], &known), None);
+    }
+
+    #[test]
+    fn device_entries_override_the_global_terms() {
+        let settings = LeasesConfig {
+            devices: vec![DeviceLease { device: "1-2".into(), idle_minutes: Some(0), max_minutes: Some(10), action: Some(LeaseAction::Unbind) }],
+            ..settings(30, 0)
+        };
+        let global = terms_for(&settings, &attached("1-1", "A"), &[]).unwrap();
+        assert_eq!(global, Terms { idle: Some(30 * MINUTE), max: None, action: LeaseAction::Detach });
+        let entry = terms_for(&settings, &attached("1-2", "B"), &[]).unwrap();
+        assert_eq!(entry, Terms { idle: None, max: Some(10 * MINUTE), action: LeaseAction::Unbind });
+    }
+
+    #[test]
+    fn an_idle_timeout_without_counters_is_reported_once() {
+        let settings = settings(30, 0);
+        let mut tracker = LeaseTracker::new();
+        let start = Instant::now();
+        let devices = [attached("1-1", "A")];
+        let events = tracker.tick(start, &settings, &[], &devices, &HashMap::new());
+        assert_eq!(summary(&events), ["no counters 1-1"]);
+        assert!(events[0].to_string().starts_with("1-1 (Device 0483:374b): idle timeout not enforced"));
+        // Never idle without counters, and not reported again.
+        assert!(tracker.tick(start + 90 * MINUTE, &settings, &[], &devices, &HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn a_failed_action_fires_again() {
+        let settings = settings(0, 60);
+        let mut tracker = LeaseTracker::new();
+        let start = Instant::now();
+        tick_at(&mut tracker, &settings, start, Duration::ZERO, 1);
+        assert_eq!(tick_at(&mut tracker, &settings, start, 60 * MINUTE, 1).len(), 1);
+        assert!(tick_at(&mut tracker, &settings, start, 61 * MINUTE, 1).is_empty());
+        tracker.retry("1-1");
+        assert_eq!(tick_at(&mut tracker, &settings, start, 62 * MINUTE, 1), ["fire 1-1 detach lease expired"]);
+    }
+
+    #[test]
+    fn huge_minutes_never_come_due() {
+        let settings = LeasesConfig { warn_minutes: u64::MAX, ..settings(u64::MAX, u64::MAX) };
+        let mut tracker = LeaseTracker::new();
+        let start = Instant::now();
+        tick_at(&mut tracker, &settings, start, Duration::ZERO, 1);
+        assert!(tick_at(&mut tracker, &settings, start, 24 * 60 * MINUTE, 1).is_empty());
+    }
+
+    #[test]
+    fn carry_out_runs_the_action() {
+        let runner = FakeRunner::new().ok("usbipd unbind", "").ok("usbipd detach", "");
+        let dir = TempDir::new("leases");
+        let attachments = Attachments::at(dir.join(wsl::ATTACHMENTS_FILE));
+        let device = attached("1-1", "A");
+        let warning = LeaseEvent::Warning { device: device.clone(), action: LeaseAction::Unbind, reason: Reason::Idle, remaining_secs: 60 };
+        carry_out(&runner, &attachments, &warning).unwrap();
+        carry_out(&runner, &attachments, &LeaseEvent::NoCounters { device: device.clone() }).unwrap();
+        assert!(runner.calls_to("usbipd unbind").is_empty());
+        carry_out(&runner, &attachments, &LeaseEvent::Fired { device: device.clone(), action: LeaseAction::Unbind, reason: Reason::Idle }).unwrap();
+        assert_eq!(runner.calls_to("usbipd unbind"), ["usbipd unbind --busid 1-1"]);
+        carry_out(&runner, &attachments, &LeaseEvent::Fired { device, action: LeaseAction::Detach, reason: Reason::Idle }).unwrap();
+        assert_eq!(runner.calls_to("usbipd detach"), ["usbipd detach --busid 1-1"]);
+    }
+}
@@ -0,0 +1,35 @@
+//! Core of the USB/IP host tool, shared by the Windows GUI and `usbipctl`.
+pub mod agent;
+pub mod aliases;
+pub mod api;
+pub mod cli;
+pub mod client_scripts;
+pub mod config;
+pub mod device_list;
+pub mod firewall;
+pub mod fleet;
+pub mod installer;
+pub mod leases;
+pub mod linux_client;
+pub mod linux_firewall;
+pub mod mdns;
+pub mod metrics;
+pub mod package_manager;
+pub mod policy;
+pub mod reservations;
+pub mod reverse;
+pub mod rules;
+pub mod runner;
+pub mod scanner;
+pub mod service;
+pub mod stats;
+#[cfg(test)]
+mod testing;
+pub mod updates;
+pub mod usbip_proto;
+pub mod version;
+pub mod watcher;
+pub mod winget;
+pub mod wsl;
+#[cfg(windows)]
+pub mod windows;
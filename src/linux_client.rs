@@ -0,0 +1,778 @@
+//! Client mode for Linux: keeps configured remote devices attached.
+//!
+//! Each target names a host and a device on it. The device is looked up in
+//! the host's `OP_REP_DEVLIST` and imported either with `usbip attach` or by
+//! handing the imported socket straight to the vhci driver through
+//! `/sys/devices/platform/vhci_hcd.0/attach`. The vhci `status` files show
+//! when the kernel dropped an attachment, which is then retried with backoff.
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::aliases::{self, DeviceAlias};
+use crate::config::{self, ClientConfig};
+use crate::policy;
+use crate::reverse::{Backoff, USBIP_PORT};
+use crate::runner::{self, CommandError, CommandRunner};
+use crate::usbip_proto::{self, ExportedDevice};
+
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+const STOP_POLL: Duration = Duration::from_millis(100);
+
+/// `enum usb_device_speed`: super speed devices go on the `ss` hub.
+const USB_SPEED_SUPER: u32 = 5;
+/// `enum vhci_device_status` values printed in the `sta` column.
+const VDEV_ST_NULL: u32 = 4;
+const VDEV_ST_USED: u32 = 6;
+
+/// One `[[client.targets]]` entry in the config file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AttachTarget {
+    /// `host` or `host:port`; the port defaults to 3240.
+    pub host: String,
+    /// A busid, a `vid:pid`, or an alias from `[[aliases]]` that has a `vidpid`.
+    pub device: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AttachMethod {
+    /// Import the device ourselves and pass the socket to the vhci driver.
+    #[default]
+    Sysfs,
+    /// Run `usbip attach` from the usbip userland tools.
+    Usbip,
+}
+
+/// How a target's device is recognized in a devlist.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selector {
+    Busid(String),
+    /// Lowercase `vid:pid`; the first matching device is used.
+    VidPid(String),
+}
+
+impl Selector {
+    pub fn parse(device: &str, known: &[DeviceAlias]) -> Option<Self> {
+        if aliases::looks_like_busid(device) {
+            return Some(Selector::Busid(device.to_string()));
+        }
+        if policy::is_hardware_id(device) {
+            return Some(Selector::VidPid(device.to_ascii_lowercase()));
+        }
+        let alias = known.iter().find(|a| a.alias.eq_ignore_ascii_case(device))?;
+        alias.vidpid.as_ref().map(|v| Selector::VidPid(v.to_ascii_lowercase()))
+    }
+
+    pub fn pick<'a>(&self, devices: &'a [ExportedDevice]) -> Option<&'a ExportedDevice> {
+        self.matches(devices).next()
+    }
+
+    /// Every device the selector fits, in the host's order.
+    pub fn matches<'a>(&self, devices: &'a [ExportedDevice]) -> impl Iterator<Item = &'a ExportedDevice> {
+        devices.iter().filter(move |d| match self {
+            Selector::Busid(busid) => d.busid == *busid,
+            Selector::VidPid(vidpid) => d.vidpid() == *vidpid,
+        })
+    }
+}
+
+/// Splits `host[:port]`, allowing bracketed IPv6 addresses.
+pub fn split_host(host: &str, default_port: u16) -> (String, u16) {
+    if let Some(rest) = host.strip_prefix('[')
+        && let Some((address, tail)) = rest.split_once(']')
+    {
+        let port = tail.strip_prefix(':').and_then(|p| p.parse().ok()).unwrap_or(default_port);
+        return (address.to_string(), port);
+    }
+    match host.rsplit_once(':') {
+        // More than one colon without brackets is a bare IPv6 address.
+        Some((name, port)) if !name.contains(':') => match port.parse() {
+            Ok(port) => (name.to_string(), port),
+            Err(_) => (host.to_string(), default_port),
+        },
+        _ => (host.to_string(), default_port),
+    }
+}
+
+pub(crate) fn resolve(host: &str, port: u16) -> io::Result<SocketAddr> {
+    (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", host)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Hub {
+    /// `hs`: low, full and high speed devices.
+    High,
+    /// `ss`: super speed devices.
+    Super,
+}
+
+/// A line of a vhci `status` file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct VhciPort {
+    pub hub: Hub,
+    pub port: u32,
+    pub status: u32,
+    pub speed: u32,
+    pub devid: u32,
+    pub sockfd: u32,
+    pub local_busid: String,
+}
+
+impl VhciPort {
+    pub fn is_free(&self) -> bool {
+        self.status == VDEV_ST_NULL
+    }
+
+    pub fn is_used(&self) -> bool {
+        self.status == VDEV_ST_USED
+    }
+}
+
+/// Parses a vhci `status` file in the layout used since Linux 4.13:
+/// `hub port sta spd dev sockfd local_busid`.
+pub fn parse_status(text: &str) -> Vec<VhciPort> {
+    text.lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [hub, port, status, speed, devid, sockfd, local_busid] = fields[..] else { return None };
+            let hub = match hub {
+                "hs" => Hub::High,
+                "ss" => Hub::Super,
+                _ => return None,
+            };
+            Some(VhciPort {
+                hub,
+                port: port.parse().ok()?,
+                status: status.parse().ok()?,
+                speed: speed.parse().ok()?,
+                devid: u32::from_str_radix(devid, 16).ok()?,
+                sockfd: sockfd.parse().ok()?,
+                local_busid: local_busid.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Which remote device a vhci port carries, as `usbip` records it in
+/// `/var/run/vhci_hcd/port<N>`: `host port busid`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PortRecord {
+    pub host: String,
+    pub port: u16,
+    pub busid: String,
+}
+
+pub fn parse_record(text: &str) -> Option<PortRecord> {
+    let mut fields = text.split_whitespace();
+    Some(PortRecord {
+        host: fields.next()?.to_string(),
+        port: fields.next()?.parse().ok()?,
+        busid: fields.next()?.to_string(),
+    })
+}
+
+/// The vhci_hcd sysfs directory and usbip's record directory.
+#[derive(Debug, Clone)]
+pub struct Vhci {
+    root: PathBuf,
+    records: PathBuf,
+}
+
+impl Vhci {
+    pub fn new(root: impl Into<PathBuf>, records: impl Into<PathBuf>) -> Self {
+        Vhci { root: root.into(), records: records.into() }
+    }
+
+    /// Every port of every controller. Controllers after the first have
+    /// `status.1`, `status.2` and so on.
+    pub fn ports(&self) -> io::Result<Vec<VhciPort>> {
+        let mut ports = parse_status(&fs::read_to_string(self.root.join("status"))?);
+        for n in 1.. {
+            match fs::read_to_string(self.root.join(format!("status.{}", n))) {
+                Ok(text) => ports.extend(parse_status(&text)),
+                Err(_) => break,
+            }
+        }
+        Ok(ports)
+    }
+
+    pub fn free_port(&self, speed: u32) -> io::Result<Option<u32>> {
+        let hub = if speed >= USB_SPEED_SUPER { Hub::Super } else { Hub::High };
+        Ok(self.ports()?.into_iter().find(|p| p.hub == hub && p.is_free()).map(|p| p.port))
+    }
+
+    pub fn is_used(&self, port: u32) -> io::Result<bool> {
+        Ok(self.ports()?.iter().any(|p| p.port == port && p.is_used()))
+    }
+
+    /// Hands an imported connection to the driver. The kernel takes its own
+    /// reference to the socket, so the caller may close `sockfd` afterwards.
+    pub fn attach(&self, port: u32, sockfd: i32, devid: u32, speed: u32) -> io::Result<()> {
+        fs::write(self.root.join("attach"), format!("{} {} {} {}", port, sockfd, devid, speed))
+    }
+
+    pub fn detach(&self, port: u32) -> io::Result<()> {
+        fs::write(self.root.join("detach"), port.to_string())?;
+        let _ = fs::remove_file(self.record_path(port));
+        Ok(())
+    }
+
+    fn record_path(&self, port: u32) -> PathBuf {
+        self.records.join(format!("port{}", port))
+    }
+
+    pub fn record(&self, port: u32) -> Option<PortRecord> {
+        parse_record(&fs::read_to_string(self.record_path(port)).ok()?)
+    }
+
+    /// Writes the record `usbip port` reads, so attachments made here show up there.
+    pub fn write_record(&self, port: u32, record: &PortRecord) -> io::Result<()> {
+        fs::create_dir_all(&self.records)?;
+        fs::write(self.record_path(port), format!("{} {} {}\n", record.host, record.port, record.busid))
+    }
+
+    /// The used port carrying `record`'s device, if any.
+    pub fn find(&self, record: &PortRecord) -> io::Result<Option<u32>> {
+        Ok(self.ports()?.iter().filter(|p| p.is_used()).map(|p| p.port).find(|&port| self.record(port).as_ref() == Some(record)))
+    }
+}
+
+#[cfg(unix)]
+fn raw_fd(stream: &TcpStream) -> io::Result<i32> {
+    use std::os::unix::io::AsRawFd;
+    Ok(stream.as_raw_fd())
+}
+
+#[cfg(not(unix))]
+fn raw_fd(_stream: &TcpStream) -> io::Result<i32> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "attaching through vhci sysfs needs Linux"))
+}
+
+#[derive(Debug)]
+pub enum AttachError {
+    /// The host didn't answer or refused the import.
+    Host(io::Error),
+    /// The host doesn't export a device matching the target.
+    NotExported(String),
+    /// The target's device is neither a busid, a VID:PID nor an alias with one.
+    UnknownDevice(String),
+    NoFreePort,
+    Vhci(io::Error),
+    Command(CommandError),
+}
+
+impl fmt::Display for AttachError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AttachError::Host(e) => write!(f, "{}", e),
+            AttachError::NotExported(device) => write!(f, "{} is not exported", device),
+            AttachError::UnknownDevice(device) => write!(f, "'{}' is not a busid, VID:PID or alias with a VID:PID", device),
+            AttachError::NoFreePort => f.write_str("no free vhci port"),
+            AttachError::Vhci(e) => write!(f, "vhci_hcd: {}", e),
+            AttachError::Command(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for AttachError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkState {
+    /// Not tried yet.
+    Idle,
+    Attached,
+    /// The last attempt failed or the attachment dropped; retrying later.
+    Waiting,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TargetStatus {
+    pub target: AttachTarget,
+    pub state: LinkState,
+    pub busid: Option<String>,
+    pub port: Option<u32>,
+    pub last_error: Option<String>,
+    #[serde(skip)]
+    backoff: Backoff,
+    #[serde(skip)]
+    retry_at: Option<Instant>,
+}
+
+/// Keeps every configured target attached.
+pub struct Companion<'a> {
+    runner: &'a dyn CommandRunner,
+    settings: ClientConfig,
+    aliases: Vec<DeviceAlias>,
+    vhci: Vhci,
+    targets: Vec<TargetStatus>,
+}
+
+impl<'a> Companion<'a> {
+    pub fn new(runner: &'a dyn CommandRunner, settings: ClientConfig, aliases: Vec<DeviceAlias>) -> Self {
+        let vhci = Vhci::new(&settings.vhci_path, &settings.record_dir);
+        let backoff = Backoff::new(settings.initial_backoff(), settings.max_backoff());
+        let targets = settings
+            .targets
+            .iter()
+            .map(|target| TargetStatus {
+                target: target.clone(),
+                state: LinkState::Idle,
+                busid: None,
+                port: None,
+                last_error: None,
+                backoff: backoff.clone(),
+                retry_at: None,
+            })
+            .collect();
+        Companion { runner, settings, aliases, vhci, targets }
+    }
+
+    pub fn from_config(runner: &'a dyn CommandRunner) -> Self {
+        let config = config::current();
+        Self::new(runner, config.client, config.aliases)
+    }
+
+    pub fn vhci(&self) -> &Vhci {
+        &self.vhci
+    }
+
+    pub fn statuses(&self) -> &[TargetStatus] {
+        &self.targets
+    }
+
+    /// Runs [`Self::tick`] every poll interval until `stop` is set.
+    pub fn run(&mut self, stop: &AtomicBool) {
+        while !stop.load(Ordering::Relaxed) {
+            self.tick(Instant::now());
+            let deadline = Instant::now() + self.settings.poll_interval();
+            while !stop.load(Ordering::Relaxed) && Instant::now() < deadline {
+                thread::sleep(STOP_POLL);
+            }
+        }
+    }
+
+    /// Notices dropped attachments and attaches every target that is due.
+    pub fn tick(&mut self, now: Instant) {
+        for i in 0..self.targets.len() {
+            let status = &mut self.targets[i];
+            if status.state == LinkState::Attached
+                && let Some(port) = status.port
+            {
+                if self.vhci.is_used(port).unwrap_or(false) {
+                    continue;
+                }
+                // Dropped by the kernel, usually because the host went away.
+                // Try again right away; failures from here on back off.
+                status.state = LinkState::Waiting;
+                status.port = None;
+                status.last_error = Some(format!("port {} was detached", port));
+                status.backoff.reset();
+                status.retry_at = None;
+            }
+            if status.retry_at.is_some_and(|at| now < at) {
+                continue;
+            }
+
+            let target = status.target.clone();
+            // Ports other targets hold, including ones attached earlier in this pass.
+            let claimed: Vec<u32> =
+                self.targets.iter().filter(|t| t.state == LinkState::Attached).filter_map(|t| t.port).collect();
+            let result = self.attach(&target, &claimed);
+            let status = &mut self.targets[i];
+            match result {
+                Ok((busid, port)) => {
+                    status.state = LinkState::Attached;
+                    status.busid = Some(busid);
+                    status.port = Some(port);
+                    status.last_error = None;
+                    status.backoff.reset();
+                    status.retry_at = None;
+                }
+                Err(e) => {
+                    status.state = LinkState::Waiting;
+                    status.last_error = Some(e.to_string());
+                    status.retry_at = Some(now + status.backoff.next_delay());
+                }
+            }
+        }
+    }
+
+    /// Attaches `target`, or adopts an attachment of it that already exists
+    /// on a port not in `claimed`. Returns the remote busid and the vhci port.
+    fn attach(&self, target: &AttachTarget, claimed: &[u32]) -> Result<(String, u32), AttachError> {
+        let selector =
+            Selector::parse(&target.device, &self.aliases).ok_or_else(|| AttachError::UnknownDevice(target.device.clone()))?;
+        let (host, port) = split_host(&target.host, USBIP_PORT);
+
+        if let Selector::Busid(busid) = &selector {
+            let record = PortRecord { host: host.clone(), port, busid: busid.clone() };
+            if let Some(vhci_port) = self.vhci.find(&record).map_err(AttachError::Vhci)?
+                && !claimed.contains(&vhci_port)
+            {
+                return Ok((busid.clone(), vhci_port));
+            }
+        }
+
+        let addr = resolve(&host, port).map_err(AttachError::Host)?;
+        let mut stream = TcpStream::connect_timeout(&addr, IO_TIMEOUT).map_err(AttachError::Host)?;
+        stream.set_read_timeout(Some(IO_TIMEOUT)).map_err(AttachError::Host)?;
+        stream.set_write_timeout(Some(IO_TIMEOUT)).map_err(AttachError::Host)?;
+        let devices = usbip_proto::request_devlist(&mut stream).map_err(AttachError::Host)?;
+        // Two targets may name the same VID:PID; each gets a device of its own.
+        let mut unattached = None;
+        for device in selector.matches(&devices) {
+            let record = PortRecord { host: host.clone(), port, busid: device.busid.clone() };
+            match self.vhci.find(&record).map_err(AttachError::Vhci)? {
+                Some(vhci_port) if claimed.contains(&vhci_port) => {}
+                Some(vhci_port) => return Ok((record.busid, vhci_port)),
+                None => {
+                    unattached.get_or_insert(record);
+                }
+            }
+        }
+        let record = unattached.ok_or_else(|| AttachError::NotExported(target.device.clone()))?;
+
+        let vhci_port = match self.settings.method {
+            AttachMethod::Sysfs => self.attach_sysfs(addr, &record)?,
+            AttachMethod::Usbip => self.attach_usbip(&record)?,
+        };
+        Ok((record.busid, vhci_port))
+    }
+
+    fn attach_sysfs(&self, addr: SocketAddr, record: &PortRecord) -> Result<u32, AttachError> {
+        // usbipd serves one request per connection, so importing needs a new one.
+        let mut stream = TcpStream::connect_timeout(&addr, IO_TIMEOUT).map_err(AttachError::Host)?;
+        stream.set_read_timeout(Some(IO_TIMEOUT)).map_err(AttachError::Host)?;
+        stream.set_write_timeout(Some(IO_TIMEOUT)).map_err(AttachError::Host)?;
+        let device = usbip_proto::request_import(&mut stream, &record.busid).map_err(AttachError::Host)?;
+        // The kernel reads and writes this socket from now on and would
+        // inherit the timeouts.
+        stream.set_read_timeout(None).map_err(AttachError::Host)?;
+        stream.set_write_timeout(None).map_err(AttachError::Host)?;
+        stream.set_nodelay(true).map_err(AttachError::Host)?;
+
+        let port = self.vhci.free_port(device.speed).map_err(AttachError::Vhci)?.ok_or(AttachError::NoFreePort)?;
+        let sockfd = raw_fd(&stream).map_err(AttachError::Vhci)?;
+        self.vhci.attach(port, sockfd, device.devid(), device.speed).map_err(AttachError::Vhci)?;
+        // Only `usbip port` reads the record; the attachment works without it.
+        let _ = self.vhci.write_record(port, record);
+        Ok(port)
+    }
+
+    fn attach_usbip(&self, record: &PortRecord) -> Result<u32, AttachError> {
+        let tcp_port = record.port.to_string();
+        let mut args = Vec::new();
+        if record.port != USBIP_PORT {
+            args.extend(["--tcp-port", &tcp_port]);
+        }
+        args.extend(["attach", "-r", &record.host, "-b", &record.busid]);
+        runner::run_checked(self.runner, "usbip", &args).map_err(AttachError::Command)?;
+        // usbip wrote the record itself.
+        self.vhci
+            .find(record)
+            .map_err(AttachError::Vhci)?
+            .ok_or_else(|| AttachError::Vhci(io::Error::other("usbip attach left no port record")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakeRunner, TempDir, closed_port, devlist_server, exported_device};
+    use std::path::Path;
+
+    const STATUS: &str = "\
+hub port sta spd dev      sockfd local_busid
+hs  0000 006 003 00010002 000003 3-1
+hs  0001 004 000 00000000 000000 0-0
+ss  0002 004 000 00000000 000000 0-0
+ss  0003 004 000 00000000 000000 0-0
+";
+
+    /// A stand-in for `vhci_hcd.0` and the record directory under the temp dir.
+    struct FakeVhci {
+        dir: TempDir,
+    }
+
+    impl FakeVhci {
+        fn new(name: &str) -> Self {
+            let dir = TempDir::new(&format!("linux-client-{}", name));
+            fs::create_dir_all(dir.join("sysfs")).unwrap();
+            FakeVhci { dir }
+        }
+
+        fn root(&self) -> PathBuf {
+            self.dir.join("sysfs")
+        }
+
+        fn records(&self) -> PathBuf {
+            self.dir.join("records")
+        }
+
+        fn vhci(&self) -> Vhci {
+            Vhci::new(self.root(), self.records())
+        }
+
+        fn status(&self, name: &str, text: &str) {
+            fs::write(self.root().join(name), text).unwrap();
+        }
+
+        fn read(&self, name: &str) -> String {
+            fs::read_to_string(self.root().join(name)).unwrap()
+        }
+    }
+
+    fn line(hub: &str, port: u32, status: u32) -> String {
+        let (speed, devid, sockfd, busid) = if status == VDEV_ST_USED { (3, "00010002", 3, "3-1") } else { (0, "00000000", 0, "0-0") };
+        format!("{}  {:04} {:03} {:03} {} {:06} {}\n", hub, port, status, speed, devid, sockfd, busid)
+    }
+
+    fn alias(name: &str, vidpid: Option<&str>) -> DeviceAlias {
+        DeviceAlias { alias: name.to_string(), vidpid: vidpid.map(str::to_string), serial: None, guid: None, note: String::new() }
+    }
+
+    fn settings(fake: &FakeVhci, host: String, device: &str) -> ClientConfig {
+        ClientConfig {
+            targets: vec![AttachTarget { host, device: device.to_string() }],
+            vhci_path: path_string(&fake.root()),
+            record_dir: path_string(&fake.records()),
+            initial_backoff_secs: 1,
+            max_backoff_secs: 60,
+            ..ClientConfig::default()
+        }
+    }
+
+    fn path_string(path: &Path) -> String {
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn parse_status_reads_both_hubs_and_skips_the_header() {
+        let ports = parse_status(STATUS);
+        assert_eq!(ports.len(), 4);
+        assert_eq!(
+            ports[0],
+            VhciPort { hub: Hub::High, port: 0, status: VDEV_ST_USED, speed: 3, devid: 0x10002, sockfd: 3, local_busid: "3-1".into() }
+        );
+        assert!(ports[0].is_used() && !ports[0].is_free());
+        assert!(ports[1].is_free());
+        assert_eq!(ports.iter().map(|p| p.hub).collect::<Vec<_>>(), [Hub::High, Hub::High, Hub::Super, Hub::Super]);
+    }
+
+    #[test]
+    fn parse_status_ignores_old_and_broken_lines() {
+        // The layout before Linux 4.13 had no hub column.
+        assert!(parse_status("prt sta spd bus dev socket local_busid\n000 004 000 000 000 000000 0-0\n").is_empty());
+        assert!(parse_status("xs  0000 004 000 00000000 000000 0-0\n").is_empty());
+        assert!(parse_status("hs  0000 004 000 zzzzzzzz 000000 0-0\n").is_empty());
+        assert!(parse_status("").is_empty());
+    }
+
+    #[test]
+    fn parse_record_needs_host_port_and_busid() {
+        assert_eq!(parse_record("10.0.0.5 3240 1-4\n"), Some(PortRecord { host: "10.0.0.5".into(), port: 3240, busid: "1-4".into() }));
+        assert_eq!(parse_record("10.0.0.5 http 1-4"), None);
+        assert_eq!(parse_record("10.0.0.5 3240"), None);
+    }
+
+    #[test]
+    fn free_port_picks_the_hub_by_speed() {
+        let fake = FakeVhci::new("free-port");
+        fake.status("status", STATUS);
+        let vhci = fake.vhci();
+        assert_eq!(vhci.free_port(3).unwrap(), Some(1));
+        assert_eq!(vhci.free_port(USB_SPEED_SUPER).unwrap(), Some(2));
+        assert!(vhci.is_used(0).unwrap());
+        assert!(!vhci.is_used(1).unwrap());
+    }
+
+    #[test]
+    fn free_port_looks_at_every_controller() {
+        let fake = FakeVhci::new("controllers");
+        fake.status("status", &format!("{}{}", line("hs", 0, VDEV_ST_USED), line("ss", 1, VDEV_ST_USED)));
+        fake.status("status.1", &format!("{}{}", line("hs", 2, VDEV_ST_USED), line("ss", 3, VDEV_ST_NULL)));
+        let vhci = fake.vhci();
+        assert_eq!(vhci.ports().unwrap().len(), 4);
+        assert_eq!(vhci.free_port(3).unwrap(), None);
+        assert_eq!(vhci.free_port(6).unwrap(), Some(3));
+    }
+
+    #[test]
+    fn missing_status_file_is_an_error() {
+        let fake = FakeVhci::new("no-status");
+        assert!(fake.vhci().free_port(3).is_err());
+    }
+
+    #[test]
+    fn attach_and_detach_write_the_sysfs_files() {
+        let fake = FakeVhci::new("attach");
+        fake.status("status", STATUS);
+        let vhci = fake.vhci();
+        let record = PortRecord { host: "10.0.0.5".into(), port: 3240, busid: "1-4".into() };
+
+        vhci.attach(1, 7, 0x10002, 3).unwrap();
+        assert_eq!(fake.read("attach"), "1 7 65538 3");
+        vhci.write_record(0, &record).unwrap();
+        assert_eq!(vhci.record(0), Some(record.clone()));
+        assert_eq!(vhci.find(&record).unwrap(), Some(0));
+
+        vhci.detach(0).unwrap();
+        assert_eq!(fake.read("detach"), "0");
+        assert_eq!(vhci.record(0), None);
+        assert_eq!(vhci.find(&record).unwrap(), None);
+    }
+
+    #[test]
+    fn find_ignores_records_of_free_ports() {
+        let fake = FakeVhci::new("stale-record");
+        fake.status("status", STATUS);
+        let vhci = fake.vhci();
+        let record = PortRecord { host: "10.0.0.5".into(), port: 3240, busid: "1-4".into() };
+        vhci.write_record(1, &record).unwrap();
+        assert_eq!(vhci.find(&record).unwrap(), None);
+    }
+
+    #[test]
+    fn selector_accepts_busids_hardware_ids_and_aliases() {
+        let known = [alias("probe", Some("0483:3748")), alias("scanner", None)];
+        assert_eq!(Selector::parse("1-4", &known), Some(Selector::Busid("1-4".into())));
+        assert_eq!(Selector::parse("0483:374B", &known), Some(Selector::VidPid("0483:374b".into())));
+        assert_eq!(Selector::parse("PROBE", &known), Some(Selector::VidPid("0483:3748".into())));
+        // An alias without a vidpid can't be recognized in a devlist.
+        assert_eq!(Selector::parse("scanner", &known), None);
+        assert_eq!(Selector::parse("nothing", &known), None);
+    }
+
+    #[test]
+    fn selector_picks_the_first_match() {
+        let devices = [exported_device("1-1", 0x3748), exported_device("1-2", 0x374b), exported_device("1-3", 0x374b)];
+        assert_eq!(Selector::Busid("1-2".into()).pick(&devices).map(|d| d.busid.as_str()), Some("1-2"));
+        assert_eq!(Selector::VidPid("0483:374b".into()).pick(&devices).map(|d| d.busid.as_str()), Some("1-2"));
+        assert_eq!(Selector::Busid("2-1".into()).pick(&devices), None);
+        assert_eq!(Selector::VidPid("1234:5678".into()).pick(&devices), None);
+    }
+
+    #[test]
+    fn split_host_handles_ports_and_ipv6() {
+        assert_eq!(split_host("pi.local", 3240), ("pi.local".into(), 3240));
+        assert_eq!(split_host("pi.local:3241", 3240), ("pi.local".into(), 3241));
+        assert_eq!(split_host("[fe80::1]:3241", 3240), ("fe80::1".into(), 3241));
+        assert_eq!(split_host("[fe80::1]", 3240), ("fe80::1".into(), 3240));
+        assert_eq!(split_host("fe80::1", 3240), ("fe80::1".into(), 3240));
+        assert_eq!(split_host("pi.local:usbip", 3240), ("pi.local:usbip".into(), 3240));
+    }
+
+    #[test]
+    fn tick_adopts_an_existing_attachment() {
+        let fake = FakeVhci::new("adopt");
+        let addr = devlist_server(vec![exported_device("1-1", 0x3748), exported_device("1-2", 0x374b)]);
+        fake.status("status", STATUS);
+        let record = PortRecord { host: "127.0.0.1".into(), port: addr.port(), busid: "1-2".into() };
+        fake.vhci().write_record(0, &record).unwrap();
+
+        let runner = FakeRunner::empty();
+        let mut companion = Companion::new(&runner, settings(&fake, addr.to_string(), "0483:374b"), Vec::new());
+        companion.tick(Instant::now());
+
+        let status = &companion.statuses()[0];
+        assert_eq!(status.state, LinkState::Attached);
+        assert_eq!(status.busid.as_deref(), Some("1-2"));
+        assert_eq!(status.port, Some(0));
+        assert!(!fake.root().join("attach").exists());
+        assert!(runner.calls().is_empty());
+    }
+
+    #[test]
+    fn targets_with_the_same_vidpid_adopt_different_ports() {
+        let fake = FakeVhci::new("same-vidpid");
+        let addr = devlist_server(vec![exported_device("1-1", 0x3748), exported_device("1-2", 0x374b), exported_device("1-3", 0x374b)]);
+        fake.status("status", &format!("{}{}", line("hs", 0, VDEV_ST_USED), line("hs", 1, VDEV_ST_USED)));
+        for (port, busid) in [(0, "1-3"), (1, "1-2")] {
+            let record = PortRecord { host: "127.0.0.1".into(), port: addr.port(), busid: busid.into() };
+            fake.vhci().write_record(port, &record).unwrap();
+        }
+
+        let runner = FakeRunner::empty();
+        let mut settings = settings(&fake, addr.to_string(), "0483:374b");
+        settings.targets.push(settings.targets[0].clone());
+        let mut companion = Companion::new(&runner, settings, Vec::new());
+        companion.tick(Instant::now());
+
+        let adopted: Vec<_> = companion.statuses().iter().map(|s| (s.state, s.busid.as_deref(), s.port)).collect();
+        assert_eq!(adopted, [(LinkState::Attached, Some("1-2"), Some(1)), (LinkState::Attached, Some("1-3"), Some(0))]);
+        assert!(!fake.root().join("attach").exists());
+    }
+
+    #[test]
+    fn tick_retries_a_dropped_port_at_once_then_backs_off() {
+        let fake = FakeVhci::new("drop");
+        // Nothing answers on the host, so only an existing attachment can be adopted.
+        let addr = closed_port();
+        let record = PortRecord { host: "127.0.0.1".into(), port: addr.port(), busid: "1-2".into() };
+        let used = format!("{}{}", line("hs", 0, VDEV_ST_USED), line("hs", 1, VDEV_ST_NULL));
+        let dropped = format!("{}{}", line("hs", 0, VDEV_ST_NULL), line("hs", 1, VDEV_ST_NULL));
+        fake.status("status", &used);
+        fake.vhci().write_record(0, &record).unwrap();
+
+        let runner = FakeRunner::empty();
+        let mut companion = Companion::new(&runner, settings(&fake, addr.to_string(), "1-2"), Vec::new());
+        let start = Instant::now();
+        companion.tick(start);
+        assert_eq!(companion.statuses()[0].state, LinkState::Attached);
+        assert_eq!(companion.statuses()[0].port, Some(0));
+
+        // The kernel drops the port: the next tick retries right away and fails.
+        fake.status("status", &dropped);
+        companion.tick(start);
+        let status = &companion.statuses()[0];
+        assert_eq!(status.state, LinkState::Waiting);
+        assert_eq!(status.port, None);
+        assert!(status.last_error.as_deref().is_some_and(|e| e != "port 0 was detached"), "{:?}", status.last_error);
+        assert_eq!(status.retry_at, Some(start + Duration::from_secs(1)));
+
+        // The port comes back, but nothing is tried before the backoff runs out.
+        fake.status("status", &used);
+        companion.tick(start + Duration::from_millis(500));
+        assert_eq!(companion.statuses()[0].state, LinkState::Waiting);
+
+        // A second failure doubles the delay.
+        fake.status("status", &dropped);
+        let second = start + Duration::from_secs(1);
+        companion.tick(second);
+        assert_eq!(companion.statuses()[0].retry_at, Some(second + Duration::from_secs(2)));
+
+        fake.status("status", &used);
+        companion.tick(second + Duration::from_secs(2));
+        let status = &companion.statuses()[0];
+        assert_eq!(status.state, LinkState::Attached);
+        assert_eq!(status.port, Some(0));
+        assert_eq!(status.last_error, None);
+        assert_eq!(status.retry_at, None);
+    }
+
+    #[test]
+    fn tick_reports_unknown_devices() {
+        let fake = FakeVhci::new("unknown");
+        fake.status("status", STATUS);
+        let runner = FakeRunner::empty();
+        let mut companion = Companion::new(&runner, settings(&fake, "127.0.0.1".into(), "scanner"), Vec::new());
+        companion.tick(Instant::now());
+        let status = &companion.statuses()[0];
+        assert_eq!(status.state, LinkState::Waiting);
+        assert_eq!(status.last_error.as_deref(), Some("'scanner' is not a busid, VID:PID or alias with a VID:PID"));
+    }
+}
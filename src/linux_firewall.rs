@@ -0,0 +1,662 @@
+//! nftables and iptables counterpart of the Windows Firewall rule.
+//!
+//! Accepting a packet in one nftables base chain does not stop another base
+//! chain on the same hook from dropping it, so instead of a table of our own
+//! the rule is inserted at the top of every `filter` chain hooked on `input`.
+//! Each rule carries the comment [`TAG`], which is how status and removal
+//! find it again.
+use std::fmt;
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::firewall::EnsureOutcome;
+use crate::runner::{self, CommandError, CommandRunner};
+use crate::{config, metrics};
+
+pub const TAG: &str = "usbip_host";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LinuxBackend {
+    Nftables,
+    Iptables,
+}
+
+impl fmt::Display for LinuxBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            LinuxBackend::Nftables => "nftables",
+            LinuxBackend::Iptables => "iptables",
+        })
+    }
+}
+
+/// `firewall.linux_backend` in the config file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendChoice {
+    #[default]
+    Auto,
+    Nftables,
+    Iptables,
+}
+
+/// Which source addresses the rule admits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+}
+
+/// One rule, either wanted or found: a port and an optional source filter.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct PortRule {
+    pub port: u16,
+    /// `None` accepts every source.
+    pub source: Option<(AddressFamily, Vec<String>)>,
+}
+
+impl fmt::Display for PortRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.source {
+            Some((family, addresses)) => {
+                let keyword = if *family == AddressFamily::Ipv4 { "ip" } else { "ip6" };
+                write!(f, "{} saddr {{ {} }} tcp dport {} accept", keyword, addresses.join(", "), self.port)
+            }
+            None => write!(f, "tcp dport {} accept", self.port),
+        }
+    }
+}
+
+/// The rules wanted for `port`, one per address family in `sources`.
+pub fn expected_rules(port: u16, sources: &[String]) -> Result<Vec<PortRule>, CommandError> {
+    if sources.is_empty() || sources.iter().any(|s| s.eq_ignore_ascii_case("any")) {
+        return Ok(vec![PortRule { port, source: None }]);
+    }
+    let mut v4 = Vec::new();
+    let mut v6 = Vec::new();
+    for source in sources {
+        match normalize_cidr(source) {
+            Some((AddressFamily::Ipv4, cidr)) => v4.push(cidr),
+            Some((AddressFamily::Ipv6, cidr)) => v6.push(cidr),
+            None => {
+                return Err(CommandError::Failed(format!(
+                    "firewall.remote_addresses: '{}' is not an address or CIDR, which is all Linux supports",
+                    source
+                )))
+            }
+        }
+    }
+    let mut rules = Vec::new();
+    for (family, mut addresses) in [(AddressFamily::Ipv4, v4), (AddressFamily::Ipv6, v6)] {
+        if !addresses.is_empty() {
+            addresses.sort();
+            rules.push(PortRule { port, source: Some((family, addresses)) });
+        }
+    }
+    Ok(rules)
+}
+
+/// `10.0.0.0/8` stays as it is, a bare address gets a full-length prefix.
+fn normalize_cidr(value: &str) -> Option<(AddressFamily, String)> {
+    let (ip, prefix) = match value.trim().split_once('/') {
+        Some((ip, prefix)) => (ip.parse::<IpAddr>().ok()?, Some(prefix.parse::<u8>().ok()?)),
+        None => (value.trim().parse::<IpAddr>().ok()?, None),
+    };
+    let (family, max) = if ip.is_ipv4() { (AddressFamily::Ipv4, 32) } else { (AddressFamily::Ipv6, 128) };
+    let prefix = prefix.unwrap_or(max);
+    if prefix > max {
+        return None;
+    }
+    Some((family, format!("{}/{}", ip, prefix)))
+}
+
+/// Where a rule goes: an nftables base chain or an iptables/ip6tables INPUT chain.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Chain {
+    pub family: String,
+    pub table: String,
+    pub name: String,
+}
+
+impl Chain {
+    fn accepts(&self, family: Option<AddressFamily>) -> bool {
+        match (self.family.as_str(), family) {
+            ("inet", _) | (_, None) => true,
+            ("ip", Some(family)) => family == AddressFamily::Ipv4,
+            ("ip6", Some(family)) => family == AddressFamily::Ipv6,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Chain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.family, self.table, self.name)
+    }
+}
+
+/// A tagged rule found in the ruleset, with what's needed to delete it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InstalledRule {
+    pub chain: Chain,
+    /// `None` if the rule uses something this module doesn't generate.
+    pub rule: Option<PortRule>,
+    /// nftables rule handle, or the iptables `-S` line.
+    pub handle: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleState {
+    /// No chain filters incoming traffic, so nothing needs opening.
+    NotNeeded,
+    Missing,
+    Ok,
+    Drifted,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LinuxRuleStatus {
+    pub backend: LinuxBackend,
+    pub state: RuleState,
+    pub rules: Vec<InstalledRule>,
+    pub drift: Vec<String>,
+}
+
+/// Compares the tagged rules in each chain with `expected`.
+fn evaluate(chains: &[Chain], installed: &[InstalledRule], expected: &[PortRule]) -> (RuleState, Vec<String>) {
+    if chains.is_empty() && installed.is_empty() {
+        return (RuleState::NotNeeded, Vec::new());
+    }
+    if installed.is_empty() {
+        return (RuleState::Missing, Vec::new());
+    }
+
+    let mut drift = Vec::new();
+    for chain in chains {
+        let mut want: Vec<&PortRule> = expected.iter().filter(|r| chain.accepts(rule_family(r))).collect();
+        let mut have: Vec<Option<&PortRule>> =
+            installed.iter().filter(|r| r.chain == *chain).map(|r| r.rule.as_ref()).collect();
+        want.sort();
+        have.sort();
+        if have.iter().any(Option::is_none) {
+            drift.push(format!("{}: a tagged rule was edited by hand", chain));
+        } else if have.is_empty() && !want.is_empty() {
+            drift.push(format!("{}: rule is missing", chain));
+        } else if have.iter().flatten().copied().ne(want.iter().copied()) {
+            let found: Vec<String> = have.iter().flatten().map(|r| r.to_string()).collect();
+            drift.push(format!("{}: found '{}'", chain, found.join("', '")));
+        }
+    }
+    for rule in installed.iter().filter(|r| !chains.contains(&r.chain)) {
+        drift.push(format!("{}: tagged rule in a chain that no longer filters input", rule.chain));
+    }
+    let state = if drift.is_empty() { RuleState::Ok } else { RuleState::Drifted };
+    (state, drift)
+}
+
+fn rule_family(rule: &PortRule) -> Option<AddressFamily> {
+    rule.source.as_ref().map(|(family, _)| *family)
+}
+
+/// The parts of `nft -j list ruleset` that matter here.
+#[derive(Debug, Default)]
+pub struct NftRuleset {
+    /// Base chains of type `filter` on the `input` hook.
+    pub input_chains: Vec<Chain>,
+    /// Rules with the [`TAG`] comment.
+    pub tagged: Vec<InstalledRule>,
+}
+
+/// Parses the JSON printed by `nft -j list ruleset`.
+pub fn parse_nft_ruleset(json: &str) -> Option<NftRuleset> {
+    let root: Value = serde_json::from_str(json).ok()?;
+    let mut ruleset = NftRuleset::default();
+    for item in root.get("nftables")?.as_array()? {
+        if let Some(chain) = item.get("chain") {
+            if chain.get("hook").and_then(Value::as_str) == Some("input")
+                && chain.get("type").and_then(Value::as_str) == Some("filter")
+            {
+                ruleset.input_chains.push(nft_chain(chain)?);
+            }
+        } else if let Some(rule) = item.get("rule")
+            && rule.get("comment").and_then(Value::as_str) == Some(TAG)
+        {
+            ruleset.tagged.push(InstalledRule {
+                chain: nft_chain(rule)?,
+                rule: rule.get("expr").and_then(Value::as_array).and_then(|e| nft_port_rule(e)),
+                handle: rule.get("handle")?.to_string(),
+            });
+        }
+    }
+    Some(ruleset)
+}
+
+fn nft_chain(value: &Value) -> Option<Chain> {
+    // Chains name themselves with "name", rules name their chain with "chain".
+    let name = value.get("chain").or_else(|| value.get("name"))?;
+    Some(Chain {
+        family: value.get("family")?.as_str()?.to_string(),
+        table: value.get("table")?.as_str()?.to_string(),
+        name: name.as_str()?.to_string(),
+    })
+}
+
+/// Reads back a rule shaped like the ones [`PortRule`] renders.
+fn nft_port_rule(exprs: &[Value]) -> Option<PortRule> {
+    let mut port = None;
+    let mut source = None;
+    let mut accept = false;
+    for expr in exprs {
+        if expr.get("accept").is_some() {
+            accept = true;
+        } else if let Some(m) = expr.get("match") {
+            if m.get("op").and_then(Value::as_str) != Some("==") {
+                return None;
+            }
+            let payload = m.get("left")?.get("payload")?;
+            let protocol = payload.get("protocol")?.as_str()?;
+            let field = payload.get("field")?.as_str()?;
+            let right = m.get("right")?;
+            match (protocol, field) {
+                ("tcp", "dport") => port = Some(u16::try_from(right.as_u64()?).ok()?),
+                ("ip" | "ip6", "saddr") => {
+                    let family = if protocol == "ip" { AddressFamily::Ipv4 } else { AddressFamily::Ipv6 };
+                    let items = match right.get("set") {
+                        Some(set) => set.as_array()?.clone(),
+                        None => vec![right.clone()],
+                    };
+                    let mut addresses = items.iter().map(nft_address).collect::<Option<Vec<_>>>()?;
+                    addresses.sort();
+                    source = Some((family, addresses));
+                }
+                _ => return None,
+            }
+        } else {
+            // Counters and the like are harmless; anything else isn't ours.
+            expr.get("counter")?;
+        }
+    }
+    if !accept {
+        return None;
+    }
+    Some(PortRule { port: port?, source })
+}
+
+fn nft_address(value: &Value) -> Option<String> {
+    match value {
+        Value::String(address) => normalize_cidr(address).map(|(_, cidr)| cidr),
+        _ => {
+            let prefix = value.get("prefix")?;
+            Some(format!("{}/{}", prefix.get("addr")?.as_str()?, prefix.get("len")?.as_u64()?))
+        }
+    }
+}
+
+/// Tables iptables-nft creates. They belong to iptables and are left to it.
+fn is_xtables_chain(chain: &Chain) -> bool {
+    matches!(chain.family.as_str(), "ip" | "ip6") && chain.table == "filter" && chain.name == "INPUT"
+}
+
+/// Parses the tagged rules from `iptables -S INPUT`.
+pub fn parse_iptables_rules(output: &str, family: &str) -> Vec<InstalledRule> {
+    let chain = Chain { family: family.to_string(), table: String::from("filter"), name: String::from("INPUT") };
+    output
+        .lines()
+        .filter(|line| line.starts_with("-A INPUT "))
+        .filter_map(|line| {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            let value_of = |flag: &str| tokens.iter().position(|t| *t == flag).and_then(|i| tokens.get(i + 1)).copied();
+            if value_of("--comment").map(|c| c.trim_matches('"')) != Some(TAG) {
+                return None;
+            }
+            Some(InstalledRule { chain: chain.clone(), rule: iptables_port_rule(&tokens, family), handle: line.to_string() })
+        })
+        .collect()
+}
+
+fn iptables_port_rule(tokens: &[&str], family: &str) -> Option<PortRule> {
+    let value_of = |flag: &str| tokens.iter().position(|t| *t == flag).and_then(|i| tokens.get(i + 1)).copied();
+    if value_of("-j") != Some("ACCEPT") || value_of("-p") != Some("tcp") {
+        return None;
+    }
+    let port = value_of("--dport")?.parse().ok()?;
+    let source = match value_of("-s") {
+        Some(cidr) => {
+            let family = if family == "ip6" { AddressFamily::Ipv6 } else { AddressFamily::Ipv4 };
+            Some((family, vec![normalize_cidr(cidr)?.1]))
+        }
+        None => None,
+    };
+    Some(PortRule { port, source })
+}
+
+/// iptables rules take one source each, so a multi-address [`PortRule`]
+/// becomes several.
+fn split_per_source(rules: &[PortRule]) -> Vec<PortRule> {
+    let mut split = Vec::new();
+    for rule in rules {
+        match &rule.source {
+            Some((family, addresses)) => {
+                for address in addresses {
+                    split.push(PortRule { port: rule.port, source: Some((*family, vec![address.clone()])) });
+                }
+            }
+            None => split.push(rule.clone()),
+        }
+    }
+    split
+}
+
+pub struct LinuxFirewall<'a> {
+    runner: &'a dyn CommandRunner,
+    choice: BackendChoice,
+    port: u16,
+    sources: Vec<String>,
+}
+
+impl<'a> LinuxFirewall<'a> {
+    pub fn new(runner: &'a dyn CommandRunner, choice: BackendChoice, port: u16, sources: Vec<String>) -> Self {
+        LinuxFirewall { runner, choice, port, sources }
+    }
+
+    /// The backend, port and sources from the config file.
+    pub fn from_config(runner: &'a dyn CommandRunner) -> Self {
+        let settings = config::current();
+        Self::new(runner, settings.firewall.linux_backend, settings.server.port, settings.firewall.remote_addresses)
+    }
+
+    /// Picks nftables unless the only input chains are iptables' own.
+    pub fn detect(&self) -> Result<LinuxBackend, CommandError> {
+        match self.choice {
+            BackendChoice::Nftables => return Ok(LinuxBackend::Nftables),
+            BackendChoice::Iptables => return Ok(LinuxBackend::Iptables),
+            BackendChoice::Auto => {}
+        }
+        let nft = self.nft_ruleset();
+        if let Ok(ruleset) = &nft
+            && ruleset.input_chains.iter().any(|c| !is_xtables_chain(c))
+        {
+            return Ok(LinuxBackend::Nftables);
+        }
+        match self.runner.run("iptables", &["-S", "INPUT"]) {
+            Ok(output) if output.success() => Ok(LinuxBackend::Iptables),
+            _ if nft.is_ok() => Ok(LinuxBackend::Nftables),
+            _ => Err(CommandError::NotFound(String::from("nft or iptables"))),
+        }
+    }
+
+    pub fn status(&self) -> Result<LinuxRuleStatus, CommandError> {
+        let backend = self.detect()?;
+        let expected = expected_rules(self.port, &self.sources)?;
+        let (chains, rules, expected) = match backend {
+            LinuxBackend::Nftables => {
+                let ruleset = self.nft_ruleset()?;
+                let chains = ruleset.input_chains.into_iter().filter(|c| !is_xtables_chain(c)).collect();
+                (chains, ruleset.tagged, expected)
+            }
+            LinuxBackend::Iptables => {
+                let (chains, rules) = self.iptables_rules()?;
+                (chains, rules, split_per_source(&expected))
+            }
+        };
+        let (state, drift) = evaluate(&chains, &rules, &expected);
+        metrics::set_firewall_rule_present(!rules.is_empty());
+        Ok(LinuxRuleStatus { backend, state, rules, drift })
+    }
+
+    /// Inserts the rules, replacing tagged rules that drifted.
+    pub fn ensure(&self) -> Result<EnsureOutcome, CommandError> {
+        let status = self.status()?;
+        let outcome = match status.state {
+            RuleState::Ok | RuleState::NotNeeded => return Ok(EnsureOutcome::Unchanged),
+            RuleState::Missing => EnsureOutcome::Added,
+            RuleState::Drifted => {
+                self.delete(status.backend, &status.rules)?;
+                EnsureOutcome::Repaired
+            }
+        };
+        let expected = expected_rules(self.port, &self.sources)?;
+        match status.backend {
+            LinuxBackend::Nftables => {
+                let chains = self.nft_ruleset()?.input_chains;
+                for chain in chains.iter().filter(|c| !is_xtables_chain(c)) {
+                    for rule in expected.iter().filter(|r| chain.accepts(rule_family(r))) {
+                        self.nft_insert(chain, rule)?;
+                    }
+                }
+            }
+            LinuxBackend::Iptables => {
+                let (chains, _) = self.iptables_rules()?;
+                for rule in split_per_source(&expected) {
+                    for chain in chains.iter().filter(|c| c.accepts(rule_family(&rule))) {
+                        self.iptables_insert(chain, &rule)?;
+                    }
+                }
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Deletes every tagged rule. Returns whether there were any.
+    pub fn remove(&self) -> Result<bool, CommandError> {
+        let status = self.status()?;
+        if status.rules.is_empty() {
+            return Ok(false);
+        }
+        self.delete(status.backend, &status.rules)?;
+        Ok(true)
+    }
+
+    fn nft_ruleset(&self) -> Result<NftRuleset, CommandError> {
+        let output = runner::run_checked(self.runner, "nft", &["-j", "list", "ruleset"])?;
+        parse_nft_ruleset(&output.stdout).ok_or_else(|| CommandError::Failed(String::from("nft printed unexpected JSON")))
+    }
+
+    /// INPUT chains of iptables and, if installed, ip6tables, with their tagged rules.
+    fn iptables_rules(&self) -> Result<(Vec<Chain>, Vec<InstalledRule>), CommandError> {
+        let mut chains = Vec::new();
+        let mut rules = Vec::new();
+        for (program, family) in [("iptables", "ip"), ("ip6tables", "ip6")] {
+            let output = match runner::run_checked(self.runner, program, &["-S", "INPUT"]) {
+                Ok(output) => output,
+                Err(CommandError::NotFound(_)) if family == "ip6" => continue,
+                Err(e) => return Err(e),
+            };
+            chains.push(Chain { family: family.to_string(), table: String::from("filter"), name: String::from("INPUT") });
+            rules.extend(parse_iptables_rules(&output.stdout, family));
+        }
+        Ok((chains, rules))
+    }
+
+    fn nft_insert(&self, chain: &Chain, rule: &PortRule) -> Result<(), CommandError> {
+        let text = format!("{} comment \"{}\"", rule, TAG);
+        let mut args = vec!["insert", "rule", &chain.family, &chain.table, &chain.name];
+        args.extend(text.split(' '));
+        runner::run_checked(self.runner, "nft", &args)?;
+        Ok(())
+    }
+
+    fn iptables_insert(&self, chain: &Chain, rule: &PortRule) -> Result<(), CommandError> {
+        let program = if chain.family == "ip6" { "ip6tables" } else { "iptables" };
+        let port = rule.port.to_string();
+        let mut args = vec!["-I", "INPUT", "-p", "tcp", "--dport", &port];
+        if let Some((_, addresses)) = &rule.source {
+            args.extend(["-s", addresses[0].as_str()]);
+        }
+        args.extend(["-m", "comment", "--comment", TAG, "-j", "ACCEPT"]);
+        runner::run_checked(self.runner, program, &args)?;
+        Ok(())
+    }
+
+    fn delete(&self, backend: LinuxBackend, rules: &[InstalledRule]) -> Result<(), CommandError> {
+        for rule in rules {
+            let chain = &rule.chain;
+            match backend {
+                LinuxBackend::Nftables => {
+                    let args = ["delete", "rule", &chain.family, &chain.table, &chain.name, "handle", &rule.handle];
+                    runner::run_checked(self.runner, "nft", &args)?;
+                }
+                LinuxBackend::Iptables => {
+                    let program = if chain.family == "ip6" { "ip6tables" } else { "iptables" };
+                    // `-S` prints the rule as it was appended; replay it as a delete.
+                    let args: Vec<&str> = rule.handle.split_whitespace().map(|t| if t == "-A" { "-D" } else { t }).collect();
+                    runner::run_checked(self.runner, program, &args)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakeRunner;
+
+    /// `nft -j list ruleset` with an inet input chain, iptables-nft's own
+    /// INPUT chain, a tagged rule as generated and one edited by hand.
+    const NFT_RULESET: &str = r#"{"nftables": [
+        {"metainfo": {"version": "1.0.9", "release_name": "Old Doc Yak #3", "json_schema_version": 1}},
+        {"table": {"family": "inet", "name": "filter", "handle": 1}},
+        {"chain": {"family": "inet", "table": "filter", "name": "input", "handle": 1, "type": "filter", "hook": "input", "prio": 0, "policy": "drop"}},
+        {"chain": {"family": "inet", "table": "filter", "name": "forward", "handle": 2, "type": "filter", "hook": "forward", "prio": 0, "policy": "drop"}},
+        {"chain": {"family": "ip", "table": "filter", "name": "INPUT", "handle": 1, "type": "filter", "hook": "input", "prio": 0, "policy": "accept"}},
+        {"rule": {"family": "inet", "table": "filter", "chain": "input", "handle": 7, "comment": "usbip_host", "expr": [
+            {"match": {"op": "==", "left": {"payload": {"protocol": "ip", "field": "saddr"}}, "right": {"set": [{"prefix": {"addr": "10.0.0.0", "len": 8}}, "192.168.1.5"]}}},
+            {"match": {"op": "==", "left": {"payload": {"protocol": "tcp", "field": "dport"}}, "right": 3240}},
+            {"counter": {"packets": 0, "bytes": 0}},
+            {"accept": null}
+        ]}},
+        {"rule": {"family": "inet", "table": "filter", "chain": "input", "handle": 8, "comment": "usbip_host", "expr": [
+            {"match": {"op": "==", "left": {"payload": {"protocol": "tcp", "field": "dport"}}, "right": 3240}},
+            {"jump": {"target": "lab"}}
+        ]}},
+        {"rule": {"family": "inet", "table": "filter", "chain": "input", "handle": 9, "expr": [
+            {"match": {"op": "==", "left": {"payload": {"protocol": "tcp", "field": "dport"}}, "right": 22}},
+            {"accept": null}
+        ]}}
+    ]}"#;
+
+    const IPTABLES: &str = "-P INPUT DROP
+-A INPUT -i lo -j ACCEPT
+-A INPUT -s 10.0.0.0/8 -p tcp -m tcp --dport 3240 -m comment --comment usbip_host -j ACCEPT
+-A INPUT -p tcp -m tcp --dport 3240 -m comment --comment \"usbip_host\" -j DROP
+";
+
+    fn chain(family: &str, table: &str, name: &str) -> Chain {
+        Chain { family: family.to_string(), table: table.to_string(), name: name.to_string() }
+    }
+
+    fn from(family: AddressFamily, addresses: &[&str]) -> PortRule {
+        PortRule { port: 3240, source: Some((family, addresses.iter().map(|a| a.to_string()).collect())) }
+    }
+
+    fn installed(chain: Chain, rule: Option<PortRule>) -> InstalledRule {
+        InstalledRule { chain, rule, handle: String::from("1") }
+    }
+
+    #[test]
+    fn parses_input_chains_and_tagged_nft_rules() {
+        let ruleset = parse_nft_ruleset(NFT_RULESET).unwrap();
+        assert_eq!(ruleset.input_chains, [chain("inet", "filter", "input"), chain("ip", "filter", "INPUT")]);
+        assert!(is_xtables_chain(&ruleset.input_chains[1]));
+
+        assert_eq!(ruleset.tagged.len(), 2);
+        assert_eq!(ruleset.tagged[0].chain, chain("inet", "filter", "input"));
+        assert_eq!(ruleset.tagged[0].handle, "7");
+        assert_eq!(ruleset.tagged[0].rule, Some(from(AddressFamily::Ipv4, &["10.0.0.0/8", "192.168.1.5/32"])));
+        assert_eq!(ruleset.tagged[1].rule, None);
+
+        assert!(parse_nft_ruleset("{}").is_none());
+        assert!(parse_nft_ruleset("not json").is_none());
+    }
+
+    #[test]
+    fn parses_tagged_iptables_rules() {
+        let rules = parse_iptables_rules(IPTABLES, "ip");
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].chain, chain("ip", "filter", "INPUT"));
+        assert_eq!(rules[0].rule, Some(from(AddressFamily::Ipv4, &["10.0.0.0/8"])));
+        assert_eq!(rules[0].handle, "-A INPUT -s 10.0.0.0/8 -p tcp -m tcp --dport 3240 -m comment --comment usbip_host -j ACCEPT");
+        // Dropping instead of accepting isn't a rule this module writes.
+        assert_eq!(rules[1].rule, None);
+
+        let v6 = parse_iptables_rules("-A INPUT -s fd00::/8 -p tcp -m tcp --dport 3240 -m comment --comment usbip_host -j ACCEPT", "ip6");
+        assert_eq!(v6[0].rule, Some(from(AddressFamily::Ipv6, &["fd00::/8"])));
+    }
+
+    #[test]
+    fn expected_rules_split_by_family() {
+        assert_eq!(expected_rules(3240, &[]).unwrap(), [PortRule { port: 3240, source: None }]);
+        assert_eq!(expected_rules(3240, &[String::from("10.0.0.1"), String::from("Any")]).unwrap(), [PortRule { port: 3240, source: None }]);
+        let sources = [String::from("192.168.1.5"), String::from("fd00::/8"), String::from("10.0.0.0/8")];
+        assert_eq!(
+            expected_rules(3240, &sources).unwrap(),
+            [from(AddressFamily::Ipv4, &["10.0.0.0/8", "192.168.1.5/32"]), from(AddressFamily::Ipv6, &["fd00::/8"])]
+        );
+        assert!(expected_rules(3240, &[String::from("localsubnet")]).is_err());
+        assert!(expected_rules(3240, &[String::from("10.0.0.0/33")]).is_err());
+    }
+
+    #[test]
+    fn evaluate_states() {
+        let input = chain("inet", "filter", "input");
+        let any = [PortRule { port: 3240, source: None }];
+
+        assert_eq!(evaluate(&[], &[], &any), (RuleState::NotNeeded, Vec::new()));
+        assert_eq!(evaluate(std::slice::from_ref(&input), &[], &any), (RuleState::Missing, Vec::new()));
+        let ok = [installed(input.clone(), Some(any[0].clone()))];
+        assert_eq!(evaluate(std::slice::from_ref(&input), &ok, &any), (RuleState::Ok, Vec::new()));
+    }
+
+    #[test]
+    fn evaluate_reports_drift_per_chain() {
+        let (input, v4) = (chain("inet", "filter", "input"), chain("ip", "lab", "in4"));
+        let expected = [from(AddressFamily::Ipv4, &["10.0.0.0/8"]), from(AddressFamily::Ipv6, &["fd00::/8"])];
+
+        // An ip chain only needs the IPv4 rule.
+        let rules = [
+            installed(input.clone(), Some(expected[0].clone())),
+            installed(input.clone(), Some(expected[1].clone())),
+            installed(v4.clone(), Some(expected[0].clone())),
+        ];
+        assert_eq!(evaluate(&[input.clone(), v4.clone()], &rules, &expected).0, RuleState::Ok);
+
+        let rules = [
+            installed(input.clone(), None),
+            installed(v4.clone(), Some(from(AddressFamily::Ipv4, &["0.0.0.0/0"]))),
+            installed(chain("inet", "old", "input"), Some(expected[0].clone())),
+        ];
+        let (state, drift) = evaluate(&[input, v4, chain("ip6", "lab", "in6")], &rules, &expected);
+        assert_eq!(state, RuleState::Drifted);
+        assert_eq!(
+            drift,
+            [
+                "inet filter input: a tagged rule was edited by hand",
+                "ip lab in4: found 'ip saddr { 0.0.0.0/0 } tcp dport 3240 accept'",
+                "ip6 lab in6: rule is missing",
+                "inet old input: tagged rule in a chain that no longer filters input",
+            ]
+        );
+    }
+
+    #[test]
+    fn ensure_replaces_drifted_nft_rules_in_non_iptables_chains() {
+        let runner = FakeRunner::empty().ok("nft -j list ruleset", NFT_RULESET).ok("nft delete", "").ok("nft insert", "");
+        let firewall = LinuxFirewall::new(&runner, BackendChoice::Auto, 3240, vec![String::from("10.0.0.0/8")]);
+        assert_eq!(firewall.ensure().unwrap(), EnsureOutcome::Repaired);
+        assert_eq!(
+            runner.calls_to("nft delete"),
+            ["nft delete rule inet filter input handle 7", "nft delete rule inet filter input handle 8"]
+        );
+        assert_eq!(
+            runner.calls_to("nft insert"),
+            ["nft insert rule inet filter input ip saddr { 10.0.0.0/8 } tcp dport 3240 accept comment \"usbip_host\""]
+        );
+    }
+}
@@ -1,22 +1,52 @@
-#![windows_subsystem = "windows"]
+#![cfg_attr(windows, windows_subsystem = "windows")]
 /*!
     A very simple application that shows your name in a message box.
     Unlike `basic_d`, this example uses layout to position the controls in the window
 */
+#[cfg(windows)]
 mod menu_handlers;
-mod windows;
-mod device_list;
 
+#[cfg(windows)]
 extern crate native_windows_derive as nwd;
+#[cfg(windows)]
 extern crate native_windows_gui as nwg;
 
+#[cfg(windows)]
 use nwd::NwgUi;
+#[cfg(windows)]
 use nwg::NativeUi;
-use nwg::InsertListViewItem;
+#[cfg(windows)]
+use std::cell::RefCell;
+#[cfg(windows)]
+use std::collections::HashMap;
+#[cfg(windows)]
+use std::sync::atomic::AtomicBool;
+#[cfg(windows)]
+use std::sync::{Arc, Mutex};
 
-use crate::device_list::UsbipDevice;
-use crate::device_list::list_devices;
+#[cfg(windows)]
+use usb_ip_host::device_list::{UsbipDevice, list_devices};
+#[cfg(windows)]
+use usb_ip_host::runner::SystemRunner;
+#[cfg(windows)]
+use usb_ip_host::{agent, aliases, api, config, fleet, leases, mdns, metrics, policy, reservations, rules, stats, version, watcher, windows, wsl};
+#[cfg(windows)]
+use std::time::{Duration, Instant};
 
+#[cfg(windows)]
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+// First of the URBs, IN, OUT, ERR and RTT columns
+#[cfg(windows)]
+const TRAFFIC_COLUMN: i32 = 5;
+#[cfg(windows)]
+const NOTES_COLUMN: i32 = TRAFFIC_COLUMN + 5;
+#[cfg(windows)]
+const HOST_COLUMN: i32 = NOTES_COLUMN + 1;
+#[cfg(windows)]
+const FLEET_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+#[cfg(windows)]
 #[derive(Default, NwgUi)]
 pub struct BasicApp {
     
@@ -61,14 +91,102 @@ pub struct BasicApp {
     view_menu: nwg::Menu,
 
     #[nwg_control(parent: view_menu, text: "Refresh")]
-    #[nwg_events()]
+    #[nwg_events( OnMenuItemSelected: [BasicApp::show_devices] )]
     refresh_menu: nwg::MenuItem,
 
+    // Policy Menu
+    #[nwg_control(text: "Policy")]
+    #[nwg_events()]
+    policy_menu: nwg::Menu,
+
+    #[nwg_control(parent: policy_menu, text: "Allow Auto-Bind on This Port")]
+    #[nwg_events( OnMenuItemSelected: [BasicApp::allow_auto_bind_busid] )]
+    allow_busid_menu: nwg::MenuItem,
+
+    #[nwg_control(parent: policy_menu, text: "Allow Auto-Bind for This Hardware ID")]
+    #[nwg_events( OnMenuItemSelected: [BasicApp::allow_auto_bind_hardware_id] )]
+    allow_hardware_id_menu: nwg::MenuItem,
+
+    #[nwg_control(parent: policy_menu, text: "Remove Selected Policy")]
+    #[nwg_events( OnMenuItemSelected: [BasicApp::remove_selected_policy] )]
+    remove_policy_menu: nwg::MenuItem,
+
+    // WSL Menu
+    #[nwg_control(text: "WSL")]
+    #[nwg_events()]
+    wsl_menu: nwg::Menu,
+
+    #[nwg_control(parent: wsl_menu, text: "Attach to Default Distribution")]
+    #[nwg_events( OnMenuItemSelected: [BasicApp::attach_selected_to_wsl] )]
+    wsl_attach_menu: nwg::MenuItem,
+
+    #[nwg_control(parent: wsl_menu, text: "Detach from WSL")]
+    #[nwg_events( OnMenuItemSelected: [BasicApp::detach_selected_from_wsl] )]
+    wsl_detach_menu: nwg::MenuItem,
+
+    // Client Menu
+    #[nwg_control(text: "Client")]
+    #[nwg_events()]
+    client_menu: nwg::Menu,
+
+    #[nwg_control(parent: client_menu, text: "Copy Linux Command")]
+    #[nwg_events( OnMenuItemSelected: [BasicApp::copy_shell_command] )]
+    copy_shell_menu: nwg::MenuItem,
+
+    #[nwg_control(parent: client_menu, text: "Copy systemd Unit")]
+    #[nwg_events( OnMenuItemSelected: [BasicApp::copy_systemd_unit] )]
+    copy_systemd_menu: nwg::MenuItem,
+
+    #[nwg_control(parent: client_menu, text: "Copy PowerShell Command")]
+    #[nwg_events( OnMenuItemSelected: [BasicApp::copy_powershell_command] )]
+    copy_powershell_menu: nwg::MenuItem,
+
+    #[nwg_control(parent: client_menu, text: "Export Client Scripts...")]
+    #[nwg_events( OnMenuItemSelected: [BasicApp::export_client_scripts] )]
+    export_scripts_menu: nwg::MenuItem,
+
+    #[nwg_resource(title: "Export Client Scripts", action: nwg::FileDialogAction::OpenDirectory)]
+    export_dialog: nwg::FileDialog,
+
+    // Hosts Menu, for devices of the [[hosts]] agents
+    #[nwg_control(text: "Hosts")]
+    #[nwg_events()]
+    hosts_menu: nwg::Menu,
+
+    #[nwg_control(parent: hosts_menu, text: "Bind on Host")]
+    #[nwg_events( OnMenuItemSelected: [BasicApp::bind_on_host] )]
+    bind_on_host_menu: nwg::MenuItem,
+
+    #[nwg_control(parent: hosts_menu, text: "Unbind on Host")]
+    #[nwg_events( OnMenuItemSelected: [BasicApp::unbind_on_host] )]
+    unbind_on_host_menu: nwg::MenuItem,
+
+    // Reservations Menu
+    #[nwg_control(text: "Reservations")]
+    #[nwg_events()]
+    reservations_menu: nwg::Menu,
+
+    #[nwg_control(parent: reservations_menu, text: "Reserve Selected")]
+    #[nwg_events( OnMenuItemSelected: [BasicApp::reserve_selected] )]
+    reserve_menu: nwg::MenuItem,
+
+    #[nwg_control(parent: reservations_menu, text: "Release Selected")]
+    #[nwg_events( OnMenuItemSelected: [BasicApp::release_selected] )]
+    release_menu: nwg::MenuItem,
+
+    #[nwg_control(parent: reservations_menu, text: "Force Release Selected")]
+    #[nwg_events( OnMenuItemSelected: [BasicApp::force_release_selected] )]
+    force_release_menu: nwg::MenuItem,
+
     // Help Menu
     #[nwg_control(text: "Help")]
     #[nwg_events()]
     help_menu: nwg::Menu,
 
+    #[nwg_control(parent: help_menu, text: "Check for Updates")]
+    #[nwg_events( OnMenuItemSelected: [BasicApp::check_for_updates] )]
+    check_updates_menu: nwg::MenuItem,
+
     #[nwg_control(parent: help_menu, text: "About")]
     #[nwg_events( OnMenuItemSelected: [BasicApp::show_about] )]
     about_menu: nwg::MenuItem,
@@ -81,8 +199,50 @@ pub struct BasicApp {
     #[nwg_events( OnListViewDoubleClick: [] )]
     #[nwg_layout_item(layout: layout, col: 0, row: 1, col_span: 4)]
     list: nwg::ListView,
+
+    // usbipd policy rules
+    #[nwg_control(parent: window, list_style: nwg::ListViewStyle::Detailed, size: (940, 120))]
+    #[nwg_layout_item(layout: layout, col: 0, row: 2, col_span: 4)]
+    policy_list: nwg::ListView,
+
+    // Raised from the watcher thread whenever the device list changed
+    #[nwg_control(parent: window)]
+    #[nwg_events( OnNotice: [BasicApp::show_devices] )]
+    devices_changed: nwg::Notice,
+
+    // Raised from the fleet thread after each round of agent requests
+    #[nwg_control(parent: window)]
+    #[nwg_events( OnNotice: [BasicApp::show_devices] )]
+    fleet_changed: nwg::Notice,
+
+    // Raised from the lease thread when a warning was raised or an action fired
+    #[nwg_control(parent: window)]
+    #[nwg_events( OnNotice: [BasicApp::show_devices] )]
+    leases_changed: nwg::Notice,
+
+    // Raised from the config watcher thread when config.toml changed
+    #[nwg_control(parent: window)]
+    #[nwg_events( OnNotice: [BasicApp::apply_config] )]
+    config_changed: nwg::Notice,
+
+    // What the list views show, row for row. Rows of other hosts follow
+    // the local devices.
+    shown_devices: RefCell<Vec<UsbipDevice>>,
+    shown_remote: RefCell<Vec<fleet::HostDevice>>,
+    // Latest answers from the [[hosts]] agents, written by the fleet thread
+    fleet_snapshot: Arc<Mutex<fleet::Snapshot>>,
+    // Pending lease warnings by busid, shown until the action is due
+    lease_warnings: Arc<Mutex<HashMap<String, (String, Instant)>>>,
+    shown_policies: RefCell<Vec<policy::PolicyRule>>,
+
+    advertiser: RefCell<Option<mdns::Advertiser>>,
+    watcher: RefCell<Option<watcher::Watcher>>,
+    config_watcher: RefCell<Option<config::ConfigWatcher>>,
+    // Set by the config watcher thread when the edited file didn't load
+    config_error: Arc<Mutex<Option<String>>>,
 }
 
+#[cfg(windows)]
 impl BasicApp {
     fn setup_columns(&self) {
         if self.list.column_len() == 0 {
@@ -94,36 +254,120 @@ impl BasicApp {
             });
             self.list.insert_column(nwg::InsertListViewColumn {
                 index: Some(1),
+                text: Some("Alias".to_string()),
+                width: Some(150),
+                fmt: Some(nwg::ListViewColumnFlags::LEFT),
+            });
+            self.list.insert_column(nwg::InsertListViewColumn {
+                index: Some(2),
                 text: Some("VID:PID".to_string()),
                 width: Some(200),
                 fmt: Some(nwg::ListViewColumnFlags::LEFT),
             });
             self.list.insert_column(nwg::InsertListViewColumn {
-                index: Some(2),
+                index: Some(3),
                 text: Some("Device".to_string()),
                 width: Some(400),
                 fmt: Some(nwg::ListViewColumnFlags::LEFT),
             });
             self.list.insert_column(nwg::InsertListViewColumn {
-                index: Some(3),
+                index: Some(4),
                 text: Some("STATE".to_string()),
                 width: Some(200),
                 fmt: Some(nwg::ListViewColumnFlags::LEFT),
             });
+            for (i, title) in ["URBs", "IN", "OUT", "ERR", "RTT p50"].iter().enumerate() {
+                self.list.insert_column(nwg::InsertListViewColumn {
+                    index: Some(TRAFFIC_COLUMN + i as i32),
+                    text: Some(title.to_string()),
+                    width: Some(80),
+                    fmt: Some(nwg::ListViewColumnFlags::RIGHT),
+                });
+            }
+            self.list.insert_column(nwg::InsertListViewColumn {
+                index: Some(NOTES_COLUMN),
+                text: Some("Notes".to_string()),
+                width: Some(300),
+                fmt: Some(nwg::ListViewColumnFlags::LEFT),
+            });
+            self.list.insert_column(nwg::InsertListViewColumn {
+                index: Some(HOST_COLUMN),
+                text: Some("Host".to_string()),
+                width: Some(150),
+                fmt: Some(nwg::ListViewColumnFlags::LEFT),
+            });
+        }
+    }
+
+    fn setup_policy_columns(&self) {
+        if self.policy_list.column_len() == 0 {
+            let columns = [("Policy GUID", 280), ("Effect", 80), ("Operation", 100), ("BUSID", 100), ("Hardware ID", 120), ("Matches", 200)];
+            for (i, (title, width)) in columns.iter().enumerate() {
+                self.policy_list.insert_column(nwg::InsertListViewColumn {
+                    index: Some(i as i32),
+                    text: Some(title.to_string()),
+                    width: Some(*width),
+                    fmt: Some(nwg::ListViewColumnFlags::LEFT),
+                });
+            }
+        }
+    }
+
+    fn show_policies(&self) {
+        self.setup_policy_columns();
+        self.policy_list.clear();
+        // Older usbipd-win has no policies; the list just stays empty.
+        let rules = if version::capabilities(&SystemRunner).supports(version::Capability::Policy) {
+            policy::list_policies(&SystemRunner).unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        for row in policy::policy_rows(&rules, &self.shown_devices.borrow()) {
+            let rule = &row.rule;
+            let columns = [
+                rule.guid.clone(),
+                rule.effect.to_string(),
+                rule.operation.to_string(),
+                rule.busid.clone().unwrap_or_default(),
+                rule.hardware_id.clone().unwrap_or_default(),
+                row.devices.join(", "),
+            ];
+            self.policy_list.insert_item(nwg::InsertListViewItem {
+                index: None,
+                column_index: 0,
+                text: Some(columns[0].clone()),
+                image: None,
+            });
+            let row_index = self.policy_list.len() as i32 - 1;
+            for (i, text) in columns.into_iter().enumerate().skip(1) {
+                self.policy_list.insert_item(nwg::InsertListViewItem {
+                    index: Some(row_index),
+                    column_index: i as i32,
+                    text: Some(text),
+                    image: None,
+                });
+            }
         }
+        *self.shown_policies.borrow_mut() = rules;
     }
 
     fn show_devices(&self) {
         self.setup_columns();
         self.list.clear();
-        let devices: Vec<UsbipDevice> = list_devices();
+        let mut devices: Vec<UsbipDevice> = list_devices(&SystemRunner).unwrap_or_default();
+        wsl::Attachments::open().annotate(&mut devices);
+        let traffic = stats::current();
+        let known_aliases = aliases::current();
+        let store = reservations::Reservations::open();
+        let reserved = store.list().unwrap_or_default();
+        let lease_warnings = self.lease_warnings.lock().unwrap().clone();
 
         for usb_device in devices.iter() {
             // 1. Insert the first column at the end of the list
             self.list.insert_item(nwg::InsertListViewItem {
                 index: None, // None = append as new row
                 column_index: 0,
-                text: Some(usb_device.busid.clone()),
+                text: Some(usb_device.display_id().to_string()),
                 image: None,
             });
             
@@ -131,35 +375,294 @@ impl BasicApp {
             let row_index = self.list.len() as i32 - 1;
             
             // 3. Insert the other columns for this row
+            let alias = aliases::lookup(&known_aliases, usb_device);
             self.list.insert_item(nwg::InsertListViewItem {
                 index: Some(row_index), // Specify row
                 column_index: 1,
-                text: Some(usb_device.vidpid.clone()),
+                text: Some(alias.map(|a| a.alias.clone()).unwrap_or_default()),
                 image: None,
             });
             self.list.insert_item(nwg::InsertListViewItem {
                 index: Some(row_index),
                 column_index: 2,
-                text: Some(usb_device.device.clone()),
+                text: Some(usb_device.vidpid.clone()),
                 image: None,
             });
             self.list.insert_item(nwg::InsertListViewItem {
                 index: Some(row_index),
                 column_index: 3,
-                text: Some(usb_device.state.clone()),
+                text: Some(usb_device.device.clone()),
                 image: None,
             });
+            self.list.insert_item(nwg::InsertListViewItem {
+                index: Some(row_index),
+                column_index: 4,
+                text: Some(usb_device.state_label()),
+                image: None,
+            });
+            let reservation = reserved.iter().find(|r| !usb_device.busid.is_empty() && r.busid == usb_device.busid);
+            let notes: Vec<String> = alias
+                .map(|a| a.note.clone())
+                .filter(|note| !note.is_empty())
+                .into_iter()
+                .chain(reservation.map(|r| {
+                    let remaining = reservations::format_remaining(r.holder.remaining(store.now()));
+                    format!("Reserved by {} ({} left)", r.holder.user, remaining)
+                }))
+                .chain(
+                    lease_warnings
+                        .get(&usb_device.busid)
+                        .filter(|(_, due)| Instant::now() < *due)
+                        .map(|(text, _)| text.clone()),
+                )
+                .collect();
+            if !notes.is_empty() {
+                self.list.insert_item(nwg::InsertListViewItem {
+                    index: Some(row_index),
+                    column_index: NOTES_COLUMN,
+                    text: Some(notes.join("; ")),
+                    image: None,
+                });
+            }
+            self.list.insert_item(nwg::InsertListViewItem {
+                index: Some(row_index),
+                column_index: HOST_COLUMN,
+                text: Some(fleet::LOCAL.to_string()),
+                image: None,
+            });
+
+            // 4. Traffic columns, only for devices that went through the proxy
+            if let Some(counters) = traffic.devices.get(&usb_device.busid) {
+                let latency = counters.latency.percentile_us(0.5)
+                    .map(|us| format!("{:.1} ms", us as f64 / 1000.0))
+                    .unwrap_or_default();
+                let columns = [
+                    counters.urbs.to_string(),
+                    format_bytes(counters.bytes_in),
+                    format_bytes(counters.bytes_out),
+                    counters.errors.to_string(),
+                    latency,
+                ];
+                for (i, text) in columns.into_iter().enumerate() {
+                    self.list.insert_item(nwg::InsertListViewItem {
+                        index: Some(row_index),
+                        column_index: TRAFFIC_COLUMN + i as i32,
+                        text: Some(text),
+                        image: None,
+                    });
+                }
+            }
         }
+
+        metrics::observe_devices(&devices);
+        if let Some(advertiser) = self.advertiser.borrow().as_ref() {
+            advertiser.update(&devices);
+        }
+        *self.shown_devices.borrow_mut() = devices;
+        self.show_remote_devices();
+        self.show_policies();
+    }
+
+    fn insert_row(&self, columns: &[(i32, String)]) {
+        self.list.insert_item(nwg::InsertListViewItem {
+            index: None,
+            column_index: 0,
+            text: Some(columns[0].1.clone()),
+            image: None,
+        });
+        let row_index = self.list.len() as i32 - 1;
+        for (column, text) in &columns[1..] {
+            self.list.insert_item(nwg::InsertListViewItem {
+                index: Some(row_index),
+                column_index: *column,
+                text: Some(text.clone()),
+                image: None,
+            });
+        }
+    }
+
+    /// Rows for the [[hosts]] agents, then one row per host that didn't answer.
+    fn show_remote_devices(&self) {
+        let snapshot = self.fleet_snapshot.lock().unwrap().clone();
+        for row in &snapshot.devices {
+            let d = &row.device;
+            self.insert_row(&[
+                (0, d.display_id().to_string()),
+                (2, d.vidpid.clone()),
+                (3, d.device.clone()),
+                (4, d.state_label()),
+                (HOST_COLUMN, row.host.clone()),
+            ]);
+        }
+        for health in snapshot.health.iter().filter(|h| h.health != fleet::Health::Online) {
+            self.insert_row(&[
+                (0, String::new()),
+                (3, health.error.clone().unwrap_or_default()),
+                (4, health.health.to_string()),
+                (HOST_COLUMN, health.host.clone()),
+            ]);
+        }
+        *self.shown_remote.borrow_mut() = snapshot.devices;
     }
-}
 
+    fn start_leases(&self) {
+        let warnings = self.lease_warnings.clone();
+        let notice = self.leases_changed.sender();
+        std::thread::spawn(move || {
+            let stop = AtomicBool::new(false);
+            leases::run(&SystemRunner, &wsl::Attachments::open(), &stop, false, |event, _| {
+                let busid = event.device().busid.clone();
+                match event {
+                    leases::LeaseEvent::Warning { action, reason, remaining_secs, .. } => {
+                        let remaining = Duration::from_secs(*remaining_secs);
+                        let text = format!(
+                            "{}: {} in {}",
+                            reason,
+                            action,
+                            reservations::format_remaining(remaining)
+                        );
+                        warnings.lock().unwrap().insert(busid, (text, Instant::now() + remaining));
+                    }
+                    leases::LeaseEvent::Fired { .. } => {
+                        warnings.lock().unwrap().remove(&busid);
+                    }
+                    leases::LeaseEvent::NoCounters { .. } => {
+                        // Sent once per lease, so keep the note up for the day.
+                        let text = String::from("idle timeout not enforced: no traffic counters");
+                        warnings.lock().unwrap().insert(busid, (text, Instant::now() + Duration::from_secs(24 * 60 * 60)));
+                    }
+                }
+                notice.notice();
+            });
+        });
+    }
+
+    fn start_fleet_polling(&self) {
+        let snapshot = self.fleet_snapshot.clone();
+        let notice = self.fleet_changed.sender();
+        std::thread::spawn(move || loop {
+            // Read per round so edits to [[hosts]] apply without a restart.
+            let fleet = fleet::Fleet::from_config();
+            if !fleet.hosts().is_empty() || !snapshot.lock().unwrap().devices.is_empty() {
+                *snapshot.lock().unwrap() = fleet.poll(None);
+                notice.notice();
+            }
+            std::thread::sleep(FLEET_POLL_INTERVAL);
+        });
+    }
 
+    fn start_advertising(&self) {
+        // Dropping the old advertiser sends its goodbye first.
+        *self.advertiser.borrow_mut() = None;
+        if !config::current().mdns.enabled {
+            return;
+        }
+        // Not fatal: another responder may hold the port or multicast may be blocked.
+        if let Ok(advertiser) = mdns::Advertiser::start(mdns::MdnsConfig::default()) {
+            *self.advertiser.borrow_mut() = Some(advertiser);
+        }
+    }
 
+    fn start_watching(&self) {
+        *self.watcher.borrow_mut() = None;
+        let interval = config::current().watcher.poll_interval();
+        let watcher = watcher::Watcher::start(interval, || list_devices(&SystemRunner));
+        let events = watcher.subscribe();
+        let notice = self.devices_changed.sender();
+        std::thread::spawn(move || {
+            // One refresh per batch is enough; drain whatever else queued up.
+            while events.recv().is_ok() {
+                while events.try_recv().is_ok() {}
+                notice.notice();
+            }
+        });
+        self.start_auto_share(&watcher);
+        *self.watcher.borrow_mut() = Some(watcher);
+    }
 
+    fn start_auto_share(&self, watcher: &watcher::Watcher) {
+        let events = watcher.subscribe();
+        std::thread::spawn(move || {
+            let store = reservations::Reservations::open();
+            let user = reservations::current_user();
+            let attachments = wsl::Attachments::open();
+            for event in events {
+                // Loaded per event so edits to the config file apply right away.
+                // The config watcher already rejected invalid rules.
+                if let Ok(rule_set) = rules::RuleSet::load() {
+                    // The watcher picks up the new state on its next poll.
+                    let _ = rule_set.apply(&SystemRunner, &store, &user, &event);
+                }
+                let config = config::current();
+                if !config.wsl.auto_attach.is_empty() {
+                    let _ = wsl::apply_auto_attach(&SystemRunner, &store, &attachments, &user, &config.wsl.auto_attach, &config.aliases, &event);
+                }
+            }
+        });
+    }
+
+    fn apply_window_config(&self) {
+        let window = config::current().window;
+        self.window.set_size(window.width, window.height);
+        self.window.set_position(window.x, window.y);
+    }
+
+    fn watch_config(&self) {
+        let config_watcher = config::ConfigWatcher::start(config::config_path(), CONFIG_POLL_INTERVAL);
+        let events = config_watcher.subscribe();
+        let notice = self.config_changed.sender();
+        let config_error = self.config_error.clone();
+        std::thread::spawn(move || {
+            for event in events {
+                if let config::ConfigEvent::Invalid(message) = event {
+                    *config_error.lock().unwrap() = Some(message);
+                }
+                notice.notice();
+            }
+        });
+        *self.config_watcher.borrow_mut() = Some(config_watcher);
+    }
+
+    /// Restarts everything that only reads its settings on start-up.
+    fn apply_config(&self) {
+        if let Some(message) = self.config_error.lock().unwrap().take() {
+            let message = format!("The config file was not reloaded:\n{}", message);
+            nwg::modal_error_message(&self.window, "Error", &message);
+            return;
+        }
+        self.apply_window_config();
+        self.start_advertising();
+        // A new watcher would report every device as added again and re-run
+        // auto-share and auto-attach on them.
+        if let Some(watcher) = self.watcher.borrow().as_ref() {
+            watcher.set_interval(config::current().watcher.poll_interval());
+        }
+        self.show_devices();
+    }
+}
+
+#[cfg(windows)]
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{} B", bytes) } else { format!("{:.1} {}", value, UNITS[unit]) }
+}
+
+#[cfg(windows)]
 fn main() {
     nwg::init().expect("Failed to init Native Windows GUI");
     nwg::Font::set_global_family("Segoe UI").expect("Failed to set default font");
     let _app = BasicApp::build_ui(Default::default()).expect("Failed to build UI");
+    if let Err(e) = config::init() {
+        let message = format!("{}:\n{}\n\nUsing the default settings.", config::config_path().display(), e);
+        nwg::modal_error_message(&_app.window, "Error", &message);
+    }
+    _app.apply_window_config();
     match windows::is_app_elevated() {
         true => {
             ();
@@ -172,6 +675,27 @@ fn main() {
     _app.add_firewall_rule()
         .expect("Failed to add firewall rule!");
     _app.install_if_needed();
+    _app.check_usbipd_version();
+    _app.start_advertising();
+    if let Err(e) = metrics::serve_configured() {
+        nwg::modal_error_message(&_app.window, "Error", &format!("Failed to start metrics endpoint: {}", e));
+    }
+    if let Err(e) = agent::serve_configured(Arc::new(SystemRunner)) {
+        nwg::modal_error_message(&_app.window, "Error", &format!("Failed to start agent mode: {}", e));
+    }
+    if let Err(e) = api::serve_configured(Arc::new(SystemRunner)) {
+        nwg::modal_error_message(&_app.window, "Error", &format!("Failed to start the control API: {}", e));
+    }
+    _app.start_fleet_polling();
+    _app.start_leases();
     _app.show_devices();
+    _app.start_watching();
+    _app.watch_config();
     nwg::dispatch_thread_events();
 }
+
+#[cfg(not(windows))]
+fn main() {
+    eprintln!("The graphical interface is only available on Windows. Use usbipctl instead.");
+    std::process::exit(1);
+}
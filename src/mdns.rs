@@ -0,0 +1,792 @@
+//! mDNS/DNS-SD advertisement of this host as `_usbip._tcp`.
+//!
+//! The TXT record lists every shared device so teammates can find "the box
+//! with the JTAG probe" without asking for its IP.
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::config;
+use crate::device_list::UsbipDevice;
+
+pub const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub const MDNS_PORT: u16 = 5353;
+pub const SERVICE_TYPE: &str = "_usbip._tcp.local";
+const SERVICES_META: &str = "_services._dns-sd._udp.local";
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CACHE_FLUSH: u16 = 0x8000;
+const UNICAST_RESPONSE: u16 = 0x8000;
+
+#[derive(Debug, Clone)]
+pub struct MdnsConfig {
+    /// Interface to join the multicast group on. `UNSPECIFIED` lets the OS pick.
+    pub interface: Ipv4Addr,
+    /// mDNS port, only changed for testing.
+    pub port: u16,
+    /// Service instance name, defaults to the computer name.
+    pub instance: String,
+    /// Port the USB/IP server listens on.
+    pub service_port: u16,
+    pub ttl: u32,
+}
+
+impl Default for MdnsConfig {
+    fn default() -> Self {
+        let settings = config::current();
+        MdnsConfig {
+            interface: Ipv4Addr::UNSPECIFIED,
+            port: MDNS_PORT,
+            instance: if settings.mdns.instance.is_empty() { host_name() } else { settings.mdns.instance },
+            service_port: settings.server.port,
+            ttl: 120,
+        }
+    }
+}
+
+/// A shared device as listed in the TXT record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdvertisedDevice {
+    pub busid: String,
+    pub vidpid: String,
+    pub description: String,
+}
+
+impl From<&UsbipDevice> for AdvertisedDevice {
+    fn from(device: &UsbipDevice) -> Self {
+        AdvertisedDevice {
+            busid: device.busid.clone(),
+            vidpid: device.vidpid.clone(),
+            description: device.device.clone(),
+        }
+    }
+}
+
+/// A host found by [`browse`].
+#[derive(Debug, Clone, Default)]
+pub struct DiscoveredHost {
+    pub instance: String,
+    pub hostname: String,
+    pub addresses: Vec<Ipv4Addr>,
+    pub port: u16,
+    pub devices: Vec<AdvertisedDevice>,
+}
+
+struct Advert {
+    config: MdnsConfig,
+    address: Ipv4Addr,
+    devices: Vec<AdvertisedDevice>,
+}
+
+/// Answers mDNS queries for this host until dropped.
+pub struct Advertiser {
+    advert: Arc<Mutex<Advert>>,
+    socket: UdpSocket,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Advertiser {
+    pub fn start(config: MdnsConfig) -> io::Result<Self> {
+        let socket = multicast_socket(config.interface, config.port)?;
+        socket.set_read_timeout(Some(Duration::from_millis(250)))?;
+        let address = match config.interface {
+            Ipv4Addr::UNSPECIFIED => outbound_address().unwrap_or(Ipv4Addr::LOCALHOST),
+            ip => ip,
+        };
+
+        let advert = Arc::new(Mutex::new(Advert { config, address, devices: Vec::new() }));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let responder = Responder {
+            advert: advert.clone(),
+            socket: socket.try_clone()?,
+            stop: stop.clone(),
+        };
+        let handle = thread::spawn(move || responder.run());
+
+        let advertiser = Advertiser { advert, socket, stop, handle: Some(handle) };
+        advertiser.announce(false);
+        Ok(advertiser)
+    }
+
+    /// Replaces the advertised device list with the shared devices in
+    /// `devices` and re-announces if anything changed.
+    pub fn update(&self, devices: &[UsbipDevice]) {
+        let shared: Vec<AdvertisedDevice> =
+            devices.iter().filter(|d| d.is_shared()).map(AdvertisedDevice::from).collect();
+        {
+            let mut advert = self.advert.lock().unwrap();
+            if advert.devices == shared {
+                return;
+            }
+            advert.devices = shared;
+        }
+        self.announce(false);
+    }
+
+    fn announce(&self, goodbye: bool) {
+        let advert = self.advert.lock().unwrap();
+        let packet = advert.response(0, &[], goodbye);
+        let group = SocketAddrV4::new(MDNS_GROUP, advert.config.port);
+        let _ = self.socket.send_to(&packet, group);
+    }
+}
+
+impl Drop for Advertiser {
+    fn drop(&mut self) {
+        self.announce(true);
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+struct Responder {
+    advert: Arc<Mutex<Advert>>,
+    socket: UdpSocket,
+    stop: Arc<AtomicBool>,
+}
+
+impl Responder {
+    fn run(self) {
+        let mut buf = [0u8; 9000];
+        while !self.stop.load(Ordering::Relaxed) {
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(_) => continue,
+            };
+            let message = match Message::parse(&buf[..len]) {
+                Some(m) if !m.is_response() => m,
+                _ => continue,
+            };
+
+            let advert = self.advert.lock().unwrap();
+            if !message.questions.iter().any(|q| advert.answers(q)) {
+                continue;
+            }
+
+            // Legacy one-shot resolvers query from an ephemeral port and
+            // expect a unicast reply that echoes their id and question.
+            let legacy = from.port() != advert.config.port;
+            let unicast = legacy || message.questions.iter().any(|q| q.class & UNICAST_RESPONSE != 0);
+            let (id, questions) = if legacy { (message.id, &message.questions[..]) } else { (0, &[][..]) };
+            let packet = advert.response(id, questions, false);
+            let target = if unicast {
+                from
+            } else {
+                SocketAddr::V4(SocketAddrV4::new(MDNS_GROUP, advert.config.port))
+            };
+            let _ = self.socket.send_to(&packet, target);
+        }
+    }
+}
+
+impl Advert {
+    fn instance_name(&self) -> String {
+        format!("{}.{}", self.config.instance, SERVICE_TYPE)
+    }
+
+    fn target_name(&self) -> String {
+        format!("{}.local", self.config.instance.replace([' ', '.'], "-"))
+    }
+
+    fn answers(&self, question: &Question) -> bool {
+        let name = question.name.as_str();
+        let wants = |t: u16| question.qtype == t || question.qtype == TYPE_ANY;
+        (eq_name(name, SERVICE_TYPE) && wants(TYPE_PTR))
+            || (eq_name(name, SERVICES_META) && wants(TYPE_PTR))
+            || (eq_name(name, &self.instance_name()) && (wants(TYPE_SRV) || wants(TYPE_TXT)))
+            || (eq_name(name, &self.target_name()) && wants(TYPE_A))
+    }
+
+    fn txt(&self) -> Vec<String> {
+        let mut entries = vec![
+            String::from("txtvers=1"),
+            format!("devices={}", self.devices.len()),
+        ];
+        for (i, dev) in self.devices.iter().enumerate() {
+            let entry = format!("dev{}={}|{}|{}", i, dev.busid, dev.vidpid, dev.description);
+            entries.push(truncate(entry, 255));
+        }
+        entries
+    }
+
+    /// Builds the full answer set. A goodbye carries a TTL of zero.
+    fn response(&self, id: u16, questions: &[Question], goodbye: bool) -> Vec<u8> {
+        let ttl = if goodbye { 0 } else { self.config.ttl };
+        let instance = self.instance_name();
+        let target = self.target_name();
+
+        let mut w = Writer::new(id, 0x8400);
+        for q in questions {
+            w.question(&q.name, q.qtype, q.class & !UNICAST_RESPONSE);
+        }
+        w.record(SERVICE_TYPE, TYPE_PTR, CLASS_IN, ttl, |w| w.name(&instance));
+        w.record(SERVICES_META, TYPE_PTR, CLASS_IN, ttl, |w| w.name(SERVICE_TYPE));
+        w.record(&instance, TYPE_SRV, CLASS_IN | CACHE_FLUSH, ttl, |w| {
+            w.u16(0);
+            w.u16(0);
+            w.u16(self.config.service_port);
+            w.name(&target);
+        });
+        let txt = self.txt();
+        w.record(&instance, TYPE_TXT, CLASS_IN | CACHE_FLUSH, ttl, |w| {
+            for entry in &txt {
+                w.buf.push(entry.len() as u8);
+                w.buf.extend_from_slice(entry.as_bytes());
+            }
+        });
+        w.record(&target, TYPE_A, CLASS_IN | CACHE_FLUSH, ttl, |w| {
+            w.buf.extend_from_slice(&self.address.octets())
+        });
+        w.finish()
+    }
+}
+
+/// Lists `_usbip._tcp` hosts that answer within `timeout`.
+pub fn browse(config: &MdnsConfig, timeout: Duration) -> io::Result<Vec<DiscoveredHost>> {
+    let socket = query_socket(config.interface)?;
+    let mut w = Writer::new(0x5553, 0);
+    w.question(SERVICE_TYPE, TYPE_PTR, CLASS_IN);
+    socket.send_to(&w.finish(), SocketAddrV4::new(MDNS_GROUP, config.port))?;
+
+    let mut instances: Vec<String> = Vec::new();
+    let mut srv: HashMap<String, (String, u16)> = HashMap::new();
+    let mut txt: HashMap<String, Vec<String>> = HashMap::new();
+    let mut addrs: HashMap<String, Vec<Ipv4Addr>> = HashMap::new();
+
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0u8; 9000];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        let len = match socket.recv_from(&mut buf) {
+            Ok((len, _)) => len,
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => break,
+            Err(e) => return Err(e),
+        };
+        let message = match Message::parse(&buf[..len]) {
+            Some(m) if m.is_response() => m,
+            _ => continue,
+        };
+        for record in message.records {
+            let key = record.name.to_ascii_lowercase();
+            match record.data {
+                RecordData::Ptr(instance)
+                    if eq_name(&record.name, SERVICE_TYPE)
+                        && !instances.iter().any(|i| eq_name(i, &instance)) =>
+                {
+                    instances.push(instance);
+                }
+                RecordData::Srv { port, target } => {
+                    srv.insert(key, (target, port));
+                }
+                RecordData::Txt(entries) => {
+                    txt.insert(key, entries);
+                }
+                RecordData::A(ip) => {
+                    let list = addrs.entry(key).or_default();
+                    if !list.contains(&ip) {
+                        list.push(ip);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let hosts = instances
+        .into_iter()
+        .map(|instance| {
+            let key = instance.to_ascii_lowercase();
+            let (hostname, port) = srv.remove(&key).unwrap_or_default();
+            let addresses = addrs.get(&hostname.to_ascii_lowercase()).cloned().unwrap_or_default();
+            let devices = txt.remove(&key).map(|t| parse_txt(&t)).unwrap_or_default();
+            let label = instance
+                .strip_suffix(&format!(".{}", SERVICE_TYPE))
+                .unwrap_or(&instance)
+                .to_string();
+            DiscoveredHost { instance: label, hostname, addresses, port, devices }
+        })
+        .collect();
+    Ok(hosts)
+}
+
+fn parse_txt(entries: &[String]) -> Vec<AdvertisedDevice> {
+    entries
+        .iter()
+        .filter_map(|e| {
+            let (key, value) = e.split_once('=')?;
+            if !key.starts_with("dev") || key[3..].parse::<usize>().is_err() {
+                return None;
+            }
+            let mut parts = value.splitn(3, '|');
+            Some(AdvertisedDevice {
+                busid: parts.next()?.to_string(),
+                vidpid: parts.next().unwrap_or_default().to_string(),
+                description: parts.next().unwrap_or_default().to_string(),
+            })
+        })
+        .collect()
+}
+
+fn multicast_socket(interface: Ipv4Addr, port: u16) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // Other responders (Bonjour, the OS) usually already hold 5353.
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into())?;
+    socket.set_multicast_if_v4(&interface)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.join_multicast_v4(&MDNS_GROUP, &interface)?;
+    Ok(socket.into())
+}
+
+fn query_socket(interface: Ipv4Addr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0).into())?;
+    socket.set_multicast_if_v4(&interface)?;
+    socket.set_multicast_loop_v4(true)?;
+    Ok(socket.into())
+}
+
+/// The address the OS would use to reach the multicast group.
+fn outbound_address() -> Option<Ipv4Addr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect(SocketAddrV4::new(MDNS_GROUP, MDNS_PORT)).ok()?;
+    match socket.local_addr().ok()? {
+        SocketAddr::V4(addr) if !addr.ip().is_unspecified() => Some(*addr.ip()),
+        _ => None,
+    }
+}
+
+pub(crate) fn host_name() -> String {
+    std::env::var("COMPUTERNAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_else(|_| String::from("usbip-host"))
+}
+
+fn eq_name(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.').eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+fn truncate(mut s: String, max: usize) -> String {
+    s.truncate(floor_char_boundary(&s, max));
+    s
+}
+
+/// The largest length up to `max` that doesn't split a character of `s`.
+fn floor_char_boundary(s: &str, max: usize) -> usize {
+    let mut end = max.min(s.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    end
+}
+
+struct Question {
+    name: String,
+    qtype: u16,
+    class: u16,
+}
+
+enum RecordData {
+    A(Ipv4Addr),
+    Ptr(String),
+    Srv { port: u16, target: String },
+    Txt(Vec<String>),
+    Other,
+}
+
+struct Record {
+    name: String,
+    data: RecordData,
+}
+
+struct Message {
+    id: u16,
+    flags: u16,
+    questions: Vec<Question>,
+    records: Vec<Record>,
+}
+
+impl Message {
+    fn is_response(&self) -> bool {
+        self.flags & 0x8000 != 0
+    }
+
+    fn parse(buf: &[u8]) -> Option<Message> {
+        let mut r = Reader { buf, pos: 0 };
+        let id = r.u16()?;
+        let flags = r.u16()?;
+        let qdcount = r.u16()?;
+        let rrcount = r.u16()? as usize + r.u16()? as usize + r.u16()? as usize;
+
+        let mut questions = Vec::new();
+        for _ in 0..qdcount {
+            let name = r.name()?;
+            questions.push(Question { name, qtype: r.u16()?, class: r.u16()? });
+        }
+
+        let mut records = Vec::new();
+        for _ in 0..rrcount {
+            let name = r.name()?;
+            let rtype = r.u16()?;
+            let _class = r.u16()?;
+            let _ttl = r.u32()?;
+            let len = r.u16()? as usize;
+            let end = r.pos.checked_add(len).filter(|&e| e <= buf.len())?;
+            let data = match rtype {
+                TYPE_A if len == 4 => RecordData::A(Ipv4Addr::new(buf[r.pos], buf[r.pos + 1], buf[r.pos + 2], buf[r.pos + 3])),
+                TYPE_PTR => RecordData::Ptr(r.name()?),
+                TYPE_SRV => {
+                    let _priority = r.u16()?;
+                    let _weight = r.u16()?;
+                    let port = r.u16()?;
+                    RecordData::Srv { port, target: r.name()? }
+                }
+                TYPE_TXT => {
+                    let mut entries = Vec::new();
+                    let mut pos = r.pos;
+                    while pos < end {
+                        let n = buf[pos] as usize;
+                        // A string running past its record makes the message malformed.
+                        let s = buf[..end].get(pos + 1..pos + 1 + n)?;
+                        entries.push(String::from_utf8_lossy(s).into_owned());
+                        pos += 1 + n;
+                    }
+                    RecordData::Txt(entries)
+                }
+                _ => RecordData::Other,
+            };
+            r.pos = end;
+            records.push(Record { name, data });
+        }
+        Some(Message { id, flags, questions, records })
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn u16(&mut self) -> Option<u16> {
+        let b = self.buf.get(self.pos..self.pos + 2)?;
+        self.pos += 2;
+        Some(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let b = self.buf.get(self.pos..self.pos + 4)?;
+        self.pos += 4;
+        Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// Reads a possibly compressed name and returns it dot-joined.
+    fn name(&mut self) -> Option<String> {
+        let mut labels: Vec<String> = Vec::new();
+        let mut pos = self.pos;
+        let mut jumped = false;
+        // Bound the number of pointer hops so a malicious packet can't loop us.
+        for _ in 0..128 {
+            let len = *self.buf.get(pos)? as usize;
+            if len == 0 {
+                if !jumped {
+                    self.pos = pos + 1;
+                }
+                return Some(labels.join("."));
+            }
+            if len & 0xC0 == 0xC0 {
+                let target = ((len & 0x3F) << 8) | *self.buf.get(pos + 1)? as usize;
+                if !jumped {
+                    self.pos = pos + 2;
+                }
+                jumped = true;
+                pos = target;
+                continue;
+            }
+            let label = self.buf.get(pos + 1..pos + 1 + len)?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            pos += 1 + len;
+        }
+        None
+    }
+}
+
+struct Writer {
+    buf: Vec<u8>,
+    questions: u16,
+    answers: u16,
+}
+
+impl Writer {
+    fn new(id: u16, flags: u16) -> Self {
+        let mut buf = Vec::with_capacity(512);
+        buf.extend_from_slice(&id.to_be_bytes());
+        buf.extend_from_slice(&flags.to_be_bytes());
+        buf.extend_from_slice(&[0; 8]);
+        Writer { buf, questions: 0, answers: 0 }
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    /// Writes `name` uncompressed. The first label is the instance name and
+    /// may itself contain dots, so it is split off the known suffix.
+    fn name(&mut self, name: &str) {
+        let (first, rest) = match split_instance(name) {
+            Some((instance, suffix)) => (Some(instance), suffix),
+            None => (None, name),
+        };
+        for label in first.into_iter().chain(rest.split('.').filter(|l| !l.is_empty())) {
+            let label = &label.as_bytes()[..floor_char_boundary(label, 63)];
+            self.buf.push(label.len() as u8);
+            self.buf.extend_from_slice(label);
+        }
+        self.buf.push(0);
+    }
+
+    fn question(&mut self, name: &str, qtype: u16, class: u16) {
+        self.name(name);
+        self.u16(qtype);
+        self.u16(class);
+        self.questions += 1;
+    }
+
+    fn record(&mut self, name: &str, rtype: u16, class: u16, ttl: u32, rdata: impl FnOnce(&mut Writer)) {
+        self.name(name);
+        self.u16(rtype);
+        self.u16(class);
+        self.buf.extend_from_slice(&ttl.to_be_bytes());
+        let len_pos = self.buf.len();
+        self.u16(0);
+        rdata(self);
+        let len = (self.buf.len() - len_pos - 2) as u16;
+        self.buf[len_pos..len_pos + 2].copy_from_slice(&len.to_be_bytes());
+        self.answers += 1;
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.buf[4..6].copy_from_slice(&self.questions.to_be_bytes());
+        self.buf[6..8].copy_from_slice(&self.answers.to_be_bytes());
+        self.buf
+    }
+}
+
+fn split_instance(name: &str) -> Option<(&str, &str)> {
+    let suffix = format!(".{}", SERVICE_TYPE);
+    if name.len() > suffix.len() && name.to_ascii_lowercase().ends_with(&suffix.to_ascii_lowercase()) {
+        let at = name.len() - suffix.len();
+        return Some((&name[..at], &name[at + 1..]));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_list::DeviceState;
+    use crate::testing;
+
+    fn advert(devices: Vec<AdvertisedDevice>) -> Advert {
+        let config = MdnsConfig {
+            interface: Ipv4Addr::LOCALHOST,
+            port: MDNS_PORT,
+            instance: String::from("Lab Bench 2.east"),
+            service_port: 3240,
+            ttl: 120,
+        };
+        Advert { config, address: Ipv4Addr::new(192, 168, 1, 20), devices }
+    }
+
+    fn device(busid: &str, description: &str) -> AdvertisedDevice {
+        AdvertisedDevice { busid: busid.to_string(), vidpid: String::from("0483:374b"), description: description.to_string() }
+    }
+
+    fn question(name: &str, qtype: u16) -> Question {
+        Question { name: name.to_string(), qtype, class: CLASS_IN }
+    }
+
+    #[test]
+    fn response_decodes_back_to_every_record() {
+        let advert = advert(vec![device("1-2", "ST-Link"), device("3-1", "FTDI | serial")]);
+        let message = Message::parse(&advert.response(0, &[], false)).unwrap();
+        assert!(message.is_response());
+        assert_eq!(message.records.len(), 5);
+
+        let instance = "Lab Bench 2.east._usbip._tcp.local";
+        let mut seen = 0;
+        for record in &message.records {
+            match &record.data {
+                RecordData::Ptr(target) if record.name == SERVICE_TYPE => assert_eq!(target, instance),
+                RecordData::Ptr(target) => {
+                    assert_eq!(record.name, SERVICES_META);
+                    assert_eq!(target, SERVICE_TYPE);
+                }
+                RecordData::Srv { port, target } => {
+                    assert_eq!(record.name, instance);
+                    assert_eq!(*port, 3240);
+                    assert_eq!(target, "Lab-Bench-2-east.local");
+                }
+                RecordData::Txt(entries) => {
+                    assert_eq!(entries[..2], [String::from("txtvers=1"), String::from("devices=2")]);
+                    assert_eq!(parse_txt(entries), advert.devices);
+                }
+                RecordData::A(ip) => {
+                    assert_eq!(record.name, "Lab-Bench-2-east.local");
+                    assert_eq!(*ip, Ipv4Addr::new(192, 168, 1, 20));
+                }
+                RecordData::Other => panic!("unexpected record"),
+            }
+            seen += 1;
+        }
+        assert_eq!(seen, 5);
+    }
+
+    #[test]
+    fn legacy_response_echoes_id_and_question() {
+        let advert = advert(Vec::new());
+        let packet = advert.response(0x1234, &[Question { class: CLASS_IN | UNICAST_RESPONSE, ..question(SERVICE_TYPE, TYPE_PTR) }], false);
+        let message = Message::parse(&packet).unwrap();
+        assert_eq!(message.id, 0x1234);
+        assert_eq!(message.questions.len(), 1);
+        assert_eq!(message.questions[0].class, CLASS_IN);
+    }
+
+    #[test]
+    fn goodbye_has_a_zero_ttl() {
+        let packet = advert(Vec::new()).response(0, &[], true);
+        // The first record's TTL sits after the 12-byte header, its name, type and class.
+        let name_len = SERVICE_TYPE.len() + 2;
+        let ttl = &packet[12 + name_len + 4..12 + name_len + 8];
+        assert_eq!(ttl, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn answers_only_our_names() {
+        let advert = advert(Vec::new());
+        assert!(advert.answers(&question("_usbip._tcp.local.", TYPE_PTR)));
+        assert!(advert.answers(&question(SERVICES_META, TYPE_ANY)));
+        assert!(advert.answers(&question("lab bench 2.EAST._usbip._tcp.local", TYPE_TXT)));
+        assert!(advert.answers(&question("Lab-Bench-2-east.local", TYPE_A)));
+        assert!(!advert.answers(&question("Lab-Bench-2-east.local", TYPE_SRV)));
+        assert!(!advert.answers(&question("_http._tcp.local", TYPE_PTR)));
+    }
+
+    #[test]
+    fn query_round_trips() {
+        let mut w = Writer::new(0x5553, 0);
+        w.question(SERVICE_TYPE, TYPE_PTR, CLASS_IN);
+        let message = Message::parse(&w.finish()).unwrap();
+        assert!(!message.is_response());
+        assert_eq!(message.questions.len(), 1);
+        assert_eq!(message.questions[0].name, SERVICE_TYPE);
+        assert_eq!(message.questions[0].qtype, TYPE_PTR);
+    }
+
+    #[test]
+    fn reader_follows_compression_pointers() {
+        let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 2, 0, 0, 0, 0];
+        // local (offset 12), then a PTR record named "_usbip._tcp" + pointer to it.
+        let local = packet.len();
+        packet.extend_from_slice(&[5, b'l', b'o', b'c', b'a', b'l', 0]);
+        packet.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        packet.extend_from_slice(&[6, b'_', b'u', b's', b'b', b'i', b'p', 4, b'_', b't', b'c', b'p', 0xC0, local as u8]);
+        packet.extend_from_slice(&[0, 12, 0, 1, 0, 0, 0, 120, 0, 2, 0xC0, local as u8]);
+        let message = Message::parse(&packet).unwrap();
+        assert_eq!(message.records[1].name, SERVICE_TYPE);
+        assert!(matches!(&message.records[1].data, RecordData::Ptr(target) if target == "local"));
+    }
+
+    #[test]
+    fn reader_rejects_pointer_loops_and_truncation() {
+        let mut looped = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        looped.extend_from_slice(&[0xC0, 12, 0, 12, 0, 1]);
+        assert!(Message::parse(&looped).is_none());
+
+        let packet = advert(vec![device("1-2", "ST-Link")]).response(0, &[], false);
+        assert!(Message::parse(&packet[..packet.len() - 3]).is_none());
+    }
+
+    #[test]
+    fn txt_strings_stay_inside_their_record() {
+        let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 2, 0, 0, 0, 0];
+        // A TXT record two bytes long whose string claims five, then an A record.
+        packet.extend_from_slice(&[1, b'a', 0, 0, 16, 0, 1, 0, 0, 0, 120, 0, 2, 5, b'x']);
+        packet.extend_from_slice(&[1, b'b', 0, 0, 1, 0, 1, 0, 0, 0, 120, 0, 4, 192, 168, 1, 20]);
+        assert!(Message::parse(&packet).is_none());
+
+        packet[25] = 1;
+        let message = Message::parse(&packet).unwrap();
+        assert!(matches!(&message.records[0].data, RecordData::Txt(entries) if entries == &["x"]));
+        assert!(matches!(message.records[1].data, RecordData::A(ip) if ip == Ipv4Addr::new(192, 168, 1, 20)));
+    }
+
+    #[test]
+    fn long_labels_are_cut_between_characters() {
+        let instance = "é".repeat(40);
+        let mut w = Writer::new(0, 0);
+        w.name(&format!("{}.{}", instance, SERVICE_TYPE));
+        let mut reader = Reader { buf: &w.buf, pos: 12 };
+        assert_eq!(reader.name().unwrap(), format!("{}.{}", "é".repeat(31), SERVICE_TYPE));
+    }
+
+    #[test]
+    fn browse_finds_an_advertiser_on_loopback() {
+        let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let config = MdnsConfig {
+            interface: Ipv4Addr::LOCALHOST,
+            port,
+            instance: String::from("Loopback Bench"),
+            service_port: 3241,
+            ttl: 120,
+        };
+        let advertiser = Advertiser::start(config.clone()).unwrap();
+        let mut shared = testing::device("1-2", "0483:374b", DeviceState::Shared);
+        shared.device = String::from("ST-Link");
+        advertiser.update(&[shared, testing::device("1-3", "046d:c52b", DeviceState::NotShared)]);
+
+        let hosts = browse(&config, Duration::from_secs(2)).unwrap();
+        let host = hosts.iter().find(|h| h.instance == "Loopback Bench").expect("advertiser not found");
+        assert_eq!(host.hostname, "Loopback-Bench.local");
+        assert_eq!(host.port, 3241);
+        assert_eq!(host.addresses, [Ipv4Addr::LOCALHOST]);
+        assert_eq!(
+            host.devices,
+            [AdvertisedDevice { busid: String::from("1-2"), vidpid: String::from("0483:374b"), description: String::from("ST-Link") }]
+        );
+    }
+
+    #[test]
+    fn txt_entries_are_capped_at_255_bytes() {
+        let long = "é".repeat(200);
+        let advert = advert(vec![device("1-2", &long)]);
+        let entry = &advert.txt()[2];
+        assert!(entry.len() <= 255);
+        assert!(entry.starts_with("dev0=1-2|0483:374b|é"));
+    }
+
+    #[test]
+    fn parse_txt_skips_other_keys() {
+        let entries = [String::from("txtvers=1"), String::from("devices=1"), String::from("device=x"), String::from("dev0=4-1")];
+        assert_eq!(parse_txt(&entries), [AdvertisedDevice { busid: String::from("4-1"), vidpid: String::new(), description: String::new() }]);
+    }
+}
@@ -1,11 +1,15 @@
 use crate::BasicApp;
 use native_windows_gui as nwg;
 use std::error::Error;
-use std::io::{self, Read};
-use std::os::windows::process::CommandExt;
-use std::process::{Command, Stdio};
-
-const CREATE_NO_WINDOW: u32 = 0x08000000;
+use usb_ip_host::client_scripts::{self, ClientTarget, ScriptKind};
+use usb_ip_host::installer::InstallError;
+use usb_ip_host::package_manager::Transition;
+use usb_ip_host::runner::{CommandError, SystemRunner};
+use usb_ip_host::updates::{Product, UpdateChecker, UpdateError};
+use usb_ip_host::policy::{self, MatchBy, NewRule};
+use usb_ip_host::fleet::Fleet;
+use usb_ip_host::reservations::{self, Outcome, Reservations};
+use usb_ip_host::{config, firewall, service, version, wsl};
 
 impl BasicApp {
     pub fn say_goodbye(&self) {
@@ -27,98 +31,27 @@ impl BasicApp {
     }
 
     fn usbipd_installed(&self) -> bool {
-        let status = Command::new("where.exe")
-            .arg("usbipd.exe")
-            .creation_flags(CREATE_NO_WINDOW)
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .status();
-
-        match status {
-            Ok(s) => s.success(), // returns true if exit code == 0
-            Err(_) => false,
-        }
+        service::usbipd_installed(&SystemRunner)
     }
 
-    fn install_usbipd(&self) -> Result<bool, io::Error> {
-        let hash_override = Command::new("winget")
-            .args(["settings", "--enable", "InstallerHashOverride"])
-            .creation_flags(CREATE_NO_WINDOW)
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .status()?;
-
-        if !hash_override.success() {
-            return Ok(false);
-        }
-
-        let install = Command::new("winget")
-            .args([
-                "install",
-                "--accept-source-agreements",
-                "--ignore-security-hash",
-                "--nowarn",
-                "--force",
-                "--accept-package-agreements",
-                "--silent",
-                "--disable-interactivity",
-                "--ignore-security-hash",
-                "--exact",
-                "dorssel.usbipd-win",
-            ])
-            .creation_flags(CREATE_NO_WINDOW)
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .status()?;
-
-        Ok(install.success())
+    fn install_usbipd(&self) -> Result<Transition, InstallError> {
+        service::install_usbipd(&SystemRunner)
     }
 
     pub fn add_firewall_rule(&self) -> Result<(), Box<dyn Error>> {
-        let rule_name = "_Plex (Port 3240)";
-
-        let output = Command::new("netsh")
-            .args([
-                "advfirewall",
-                "firewall",
-                "show",
-                "rule",
-                "name=_Plex (Port 3240)",
-            ])
-            .creation_flags(CREATE_NO_WINDOW)
-            .stdout(Stdio::piped())
-            .stderr(Stdio::null())
-            .output()?;
-
-        let data = String::from_utf8_lossy(&output.stdout);
-
-        if !data.contains(rule_name) {
-            let status = Command::new("netsh")
-                .args([
-                    "advfirewall",
-                    "firewall",
-                    "add",
-                    "rule",
-                    &format!("name={}", rule_name),
-                    "dir=in",
-                    "action=allow",
-                    "protocol=TCP",
-                    "localport=3240",
-                    "edge=yes",
-                ])
-                .creation_flags(CREATE_NO_WINDOW)
-                .stdout(Stdio::null())
-                .stderr(Stdio::null())
-                .status()?;
-
-            if !status.success() {
-                return Err(format!("Add rule command failed with status: {}", status).into());
-            }
-        }
-
+        firewall::FirewallManager::from_config(&SystemRunner).ensure()?;
         Ok(())
     }
 
+    /// Warns if the installed usbipd-win is older than the configured minimum.
+    pub fn check_usbipd_version(&self) {
+        if let Ok(version) = service::usbipd_version(&SystemRunner)
+            && let Some(warning) = version::minimum_warning(&version)
+        {
+            nwg::modal_info_message(&self.window, "usbipd-win is outdated", &warning);
+        }
+    }
+
     pub fn install_if_needed(&self) {
         if !self.usbipd_installed() {
             let accepted =
@@ -126,26 +59,27 @@ impl BasicApp {
 
             if accepted {
                 match self.install_usbipd() {
-                    Ok(true) => {
-                        nwg::modal_info_message(
-                            &self.window,
-                            "Successful",
-                            "Installation Successful! Close the application and start it again.",
+                    Ok(transition) => {
+                        let message = format!(
+                            "{}\nClose the application and start it again.",
+                            transition.message()
                         );
+                        nwg::modal_info_message(&self.window, "Successful", &message);
                         nwg::stop_thread_dispatch();
                     }
-                    Ok(false) => {
-                        nwg::modal_error_message(&self.window, "Error", "Error while installing.");
-                        nwg::stop_thread_dispatch();
-                    }
-                    Err(_) => {
+                    Err(InstallError::Command(CommandError::NotFound(program))) => {
                         nwg::modal_error_message(
                             &self.window,
                             "Error",
-                            "Winget can't be found. Is it installed?",
+                            &format!("{} can't be found. Is it installed?", program),
                         );
                         nwg::stop_thread_dispatch();
                     }
+                    Err(e) => {
+                        let message = format!("Error while installing:\n{}", e);
+                        nwg::modal_error_message(&self.window, "Error", &message);
+                        nwg::stop_thread_dispatch();
+                    }
                 }
             } else {
                 nwg::modal_info_message(
@@ -159,32 +93,10 @@ impl BasicApp {
     }
 
     fn get_usbipd_version(&self) -> String {
-        let mut output = String::new();
-
-        let mut child = match Command::new("cmd")
-            .args(["/C", "usbipd --version"])
-            .creation_flags(CREATE_NO_WINDOW)
-            .stdout(Stdio::piped())
-            .stderr(Stdio::null())
-            .spawn()
-        {
-            Ok(child) => child,
-            Err(_) => return String::from("Error: Could not execute usbipd"),
-        };
-
-        if let Some(mut stdout) = child.stdout.take() {
-            let _ = stdout.read_to_string(&mut output);
-        }
-
-        let _ = child.wait();
-
-        let search = "Branch.master";
-        if let Some(pos) = output.find(search) {
-            let end = pos + search.len();
-            output = output[..end].to_string();
+        match service::usbipd_version(&SystemRunner) {
+            Ok(version) => version.full(),
+            Err(e) => format!("Error: {}", e),
         }
-
-        output.trim().to_string()
     }
 
     pub fn show_about(&self) {
@@ -200,48 +112,48 @@ impl BasicApp {
         nwg::modal_info_message(&self.window, "About", &message);
     }
 
+    /// Shows what the release feeds offer for usbipd-win and this application.
+    pub fn check_for_updates(&self) {
+        let checker = UpdateChecker::from_config(&SystemRunner).refresh(true);
+        let mut sections = Vec::new();
+        for product in [Product::Usbipd, Product::App] {
+            match checker.check(product) {
+                Ok(info) => sections.push(info.summary()),
+                Err(UpdateError::NoFeed(_)) => {}
+                Err(e) => sections.push(format!("{}: {}", product, e)),
+            }
+        }
+        nwg::modal_info_message(&self.window, "Updates", &sections.join("\n\n"));
+    }
+
+    /// Asks before upgrading, showing the new version and its changelog.
+    /// Returns false if the user declined or nothing newer is available.
+    fn confirm_usbipd_upgrade(&self) -> bool {
+        match UpdateChecker::from_config(&SystemRunner).check(Product::Usbipd) {
+            Ok(info) if info.available() => self.ask_user_yes_no(&format!("{}\n\nUpgrade now?", info.summary())),
+            Ok(info) => {
+                nwg::modal_info_message(&self.window, "Upgrade", &info.summary());
+                false
+            }
+            Err(e) => self.ask_user_yes_no(&format!(
+                "Could not check for a newer usbipd-win:\n{}\n\nRun the upgrade anyway?",
+                e
+            )),
+        }
+    }
+
     pub fn upgrade_usbipd(&self) {
         match self.usbipd_installed() {
             true => {
-                let upgrade_result = Command::new("winget")
-                    .args([
-                        "upgrade",
-                        "--silent",
-                        "--disable-interactivity",
-                        "--exact",
-                        "dorssel.usbipd-win",
-                    ])
-                    .creation_flags(CREATE_NO_WINDOW)
-                    .stdout(Stdio::null())
-                    .stderr(Stdio::null())
-                    .status();
-
-                match upgrade_result {
-                    Ok(status) => {
-                        if status.success() {
-                            nwg::modal_info_message(&self.window, "Upgrade", "Upgrade successful.");
-                        } else {
-                            if self.usbipd_installed() {
-                                nwg::modal_error_message(
-                                    &self.window,
-                                    "Upgrade failed",
-                                    "Winget failed to upgrade the package. The package is probably already installed.",
-                                );
-                            } else {
-                                nwg::modal_error_message(
-                                    &self.window,
-                                    "Upgrade failed",
-                                    "Winget failed to upgrade the package.",
-                                );
-                            }
-                        }
+                if !self.confirm_usbipd_upgrade() {
+                    return;
+                }
+                match service::upgrade_usbipd(&SystemRunner) {
+                    Ok(transition) => {
+                        nwg::modal_info_message(&self.window, "Upgrade", &transition.message());
                     }
-                    Err(_) => {
-                        nwg::modal_error_message(
-                            &self.window,
-                            "Error",
-                            "Failed to run winget. Is it installed?",
-                        );
+                    Err(e) => {
+                        nwg::modal_error_message(&self.window, "Upgrade failed", &e.to_string());
                     }
                 }
             }
@@ -257,37 +169,214 @@ impl BasicApp {
         ));
 
         if accepted {
-            let uninstall_result = Command::new("winget")
-                .args(["uninstall", "-h", "dorssel.usbipd-win"])
-                .creation_flags(CREATE_NO_WINDOW)
-                .stdout(Stdio::null())
-                .stderr(Stdio::null())
-                .status();
-
-            match uninstall_result {
-                Ok(status) => {
-                    if status.success() {
-                        nwg::modal_info_message(
-                            &self.window,
-                            "Uninstall",
-                            "Uninstallation successful.",
-                        );
-                    } else {
-                        nwg::modal_error_message(
-                            &self.window,
-                            "Uninstall Failed",
-                            "Winget failed to uninstall the package.",
-                        );
-                    }
+            match service::uninstall_usbipd(&SystemRunner) {
+                Ok(transition) => {
+                    nwg::modal_info_message(&self.window, "Uninstall", &transition.message());
                 }
-                Err(_) => {
-                    nwg::modal_error_message(
-                        &self.window,
-                        "Error",
-                        "Failed to run winget. Is it installed?",
-                    );
+                Err(e) => {
+                    nwg::modal_error_message(&self.window, "Uninstall failed", &e.to_string());
                 }
             }
         }
     }
+
+    fn allow_auto_bind(&self, by: MatchBy) {
+        let device = self.list.selected_item().and_then(|i| self.shown_devices.borrow().get(i).cloned());
+        let Some(device) = device.filter(|d| !d.busid.is_empty()) else {
+            nwg::modal_info_message(&self.window, "Policy", "Select a connected device first.");
+            return;
+        };
+        let rule = NewRule::allow_auto_bind(&device, by);
+        match policy::add_policy(&SystemRunner, &rule) {
+            Ok(()) => self.show_policies(),
+            Err(e) => nwg::modal_error_message(&self.window, "Error", &e.to_string()),
+        }
+    }
+
+    pub fn allow_auto_bind_busid(&self) {
+        self.allow_auto_bind(MatchBy::Busid);
+    }
+
+    pub fn allow_auto_bind_hardware_id(&self) {
+        self.allow_auto_bind(MatchBy::HardwareId);
+    }
+
+    pub fn remove_selected_policy(&self) {
+        let rule = self.policy_list.selected_item().and_then(|i| self.shown_policies.borrow().get(i).cloned());
+        let Some(rule) = rule else {
+            nwg::modal_info_message(&self.window, "Policy", "Select a policy first.");
+            return;
+        };
+        if !self.ask_user_yes_no(&format!("Remove policy {}?", rule.guid)) {
+            return;
+        }
+        match policy::remove_policy(&SystemRunner, &rule.guid) {
+            Ok(()) => self.show_policies(),
+            Err(e) => nwg::modal_error_message(&self.window, "Error", &e.to_string()),
+        }
+    }
+
+    fn selected_connected_device(&self, title: &str) -> Option<String> {
+        let device = self.list.selected_item().and_then(|i| self.shown_devices.borrow().get(i).cloned());
+        match device.filter(|d| !d.busid.is_empty()) {
+            Some(device) => Some(device.busid),
+            None => {
+                nwg::modal_info_message(&self.window, title, "Select a connected device first.");
+                None
+            }
+        }
+    }
+
+    pub fn attach_selected_to_wsl(&self) {
+        let Some(busid) = self.selected_connected_device("WSL") else { return };
+        if let Err(e) = Reservations::open().check(&busid, &reservations::current_user()) {
+            nwg::modal_error_message(&self.window, "WSL", &e.to_string());
+            return;
+        }
+        match wsl::attach(&SystemRunner, &wsl::Attachments::open(), &busid, None) {
+            Ok(()) => self.show_devices(),
+            Err(e) => nwg::modal_error_message(&self.window, "Error", &e.to_string()),
+        }
+    }
+
+    pub fn detach_selected_from_wsl(&self) {
+        let Some(busid) = self.selected_connected_device("WSL") else { return };
+        match wsl::detach(&SystemRunner, &wsl::Attachments::open(), &busid) {
+            Ok(()) => self.show_devices(),
+            Err(e) => nwg::modal_error_message(&self.window, "Error", &e.to_string()),
+        }
+    }
+
+    fn selected_client_target(&self) -> Option<ClientTarget> {
+        let device = self.list.selected_item().and_then(|i| self.shown_devices.borrow().get(i).cloned());
+        let Some(device) = device else {
+            nwg::modal_info_message(&self.window, "Client", "Select a shared device first.");
+            return None;
+        };
+        match ClientTarget::new(&device, &client_scripts::detected_hosts(), config::current().server.port) {
+            Ok(target) => Some(target),
+            Err(e) => {
+                nwg::modal_error_message(&self.window, "Client", &e.to_string());
+                None
+            }
+        }
+    }
+
+    fn copy_client_script(&self, kind: ScriptKind) {
+        if let Some(target) = self.selected_client_target() {
+            nwg::Clipboard::set_data_text(&self.window, &target.render(kind));
+        }
+    }
+
+    pub fn copy_shell_command(&self) {
+        self.copy_client_script(ScriptKind::Shell);
+    }
+
+    pub fn copy_systemd_unit(&self) {
+        self.copy_client_script(ScriptKind::Systemd);
+    }
+
+    pub fn copy_powershell_command(&self) {
+        self.copy_client_script(ScriptKind::PowerShell);
+    }
+
+    pub fn export_client_scripts(&self) {
+        let Some(target) = self.selected_client_target() else { return };
+        if !self.export_dialog.run(Some(&self.window)) {
+            return;
+        }
+        let Ok(dir) = self.export_dialog.get_selected_item() else { return };
+        match client_scripts::export(&target, std::path::Path::new(&dir)) {
+            Ok(written) => {
+                let names: Vec<String> = written.iter().map(|p| p.display().to_string()).collect();
+                nwg::modal_info_message(&self.window, "Client", &format!("Wrote:\n{}", names.join("\n")));
+            }
+            Err(e) => nwg::modal_error_message(&self.window, "Error", &e.to_string()),
+        }
+    }
+
+    /// The selected row of another host, as (host, busid).
+    fn selected_remote_device(&self) -> Option<(String, String)> {
+        let local = self.shown_devices.borrow().len();
+        let row = self.list.selected_item()?.checked_sub(local)?;
+        let remote = self.shown_remote.borrow();
+        remote.get(row).filter(|r| !r.device.busid.is_empty()).map(|r| (r.host.clone(), r.device.busid.clone()))
+    }
+
+    fn run_on_host(&self, bind: bool) {
+        let Some((host, busid)) = self.selected_remote_device() else {
+            nwg::modal_info_message(&self.window, "Hosts", "Select a connected device of another host first.");
+            return;
+        };
+        let fleet = Fleet::from_config();
+        let result = if bind {
+            fleet.bind(&SystemRunner, &host, &busid, false)
+        } else {
+            fleet.unbind(&SystemRunner, &host, &busid)
+        };
+        match result {
+            Ok(devices) => {
+                self.fleet_snapshot.lock().unwrap().replace(&host, devices);
+                self.show_devices();
+            }
+            Err(e) => nwg::modal_error_message(&self.window, "Error", &e.to_string()),
+        }
+    }
+
+    pub fn bind_on_host(&self) {
+        self.run_on_host(true);
+    }
+
+    pub fn unbind_on_host(&self) {
+        self.run_on_host(false);
+    }
+
+    pub fn reserve_selected(&self) {
+        let Some(busid) = self.selected_connected_device("Reservations") else { return };
+        let store = Reservations::open();
+        let duration = config::current().reservations.default_duration();
+        let user = reservations::current_user();
+        match store.reserve(&busid, &user, duration) {
+            Ok(Outcome::Queued { position, holder }) => {
+                let remaining = reservations::format_remaining(holder.remaining(store.now()));
+                let message = format!(
+                    "{} is reserved by {} for another {}. You are number {} in the queue.",
+                    busid, holder.user, remaining, position
+                );
+                nwg::modal_info_message(&self.window, "Reservations", &message);
+            }
+            Ok(_) => self.show_devices(),
+            Err(e) => nwg::modal_error_message(&self.window, "Error", &e.to_string()),
+        }
+    }
+
+    pub fn release_selected(&self) {
+        let Some(busid) = self.selected_connected_device("Reservations") else { return };
+        match Reservations::open().release(&busid, &reservations::current_user()) {
+            Ok(_) => self.show_devices(),
+            Err(e) => nwg::modal_error_message(&self.window, "Error", &e.to_string()),
+        }
+    }
+
+    pub fn force_release_selected(&self) {
+        let Some(busid) = self.selected_connected_device("Reservations") else { return };
+        let device = match Reservations::open().get(&busid) {
+            Ok(Some(device)) => device,
+            Ok(None) => {
+                nwg::modal_info_message(&self.window, "Reservations", &format!("{} is not reserved.", busid));
+                return;
+            }
+            Err(e) => {
+                nwg::modal_error_message(&self.window, "Error", &e.to_string());
+                return;
+            }
+        };
+        if !self.ask_user_yes_no(&format!("End {}'s reservation of {}?", device.holder.user, busid)) {
+            return;
+        }
+        match Reservations::open().force_release(&busid) {
+            Ok(_) => self.show_devices(),
+            Err(e) => nwg::modal_error_message(&self.window, "Error", &e.to_string()),
+        }
+    }
 }
@@ -0,0 +1,370 @@
+//! Prometheus metrics and the optional HTTP endpoint that serves them.
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use crate::config;
+use crate::device_list::{DeviceState, UsbipDevice};
+use crate::stats::{self, LATENCY_BUCKETS_US, StatsSnapshot};
+
+#[derive(Default)]
+struct CommandMetrics {
+    runs: u64,
+    failures: u64,
+    seconds: f64,
+}
+
+#[derive(Default)]
+struct Registry {
+    devices_by_state: HashMap<DeviceState, u64>,
+    last_states: HashMap<String, DeviceState>,
+    attaches: u64,
+    detaches: u64,
+    usbipd_version: Option<String>,
+    firewall_rule_present: Option<bool>,
+    commands: BTreeMap<String, CommandMetrics>,
+}
+
+fn registry() -> &'static Mutex<Registry> {
+    static REGISTRY: OnceLock<Mutex<Registry>> = OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+impl Registry {
+    fn record_command(&mut self, subcommand: &str, duration: Duration, success: bool) {
+        let entry = self.commands.entry(subcommand.to_string()).or_default();
+        entry.runs += 1;
+        entry.seconds += duration.as_secs_f64();
+        if !success {
+            entry.failures += 1;
+        }
+    }
+
+    fn observe_devices(&mut self, devices: &[UsbipDevice]) {
+        let mut by_state = HashMap::new();
+        let mut states = HashMap::new();
+        for device in devices {
+            *by_state.entry(device.state).or_insert(0) += 1;
+            let was_attached = self.last_states.get(&device.busid) == Some(&DeviceState::Attached);
+            let is_attached = device.state == DeviceState::Attached;
+            if is_attached && !was_attached {
+                self.attaches += 1;
+            }
+            if was_attached && !is_attached {
+                self.detaches += 1;
+            }
+            states.insert(device.busid.clone(), device.state);
+        }
+        // Devices that vanished while attached were detached as well.
+        let vanished = self
+            .last_states
+            .iter()
+            .filter(|(busid, state)| **state == DeviceState::Attached && !states.contains_key(*busid))
+            .count() as u64;
+        self.detaches += vanished;
+        self.devices_by_state = by_state;
+        self.last_states = states;
+    }
+}
+
+/// Records one run of a usbipd subcommand.
+pub fn record_command(subcommand: &str, duration: Duration, success: bool) {
+    registry().lock().unwrap().record_command(subcommand, duration, success);
+}
+
+/// Updates the per-state gauges from a fresh device list and counts
+/// attach/detach transitions since the previous one.
+pub fn observe_devices(devices: &[UsbipDevice]) {
+    registry().lock().unwrap().observe_devices(devices);
+}
+
+pub fn set_usbipd_version(version: &str) {
+    registry().lock().unwrap().usbipd_version = Some(version.to_string());
+}
+
+pub fn set_firewall_rule_present(present: bool) {
+    registry().lock().unwrap().firewall_rule_present = Some(present);
+}
+
+/// Renders everything in the Prometheus text exposition format.
+pub fn render() -> String {
+    let traffic = stats::current();
+    render_from(&registry().lock().unwrap(), &traffic)
+}
+
+fn render_from(registry: &Registry, traffic: &StatsSnapshot) -> String {
+    let mut out = String::new();
+
+    header(&mut out, "usbip_host_info", "gauge", "Build information of this application.");
+    let _ = writeln!(out, "usbip_host_info{{version=\"{}\"}} 1", escape(env!("CARGO_PKG_VERSION")));
+
+    if let Some(version) = &registry.usbipd_version {
+        header(&mut out, "usbip_host_usbipd_version_info", "gauge", "Installed usbipd-win version.");
+        let _ = writeln!(out, "usbip_host_usbipd_version_info{{version=\"{}\"}} 1", escape(version));
+    }
+
+    header(&mut out, "usbip_host_devices", "gauge", "Number of devices by state.");
+    for state in DeviceState::ALL {
+        let count = registry.devices_by_state.get(&state).copied().unwrap_or(0);
+        let _ = writeln!(out, "usbip_host_devices{{state=\"{}\"}} {}", state.as_str(), count);
+    }
+
+    header(&mut out, "usbip_host_attaches_total", "counter", "Devices seen going to the attached state.");
+    let _ = writeln!(out, "usbip_host_attaches_total {}", registry.attaches);
+    header(&mut out, "usbip_host_detaches_total", "counter", "Devices seen leaving the attached state.");
+    let _ = writeln!(out, "usbip_host_detaches_total {}", registry.detaches);
+
+    if let Some(present) = registry.firewall_rule_present {
+        header(&mut out, "usbip_host_firewall_rule_present", "gauge", "Whether the USB/IP firewall rule exists.");
+        let _ = writeln!(out, "usbip_host_firewall_rule_present {}", present as u8);
+    }
+
+    header(&mut out, "usbip_host_command_runs_total", "counter", "usbipd invocations by subcommand.");
+    for (name, c) in &registry.commands {
+        let _ = writeln!(out, "usbip_host_command_runs_total{{subcommand=\"{}\"}} {}", escape(name), c.runs);
+    }
+    header(&mut out, "usbip_host_command_failures_total", "counter", "Failed usbipd invocations by subcommand.");
+    for (name, c) in &registry.commands {
+        let _ = writeln!(out, "usbip_host_command_failures_total{{subcommand=\"{}\"}} {}", escape(name), c.failures);
+    }
+    header(&mut out, "usbip_host_command_duration_seconds", "summary", "Time spent running usbipd by subcommand.");
+    for (name, c) in &registry.commands {
+        let name = escape(name);
+        let _ = writeln!(out, "usbip_host_command_duration_seconds_sum{{subcommand=\"{}\"}} {}", name, c.seconds);
+        let _ = writeln!(out, "usbip_host_command_duration_seconds_count{{subcommand=\"{}\"}} {}", name, c.runs);
+    }
+
+    render_traffic(&mut out, traffic);
+    out
+}
+
+fn render_traffic(out: &mut String, snapshot: &StatsSnapshot) {
+    if snapshot.devices.is_empty() {
+        return;
+    }
+    let devices: BTreeMap<_, _> = snapshot.devices.iter().collect();
+
+    header(out, "usbip_host_device_urbs_total", "counter", "URBs submitted per device.");
+    for (busid, c) in &devices {
+        let _ = writeln!(out, "usbip_host_device_urbs_total{{busid=\"{}\"}} {}", escape(busid), c.urbs);
+    }
+    header(out, "usbip_host_device_bytes_total", "counter", "Transfer payload per device and direction.");
+    for (busid, c) in &devices {
+        let busid = escape(busid);
+        let _ = writeln!(out, "usbip_host_device_bytes_total{{busid=\"{}\",direction=\"in\"}} {}", busid, c.bytes_in);
+        let _ = writeln!(out, "usbip_host_device_bytes_total{{busid=\"{}\",direction=\"out\"}} {}", busid, c.bytes_out);
+    }
+    header(out, "usbip_host_device_errors_total", "counter", "URBs completed with an error status.");
+    for (busid, c) in &devices {
+        let _ = writeln!(out, "usbip_host_device_errors_total{{busid=\"{}\"}} {}", escape(busid), c.errors);
+    }
+    header(out, "usbip_host_device_unlinks_total", "counter", "URBs cancelled by the client.");
+    for (busid, c) in &devices {
+        let _ = writeln!(out, "usbip_host_device_unlinks_total{{busid=\"{}\"}} {}", escape(busid), c.unlinks);
+    }
+    header(out, "usbip_host_device_urb_latency_seconds", "histogram", "URB round-trip time per device.");
+    for (busid, c) in &devices {
+        let busid = escape(busid);
+        let mut cumulative = 0;
+        for (bound, n) in LATENCY_BUCKETS_US.iter().zip(c.latency.buckets.iter()) {
+            cumulative += n;
+            let le = *bound as f64 / 1_000_000.0;
+            let _ = writeln!(out, "usbip_host_device_urb_latency_seconds_bucket{{busid=\"{}\",le=\"{}\"}} {}", busid, le, cumulative);
+        }
+        let _ = writeln!(out, "usbip_host_device_urb_latency_seconds_bucket{{busid=\"{}\",le=\"+Inf\"}} {}", busid, c.latency.count);
+        let _ = writeln!(out, "usbip_host_device_urb_latency_seconds_sum{{busid=\"{}\"}} {}", busid, c.latency.sum_us as f64 / 1_000_000.0);
+        let _ = writeln!(out, "usbip_host_device_urb_latency_seconds_count{{busid=\"{}\"}} {}", busid, c.latency.count);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Serves `GET /metrics` on `addr` from a background thread.
+pub fn serve(addr: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            thread::spawn(move || {
+                let _ = handle(stream);
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Starts the endpoint if `metrics.listen` is set in the config file.
+pub fn serve_configured() -> io::Result<()> {
+    let listen = config::current().metrics.listen;
+    if listen.is_empty() { Ok(()) } else { serve(&listen) }
+}
+
+fn handle(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Drain the headers; we don't need any of them.
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let (status, content_type, body) = match (method, path.split('?').next().unwrap_or("")) {
+        ("GET", "/metrics") => ("200 OK", "text/plain; version=0.0.4; charset=utf-8", render()),
+        ("GET", _) => ("404 Not Found", "text/plain", String::from("Not found\n")),
+        _ => ("405 Method Not Allowed", "text/plain", String::from("Method not allowed\n")),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::TrafficCounters;
+    use std::io::Read;
+
+    fn device(busid: &str, state: DeviceState) -> UsbipDevice {
+        UsbipDevice {
+            busid: busid.to_string(),
+            vidpid: String::from("0483:374b"),
+            device: String::from("ST-Link"),
+            state,
+            persisted: false,
+            guid: None,
+            instance_id: None,
+            wsl_distribution: None,
+        }
+    }
+
+    fn lines_starting(text: &str, prefix: &str) -> Vec<String> {
+        text.lines().filter(|l| l.starts_with(prefix)).map(String::from).collect()
+    }
+
+    #[test]
+    fn counts_attaches_and_detaches_between_lists() {
+        let mut registry = Registry::default();
+        registry.observe_devices(&[device("1-1", DeviceState::Shared), device("1-2", DeviceState::Attached)]);
+        registry.observe_devices(&[device("1-1", DeviceState::Attached), device("1-2", DeviceState::Attached)]);
+        registry.observe_devices(&[device("1-1", DeviceState::Shared)]);
+        // 1-2 attached first, then 1-1; 1-1 was detached and 1-2 vanished.
+        assert_eq!((registry.attaches, registry.detaches), (2, 2));
+        assert_eq!(registry.devices_by_state.get(&DeviceState::Shared), Some(&1));
+        assert_eq!(registry.devices_by_state.get(&DeviceState::Attached), None);
+    }
+
+    #[test]
+    fn renders_gauges_counters_and_commands() {
+        let mut registry = Registry { usbipd_version: Some(String::from("4.3.0")), firewall_rule_present: Some(true), ..Registry::default() };
+        registry.observe_devices(&[device("1-1", DeviceState::Attached), device("1-2", DeviceState::Shared)]);
+        registry.record_command("list", Duration::from_millis(250), true);
+        registry.record_command("list", Duration::from_millis(250), false);
+        let text = render_from(&registry, &StatsSnapshot::default());
+
+        assert!(text.starts_with("# HELP usbip_host_info Build information of this application.\n# TYPE usbip_host_info gauge\n"));
+        assert!(text.contains("usbip_host_usbipd_version_info{version=\"4.3.0\"} 1\n"));
+        assert_eq!(
+            lines_starting(&text, "usbip_host_devices{"),
+            [
+                "usbip_host_devices{state=\"not_shared\"} 0",
+                "usbip_host_devices{state=\"shared\"} 1",
+                "usbip_host_devices{state=\"shared_forced\"} 0",
+                "usbip_host_devices{state=\"attached\"} 1",
+                "usbip_host_devices{state=\"persisted\"} 0",
+                "usbip_host_devices{state=\"unknown\"} 0",
+            ]
+        );
+        assert!(text.contains("usbip_host_attaches_total 1\n"));
+        assert!(text.contains("usbip_host_firewall_rule_present 1\n"));
+        assert!(text.contains("usbip_host_command_runs_total{subcommand=\"list\"} 2\n"));
+        assert!(text.contains("usbip_host_command_failures_total{subcommand=\"list\"} 1\n"));
+        assert!(text.contains("usbip_host_command_duration_seconds_sum{subcommand=\"list\"} 0.5\n"));
+        assert!(text.contains("# TYPE usbip_host_command_duration_seconds summary\n"));
+        assert!(!text.contains("usbip_host_device_"));
+    }
+
+    #[test]
+    fn leaves_out_what_is_not_known_yet() {
+        let text = render_from(&Registry::default(), &StatsSnapshot::default());
+        assert!(!text.contains("usbipd_version_info"));
+        assert!(!text.contains("firewall_rule_present"));
+    }
+
+    #[test]
+    fn renders_traffic_with_cumulative_latency_buckets() {
+        let mut counters = TrafficCounters { urbs: 4, bytes_in: 100, bytes_out: 64, errors: 1, unlinks: 2, ..Default::default() };
+        for us in [80, 200, 200, 1_000_000] {
+            counters.latency.record(Duration::from_micros(us));
+        }
+        let mut traffic = StatsSnapshot::default();
+        traffic.devices.insert(String::from("2-1"), TrafficCounters::default());
+        traffic.devices.insert(String::from("1-4"), counters);
+        let text = render_from(&Registry::default(), &traffic);
+
+        assert_eq!(
+            lines_starting(&text, "usbip_host_device_urbs_total{"),
+            ["usbip_host_device_urbs_total{busid=\"1-4\"} 4", "usbip_host_device_urbs_total{busid=\"2-1\"} 0"]
+        );
+        assert!(text.contains("usbip_host_device_bytes_total{busid=\"1-4\",direction=\"in\"} 100\n"));
+        assert!(text.contains("usbip_host_device_bytes_total{busid=\"1-4\",direction=\"out\"} 64\n"));
+        assert!(text.contains("usbip_host_device_errors_total{busid=\"1-4\"} 1\n"));
+        assert!(text.contains("usbip_host_device_unlinks_total{busid=\"1-4\"} 2\n"));
+        let buckets = lines_starting(&text, "usbip_host_device_urb_latency_seconds_bucket{busid=\"1-4\"");
+        assert_eq!(buckets.len(), LATENCY_BUCKETS_US.len() + 1);
+        assert_eq!(buckets[0], "usbip_host_device_urb_latency_seconds_bucket{busid=\"1-4\",le=\"0.0001\"} 1");
+        assert_eq!(buckets[1], "usbip_host_device_urb_latency_seconds_bucket{busid=\"1-4\",le=\"0.00025\"} 3");
+        assert_eq!(buckets[10], "usbip_host_device_urb_latency_seconds_bucket{busid=\"1-4\",le=\"0.25\"} 3");
+        assert_eq!(buckets[11], "usbip_host_device_urb_latency_seconds_bucket{busid=\"1-4\",le=\"+Inf\"} 4");
+        assert!(text.contains("usbip_host_device_urb_latency_seconds_sum{busid=\"1-4\"} 1.00048\n"));
+        assert!(text.contains("usbip_host_device_urb_latency_seconds_count{busid=\"1-4\"} 4\n"));
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    fn get(method: &str, path: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let _ = handle(stream);
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serves_metrics_over_http() {
+        let response = get("GET", "/metrics?x=1");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n"));
+        assert!(response.contains("# TYPE usbip_host_info gauge\n"));
+        assert!(get("GET", "/other").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(get("POST", "/metrics").starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+}
@@ -0,0 +1,573 @@
+//! Installs, upgrades and removes usbipd-win through whichever package
+//! manager the machine has.
+//!
+//! `installer.method = "auto"` tries the managers in `installer.order` and
+//! uses the first one that is available, so locked-down machines without
+//! winget can fall back to Chocolatey, Scoop or a pinned MSI.
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::config;
+use crate::installer::{InstallError, InstallMethod, Installer};
+use crate::runner::{self, CommandError, CommandOutput, CommandRunner};
+use crate::version::{self, Version};
+use crate::winget::{Winget, WingetStatus};
+
+/// Exit codes Windows installers use for "success, restart needed".
+const REBOOT_REQUIRED_CODES: [i32; 2] = [1641, 3010];
+
+/// Scoop is a PowerShell script with a `.cmd` shim, which `CreateProcess`
+/// doesn't find without the extension.
+const SCOOP: &str = if cfg!(windows) { "scoop.cmd" } else { "scoop" };
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Install,
+    Upgrade,
+    Uninstall,
+}
+
+/// Where usbipd-win stands as far as the package manager is concerned.
+///
+/// `NotInstalled` → `Installing` → `Installed` → `UpgradeAvailable`, and back
+/// through `Installing` to `Installed` on upgrade.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum PackageState {
+    NotInstalled,
+    /// An install, upgrade or uninstall is running.
+    Installing,
+    Installed { version: Version },
+    UpgradeAvailable { installed: Version, available: Version },
+}
+
+impl PackageState {
+    /// The state for an installed version and the newest one on offer.
+    pub fn installed(installed: Version, available: Option<Version>) -> Self {
+        match available {
+            Some(available) if available > installed => PackageState::UpgradeAvailable { installed, available },
+            _ => PackageState::Installed { version: installed },
+        }
+    }
+
+    pub fn installed_version(&self) -> Option<&Version> {
+        match self {
+            PackageState::Installed { version } => Some(version),
+            PackageState::UpgradeAvailable { installed, .. } => Some(installed),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PackageState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PackageState::NotInstalled => f.write_str("usbipd-win is not installed."),
+            PackageState::Installing => f.write_str("usbipd-win is being installed."),
+            PackageState::Installed { version } => write!(f, "usbipd-win {} is installed and up to date.", version),
+            PackageState::UpgradeAvailable { installed, available } => {
+                write!(f, "usbipd-win {} is installed; {} is available.", installed, available)
+            }
+        }
+    }
+}
+
+/// The result of an install, upgrade or uninstall.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Transition {
+    pub method: InstallMethod,
+    pub from: PackageState,
+    pub to: PackageState,
+    pub reboot_required: bool,
+}
+
+impl Transition {
+    /// What happened, for the user.
+    pub fn message(&self) -> String {
+        let mut message = match (&self.from, &self.to) {
+            (_, PackageState::NotInstalled) if self.from.installed_version().is_some() => {
+                String::from("usbipd-win was uninstalled.")
+            }
+            (_, PackageState::NotInstalled) => String::from("usbipd-win is not installed, there was nothing to uninstall."),
+            (PackageState::NotInstalled, to) => match to.installed_version() {
+                Some(version) => format!("usbipd-win {} was installed.", version),
+                None => to.to_string(),
+            },
+            (from, to) => match (from.installed_version(), to.installed_version()) {
+                (Some(old), Some(new)) if new > old => format!("usbipd-win was upgraded from {} to {}.", old, new),
+                (Some(old), Some(new)) if new == old => {
+                    format!("usbipd-win {} is already the newest version {} offers.", new, self.method)
+                }
+                _ => to.to_string(),
+            },
+        };
+        if self.reboot_required {
+            message.push_str(" Restart Windows to finish.");
+        }
+        message
+    }
+}
+
+pub trait PackageManager {
+    fn method(&self) -> InstallMethod;
+
+    /// Whether this manager can be used on this machine.
+    fn is_available(&self) -> bool;
+
+    fn state(&self) -> Result<PackageState, InstallError>;
+
+    /// The newest version the manager would install, if it can tell.
+    fn available_version(&self) -> Result<Option<Version>, InstallError> {
+        Ok(None)
+    }
+
+    /// Runs `action`. Returns whether Windows must be restarted to finish.
+    fn run(&self, action: Action) -> Result<bool, InstallError>;
+
+    /// Runs `action` and reads the state it leaves the package in.
+    fn apply(&self, action: Action) -> Result<Transition, InstallError> {
+        let from = self.state()?;
+        let reboot_required = self.run(action)?;
+        let to = self.state()?;
+        Ok(Transition { method: self.method(), from, to, reboot_required })
+    }
+}
+
+/// Whether `program --version` runs successfully.
+fn responds(runner: &dyn CommandRunner, program: &str) -> bool {
+    runner.run(program, &["--version"]).is_ok_and(|output| output.success())
+}
+
+/// Judges an installer-style exit code.
+fn reboot_required(program: &str, output: &CommandOutput) -> Result<bool, CommandError> {
+    match output.code {
+        Some(0) => Ok(false),
+        Some(code) if REBOOT_REQUIRED_CODES.contains(&code) => Ok(true),
+        code => {
+            let message = output.message();
+            Err(CommandError::Failed(if message.is_empty() {
+                format!("{} failed with exit code {:?}", program, code)
+            } else {
+                format!("{} failed: {}", program, message)
+            }))
+        }
+    }
+}
+
+pub struct WingetManager<'a> {
+    winget: Winget<'a>,
+    runner: &'a dyn CommandRunner,
+    ignore_hash: bool,
+}
+
+impl<'a> WingetManager<'a> {
+    pub fn new(runner: &'a dyn CommandRunner, package_id: &str, ignore_hash: bool) -> Self {
+        WingetManager { winget: Winget::new(runner, package_id), runner, ignore_hash }
+    }
+
+    /// Runs `action` with `--ignore-security-hash`, which winget only accepts
+    /// while its `InstallerHashOverride` admin setting is on. The setting is
+    /// restored afterwards.
+    fn run_ignoring_hash(&self, action: Action) -> Result<WingetStatus, CommandError> {
+        let was_enabled = hash_override_enabled(self.runner)?;
+        if !was_enabled {
+            runner::run_checked(self.runner, "winget", &["settings", "--enable", "InstallerHashOverride"])?;
+        }
+        let result = self.winget.apply(action, &["--ignore-security-hash"]);
+        if !was_enabled {
+            runner::run_checked(self.runner, "winget", &["settings", "--disable", "InstallerHashOverride"])?;
+        }
+        result
+    }
+}
+
+impl PackageManager for WingetManager<'_> {
+    fn method(&self) -> InstallMethod {
+        InstallMethod::Winget
+    }
+
+    fn is_available(&self) -> bool {
+        responds(self.runner, "winget")
+    }
+
+    fn state(&self) -> Result<PackageState, InstallError> {
+        Ok(self.winget.state()?)
+    }
+
+    fn available_version(&self) -> Result<Option<Version>, InstallError> {
+        Ok(self.winget.available_version()?)
+    }
+
+    fn run(&self, action: Action) -> Result<bool, InstallError> {
+        let status = if self.ignore_hash && action != Action::Uninstall {
+            self.run_ignoring_hash(action)?
+        } else {
+            self.winget.apply(action, &[])?
+        };
+        Ok(status == WingetStatus::RebootRequired)
+    }
+}
+
+/// Reads the admin setting from `winget settings export`.
+fn hash_override_enabled(runner: &dyn CommandRunner) -> Result<bool, CommandError> {
+    let output = runner::run_checked(runner, "winget", &["settings", "export"])?;
+    let settings: serde_json::Value = serde_json::from_str(output.stdout.trim())
+        .map_err(|e| CommandError::Failed(format!("winget settings export: {}", e)))?;
+    Ok(settings["adminSettings"]["InstallerHashOverride"].as_bool().unwrap_or(false))
+}
+
+/// Chocolatey 2 or later, where `choco list` only lists local packages.
+pub struct ChocolateyManager<'a> {
+    runner: &'a dyn CommandRunner,
+    package: String,
+}
+
+impl<'a> ChocolateyManager<'a> {
+    pub fn new(runner: &'a dyn CommandRunner, package: &str) -> Self {
+        ChocolateyManager { runner, package: package.to_string() }
+    }
+
+    /// The `--limit-output` line for the package, split at `|`.
+    fn find(&self, args: &[&str]) -> Result<Option<Vec<String>>, CommandError> {
+        let output = runner::run_checked(self.runner, "choco", args)?;
+        Ok(parse_choco_line(&output.stdout, &self.package))
+    }
+}
+
+/// Finds `package` in `--limit-output` output, e.g. `usbipd-win|4.2.0|4.3.0|false`.
+pub fn parse_choco_line(output: &str, package: &str) -> Option<Vec<String>> {
+    output
+        .lines()
+        .map(|line| line.trim().split('|').map(str::to_string).collect::<Vec<_>>())
+        .find(|fields| fields.len() >= 2 && fields[0].eq_ignore_ascii_case(package))
+}
+
+impl PackageManager for ChocolateyManager<'_> {
+    fn method(&self) -> InstallMethod {
+        InstallMethod::Chocolatey
+    }
+
+    fn is_available(&self) -> bool {
+        responds(self.runner, "choco")
+    }
+
+    fn state(&self) -> Result<PackageState, InstallError> {
+        let Some(installed) = self.find(&["list", "--exact", "--limit-output", &self.package])? else {
+            return Ok(PackageState::NotInstalled);
+        };
+        let installed: Version = installed[1]
+            .parse()
+            .map_err(|_| CommandError::Failed(format!("choco reports an unreadable version '{}'", installed[1])))?;
+        let outdated = self.find(&["outdated", "--limit-output"])?;
+        let available = outdated.and_then(|fields| fields.get(2).and_then(|v| v.parse().ok()));
+        Ok(PackageState::installed(installed, available))
+    }
+
+    fn available_version(&self) -> Result<Option<Version>, InstallError> {
+        let found = self.find(&["search", "--exact", "--limit-output", &self.package])?;
+        Ok(found.and_then(|fields| fields[1].parse().ok()))
+    }
+
+    fn run(&self, action: Action) -> Result<bool, InstallError> {
+        let command = match action {
+            Action::Install => "install",
+            Action::Upgrade => "upgrade",
+            Action::Uninstall => "uninstall",
+        };
+        let output = self
+            .runner
+            .run("choco", &[command, &self.package, "--yes", "--no-progress", "--limit-output"])
+            .map_err(|e| CommandError::from_io("choco", e))?;
+        Ok(reboot_required("choco", &output)?)
+    }
+}
+
+pub struct ScoopManager<'a> {
+    runner: &'a dyn CommandRunner,
+    /// As given to `scoop install`, possibly `bucket/app`.
+    package: String,
+}
+
+impl<'a> ScoopManager<'a> {
+    pub fn new(runner: &'a dyn CommandRunner, package: &str) -> Self {
+        ScoopManager { runner, package: package.to_string() }
+    }
+
+    /// The app name without its bucket, as Scoop lists it.
+    fn app(&self) -> &str {
+        self.package.rsplit('/').next().unwrap_or(&self.package)
+    }
+}
+
+/// The installed version of `app` in `scoop export` JSON.
+pub fn parse_scoop_export(output: &str, app: &str) -> Option<String> {
+    let export: serde_json::Value = serde_json::from_str(output.trim()).ok()?;
+    export["apps"]
+        .as_array()?
+        .iter()
+        .find(|entry| entry["Name"].as_str().is_some_and(|name| name.eq_ignore_ascii_case(app)))
+        .and_then(|entry| entry["Version"].as_str())
+        .map(str::to_string)
+}
+
+/// The latest version of `app` in `scoop status`, if it's outdated.
+///
+/// Rows read `Name  Installed Version  Latest Version  ...`.
+pub fn parse_scoop_status(output: &str, app: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields[..] {
+            [name, _installed, latest, ..] if name.eq_ignore_ascii_case(app) => Some(latest.to_string()),
+            _ => None,
+        }
+    })
+}
+
+impl PackageManager for ScoopManager<'_> {
+    fn method(&self) -> InstallMethod {
+        InstallMethod::Scoop
+    }
+
+    fn is_available(&self) -> bool {
+        responds(self.runner, SCOOP)
+    }
+
+    fn state(&self) -> Result<PackageState, InstallError> {
+        let export = runner::run_checked(self.runner, SCOOP, &["export"])?;
+        let Some(installed) = parse_scoop_export(&export.stdout, self.app()) else {
+            return Ok(PackageState::NotInstalled);
+        };
+        let installed: Version = installed
+            .parse()
+            .map_err(|_| CommandError::Failed(format!("scoop reports an unreadable version '{}'", installed)))?;
+        let status = runner::run_checked(self.runner, SCOOP, &["status"])?;
+        let available = parse_scoop_status(&status.stdout, self.app()).and_then(|v| v.parse().ok());
+        Ok(PackageState::installed(installed, available))
+    }
+
+    fn run(&self, action: Action) -> Result<bool, InstallError> {
+        let args = match action {
+            Action::Install => ["install", self.package.as_str()],
+            Action::Upgrade => ["update", self.app()],
+            Action::Uninstall => ["uninstall", self.app()],
+        };
+        runner::run_checked(self.runner, SCOOP, &args)?;
+        Ok(false)
+    }
+}
+
+/// The hash-verified MSI from [`Installer`].
+pub struct MsiManager<'a> {
+    runner: &'a dyn CommandRunner,
+    installer: Installer<'a>,
+}
+
+impl<'a> MsiManager<'a> {
+    pub fn new(runner: &'a dyn CommandRunner, settings: config::InstallerConfig) -> Self {
+        MsiManager { runner, installer: Installer::new(runner, settings) }
+    }
+}
+
+impl PackageManager for MsiManager<'_> {
+    fn method(&self) -> InstallMethod {
+        InstallMethod::Msi
+    }
+
+    /// msiexec is always there; what counts is a configured MSI.
+    fn is_available(&self) -> bool {
+        self.installer.configured()
+    }
+
+    fn state(&self) -> Result<PackageState, InstallError> {
+        let installed = match version::detect(self.runner) {
+            Ok(version) => version,
+            Err(CommandError::NotFound(_)) => return Ok(PackageState::NotInstalled),
+            Err(e) => return Err(e.into()),
+        };
+        // An unreachable manifest shouldn't hide what is installed.
+        let available = self.installer.manifest_version().ok().flatten();
+        Ok(PackageState::installed(installed, available))
+    }
+
+    fn available_version(&self) -> Result<Option<Version>, InstallError> {
+        self.installer.manifest_version()
+    }
+
+    fn run(&self, action: Action) -> Result<bool, InstallError> {
+        match action {
+            Action::Install | Action::Upgrade => self.installer.install(),
+            Action::Uninstall => self.installer.uninstall(),
+        }
+    }
+}
+
+/// The manager for `method`, which must not be `Auto`.
+pub fn manager<'a>(
+    runner: &'a dyn CommandRunner,
+    method: InstallMethod,
+    settings: &config::InstallerConfig,
+    winget_package_id: &str,
+) -> Box<dyn PackageManager + 'a> {
+    match method {
+        InstallMethod::Auto | InstallMethod::Winget => {
+            Box::new(WingetManager::new(runner, winget_package_id, settings.winget_ignore_hash))
+        }
+        InstallMethod::Chocolatey => Box::new(ChocolateyManager::new(runner, &settings.chocolatey_package)),
+        InstallMethod::Scoop => Box::new(ScoopManager::new(runner, &settings.scoop_package)),
+        InstallMethod::Msi => Box::new(MsiManager::new(runner, settings.clone())),
+    }
+}
+
+/// The configured manager, or the first available one in `order` for `Auto`.
+pub fn select<'a>(
+    runner: &'a dyn CommandRunner,
+    settings: &config::InstallerConfig,
+    winget_package_id: &str,
+) -> Result<Box<dyn PackageManager + 'a>, InstallError> {
+    let candidates = match settings.method {
+        InstallMethod::Auto => settings.order.clone(),
+        method => vec![method],
+    };
+    candidates
+        .iter()
+        .map(|method| manager(runner, *method, settings, winget_package_id))
+        .find(|manager| manager.is_available())
+        .ok_or(InstallError::NoPackageManager(candidates))
+}
+
+pub fn from_config(runner: &dyn CommandRunner) -> Result<Box<dyn PackageManager + '_>, InstallError> {
+    let config = config::current();
+    select(runner, &config.installer, &config.service.winget_package_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakeRunner;
+
+    const WINGET_ID: &str = "dorssel.usbipd-win";
+
+    fn settings(method: InstallMethod) -> config::InstallerConfig {
+        config::InstallerConfig { method, ..Default::default() }
+    }
+
+    fn selected(runner: &FakeRunner, settings: &config::InstallerConfig) -> Result<InstallMethod, String> {
+        select(runner, settings, WINGET_ID).map(|m| m.method()).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn auto_picks_the_first_available_manager_in_order() {
+        let runner = FakeRunner::empty().ok("choco --version", "2.2.2").ok(&format!("{} --version", SCOOP), "v0.4.0");
+        assert_eq!(selected(&runner, &settings(InstallMethod::Auto)), Ok(InstallMethod::Chocolatey));
+        // The MSI isn't configured and winget isn't installed; Scoop is never asked.
+        assert_eq!(runner.calls(), ["winget --version", "choco --version"]);
+
+        let reordered = config::InstallerConfig { order: vec![InstallMethod::Scoop, InstallMethod::Chocolatey], ..settings(InstallMethod::Auto) };
+        assert_eq!(selected(&runner, &reordered), Ok(InstallMethod::Scoop));
+    }
+
+    #[test]
+    fn a_configured_msi_comes_first_without_probing() {
+        let runner = FakeRunner::empty().ok("winget --version", "v1.7.10861");
+        let msi = config::InstallerConfig { msi_url: String::from("\\\\share\\usbipd-win.msi"), ..settings(InstallMethod::Auto) };
+        assert_eq!(selected(&runner, &msi), Ok(InstallMethod::Msi));
+        assert!(runner.calls().is_empty());
+        assert_eq!(selected(&runner, &settings(InstallMethod::Auto)), Ok(InstallMethod::Winget));
+    }
+
+    #[test]
+    fn nothing_available_names_what_was_tried() {
+        let runner = FakeRunner::empty().fail("winget --version", 1, "", "");
+        assert_eq!(
+            selected(&runner, &settings(InstallMethod::Auto)),
+            Err(String::from("no package manager is available to install usbipd-win (tried msi, winget, chocolatey, scoop)"))
+        );
+        // An explicit method isn't swapped for another.
+        let runner = FakeRunner::empty().ok("winget --version", "v1.7.10861");
+        assert_eq!(
+            selected(&runner, &settings(InstallMethod::Scoop)),
+            Err(String::from("no package manager is available to install usbipd-win (tried scoop)"))
+        );
+    }
+
+    #[test]
+    fn chocolatey_commands_and_state() {
+        let runner = FakeRunner::empty()
+            .ok("choco list", "usbipd-win|4.2.0\n")
+            .ok("choco outdated", "git|2.44.0|2.45.0|false\nusbipd-win|4.2.0|4.3.0|false\n")
+            .fail("choco upgrade", 3010, "", "");
+        let choco = ChocolateyManager::new(&runner, "usbipd-win");
+        let transition = choco.apply(Action::Upgrade).unwrap();
+        assert_eq!(transition.from, PackageState::UpgradeAvailable { installed: Version::new(4, 2, 0), available: Version::new(4, 3, 0) });
+        assert!(transition.reboot_required);
+        assert_eq!(runner.calls_to("choco upgrade"), ["choco upgrade usbipd-win --yes --no-progress --limit-output"]);
+        assert_eq!(runner.calls_to("choco list"), ["choco list --exact --limit-output usbipd-win"; 2]);
+
+        runner.set("choco install", 1, "", "Access to the path is denied.");
+        assert_eq!(choco.run(Action::Install).unwrap_err().to_string(), "choco failed: Access to the path is denied.");
+        runner.set("choco list", 0, "", "");
+        assert_eq!(choco.state().unwrap(), PackageState::NotInstalled);
+    }
+
+    #[test]
+    fn scoop_commands_and_state() {
+        let scoop = |args: &str| format!("{} {}", SCOOP, args);
+        let runner = FakeRunner::empty()
+            .ok(&scoop("export"), r#"{"apps": [{"Name": "usbipd-win-np", "Version": "4.3.0", "Source": "nonportable"}]}"#)
+            .ok(&scoop("status"), "Name          Installed Version Latest Version Missing Dependencies Info\n----          ----------------- -------------- -------------------- ----\n")
+            .ok(&scoop("install"), "")
+            .ok(&scoop("update"), "")
+            .ok(&scoop("uninstall"), "");
+        let manager = ScoopManager::new(&runner, "nonportable/usbipd-win-np");
+        assert_eq!(manager.state().unwrap(), PackageState::Installed { version: Version::new(4, 3, 0) });
+        for action in [Action::Install, Action::Upgrade, Action::Uninstall] {
+            assert!(!manager.run(action).unwrap());
+        }
+        let runs: Vec<String> = runner.calls().into_iter().filter(|c| !c.ends_with("export") && !c.ends_with("status")).collect();
+        assert_eq!(runs, [scoop("install nonportable/usbipd-win-np"), scoop("update usbipd-win-np"), scoop("uninstall usbipd-win-np")]);
+
+        assert_eq!(parse_scoop_status("usbipd-win-np 4.2.0             4.3.0\n", "usbipd-win-np").as_deref(), Some("4.3.0"));
+    }
+
+    #[test]
+    fn winget_ignores_the_hash_only_for_the_one_run() {
+        let runner = FakeRunner::empty()
+            .ok("winget settings export", r#"{"adminSettings": {"InstallerHashOverride": false}, "userSettingsFile": "settings.json"}"#)
+            .ok("winget settings --enable", "")
+            .ok("winget settings --disable", "")
+            .ok("winget install", "Successfully installed");
+        let winget = WingetManager::new(&runner, WINGET_ID, true);
+        assert!(!winget.run(Action::Install).unwrap());
+        assert_eq!(
+            runner.calls(),
+            [
+                "winget settings export",
+                "winget settings --enable InstallerHashOverride",
+                "winget install --accept-source-agreements --accept-package-agreements --silent --disable-interactivity --exact --ignore-security-hash --id dorssel.usbipd-win",
+                "winget settings --disable InstallerHashOverride",
+            ]
+        );
+
+        let runner = FakeRunner::empty().ok("winget uninstall", "");
+        assert!(!WingetManager::new(&runner, WINGET_ID, true).run(Action::Uninstall).unwrap());
+        assert_eq!(runner.calls(), ["winget uninstall --silent --disable-interactivity --exact --id dorssel.usbipd-win"]);
+    }
+
+    #[test]
+    fn transition_messages() {
+        let v = |minor| Version::new(4, minor, 0);
+        let message = |from, to, reboot_required| Transition { method: InstallMethod::Winget, from, to, reboot_required }.message();
+        assert_eq!(message(PackageState::NotInstalled, PackageState::Installed { version: v(3) }, false), "usbipd-win 4.3.0 was installed.");
+        assert_eq!(
+            message(PackageState::Installed { version: v(2) }, PackageState::Installed { version: v(3) }, true),
+            "usbipd-win was upgraded from 4.2.0 to 4.3.0. Restart Windows to finish."
+        );
+        assert_eq!(
+            message(PackageState::Installed { version: v(3) }, PackageState::Installed { version: v(3) }, false),
+            "usbipd-win 4.3.0 is already the newest version winget offers."
+        );
+        assert_eq!(message(PackageState::Installed { version: v(3) }, PackageState::NotInstalled, false), "usbipd-win was uninstalled.");
+        assert_eq!(message(PackageState::NotInstalled, PackageState::NotInstalled, false), "usbipd-win is not installed, there was nothing to uninstall.");
+    }
+}
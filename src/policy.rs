@@ -0,0 +1,283 @@
+//! usbipd-win policy rules, which let clients bind devices without an
+//! administrator prompt.
+//!
+//! `usbipd policy list` prints one rule per line: GUID, effect, operation and
+//! then a busid and/or hardware ID, either of which may be blank. Rules are
+//! only available from usbipd-win 4.0, see [`Capability::Policy`].
+use std::fmt;
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::aliases;
+use crate::device_list::UsbipDevice;
+use crate::runner::{self, CommandError, CommandRunner};
+use crate::version::{self, Capability};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Effect {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Operation {
+    /// `usbipd attach --auto-bind` binds the device on the fly.
+    AutoBind,
+}
+
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Effect::Allow => "Allow",
+            Effect::Deny => "Deny",
+        })
+    }
+}
+
+impl FromStr for Effect {
+    type Err = ();
+
+    fn from_str(text: &str) -> Result<Self, ()> {
+        match text.to_ascii_lowercase().as_str() {
+            "allow" => Ok(Effect::Allow),
+            "deny" => Ok(Effect::Deny),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad("AutoBind")
+    }
+}
+
+impl FromStr for Operation {
+    type Err = ();
+
+    fn from_str(text: &str) -> Result<Self, ()> {
+        if text.eq_ignore_ascii_case("autobind") { Ok(Operation::AutoBind) } else { Err(()) }
+    }
+}
+
+/// A rule as usbipd stores it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PolicyRule {
+    pub guid: String,
+    pub effect: Effect,
+    pub operation: Operation,
+    pub busid: Option<String>,
+    /// `VID:PID`.
+    pub hardware_id: Option<String>,
+}
+
+impl PolicyRule {
+    /// Whether the rule applies to `device`. Every condition the rule sets must hold.
+    pub fn matches(&self, device: &UsbipDevice) -> bool {
+        self.busid.as_ref().is_none_or(|busid| *busid == device.busid)
+            && self.hardware_id.as_ref().is_none_or(|id| id.eq_ignore_ascii_case(&device.vidpid))
+    }
+}
+
+/// A rule to add. usbipd assigns the GUID.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewRule {
+    pub effect: Effect,
+    pub operation: Operation,
+    pub busid: Option<String>,
+    pub hardware_id: Option<String>,
+}
+
+/// What a rule created from a device should match on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchBy {
+    /// The port the device is plugged into.
+    Busid,
+    /// Any device with the same VID:PID, wherever it is plugged in.
+    HardwareId,
+}
+
+impl NewRule {
+    /// An `Allow AutoBind` rule for `device`.
+    pub fn allow_auto_bind(device: &UsbipDevice, by: MatchBy) -> Self {
+        let (busid, hardware_id) = match by {
+            MatchBy::Busid => (Some(device.busid.clone()), None),
+            MatchBy::HardwareId => (None, Some(device.vidpid.clone())),
+        };
+        NewRule { effect: Effect::Allow, operation: Operation::AutoBind, busid, hardware_id }
+    }
+
+    fn args(&self) -> Vec<String> {
+        let mut args = vec![
+            String::from("policy"),
+            String::from("add"),
+            String::from("--effect"),
+            self.effect.to_string(),
+            String::from("--operation"),
+            self.operation.to_string(),
+        ];
+        if let Some(busid) = &self.busid {
+            args.extend([String::from("--busid"), busid.clone()]);
+        }
+        if let Some(hardware_id) = &self.hardware_id {
+            args.extend([String::from("--hardware-id"), hardware_id.clone()]);
+        }
+        args
+    }
+}
+
+fn is_guid(text: &str) -> bool {
+    let groups: Vec<&str> = text.split('-').collect();
+    groups.len() == 5
+        && groups.iter().zip([8, 4, 4, 4, 12]).all(|(g, len)| g.len() == len && g.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// `VID:PID`, four hex digits each.
+pub(crate) fn is_hardware_id(text: &str) -> bool {
+    text.split_once(':')
+        .is_some_and(|(vid, pid)| [vid, pid].iter().all(|p| p.len() == 4 && p.chars().all(|c| c.is_ascii_hexdigit())))
+}
+
+/// Parses `usbipd policy list`. Lines that aren't rules (the heading, column
+/// titles, "no rules") are skipped.
+pub fn parse_policy_list(output: &str) -> Vec<PolicyRule> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [guid, effect, operation, ref rest @ ..] = fields[..] else { return None };
+            if !is_guid(guid) {
+                return None;
+            }
+            let mut rule = PolicyRule {
+                guid: guid.to_string(),
+                effect: effect.parse().ok()?,
+                operation: operation.parse().ok()?,
+                busid: None,
+                hardware_id: None,
+            };
+            // Either column may be blank, so tell them apart by their form.
+            for field in rest {
+                if is_hardware_id(field) {
+                    rule.hardware_id = Some(field.to_ascii_lowercase());
+                } else if aliases::looks_like_busid(field) {
+                    rule.busid = Some(field.to_string());
+                }
+            }
+            Some(rule)
+        })
+        .collect()
+}
+
+pub fn list_policies(runner: &dyn CommandRunner) -> Result<Vec<PolicyRule>, CommandError> {
+    version::capabilities(runner).require(Capability::Policy)?;
+    let output = runner::usbipd(runner, &["policy", "list"])?;
+    Ok(parse_policy_list(&output.stdout))
+}
+
+pub fn add_policy(runner: &dyn CommandRunner, rule: &NewRule) -> Result<(), CommandError> {
+    version::capabilities(runner).require(Capability::Policy)?;
+    let args = rule.args();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    runner::usbipd(runner, &args)?;
+    Ok(())
+}
+
+pub fn remove_policy(runner: &dyn CommandRunner, guid: &str) -> Result<(), CommandError> {
+    version::capabilities(runner).require(Capability::Policy)?;
+    runner::usbipd(runner, &["policy", "remove", "--guid", guid])?;
+    Ok(())
+}
+
+pub fn remove_all_policies(runner: &dyn CommandRunner) -> Result<(), CommandError> {
+    version::capabilities(runner).require(Capability::Policy)?;
+    runner::usbipd(runner, &["policy", "remove", "--all"])?;
+    Ok(())
+}
+
+/// A policy with the connected devices it covers, for the Policies view.
+#[derive(Debug, Clone, Serialize)]
+pub struct PolicyRow {
+    #[serde(flatten)]
+    pub rule: PolicyRule,
+    /// Busids of connected devices the rule matches.
+    pub devices: Vec<String>,
+}
+
+pub fn policy_rows(rules: &[PolicyRule], devices: &[UsbipDevice]) -> Vec<PolicyRow> {
+    rules
+        .iter()
+        .map(|rule| PolicyRow {
+            rule: rule.clone(),
+            devices: devices.iter().filter(|d| !d.busid.is_empty() && rule.matches(d)).map(|d| d.busid.clone()).collect(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_list::DeviceState;
+    use crate::testing::{self, FakeRunner};
+
+    const LIST: &str = "Policy rules:
+
+GUID                                  EFFECT  OPERATION  BUSID  HARDWARE-ID
+6a7b3c2e-1f4d-4e8a-9b0c-1d2e3f405162  Allow   AutoBind   1-3
+0f1e2d3c-4b5a-4968-8776-a5b4c3d2e1f0  Allow   AutoBind          0483:374B
+c0ffee00-1234-4abc-8def-0123456789ab  Deny    AutoBind   2-1.4  046d:c52b
+";
+
+    fn rule(guid: &str, effect: Effect, busid: Option<&str>, hardware_id: Option<&str>) -> PolicyRule {
+        PolicyRule { guid: guid.to_string(), effect, operation: Operation::AutoBind, busid: busid.map(str::to_string), hardware_id: hardware_id.map(str::to_string) }
+    }
+
+    #[test]
+    fn rows_with_a_busid_a_hardware_id_or_both() {
+        assert_eq!(
+            parse_policy_list(LIST),
+            [
+                rule("6a7b3c2e-1f4d-4e8a-9b0c-1d2e3f405162", Effect::Allow, Some("1-3"), None),
+                rule("0f1e2d3c-4b5a-4968-8776-a5b4c3d2e1f0", Effect::Allow, None, Some("0483:374b")),
+                rule("c0ffee00-1234-4abc-8def-0123456789ab", Effect::Deny, Some("2-1.4"), Some("046d:c52b")),
+            ]
+        );
+    }
+
+    #[test]
+    fn lines_that_arent_rules_are_skipped() {
+        assert!(parse_policy_list("Policy rules:\n\nThere are no policy rules.\n").is_empty());
+        let unknown = "6a7b3c2e-1f4d-4e8a-9b0c-1d2e3f405162  Allow   Attach   1-3\n6a7b3c2e-1f4d-4e8a-9b0c  Allow   AutoBind   1-3\n";
+        assert!(parse_policy_list(unknown).is_empty());
+    }
+
+    #[test]
+    fn every_condition_a_rule_sets_must_hold() {
+        let rules = parse_policy_list(LIST);
+        let probe = testing::device("1-3", "0483:374b", DeviceState::NotShared);
+        let moved = testing::device("2-1.4", "0483:374b", DeviceState::NotShared);
+        let mouse = testing::device("2-1.4", "046d:c52b", DeviceState::NotShared);
+        assert_eq!(rules.iter().map(|r| r.matches(&probe)).collect::<Vec<_>>(), [true, true, false]);
+        assert_eq!(rules.iter().map(|r| r.matches(&moved)).collect::<Vec<_>>(), [false, true, false]);
+        assert_eq!(rules.iter().map(|r| r.matches(&mouse)).collect::<Vec<_>>(), [false, false, true]);
+
+        let rows = policy_rows(&rules, &[probe, moved, testing::device("", "0483:374b", DeviceState::Persisted)]);
+        assert_eq!(rows.iter().map(|r| r.devices.clone()).collect::<Vec<_>>(), [vec!["1-3"], vec!["1-3", "2-1.4"], vec![]]);
+    }
+
+    #[test]
+    fn add_passes_the_matching_option() {
+        let runner = FakeRunner::new().ok("usbipd policy add", "");
+        let device = testing::device("1-3", "0483:374b", DeviceState::NotShared);
+        add_policy(&runner, &NewRule::allow_auto_bind(&device, MatchBy::Busid)).unwrap();
+        add_policy(&runner, &NewRule::allow_auto_bind(&device, MatchBy::HardwareId)).unwrap();
+        assert_eq!(
+            runner.calls_to("usbipd policy"),
+            [
+                "usbipd policy add --effect Allow --operation AutoBind --busid 1-3",
+                "usbipd policy add --effect Allow --operation AutoBind --hardware-id 0483:374b",
+            ]
+        );
+    }
+}
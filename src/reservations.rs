@@ -0,0 +1,655 @@
+//! Reservations of shared lab devices, so only one person binds a probe at a time.
+//!
+//! A reservation names a user and runs out on its own; others asking for the
+//! device wait in a queue and get it in turn. The state lives in a JSON file
+//! in the config directory and is read and written on every operation, under
+//! a lock file, so the GUI, the CLI, the agent and the API see the same
+//! reservations. Time comes from a [`Clock`] so expiry can be exercised
+//! without waiting.
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+
+pub const RESERVATIONS_FILE: &str = "reservations.json";
+
+/// Longest reservation or queued request accepted.
+pub const MAX_DURATION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// How long to wait for another process to finish with the state file.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+const LOCK_RETRY: Duration = Duration::from_millis(20);
+/// A lock file older than this was left behind by a process that died.
+const STALE_LOCK: Duration = Duration::from_secs(30);
+
+/// Seconds since the Unix epoch.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+    }
+}
+
+/// Who holds a device, and until when.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reservation {
+    pub user: String,
+    pub since: u64,
+    pub until: u64,
+}
+
+impl Reservation {
+    pub fn remaining(&self, now: u64) -> Duration {
+        Duration::from_secs(self.until.saturating_sub(now))
+    }
+}
+
+/// Someone waiting for a reserved device.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Waiter {
+    pub user: String,
+    /// How long the reservation lasts once it's their turn.
+    pub duration_secs: u64,
+    pub queued_at: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceReservation {
+    pub busid: String,
+    pub holder: Reservation,
+    pub queue: Vec<Waiter>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    devices: Vec<DeviceReservation>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Granted(Reservation),
+    /// The user already held the device; it now runs for the new duration.
+    Extended(Reservation),
+    /// 1-based place in the queue behind `holder`.
+    Queued { position: usize, holder: Reservation },
+}
+
+#[derive(Debug)]
+pub enum ReservationError {
+    /// Someone else holds the device.
+    Reserved { busid: String, holder: String, remaining: Duration },
+    NotReserved(String),
+    EmptyUser,
+    ZeroDuration,
+    /// Longer than [`MAX_DURATION`].
+    TooLong,
+    /// The state file exists but isn't a reservations file.
+    Corrupt { path: PathBuf, message: String },
+    /// Another process kept the lock file for longer than it should.
+    Locked(PathBuf),
+    Io(io::Error),
+}
+
+/// "42 minutes", rounded up so a reservation never shows as 0 minutes left.
+pub fn format_remaining(remaining: Duration) -> String {
+    let minutes = remaining.as_secs().div_ceil(60);
+    match minutes {
+        1 => String::from("1 minute"),
+        m if m < 120 => format!("{} minutes", m),
+        m => format!("{}h {:02}m", m / 60, m % 60),
+    }
+}
+
+impl fmt::Display for ReservationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReservationError::Reserved { busid, holder, remaining } => {
+                write!(f, "{} is reserved by {} for another {}", busid, holder, format_remaining(*remaining))
+            }
+            ReservationError::NotReserved(busid) => write!(f, "{} is not reserved", busid),
+            ReservationError::EmptyUser => f.write_str("a reservation needs a user name"),
+            ReservationError::ZeroDuration => f.write_str("a reservation needs a duration"),
+            ReservationError::TooLong => write!(f, "a reservation can last at most {} days", MAX_DURATION.as_secs() / (24 * 60 * 60)),
+            ReservationError::Corrupt { path, message } => {
+                write!(f, "{} is not a reservations file ({}); fix or delete it", path.display(), message)
+            }
+            ReservationError::Locked(path) => write!(f, "reservations are locked by another process; delete {} if none is running", path.display()),
+            ReservationError::Io(e) => write!(f, "could not read or save reservations: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReservationError {}
+
+impl From<io::Error> for ReservationError {
+    fn from(e: io::Error) -> Self {
+        ReservationError::Io(e)
+    }
+}
+
+/// The account this process runs as, which is who local binds and
+/// reservations are for. Asked of the OS rather than read from USER or
+/// USERNAME, which whoever starts the process can set to anything.
+pub fn current_user() -> String {
+    os_user().unwrap_or_default()
+}
+
+#[cfg(windows)]
+fn os_user() -> Option<String> {
+    crate::windows::user_name()
+}
+
+#[cfg(unix)]
+fn os_user() -> Option<String> {
+    use std::os::unix::fs::MetadataExt;
+    // /proc/self belongs to the process's effective user.
+    let uid = fs::metadata("/proc/self").ok()?.uid().to_string();
+    let passwd = fs::read_to_string("/etc/passwd").ok()?;
+    passwd.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        let name = fields.next()?;
+        (fields.nth(1)? == uid).then(|| name.to_string())
+    })
+}
+
+#[cfg(not(any(windows, unix)))]
+fn os_user() -> Option<String> {
+    None
+}
+
+pub struct Reservations {
+    path: PathBuf,
+    clock: Box<dyn Clock>,
+}
+
+impl Reservations {
+    pub fn new(path: PathBuf, clock: Box<dyn Clock>) -> Self {
+        Reservations { path, clock }
+    }
+
+    /// The state file at `path`, on the system clock.
+    pub fn at(path: PathBuf) -> Self {
+        Self::new(path, Box::new(SystemClock))
+    }
+
+    /// The state file in the config directory, on the system clock.
+    pub fn open() -> Self {
+        Self::at(default_path())
+    }
+
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    fn save(&self, state: &State) -> io::Result<()> {
+        // Written aside and renamed, so a crash never leaves half a file.
+        let temp = self.path.with_extension("tmp");
+        fs::write(&temp, serde_json::to_string_pretty(state).unwrap_or_default())?;
+        fs::rename(&temp, &self.path)
+    }
+
+    /// Loads the state with run-out reservations passed on to the next
+    /// waiter. The bool says whether anything changed and needs saving.
+    /// Saves are atomic, so reading needs no lock.
+    fn current(&self) -> Result<(State, bool), ReservationError> {
+        let mut state = load(&self.path)?;
+        let changed = expire(&mut state, self.now());
+        Ok((state, changed))
+    }
+
+    /// Runs `change` on the current state while holding the lock file and
+    /// saves the result.
+    fn update<T>(&self, change: impl FnOnce(&mut State, u64) -> Result<T, ReservationError>) -> Result<T, ReservationError> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let _lock = FileLock::acquire(self.path.with_extension("lock"))?;
+        let (mut state, _) = self.current()?;
+        let value = change(&mut state, self.now())?;
+        self.save(&state)?;
+        Ok(value)
+    }
+
+    /// Every reservation in effect now.
+    pub fn list(&self) -> Result<Vec<DeviceReservation>, ReservationError> {
+        let (state, changed) = self.current()?;
+        if !changed {
+            return Ok(state.devices);
+        }
+        // The next waiter's time started now; saving keeps it from starting
+        // again on the next read.
+        self.update(|state, _| Ok(state.devices.clone()))
+    }
+
+    pub fn get(&self, busid: &str) -> Result<Option<DeviceReservation>, ReservationError> {
+        Ok(self.list()?.into_iter().find(|d| d.busid == busid))
+    }
+
+    /// Reserves `busid` for `user`, or queues them if someone else holds it.
+    pub fn reserve(&self, busid: &str, user: &str, duration: Duration) -> Result<Outcome, ReservationError> {
+        if user.trim().is_empty() {
+            return Err(ReservationError::EmptyUser);
+        }
+        if duration.as_secs() == 0 {
+            return Err(ReservationError::ZeroDuration);
+        }
+        if duration > MAX_DURATION {
+            return Err(ReservationError::TooLong);
+        }
+        let secs = duration.as_secs();
+        self.update(|state, now| {
+            let outcome = match state.devices.iter_mut().find(|d| d.busid == busid) {
+                None => {
+                    let holder = Reservation { user: user.to_string(), since: now, until: now.saturating_add(secs) };
+                    state.devices.push(DeviceReservation { busid: busid.to_string(), holder: holder.clone(), queue: Vec::new() });
+                    Outcome::Granted(holder)
+                }
+                Some(device) if device.holder.user.eq_ignore_ascii_case(user) => {
+                    device.holder.until = now.saturating_add(secs);
+                    Outcome::Extended(device.holder.clone())
+                }
+                Some(device) => {
+                    let position = match device.queue.iter().position(|w| w.user.eq_ignore_ascii_case(user)) {
+                        Some(index) => {
+                            device.queue[index].duration_secs = secs;
+                            index + 1
+                        }
+                        None => {
+                            device.queue.push(Waiter { user: user.to_string(), duration_secs: secs, queued_at: now });
+                            device.queue.len()
+                        }
+                    };
+                    Outcome::Queued { position, holder: device.holder.clone() }
+                }
+            };
+            Ok(outcome)
+        })
+    }
+
+    /// Gives up `user`'s reservation or place in the queue. Returns whoever
+    /// holds the device now.
+    pub fn release(&self, busid: &str, user: &str) -> Result<Option<Reservation>, ReservationError> {
+        self.update(|state, now| {
+            let Some(device) = state.devices.iter_mut().find(|d| d.busid == busid) else {
+                return Err(ReservationError::NotReserved(busid.to_string()));
+            };
+            if device.holder.user.eq_ignore_ascii_case(user) {
+                return Ok(hand_over(state, busid, now));
+            }
+            let before = device.queue.len();
+            device.queue.retain(|w| !w.user.eq_ignore_ascii_case(user));
+            if device.queue.len() == before {
+                return Err(reserved(device, now));
+            }
+            Ok(Some(device.holder.clone()))
+        })
+    }
+
+    /// Ends the reservation whoever holds it, for a holder who left for the day.
+    pub fn force_release(&self, busid: &str) -> Result<Option<Reservation>, ReservationError> {
+        self.update(|state, now| {
+            if !state.devices.iter().any(|d| d.busid == busid) {
+                return Err(ReservationError::NotReserved(busid.to_string()));
+            }
+            Ok(hand_over(state, busid, now))
+        })
+    }
+
+    /// Whether `user` may bind or attach `busid`: anyone may while it isn't
+    /// reserved, otherwise only the holder.
+    pub fn check(&self, busid: &str, user: &str) -> Result<(), ReservationError> {
+        match self.get(busid)? {
+            Some(device) if !device.holder.user.eq_ignore_ascii_case(user) => Err(reserved(&device, self.now())),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn reserved(device: &DeviceReservation, now: u64) -> ReservationError {
+    ReservationError::Reserved {
+        busid: device.busid.clone(),
+        holder: device.holder.user.clone(),
+        remaining: device.holder.remaining(now),
+    }
+}
+
+/// Ends `busid`'s reservation now and returns whoever holds it next.
+fn hand_over(state: &mut State, busid: &str, now: u64) -> Option<Reservation> {
+    if let Some(device) = state.devices.iter_mut().find(|d| d.busid == busid) {
+        // Ending it now and expiring it are the same thing.
+        device.holder.until = now;
+    }
+    expire(state, now);
+    state.devices.iter().find(|d| d.busid == busid).map(|d| d.holder.clone())
+}
+
+/// A missing file means nothing is reserved; one that doesn't parse is an
+/// error, since saving over it would drop every reservation.
+fn load(path: &Path) -> Result<State, ReservationError> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(State::default()),
+        Err(e) => return Err(e.into()),
+    };
+    serde_json::from_str(&text).map_err(|e| ReservationError::Corrupt { path: path.to_path_buf(), message: e.to_string() })
+}
+
+/// Serializes access to the state file between the GUI, the CLI, the agent
+/// and the API. Removed again when dropped.
+struct FileLock {
+    path: PathBuf,
+}
+
+impl FileLock {
+    fn acquire(path: PathBuf) -> Result<Self, ReservationError> {
+        let started = Instant::now();
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(FileLock { path }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    // Nothing holds the lock for long, so an old one was left
+                    // behind by a process that died.
+                    let age = fs::metadata(&path).and_then(|m| m.modified()).ok().and_then(|t| t.elapsed().ok());
+                    if age.is_some_and(|age| age > STALE_LOCK) {
+                        let _ = fs::remove_file(&path);
+                        continue;
+                    }
+                    if started.elapsed() > LOCK_TIMEOUT {
+                        return Err(ReservationError::Locked(path));
+                    }
+                    thread::sleep(LOCK_RETRY);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Passes run-out reservations to the next waiter, whose time starts now, and
+/// drops devices nobody waits for. Returns whether anything changed.
+fn expire(state: &mut State, now: u64) -> bool {
+    let mut changed = false;
+    state.devices.retain_mut(|device| {
+        if device.holder.until > now {
+            return true;
+        }
+        changed = true;
+        if device.queue.is_empty() {
+            return false;
+        }
+        let next = device.queue.remove(0);
+        device.holder = Reservation { user: next.user, since: now, until: now.saturating_add(next.duration_secs) };
+        true
+    });
+    changed
+}
+
+/// Where [`Reservations::open`] keeps the state.
+pub fn default_path() -> PathBuf {
+    config::config_dir().join(RESERVATIONS_FILE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    const START: u64 = 1_700_000_000;
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    #[derive(Clone)]
+    struct FakeClock(Arc<AtomicU64>);
+
+    impl FakeClock {
+        fn advance(&self, by: Duration) {
+            self.0.fetch_add(by.as_secs(), Ordering::Relaxed);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> u64 {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    /// A state file in its own temp directory, removed again on drop.
+    struct Fixture {
+        dir: PathBuf,
+        clock: FakeClock,
+        store: Reservations,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("usbip_host-reservations-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            let clock = FakeClock(Arc::new(AtomicU64::new(START)));
+            let store = Reservations::new(dir.join(RESERVATIONS_FILE), Box::new(clock.clone()));
+            Fixture { dir, clock, store }
+        }
+
+        fn path(&self) -> PathBuf {
+            self.dir.join(RESERVATIONS_FILE)
+        }
+
+        fn holder(&self, busid: &str) -> Option<Reservation> {
+            self.store.get(busid).unwrap().map(|d| d.holder)
+        }
+
+        fn queue(&self, busid: &str) -> Vec<String> {
+            self.store.get(busid).unwrap().map(|d| d.queue.into_iter().map(|w| w.user).collect()).unwrap_or_default()
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn reservation(user: &str, since: u64, until: u64) -> Reservation {
+        Reservation { user: user.to_string(), since, until }
+    }
+
+    #[test]
+    fn a_free_device_is_granted_and_saved() {
+        let f = Fixture::new("grant");
+        let outcome = f.store.reserve("1-1", "ana", HOUR).unwrap();
+        assert_eq!(outcome, Outcome::Granted(reservation("ana", START, START + 3600)));
+
+        let reopened = Reservations::new(f.path(), Box::new(f.clock.clone()));
+        assert_eq!(reopened.get("1-1").unwrap().unwrap().holder, reservation("ana", START, START + 3600));
+        assert_eq!(reopened.get("1-2").unwrap(), None);
+        // Written aside and renamed, without leaving the temp file or the lock.
+        assert!(!f.path().with_extension("tmp").exists());
+        assert!(!f.path().with_extension("lock").exists());
+    }
+
+    #[test]
+    fn the_holder_extends_from_now() {
+        let f = Fixture::new("extend");
+        f.store.reserve("1-1", "ana", HOUR).unwrap();
+        f.clock.advance(Duration::from_secs(600));
+        let outcome = f.store.reserve("1-1", "ANA", Duration::from_secs(1800)).unwrap();
+        assert_eq!(outcome, Outcome::Extended(reservation("ana", START, START + 600 + 1800)));
+    }
+
+    #[test]
+    fn others_queue_in_order() {
+        let f = Fixture::new("queue");
+        let holder = reservation("ana", START, START + 3600);
+        f.store.reserve("1-1", "ana", HOUR).unwrap();
+        assert_eq!(f.store.reserve("1-1", "bob", HOUR).unwrap(), Outcome::Queued { position: 1, holder: holder.clone() });
+        assert_eq!(f.store.reserve("1-1", "cy", HOUR).unwrap(), Outcome::Queued { position: 2, holder: holder.clone() });
+        // Asking again keeps the place and takes the new duration.
+        assert_eq!(f.store.reserve("1-1", "Bob", 2 * HOUR).unwrap(), Outcome::Queued { position: 1, holder });
+        let device = f.store.get("1-1").unwrap().unwrap();
+        assert_eq!(device.queue.iter().map(|w| (w.user.as_str(), w.duration_secs)).collect::<Vec<_>>(), [("bob", 7200), ("cy", 3600)]);
+    }
+
+    #[test]
+    fn expiry_hands_over_to_the_next_waiter() {
+        let f = Fixture::new("expiry");
+        f.store.reserve("1-1", "ana", HOUR).unwrap();
+        f.store.reserve("1-1", "bob", 2 * HOUR).unwrap();
+        f.store.reserve("1-2", "ana", HOUR).unwrap();
+
+        f.clock.advance(HOUR - Duration::from_secs(1));
+        assert_eq!(f.holder("1-1").unwrap().user, "ana");
+
+        f.clock.advance(Duration::from_secs(61));
+        let handed_over = START + 3660;
+        assert_eq!(f.holder("1-1"), Some(reservation("bob", handed_over, handed_over + 7200)));
+        assert!(f.queue("1-1").is_empty());
+        // Nobody waited for 1-2, so it's free again.
+        assert_eq!(f.holder("1-2"), None);
+
+        // The hand-over was saved, so bob's time doesn't restart on a later read.
+        f.clock.advance(Duration::from_secs(60));
+        assert_eq!(f.holder("1-1"), Some(reservation("bob", handed_over, handed_over + 7200)));
+        let saved = fs::read_to_string(f.path()).unwrap();
+        assert!(saved.contains("\"bob\"") && !saved.contains("\"ana\""), "{}", saved);
+
+        f.clock.advance(2 * HOUR);
+        assert!(f.store.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn release_hands_over_or_leaves_the_queue() {
+        let f = Fixture::new("release");
+        f.store.reserve("1-1", "ana", HOUR).unwrap();
+        f.store.reserve("1-1", "bob", HOUR).unwrap();
+        f.store.reserve("1-1", "cy", HOUR).unwrap();
+        f.clock.advance(Duration::from_secs(60));
+
+        assert_eq!(f.store.release("1-1", "cy").unwrap(), Some(reservation("ana", START, START + 3600)));
+        assert_eq!(f.queue("1-1"), ["bob"]);
+        assert_eq!(f.store.release("1-1", "ana").unwrap(), Some(reservation("bob", START + 60, START + 60 + 3600)));
+        assert_eq!(f.store.release("1-1", "bob").unwrap(), None);
+        assert!(matches!(f.store.release("1-1", "bob"), Err(ReservationError::NotReserved(_))));
+    }
+
+    #[test]
+    fn only_the_holder_or_a_waiter_can_release() {
+        let f = Fixture::new("release-stranger");
+        f.store.reserve("1-1", "ana", HOUR).unwrap();
+        let error = f.store.release("1-1", "mallory").unwrap_err();
+        assert_eq!(error.to_string(), "1-1 is reserved by ana for another 60 minutes");
+        assert_eq!(f.holder("1-1").unwrap().user, "ana");
+    }
+
+    #[test]
+    fn force_release_ends_any_reservation() {
+        let f = Fixture::new("force");
+        f.store.reserve("1-1", "ana", HOUR).unwrap();
+        f.store.reserve("1-1", "bob", HOUR).unwrap();
+        f.clock.advance(Duration::from_secs(120));
+        assert_eq!(f.store.force_release("1-1").unwrap(), Some(reservation("bob", START + 120, START + 120 + 3600)));
+        assert_eq!(f.store.force_release("1-1").unwrap(), None);
+        assert!(matches!(f.store.force_release("1-1"), Err(ReservationError::NotReserved(_))));
+    }
+
+    #[test]
+    fn check_lets_only_the_holder_through() {
+        let f = Fixture::new("check");
+        f.store.check("1-1", "").unwrap();
+        f.store.reserve("1-1", "ana", HOUR).unwrap();
+        f.store.reserve("1-1", "bob", HOUR).unwrap();
+        f.store.check("1-1", "Ana").unwrap();
+        f.store.check("1-2", "bob").unwrap();
+        f.clock.advance(Duration::from_secs(30 * 60));
+        for user in ["bob", "", "mallory"] {
+            let error = f.store.check("1-1", user).unwrap_err();
+            assert_eq!(error.to_string(), "1-1 is reserved by ana for another 30 minutes");
+        }
+        f.clock.advance(Duration::from_secs(30 * 60));
+        f.store.check("1-1", "bob").unwrap();
+        assert!(f.store.check("1-1", "ana").is_err());
+    }
+
+    #[test]
+    fn reserve_rejects_bad_requests() {
+        let f = Fixture::new("invalid");
+        assert!(matches!(f.store.reserve("1-1", " ", HOUR), Err(ReservationError::EmptyUser)));
+        assert!(matches!(f.store.reserve("1-1", "ana", Duration::ZERO), Err(ReservationError::ZeroDuration)));
+        let error = f.store.reserve("1-1", "ana", Duration::MAX).unwrap_err();
+        assert_eq!(error.to_string(), "a reservation can last at most 30 days");
+        f.store.reserve("1-1", "ana", MAX_DURATION).unwrap();
+        assert_eq!(f.store.list().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn huge_durations_in_the_file_saturate() {
+        let f = Fixture::new("saturate");
+        f.store.reserve("1-1", "ana", HOUR).unwrap();
+        let text = fs::read_to_string(f.path()).unwrap();
+        let text = text.replace("\"queue\": []", &format!("\"queue\": [{{\"user\": \"bob\", \"duration_secs\": {}, \"queued_at\": 0}}]", u64::MAX));
+        fs::write(f.path(), text).unwrap();
+        f.clock.advance(2 * HOUR);
+        assert_eq!(f.holder("1-1"), Some(reservation("bob", START + 7200, u64::MAX)));
+    }
+
+    #[test]
+    fn a_corrupt_file_is_an_error_and_left_alone() {
+        let f = Fixture::new("corrupt");
+        fs::create_dir_all(&f.dir).unwrap();
+        fs::write(f.path(), "{\"devices\": [{\"busid\": \"1-1\"").unwrap();
+        assert!(matches!(f.store.list(), Err(ReservationError::Corrupt { .. })));
+        let error = f.store.reserve("1-2", "ana", HOUR).unwrap_err();
+        assert!(error.to_string().contains("is not a reservations file"), "{}", error);
+        assert!(f.store.check("1-2", "ana").is_err());
+        assert_eq!(fs::read_to_string(f.path()).unwrap(), "{\"devices\": [{\"busid\": \"1-1\"");
+    }
+
+    #[test]
+    fn changes_wait_for_the_lock() {
+        let f = Fixture::new("lock");
+        fs::create_dir_all(&f.dir).unwrap();
+        let lock = FileLock::acquire(f.path().with_extension("lock")).unwrap();
+        let path = f.path();
+        let clock = f.clock.clone();
+        let writer = thread::spawn(move || Reservations::new(path, Box::new(clock)).reserve("1-1", "ana", HOUR).map(|_| ()));
+        thread::sleep(Duration::from_millis(200));
+        assert!(!f.path().exists());
+        drop(lock);
+        writer.join().unwrap().unwrap();
+        assert_eq!(f.holder("1-1").unwrap().user, "ana");
+    }
+
+    #[test]
+    fn a_stale_lock_is_taken_over() {
+        let f = Fixture::new("stale-lock");
+        fs::create_dir_all(&f.dir).unwrap();
+        let lock = f.path().with_extension("lock");
+        let file = fs::File::create(&lock).unwrap();
+        file.set_modified(SystemTime::now() - 2 * STALE_LOCK).unwrap();
+        f.store.reserve("1-1", "ana", HOUR).unwrap();
+        assert!(!lock.exists());
+    }
+
+    #[test]
+    fn format_remaining_rounds_up() {
+        assert_eq!(format_remaining(Duration::from_secs(1)), "1 minute");
+        assert_eq!(format_remaining(Duration::from_secs(61)), "2 minutes");
+        assert_eq!(format_remaining(Duration::from_secs(119 * 60)), "119 minutes");
+        assert_eq!(format_remaining(Duration::from_secs(150 * 60)), "2h 30m");
+    }
+}
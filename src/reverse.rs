@@ -0,0 +1,584 @@
+//! Reverse-connect mode for hosts that sit behind NAT.
+//!
+//! The host dials out to a rendezvous listener on the client side and keeps an
+//! idle connection parked there. When a local `usbip attach` connects to the
+//! rendezvous, the parked connection is handed to it and the host splices the
+//! stream onto its own usbipd on port 3240.
+//!
+//! Both ends share `reverse.secret` and prove it to each other. A host opens
+//! with [`HELLO`] and a random nonce; the rendezvous answers with a random
+//! challenge and an HMAC-SHA256 over both, keyed with the secret, and the
+//! host only answers with its own HMAC once that checks out. Either side
+//! drops the connection on a wrong proof, so a host never parks at a
+//! listener that doesn't know the secret.
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::config;
+use crate::stats::{self, ConnectionTap, Direction};
+
+pub const USBIP_PORT: u16 = 3240;
+
+/// Sent by the host on every outbound connection so the rendezvous can
+/// reject anything that isn't one of ours.
+const HELLO: &[u8; 8] = b"USBIPRV3";
+/// Length of the nonces and of the proofs.
+const PROOF_LEN: usize = 32;
+/// Which side a proof comes from, so neither can replay the other's.
+const HOST_PROOF: &[u8] = b"host";
+const RENDEZVOUS_PROOF: &[u8] = b"rendezvous";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Exponential backoff between reconnect attempts.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff { initial, max, current: initial }
+    }
+
+    /// Returns the delay to wait now and doubles it for the next failure.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReverseHostConfig {
+    /// Address of the client-side rendezvous listener, e.g. `office.example.com:3241`.
+    pub rendezvous: String,
+    /// The usbipd server this host exports, `127.0.0.1` on `server.port`.
+    pub local: String,
+    /// `reverse.secret`, which the rendezvous must have too.
+    pub secret: String,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for ReverseHostConfig {
+    fn default() -> Self {
+        let config = config::current();
+        ReverseHostConfig {
+            rendezvous: String::new(),
+            local: format!("127.0.0.1:{}", config.server.port),
+            secret: config.reverse.secret,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RendezvousConfig {
+    /// Where hosts dial in.
+    pub host_listen: String,
+    /// Where local `usbip attach` connects, normally `127.0.0.1:3240`.
+    pub client_listen: String,
+    /// How long a local client waits for a parked host connection.
+    pub pair_timeout: Duration,
+    /// `reverse.secret`; hosts that don't know it are turned away.
+    pub secret: String,
+}
+
+impl Default for RendezvousConfig {
+    fn default() -> Self {
+        RendezvousConfig {
+            host_listen: format!("0.0.0.0:{}", USBIP_PORT + 1),
+            client_listen: format!("127.0.0.1:{}", USBIP_PORT),
+            pair_timeout: Duration::from_secs(10),
+            secret: config::current().reverse.secret,
+        }
+    }
+}
+
+fn no_secret() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "reverse.secret is not set")
+}
+
+/// Runs the host side until `stop` is set.
+///
+/// Keeps exactly one idle connection parked at the rendezvous. As soon as the
+/// parked connection carries data it becomes a session and a fresh one is
+/// dialed. Anything short of a session, including the rendezvous closing the
+/// parked connection, waits out the backoff before dialing again.
+pub fn run_host(config: &ReverseHostConfig, stop: &AtomicBool) -> io::Result<()> {
+    if config.secret.is_empty() {
+        return Err(no_secret());
+    }
+    let mut backoff = Backoff::new(config.initial_backoff, config.max_backoff);
+
+    while !stop.load(Ordering::Relaxed) {
+        if let Ok(Some((remote, first))) = park_at_rendezvous(config, stop) {
+            match connect(&config.local) {
+                Ok(mut local) => {
+                    backoff.reset();
+                    let tap = stats::global().open(remote.peer_addr().ok());
+                    tap.feed(Direction::ClientToServer, &first);
+                    if local.write_all(&first).is_ok() {
+                        splice(remote, local, Some(tap));
+                    }
+                    continue;
+                }
+                Err(_) => {
+                    let _ = remote.shutdown(Shutdown::Both);
+                }
+            }
+        }
+        sleep_unless_stopped(backoff.next_delay(), stop);
+    }
+    Ok(())
+}
+
+/// A fresh nonce from the OS's random source.
+fn nonce() -> io::Result<[u8; PROOF_LEN]> {
+    let mut nonce = [0u8; PROOF_LEN];
+    getrandom::getrandom(&mut nonce).map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(nonce)
+}
+
+fn mac(secret: &str, side: &[u8], host_nonce: &[u8], challenge: &[u8]) -> Hmac<Sha256> {
+    // HMAC takes keys of any length, so this can't fail.
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(side);
+    mac.update(host_nonce);
+    mac.update(challenge);
+    mac
+}
+
+/// What `side` sends to prove it knows `secret` for this pair of nonces.
+fn proof(secret: &str, side: &[u8], host_nonce: &[u8], challenge: &[u8]) -> [u8; PROOF_LEN] {
+    mac(secret, side, host_nonce, challenge).finalize().into_bytes().into()
+}
+
+/// Checks the other side's proof in constant time.
+fn verify(secret: &str, side: &[u8], host_nonce: &[u8], challenge: &[u8], answer: &[u8]) -> bool {
+    mac(secret, side, host_nonce, challenge).verify_slice(answer).is_ok()
+}
+
+/// Dials the rendezvous, answers its challenge and waits for the first bytes
+/// of a session.
+///
+/// Returns `Ok(None)` when the rendezvous closed the idle connection, which
+/// is also how it turns away a wrong secret, or when `stop` was set. A
+/// rendezvous that can't prove it knows the secret is an error.
+fn park_at_rendezvous(
+    config: &ReverseHostConfig,
+    stop: &AtomicBool,
+) -> io::Result<Option<(TcpStream, Vec<u8>)>> {
+    let mut remote = connect(&config.rendezvous)?;
+    remote.set_nodelay(true)?;
+    let host_nonce = nonce()?;
+    remote.write_all(HELLO)?;
+    remote.write_all(&host_nonce)?;
+    remote.set_read_timeout(Some(HELLO_TIMEOUT))?;
+    let mut challenge = [0u8; PROOF_LEN];
+    remote.read_exact(&mut challenge)?;
+    let mut answer = [0u8; PROOF_LEN];
+    remote.read_exact(&mut answer)?;
+    if !verify(&config.secret, RENDEZVOUS_PROOF, &host_nonce, &challenge, &answer) {
+        let _ = remote.shutdown(Shutdown::Both);
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "the rendezvous doesn't know reverse.secret"));
+    }
+    remote.write_all(&proof(&config.secret, HOST_PROOF, &host_nonce, &challenge))?;
+    remote.set_read_timeout(Some(POLL_INTERVAL * 10))?;
+
+    let mut buf = [0u8; 4096];
+    loop {
+        if stop.load(Ordering::Relaxed) {
+            let _ = remote.shutdown(Shutdown::Both);
+            return Ok(None);
+        }
+        match remote.read(&mut buf) {
+            Ok(0) => return Ok(None),
+            Ok(n) => {
+                remote.set_read_timeout(None)?;
+                return Ok(Some((remote, buf[..n].to_vec())));
+            }
+            Err(e) if is_timeout(&e) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Runs the client side until `stop` is set.
+///
+/// Accepts host connections on `host_listen` and local clients on
+/// `client_listen`, pairing each client with the oldest parked host connection.
+pub fn run_rendezvous(config: &RendezvousConfig, stop: &AtomicBool) -> io::Result<()> {
+    if config.secret.is_empty() {
+        return Err(no_secret());
+    }
+    let hosts = TcpListener::bind(&config.host_listen)?;
+    let clients = TcpListener::bind(&config.client_listen)?;
+    rendezvous(hosts, clients, config, stop)
+}
+
+/// [`run_rendezvous`] on listeners that are already bound.
+fn rendezvous(hosts: TcpListener, clients: TcpListener, config: &RendezvousConfig, stop: &AtomicBool) -> io::Result<()> {
+    hosts.set_nonblocking(true)?;
+    clients.set_nonblocking(true)?;
+
+    let parked: Arc<(Mutex<VecDeque<TcpStream>>, Condvar)> =
+        Arc::new((Mutex::new(VecDeque::new()), Condvar::new()));
+
+    while !stop.load(Ordering::Relaxed) {
+        let mut idle = true;
+
+        match hosts.accept() {
+            Ok((stream, _)) => {
+                idle = false;
+                let parked = parked.clone();
+                let secret = config.secret.clone();
+                thread::spawn(move || {
+                    if let Ok(stream) = accept_host(stream, &secret) {
+                        let (queue, ready) = &*parked;
+                        queue.lock().unwrap().push_back(stream);
+                        ready.notify_one();
+                    }
+                });
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+
+        match clients.accept() {
+            Ok((client, _)) => {
+                idle = false;
+                let parked = parked.clone();
+                let timeout = config.pair_timeout;
+                thread::spawn(move || {
+                    let _ = client.set_nonblocking(false);
+                    match take_parked(&parked, timeout) {
+                        Some(host) => {
+                            let tap = stats::global().open(client.peer_addr().ok());
+                            splice(client, host, Some(tap));
+                        }
+                        None => {
+                            let _ = client.shutdown(Shutdown::Both);
+                        }
+                    }
+                });
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+
+        if idle {
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+    Ok(())
+}
+
+/// Checks the hello of a freshly accepted host connection, proves to the
+/// host that this end knows `secret` and challenges it to prove the same.
+fn accept_host(mut stream: TcpStream, secret: &str) -> io::Result<TcpStream> {
+    stream.set_nonblocking(false)?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(HELLO_TIMEOUT))?;
+    let mut hello = [0u8; 8];
+    stream.read_exact(&mut hello)?;
+    if &hello != HELLO {
+        let _ = stream.shutdown(Shutdown::Both);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected hello"));
+    }
+    let mut host_nonce = [0u8; PROOF_LEN];
+    stream.read_exact(&mut host_nonce)?;
+    let challenge = nonce()?;
+    stream.write_all(&challenge)?;
+    stream.write_all(&proof(secret, RENDEZVOUS_PROOF, &host_nonce, &challenge))?;
+    let mut answer = [0u8; PROOF_LEN];
+    stream.read_exact(&mut answer)?;
+    if !verify(secret, HOST_PROOF, &host_nonce, &challenge, &answer) {
+        let _ = stream.shutdown(Shutdown::Both);
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "wrong secret"));
+    }
+    stream.set_read_timeout(None)?;
+    Ok(stream)
+}
+
+/// Pops the oldest parked host connection that is still alive.
+fn take_parked(
+    parked: &(Mutex<VecDeque<TcpStream>>, Condvar),
+    timeout: Duration,
+) -> Option<TcpStream> {
+    let (queue, ready) = parked;
+    let deadline = Instant::now() + timeout;
+    let mut queue = queue.lock().unwrap();
+    loop {
+        while let Some(host) = queue.pop_front() {
+            if is_alive(&host) {
+                return Some(host);
+            }
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return None;
+        }
+        queue = ready.wait_timeout(queue, deadline - now).unwrap().0;
+    }
+}
+
+/// A parked connection never carries data, so any readable byte or EOF means
+/// the host went away.
+fn is_alive(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let mut probe = [0u8; 1];
+    let alive = matches!(stream.peek(&mut probe), Err(ref e) if e.kind() == io::ErrorKind::WouldBlock);
+    alive && stream.set_nonblocking(false).is_ok()
+}
+
+/// Copies both directions between the USB/IP `client` and `server` on
+/// background threads, optionally counting traffic through `tap`.
+pub fn splice(client: TcpStream, server: TcpStream, tap: Option<ConnectionTap>) {
+    let _ = client.set_nodelay(true);
+    let _ = server.set_nodelay(true);
+    let (client2, server2) = match (client.try_clone(), server.try_clone()) {
+        (Ok(c), Ok(s)) => (c, s),
+        _ => return,
+    };
+    let tap = tap.map(Arc::new);
+    let tap2 = tap.clone();
+    thread::spawn(move || pump(client, server2, tap, Direction::ClientToServer));
+    thread::spawn(move || pump(server, client2, tap2, Direction::ServerToClient));
+}
+
+fn pump(mut from: TcpStream, mut to: TcpStream, tap: Option<Arc<ConnectionTap>>, direction: Direction) {
+    let mut buf = [0u8; 16 * 1024];
+    loop {
+        let n = match from.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        if let Some(tap) = &tap {
+            tap.feed(direction, &buf[..n]);
+        }
+        if to.write_all(&buf[..n]).is_err() {
+            break;
+        }
+    }
+    let _ = to.shutdown(Shutdown::Write);
+    let _ = from.shutdown(Shutdown::Read);
+}
+
+fn connect(addr: &str) -> io::Result<TcpStream> {
+    let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "no address resolved");
+    let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+fn sleep_unless_stopped(delay: Duration, stop: &AtomicBool) {
+    let deadline = Instant::now() + delay;
+    while !stop.load(Ordering::Relaxed) && Instant::now() < deadline {
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    const SECRET: &str = "0123456789abcdef";
+
+    fn listener() -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        (listener, addr)
+    }
+
+    /// A stand-in usbipd that echoes whatever it gets.
+    fn echo_server() -> String {
+        let (listener, addr) = listener();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                thread::spawn(move || {
+                    let mut buf = [0u8; 1024];
+                    while let Ok(n) = stream.read(&mut buf) {
+                        if n == 0 || stream.write_all(&buf[..n]).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    fn rendezvous_config(pair_timeout: Duration) -> RendezvousConfig {
+        RendezvousConfig {
+            host_listen: String::new(),
+            client_listen: String::new(),
+            pair_timeout,
+            secret: SECRET.to_string(),
+        }
+    }
+
+    /// Starts a rendezvous and returns its host and client addresses.
+    fn start_rendezvous(config: RendezvousConfig, stop: Arc<AtomicBool>) -> (String, String) {
+        let (hosts, host_addr) = listener();
+        let (clients, client_addr) = listener();
+        thread::spawn(move || rendezvous(hosts, clients, &config, &stop));
+        (host_addr, client_addr)
+    }
+
+    fn host_config(rendezvous: &str, local: &str, secret: &str) -> ReverseHostConfig {
+        ReverseHostConfig {
+            rendezvous: rendezvous.to_string(),
+            local: local.to_string(),
+            secret: secret.to_string(),
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum_and_resets() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        let delays: Vec<u64> = (0..5).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 5, 5]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn proof_depends_on_side_nonces_and_secret() {
+        let (a, b) = (nonce().unwrap(), nonce().unwrap());
+        assert_ne!(a, b);
+        let host = proof(SECRET, HOST_PROOF, &a, &b);
+        assert!(verify(SECRET, HOST_PROOF, &a, &b, &host));
+        assert!(!verify(SECRET, RENDEZVOUS_PROOF, &a, &b, &host));
+        assert!(!verify(SECRET, HOST_PROOF, &b, &a, &host));
+        assert!(!verify("fedcba9876543210", HOST_PROOF, &a, &b, &host));
+        assert!(!verify(SECRET, HOST_PROOF, &a, &b, &host[..16]));
+    }
+
+    #[test]
+    fn client_reaches_usbipd_through_a_parked_host() {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (host_addr, client_addr) = start_rendezvous(rendezvous_config(Duration::from_secs(5)), stop.clone());
+        let config = host_config(&host_addr, &echo_server(), SECRET);
+        let host_stop = stop.clone();
+        thread::spawn(move || run_host(&config, &host_stop));
+
+        let mut client = TcpStream::connect(&client_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(b"OP_REQ_IMPORT").unwrap();
+        let mut reply = [0u8; 13];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"OP_REQ_IMPORT");
+        stop.store(true, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn host_with_the_wrong_secret_is_turned_away() {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (host_addr, client_addr) = start_rendezvous(rendezvous_config(Duration::from_millis(500)), stop.clone());
+
+        let mut host = TcpStream::connect(&host_addr).unwrap();
+        host.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let host_nonce = nonce().unwrap();
+        host.write_all(HELLO).unwrap();
+        host.write_all(&host_nonce).unwrap();
+        let (mut challenge, mut answer) = ([0u8; PROOF_LEN], [0u8; PROOF_LEN]);
+        host.read_exact(&mut challenge).unwrap();
+        host.read_exact(&mut answer).unwrap();
+        assert!(verify(SECRET, RENDEZVOUS_PROOF, &host_nonce, &challenge, &answer));
+        host.write_all(&proof("not the shared secret", HOST_PROOF, &host_nonce, &challenge)).unwrap();
+        assert!(matches!(host.read(&mut [0u8; 1]), Ok(0) | Err(_)));
+
+        // Nothing got parked, so a client is closed once the pair timeout runs out.
+        let mut client = TcpStream::connect(&client_addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(b"x").unwrap();
+        assert!(matches!(client.read(&mut [0u8; 1]), Ok(0) | Err(_)));
+        stop.store(true, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn host_refuses_a_rendezvous_without_the_secret() {
+        let (listener, addr) = listener();
+        let impostor = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let mut hello = [0u8; 8 + PROOF_LEN];
+            stream.read_exact(&mut hello).unwrap();
+            let challenge = nonce().unwrap();
+            stream.write_all(&challenge).unwrap();
+            stream.write_all(&proof("not the shared secret", RENDEZVOUS_PROOF, &hello[8..], &challenge)).unwrap();
+            // The host hangs up instead of answering.
+            let mut rest = Vec::new();
+            let _ = stream.read_to_end(&mut rest);
+            rest
+        });
+        let error = park_at_rendezvous(&host_config(&addr, "127.0.0.1:1", SECRET), &AtomicBool::new(false)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert!(impostor.join().unwrap().is_empty());
+    }
+
+    #[test]
+    fn host_backs_off_when_the_rendezvous_keeps_closing() {
+        let (listener, addr) = listener();
+        let dials = Arc::new(AtomicUsize::new(0));
+        let counted = dials.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                counted.fetch_add(1, Ordering::Relaxed);
+                drop(stream);
+            }
+        });
+        let stop = Arc::new(AtomicBool::new(false));
+        let host_stop = stop.clone();
+        let config = host_config(&addr, "127.0.0.1:1", SECRET);
+        let host = thread::spawn(move || run_host(&config, &host_stop));
+        thread::sleep(Duration::from_millis(1000));
+        stop.store(true, Ordering::Relaxed);
+        host.join().unwrap().unwrap();
+        // 100 + 200 + 400 ms of backoff fit in a second, with room for slow runs.
+        let dials = dials.load(Ordering::Relaxed);
+        assert!((2..=6).contains(&dials), "dialed {} times", dials);
+    }
+
+    #[test]
+    fn neither_side_runs_without_a_secret() {
+        let stop = AtomicBool::new(false);
+        let config = host_config("127.0.0.1:1", "127.0.0.1:1", "");
+        assert_eq!(run_host(&config, &stop).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        let config = RendezvousConfig { secret: String::new(), ..rendezvous_config(Duration::from_secs(1)) };
+        assert_eq!(run_rendezvous(&config, &stop).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,314 @@
+//! Auto-share rules: bind devices as soon as they appear.
+//!
+//! Rules are checked in file order and the first match wins, so an `ignore`
+//! rule placed early can exempt a device from a broader `bind` rule below it.
+use std::fmt;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::device_list::{self, UsbipDevice};
+use crate::reservations::Reservations;
+use crate::runner::{CommandError, CommandRunner};
+use crate::watcher::DeviceEvent;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RuleAction {
+    Bind,
+    BindForce,
+    Ignore,
+}
+
+impl fmt::Display for RuleAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            RuleAction::Bind => "bind",
+            RuleAction::BindForce => "bind --force",
+            RuleAction::Ignore => "ignore",
+        })
+    }
+}
+
+/// One `[[auto_share.rules]]` entry in the config file. Every criterion that is set must match.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vidpid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    /// Regular expression matched against the device description.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub busid: Option<String>,
+    pub action: RuleAction,
+}
+
+#[derive(Debug)]
+pub enum RulesError {
+    /// A rule that can't be used, with its position in `auto_share.rules`.
+    Invalid { index: usize, name: String, message: String },
+}
+
+impl fmt::Display for RulesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RulesError::Invalid { index, name, message } => {
+                write!(f, "auto_share.rules[{}] ({}): {}", index, name, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RulesError {}
+
+struct CompiledRule {
+    rule: Rule,
+    description: Option<Regex>,
+}
+
+impl CompiledRule {
+    fn matches(&self, device: &UsbipDevice) -> bool {
+        let rule = &self.rule;
+        // Persisted-only entries have no busid and can't be bound by busid.
+        !device.busid.is_empty()
+            && rule.vidpid.as_ref().is_none_or(|v| v.eq_ignore_ascii_case(&device.vidpid))
+            && rule.serial.as_ref().is_none_or(|s| device.serial() == Some(s.as_str()))
+            && rule.busid.as_ref().is_none_or(|b| *b == device.busid)
+            && self.description.as_ref().is_none_or(|re| re.is_match(&device.device))
+    }
+}
+
+/// What a rule would do to the current devices.
+#[derive(Debug, Clone, Serialize)]
+pub struct DryRunEntry {
+    pub rule: String,
+    pub action: RuleAction,
+    /// Busids the rule matches and that no earlier rule claimed.
+    pub devices: Vec<String>,
+}
+
+#[derive(Default)]
+pub struct RuleSet {
+    rules: Vec<CompiledRule>,
+}
+
+impl RuleSet {
+    pub fn new(rules: Vec<Rule>) -> Result<Self, RulesError> {
+        let mut compiled = Vec::with_capacity(rules.len());
+        for (index, rule) in rules.into_iter().enumerate() {
+            let invalid = |message: String| RulesError::Invalid { index, name: rule.name.clone(), message };
+            if rule.vidpid.is_none() && rule.serial.is_none() && rule.description.is_none() && rule.busid.is_none() {
+                return Err(invalid(String::from("needs at least one of vidpid, serial, description or busid")));
+            }
+            if let Some(vidpid) = &rule.vidpid
+                && !is_vidpid(vidpid)
+            {
+                return Err(invalid(format!("vidpid '{}' is not in the form 1234:abcd", vidpid)));
+            }
+            let description = match &rule.description {
+                Some(pattern) => Some(Regex::new(pattern).map_err(|e| invalid(format!("description: {}", e)))?),
+                None => None,
+            };
+            compiled.push(CompiledRule { rule, description });
+        }
+        Ok(RuleSet { rules: compiled })
+    }
+
+    /// The rules in the `[auto_share]` section of the current config.
+    pub fn load() -> Result<Self, RulesError> {
+        Self::new(config::current().auto_share.rules)
+    }
+
+    pub fn rules(&self) -> impl Iterator<Item = &Rule> {
+        self.rules.iter().map(|r| &r.rule)
+    }
+
+    /// The first rule matching `device`.
+    pub fn evaluate(&self, device: &UsbipDevice) -> Option<&Rule> {
+        self.rules.iter().find(|r| r.matches(device)).map(|r| &r.rule)
+    }
+
+    /// Shows, for every rule, the devices it would act on right now.
+    pub fn dry_run(&self, devices: &[UsbipDevice]) -> Vec<DryRunEntry> {
+        let mut entries: Vec<DryRunEntry> = self
+            .rules
+            .iter()
+            .map(|r| DryRunEntry { rule: r.rule.name.clone(), action: r.rule.action, devices: Vec::new() })
+            .collect();
+        for device in devices {
+            if let Some(index) = self.rules.iter().position(|r| r.matches(device)) {
+                entries[index].devices.push(device.busid.clone());
+            }
+        }
+        entries
+    }
+
+    /// Applies the matching rule to a device that just appeared, binding
+    /// it for `user`. A device reserved by someone else is left alone.
+    ///
+    /// Returns the rule that bound the device, or `None` if nothing was done.
+    pub fn apply(
+        &self,
+        runner: &dyn CommandRunner,
+        reservations: &Reservations,
+        user: &str,
+        event: &DeviceEvent,
+    ) -> Result<Option<&Rule>, CommandError> {
+        let DeviceEvent::Added(device) = event else { return Ok(None) };
+        if device.is_shared() {
+            return Ok(None);
+        }
+        match self.evaluate(device) {
+            Some(rule) if rule.action != RuleAction::Ignore => {
+                reservations.check(&device.busid, user).map_err(|e| CommandError::Failed(e.to_string()))?;
+                device_list::bind_device(runner, &device.busid, rule.action == RuleAction::BindForce)?;
+                Ok(Some(rule))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+fn is_vidpid(value: &str) -> bool {
+    match value.split_once(':') {
+        Some((vid, pid)) => [vid, pid].iter().all(|p| p.len() == 4 && p.chars().all(|c| c.is_ascii_hexdigit())),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_list::DeviceState;
+    use crate::reservations::RESERVATIONS_FILE;
+    use crate::testing::{self, FakeRunner, TempDir};
+    use std::time::Duration;
+
+    fn rule(name: &str, action: RuleAction) -> Rule {
+        Rule { name: name.to_string(), vidpid: None, serial: None, description: None, busid: None, action }
+    }
+
+    fn probe() -> UsbipDevice {
+        let mut device = testing::device("1-1", "0483:374B", DeviceState::NotShared);
+        device.device = String::from("ST-Link Debug, USB Mass Storage");
+        device.instance_id = Some(String::from("USB\\VID_0483&PID_374B\\0671FF485550755187101234"));
+        device
+    }
+
+    fn mouse() -> UsbipDevice {
+        let mut device = testing::device("1-2", "046d:c52b", DeviceState::NotShared);
+        device.device = String::from("Logitech USB Input Device");
+        device.instance_id = Some(String::from("USB\\VID_046D&PID_C52B\\5&2A5E5C3&0&2"));
+        device
+    }
+
+    #[test]
+    fn every_criterion_set_must_match() {
+        let rules = RuleSet::new(vec![Rule {
+            vidpid: Some(String::from("0483:374b")),
+            serial: Some(String::from("0671FF485550755187101234")),
+            description: Some(String::from("^ST-Link")),
+            busid: Some(String::from("1-1")),
+            ..rule("probe", RuleAction::Bind)
+        }])
+        .unwrap();
+        assert_eq!(rules.evaluate(&probe()).map(|r| r.name.as_str()), Some("probe"));
+
+        let mut elsewhere = probe();
+        elsewhere.busid = String::from("2-1");
+        assert!(rules.evaluate(&elsewhere).is_none());
+        let mut other_serial = probe();
+        other_serial.instance_id = Some(String::from("USB\\VID_0483&PID_374B\\066DFF"));
+        assert!(rules.evaluate(&other_serial).is_none());
+        assert!(rules.evaluate(&mouse()).is_none());
+    }
+
+    #[test]
+    fn generated_instance_ids_have_no_serial_to_match() {
+        let rules = RuleSet::new(vec![Rule { serial: Some(String::from("5&2A5E5C3&0&2")), ..rule("mouse", RuleAction::Bind) }]).unwrap();
+        assert!(rules.evaluate(&mouse()).is_none());
+    }
+
+    #[test]
+    fn the_first_matching_rule_wins() {
+        let rules = RuleSet::new(vec![
+            Rule { busid: Some(String::from("1-2")), ..rule("keep mouse", RuleAction::Ignore) },
+            Rule { description: Some(String::from("(?i)usb")), ..rule("everything", RuleAction::BindForce) },
+        ])
+        .unwrap();
+        assert_eq!(rules.evaluate(&mouse()).map(|r| r.action), Some(RuleAction::Ignore));
+        assert_eq!(rules.evaluate(&probe()).map(|r| r.action), Some(RuleAction::BindForce));
+
+        let entries = rules.dry_run(&[probe(), mouse()]);
+        assert_eq!(entries.iter().map(|e| e.devices.clone()).collect::<Vec<_>>(), [vec![String::from("1-2")], vec![String::from("1-1")]]);
+    }
+
+    #[test]
+    fn persisted_entries_never_match() {
+        let mut persisted = testing::device("", "0483:374b", DeviceState::Persisted);
+        persisted.guid = Some(String::from("5d1f1b2a-0000-0000-0000-000000000000"));
+        let rules = RuleSet::new(vec![Rule { vidpid: Some(String::from("0483:374b")), ..rule("probe", RuleAction::Bind) }]).unwrap();
+        assert!(rules.evaluate(&persisted).is_none());
+    }
+
+    #[test]
+    fn unusable_rules_are_rejected_with_their_position() {
+        let cases = [
+            (rule("empty", RuleAction::Bind), "needs at least one"),
+            (Rule { vidpid: Some(String::from("0483-374b")), ..rule("dash", RuleAction::Bind) }, "not in the form"),
+            (Rule { vidpid: Some(String::from("483:374b")), ..rule("short", RuleAction::Bind) }, "not in the form"),
+            (Rule { description: Some(String::from("(")), ..rule("regex", RuleAction::Bind) }, "description:"),
+        ];
+        for (bad, expected) in cases {
+            let name = bad.name.clone();
+            let fine = Rule { busid: Some(String::from("1-1")), ..rule("fine", RuleAction::Bind) };
+            let message = RuleSet::new(vec![fine, bad]).err().expect("accepted").to_string();
+            assert!(message.starts_with(&format!("auto_share.rules[1] ({}): ", name)), "{}", message);
+            assert!(message.contains(expected), "{}", message);
+        }
+    }
+
+    #[test]
+    fn apply_binds_new_unshared_devices_only() {
+        let rules = RuleSet::new(vec![
+            Rule { vidpid: Some(String::from("0483:374b")), ..rule("probe", RuleAction::BindForce) },
+            Rule { vidpid: Some(String::from("046d:c52b")), ..rule("mouse", RuleAction::Ignore) },
+        ])
+        .unwrap();
+        let runner = FakeRunner::new().ok("usbipd bind", "");
+        let dir = TempDir::new("rules");
+        let store = Reservations::at(dir.join(RESERVATIONS_FILE));
+
+        let applied = rules.apply(&runner, &store, "ana", &DeviceEvent::Added(probe())).unwrap();
+        assert_eq!(applied.map(|r| r.name.as_str()), Some("probe"));
+        assert!(rules.apply(&runner, &store, "ana", &DeviceEvent::Added(mouse())).unwrap().is_none());
+
+        let mut shared = probe();
+        shared.state = DeviceState::Shared;
+        assert!(rules.apply(&runner, &store, "ana", &DeviceEvent::Added(shared)).unwrap().is_none());
+        let changed = DeviceEvent::StateChanged { device: probe(), old: DeviceState::Shared };
+        assert!(rules.apply(&runner, &store, "ana", &changed).unwrap().is_none());
+
+        assert_eq!(runner.calls_to("usbipd bind"), ["usbipd bind --force --busid 1-1"]);
+    }
+
+    #[test]
+    fn apply_leaves_devices_reserved_by_others_alone() {
+        let rules = RuleSet::new(vec![Rule { vidpid: Some(String::from("0483:374b")), ..rule("probe", RuleAction::Bind) }]).unwrap();
+        let runner = FakeRunner::new().ok("usbipd bind", "");
+        let dir = TempDir::new("rules");
+        let store = Reservations::at(dir.join(RESERVATIONS_FILE));
+        store.reserve("1-1", "bob", Duration::from_secs(3600)).unwrap();
+
+        let error = rules.apply(&runner, &store, "ana", &DeviceEvent::Added(probe())).unwrap_err();
+        assert!(error.to_string().contains("reserved by bob"), "{}", error);
+        assert!(runner.calls_to("usbipd bind").is_empty());
+        assert!(rules.apply(&runner, &store, "bob", &DeviceEvent::Added(probe())).unwrap().is_some());
+        assert_eq!(runner.calls_to("usbipd bind"), ["usbipd bind --busid 1-1"]);
+    }
+}
@@ -0,0 +1,137 @@
+//! Runs external programs (usbipd, winget, netsh) behind a trait so the
+//! core logic can be driven by something other than real processes.
+use std::fmt;
+use std::io;
+use std::process::{Command, Stdio};
+use std::sync::RwLock;
+use std::time::Instant;
+
+#[cfg(windows)]
+use std::os::windows::process::CommandExt;
+
+use crate::metrics;
+use crate::version::{self, Version};
+
+#[cfg(windows)]
+const CREATE_NO_WINDOW: u32 = 0x08000000;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandOutput {
+    /// Exit code, `None` if the process was killed by a signal.
+    pub code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+
+    /// stderr if there is any, otherwise stdout, for error messages.
+    pub fn message(&self) -> String {
+        let text = if self.stderr.trim().is_empty() { &self.stdout } else { &self.stderr };
+        text.trim().to_string()
+    }
+}
+
+#[derive(Debug)]
+pub enum CommandError {
+    /// The program isn't installed or not on the PATH.
+    NotFound(String),
+    /// The program couldn't be started for another reason.
+    Io(io::Error),
+    /// The program ran and reported a failure.
+    Failed(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::NotFound(program) => write!(f, "{} could not be found. Is it installed?", program),
+            CommandError::Io(e) => write!(f, "{}", e),
+            CommandError::Failed(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl CommandError {
+    pub fn from_io(program: &str, e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::NotFound {
+            CommandError::NotFound(program.to_string())
+        } else {
+            CommandError::Io(e)
+        }
+    }
+}
+
+pub trait CommandRunner {
+    /// Runs `program` to completion and captures its output.
+    ///
+    /// An `Err` means the program could not be started at all, typically
+    /// because it isn't installed.
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput>;
+
+    /// Where [`version::capabilities`] remembers the usbipd version this
+    /// runner reported.
+    fn version_cache(&self) -> &RwLock<Option<Version>> {
+        version::shared_cache()
+    }
+}
+
+/// Runs real processes, without flashing a console window on Windows.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+        let mut command = Command::new(program);
+        command.args(args).stdin(Stdio::null());
+        #[cfg(windows)]
+        command.creation_flags(CREATE_NO_WINDOW);
+        let output = command.output()?;
+        Ok(CommandOutput {
+            code: output.status.code(),
+            stdout: decode_output(&output.stdout),
+            stderr: decode_output(&output.stderr),
+        })
+    }
+}
+
+/// Decodes program output, which is UTF-8 except for a few Windows tools
+/// such as `wsl.exe` that write UTF-16LE, with or without a byte order mark.
+pub fn decode_output(bytes: &[u8]) -> String {
+    let utf16 = match bytes {
+        [0xFF, 0xFE, rest @ ..] => Some(rest),
+        // UTF-8 text never contains NUL; UTF-16LE ASCII has one in every second byte.
+        [_, 0, ..] if bytes.len().is_multiple_of(2) => Some(bytes),
+        _ => None,
+    };
+    match utf16 {
+        Some(bytes) => {
+            let units: Vec<u16> = bytes.chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])).collect();
+            String::from_utf16_lossy(&units)
+        }
+        None => String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
+/// Runs `program` and turns a non-zero exit status into [`CommandError::Failed`].
+pub fn run_checked(runner: &dyn CommandRunner, program: &str, args: &[&str]) -> Result<CommandOutput, CommandError> {
+    let output = runner.run(program, args).map_err(|e| CommandError::from_io(program, e))?;
+    if output.success() {
+        Ok(output)
+    } else {
+        Err(CommandError::Failed(output.message()))
+    }
+}
+
+/// Runs a usbipd subcommand and records its outcome and duration.
+pub fn usbipd(runner: &dyn CommandRunner, args: &[&str]) -> Result<CommandOutput, CommandError> {
+    let started = Instant::now();
+    let result = run_checked(runner, "usbipd", args);
+    metrics::record_command(args.first().copied().unwrap_or(""), started.elapsed(), result.is_ok());
+    result
+}
@@ -0,0 +1,365 @@
+//! Finds USB/IP servers by probing a subnet with `OP_REQ_DEVLIST`.
+//!
+//! An alternative to mDNS for networks where multicast doesn't get through.
+//! Every server that answered is remembered in a cache file in the config
+//! directory, with the time it was last seen.
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::usbip_proto::{self, ExportedDevice};
+
+pub const CACHE_FILE: &str = "scan_cache.json";
+/// How long a server that stopped answering stays in the cache.
+pub const CACHE_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+#[derive(Debug, Clone)]
+pub struct ScanConfig {
+    pub port: u16,
+    /// Connect and read timeout for a single host.
+    pub timeout: Duration,
+    /// Number of hosts probed at the same time.
+    pub concurrency: usize,
+    /// Maximum connection attempts per second across all workers.
+    pub rate_per_sec: u32,
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        ScanConfig {
+            port: config::current().server.port,
+            timeout: Duration::from_millis(500),
+            concurrency: 32,
+            rate_per_sec: 200,
+        }
+    }
+}
+
+/// A server that answered the device list request.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub addr: SocketAddr,
+    pub devices: Vec<ExportedDevice>,
+    pub last_seen: SystemTime,
+}
+
+/// How long ago a server was last seen, rounded down to the largest unit
+/// that fits: "40 seconds", "12 minutes", "5 hours" or "3 days".
+pub fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    let (n, unit) = match secs {
+        s if s < 60 => (s, "second"),
+        s if s < 60 * 60 => (s / 60, "minute"),
+        s if s < 24 * 60 * 60 => (s / (60 * 60), "hour"),
+        s => (s / (24 * 60 * 60), "day"),
+    };
+    if n == 1 { format!("1 {}", unit) } else { format!("{} {}s", n, unit) }
+}
+
+/// Expands `a.b.c.d/n` into its host addresses.
+///
+/// The network and broadcast addresses are left out except for /31 and /32,
+/// which have none.
+pub fn parse_cidr(cidr: &str) -> Result<Vec<Ipv4Addr>, String> {
+    let (ip, prefix) = match cidr.split_once('/') {
+        Some((ip, prefix)) => (ip, prefix),
+        None => (cidr, "32"),
+    };
+    let ip: Ipv4Addr = ip.trim().parse().map_err(|_| format!("Invalid address: {}", ip))?;
+    let prefix: u32 = prefix
+        .trim()
+        .parse()
+        .ok()
+        .filter(|p| *p <= 32)
+        .ok_or_else(|| format!("Invalid prefix length: {}", prefix))?;
+    // Anything wider than a /16 is almost certainly a typo and would take ages.
+    if prefix < 16 {
+        return Err(format!("Refusing to scan a /{} network", prefix));
+    }
+
+    let mask = u32::MAX << (32 - prefix);
+    let network = u32::from(ip) & mask;
+    let broadcast = network | !mask;
+    let (first, last) = if prefix >= 31 { (network, broadcast) } else { (network + 1, broadcast - 1) };
+    Ok((first..=last).map(Ipv4Addr::from).collect())
+}
+
+/// Asks one server for its exported devices.
+pub fn query_devlist(addr: SocketAddr, timeout: Duration) -> io::Result<Vec<ExportedDevice>> {
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    usbip_proto::request_devlist(&mut stream)
+}
+
+/// The addresses [`scan`] probes for `cidr`.
+pub fn targets(cidr: &str, port: u16) -> Result<Vec<SocketAddr>, String> {
+    Ok(parse_cidr(cidr)?.into_iter().map(|ip| SocketAddr::new(IpAddr::V4(ip), port)).collect())
+}
+
+/// Probes every host of `cidr` on `config.port`.
+pub fn scan(cidr: &str, config: &ScanConfig) -> Result<Vec<ServerInfo>, String> {
+    Ok(scan_targets(&targets(cidr, config.port)?, config))
+}
+
+/// Probes an explicit list of addresses and returns the ones that answered,
+/// in the order they were given.
+pub fn scan_targets(targets: &[SocketAddr], config: &ScanConfig) -> Vec<ServerInfo> {
+    let next = Mutex::new(0usize);
+    let pacer = Pacer::new(config.rate_per_sec);
+    let found = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+        for _ in 0..config.concurrency.clamp(1, targets.len().max(1)) {
+            scope.spawn(|| loop {
+                let index = {
+                    let mut next = next.lock().unwrap();
+                    let index = *next;
+                    *next += 1;
+                    index
+                };
+                let Some(&addr) = targets.get(index) else { break };
+
+                pacer.wait();
+                if let Ok(devices) = query_devlist(addr, config.timeout) {
+                    let info = ServerInfo { addr, devices, last_seen: SystemTime::now() };
+                    found.lock().unwrap().push((index, info));
+                }
+            });
+        }
+    });
+
+    let mut found = found.into_inner().unwrap();
+    found.sort_by_key(|(index, _)| *index);
+    found.into_iter().map(|(_, info)| info).collect()
+}
+
+/// Spaces connection attempts evenly to stay under the configured rate.
+struct Pacer {
+    interval: Duration,
+    next_slot: Mutex<Instant>,
+}
+
+impl Pacer {
+    fn new(rate_per_sec: u32) -> Self {
+        let interval = match rate_per_sec {
+            0 => Duration::ZERO,
+            rate => Duration::from_secs(1) / rate,
+        };
+        Pacer { interval, next_slot: Mutex::new(Instant::now()) }
+    }
+
+    fn wait(&self) {
+        let slot = {
+            let mut next = self.next_slot.lock().unwrap();
+            let slot = (*next).max(Instant::now());
+            *next = slot + self.interval;
+            slot
+        };
+        let now = Instant::now();
+        if slot > now {
+            thread::sleep(slot - now);
+        }
+    }
+}
+
+/// Remembers every server seen by previous scans.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ScanCache {
+    servers: HashMap<SocketAddr, ServerInfo>,
+}
+
+impl ScanCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the cache file. A missing or unreadable one only means the
+    /// servers get found again by the next scan.
+    pub fn load(path: &Path) -> Self {
+        fs::read_to_string(path).ok().and_then(|text| serde_json::from_str(&text).ok()).unwrap_or_default()
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string(self).unwrap_or_default())
+    }
+
+    /// Records fresh results. Servers missing from `results` keep their
+    /// previous entry and `last_seen` time.
+    pub fn update(&mut self, results: Vec<ServerInfo>) {
+        for info in results {
+            self.servers.insert(info.addr, info);
+        }
+    }
+
+    /// Drops servers not seen within `max_age`.
+    pub fn prune(&mut self, max_age: Duration) {
+        let now = SystemTime::now();
+        self.servers.retain(|_, info| {
+            now.duration_since(info.last_seen).map(|age| age <= max_age).unwrap_or(true)
+        });
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&ServerInfo> {
+        self.servers.get(addr)
+    }
+
+    /// All known servers, most recently seen first.
+    pub fn servers(&self) -> Vec<&ServerInfo> {
+        let mut servers: Vec<&ServerInfo> = self.servers.values().collect();
+        servers.sort_by(|a, b| b.last_seen.cmp(&a.last_seen).then(a.addr.cmp(&b.addr)));
+        servers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{closed_port, devlist_server, exported_device};
+    use crate::usbip_proto::OpHeader;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+
+    /// Accepts but never answers.
+    fn silent_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut held = Vec::new();
+            for stream in listener.incoming().flatten() {
+                held.push(stream);
+            }
+        });
+        addr
+    }
+
+    fn config() -> ScanConfig {
+        ScanConfig { port: 3240, timeout: Duration::from_millis(300), concurrency: 4, rate_per_sec: 0 }
+    }
+
+    fn info(port: u16, last_seen: SystemTime) -> ServerInfo {
+        ServerInfo { addr: SocketAddr::from(([10, 0, 0, 1], port)), devices: Vec::new(), last_seen }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("usbip_host-scanner-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn parse_cidr_leaves_out_network_and_broadcast() {
+        let hosts = parse_cidr("192.168.1.77/30").unwrap();
+        assert_eq!(hosts, [Ipv4Addr::new(192, 168, 1, 77), Ipv4Addr::new(192, 168, 1, 78)]);
+        assert_eq!(parse_cidr("10.0.0.4/31").unwrap().len(), 2);
+        assert_eq!(parse_cidr("10.0.0.4").unwrap(), [Ipv4Addr::new(10, 0, 0, 4)]);
+        assert_eq!(parse_cidr("10.0.0.0/24").unwrap().len(), 254);
+    }
+
+    #[test]
+    fn parse_cidr_rejects_bad_input() {
+        assert!(parse_cidr("10.0.0.300/24").is_err());
+        assert!(parse_cidr("10.0.0.0/33").is_err());
+        assert!(parse_cidr("10.0.0.0/x").is_err());
+        assert!(parse_cidr("10.0.0.0/8").unwrap_err().contains("/8"));
+    }
+
+    #[test]
+    fn scan_finds_every_stand_in_server_in_order() {
+        let first = devlist_server(vec![exported_device("1-1", 0x374b)]);
+        let second = devlist_server(Vec::new());
+        let third = devlist_server(vec![exported_device("2-1", 0x3748), exported_device("2-2", 0x5740)]);
+        let targets = [first, closed_port(), second, silent_server(), third];
+
+        let found = scan_targets(&targets, &config());
+        let addrs: Vec<SocketAddr> = found.iter().map(|s| s.addr).collect();
+        assert_eq!(addrs, [first, second, third]);
+        assert_eq!(found[0].devices, [exported_device("1-1", 0x374b)]);
+        assert!(found[1].devices.is_empty());
+        assert_eq!(found[2].devices.iter().map(|d| d.vidpid()).collect::<Vec<_>>(), ["0483:3748", "0483:5740"]);
+    }
+
+    #[test]
+    fn scan_respects_the_rate_limit() {
+        let targets: Vec<SocketAddr> = (0..5).map(|_| closed_port()).collect();
+        let started = Instant::now();
+        scan_targets(&targets, &ScanConfig { rate_per_sec: 20, ..config() });
+        // Five attempts 50 ms apart.
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn ages_are_rounded_down_to_one_unit() {
+        let cases = [
+            (0, "0 seconds"),
+            (1, "1 second"),
+            (59, "59 seconds"),
+            (60, "1 minute"),
+            (119, "1 minute"),
+            (59 * 60 + 59, "59 minutes"),
+            (60 * 60, "1 hour"),
+            (23 * 60 * 60 + 3599, "23 hours"),
+            (24 * 60 * 60, "1 day"),
+            (30 * 24 * 60 * 60, "30 days"),
+        ];
+        for (secs, text) in cases {
+            assert_eq!(format_age(Duration::from_secs(secs)), text);
+        }
+    }
+
+    #[test]
+    fn cache_keeps_servers_that_stopped_answering() {
+        let now = SystemTime::now();
+        let mut cache = ScanCache::new();
+        cache.update(vec![info(1, now - Duration::from_secs(600)), info(2, now - Duration::from_secs(600))]);
+        cache.update(vec![info(2, now)]);
+        let ports: Vec<u16> = cache.servers().iter().map(|s| s.addr.port()).collect();
+        assert_eq!(ports, [2, 1]);
+        assert_eq!(cache.get(&info(1, now).addr).unwrap().last_seen, now - Duration::from_secs(600));
+
+        cache.prune(Duration::from_secs(300));
+        assert!(cache.get(&info(1, now).addr).is_none());
+        assert!(cache.get(&info(2, now).addr).is_some());
+    }
+
+    #[test]
+    fn cache_survives_a_save_and_load() {
+        let path = temp_path("cache.json");
+        let mut cache = ScanCache::new();
+        let mut server = info(3240, SystemTime::now());
+        server.devices.push(exported_device("1-1", 0x374b));
+        cache.update(vec![server.clone()]);
+        cache.save(&path).unwrap();
+
+        let loaded = ScanCache::load(&path);
+        assert_eq!(loaded.servers(), [&server]);
+        fs::write(&path, "not json").unwrap();
+        assert!(ScanCache::load(&path).servers().is_empty());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn query_devlist_reports_protocol_errors() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            if let Some(Ok(mut stream)) = listener.incoming().next() {
+                let mut request = [0u8; 8];
+                let _ = stream.read_exact(&mut request);
+                let _ = std::io::Write::write_all(&mut stream, &OpHeader::new(0x0003).to_bytes());
+            }
+        });
+        let e = query_devlist(addr, Duration::from_secs(2)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,62 @@
+//! Installing, upgrading and querying usbipd-win.
+use std::sync::{OnceLock, RwLock};
+
+use crate::installer::InstallError;
+use crate::package_manager::{self, Action, PackageState, Transition};
+use crate::runner::{CommandError, CommandRunner};
+use crate::version::{self, Version};
+
+fn state_cache() -> &'static RwLock<Option<PackageState>> {
+    static STATE: OnceLock<RwLock<Option<PackageState>>> = OnceLock::new();
+    STATE.get_or_init(|| RwLock::new(None))
+}
+
+fn set_state(state: Option<PackageState>) {
+    *state_cache().write().unwrap() = state;
+}
+
+/// The last known package state, `Installing` while an operation runs.
+pub fn last_package_state() -> Option<PackageState> {
+    state_cache().read().unwrap().clone()
+}
+
+/// Asks the package manager whether usbipd-win is installed and whether an upgrade is available.
+pub fn package_state(runner: &dyn CommandRunner) -> Result<PackageState, InstallError> {
+    let state = package_manager::from_config(runner)?.state();
+    set_state(state.as_ref().ok().cloned());
+    state
+}
+
+/// The newest usbipd-win the package manager offers.
+pub fn available_version(runner: &dyn CommandRunner) -> Result<Option<Version>, InstallError> {
+    package_manager::from_config(runner)?.available_version()
+}
+
+/// Runs an operation through the first available package manager,
+/// reporting `Installing` until it's done.
+fn apply(runner: &dyn CommandRunner, action: Action) -> Result<Transition, InstallError> {
+    let manager = package_manager::from_config(runner)?;
+    set_state(Some(PackageState::Installing));
+    let result = manager.apply(action);
+    version::forget(runner);
+    set_state(result.as_ref().ok().map(|t| t.to.clone()));
+    result
+}
+
+/// Installs usbipd-win the way `[installer]` in the config file says.
+pub fn install_usbipd(runner: &dyn CommandRunner) -> Result<Transition, InstallError> {
+    apply(runner, Action::Install)
+}
+
+pub fn upgrade_usbipd(runner: &dyn CommandRunner) -> Result<Transition, InstallError> {
+    apply(runner, Action::Upgrade)
+}
+
+pub fn uninstall_usbipd(runner: &dyn CommandRunner) -> Result<Transition, InstallError> {
+    apply(runner, Action::Uninstall)
+}
+
+/// The version reported by `usbipd --version`.
+pub fn usbipd_version(runner: &dyn CommandRunner) -> Result<Version, CommandError> {
+    version::detect(runner)
+}
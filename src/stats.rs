@@ -0,0 +1,803 @@
+//! Per-device and per-connection USB/IP traffic statistics.
+//!
+//! A [`ConnectionTap`] watches the bytes flowing through a proxied
+//! connection, decodes just enough of the URB headers to count transfers and
+//! time them, and feeds the totals into [`TrafficStats`].
+//!
+//! Only a process that proxies USB/IP connections, such as `usbipctl reverse`,
+//! has counters of its own. It runs a [`Publisher`] that writes them to the
+//! `traffic` directory next to the config file, and [`current`] adds those to
+//! the local ones for the device list, the metrics endpoint and leases.
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::usbip_proto::{BUSID_SIZE, DEVICE_SIZE, OP_REP_IMPORT, OP_REQ_IMPORT, OpHeader, c_string};
+
+const USBIP_CMD_SUBMIT: u32 = 1;
+const USBIP_CMD_UNLINK: u32 = 2;
+const USBIP_RET_SUBMIT: u32 = 3;
+const USBIP_RET_UNLINK: u32 = 4;
+const URB_HEADER_SIZE: usize = 48;
+const ISO_DESCRIPTOR_SIZE: usize = 16;
+const USBIP_DIR_IN: u32 = 1;
+
+/// Beyond this many URBs waiting for a reply the oldest is forgotten, so a
+/// peer that never answers can't grow the table without bound.
+const MAX_PENDING: usize = 4096;
+/// The most ISO packets usbip puts in one URB; more means the stream is garbage.
+const MAX_ISO_PACKETS: u32 = 1024;
+
+/// Directory in the config directory that proxying processes publish to.
+pub const SHARED_DIR: &str = "traffic";
+/// How often a [`Publisher`] writes its counters.
+pub const PUBLISH_INTERVAL: Duration = Duration::from_secs(2);
+/// Published counters older than this belong to a process that has stopped.
+const SHARED_MAX_AGE: Duration = Duration::from_secs(15);
+const STOP_POLL: Duration = Duration::from_millis(100);
+
+/// Upper bounds of the latency buckets in microseconds; the last bucket is open.
+pub const LATENCY_BUCKETS_US: [u64; 11] =
+    [100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LatencyHistogram {
+    /// One count per entry of [`LATENCY_BUCKETS_US`] plus the overflow bucket.
+    pub buckets: [u64; LATENCY_BUCKETS_US.len() + 1],
+    pub count: u64,
+    pub sum_us: u64,
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let us = latency.as_micros().min(u64::MAX as u128) as u64;
+        let index = LATENCY_BUCKETS_US.iter().position(|&b| us <= b).unwrap_or(LATENCY_BUCKETS_US.len());
+        self.buckets[index] += 1;
+        self.count += 1;
+        self.sum_us = self.sum_us.saturating_add(us);
+    }
+
+    fn add(&mut self, other: &LatencyHistogram) {
+        for (bucket, n) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *bucket += n;
+        }
+        self.count += other.count;
+        self.sum_us = self.sum_us.saturating_add(other.sum_us);
+    }
+
+    /// Upper bound of the bucket holding the given percentile (0.0..=1.0),
+    /// or `None` for an empty or overflowing result.
+    pub fn percentile_us(&self, p: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((self.count as f64) * p).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return LATENCY_BUCKETS_US.get(i).copied();
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrafficCounters {
+    pub urbs: u64,
+    /// Payload of IN transfers, device to client.
+    pub bytes_in: u64,
+    /// Payload of OUT transfers, client to device.
+    pub bytes_out: u64,
+    /// Completed URBs with a non-zero status.
+    pub errors: u64,
+    pub unlinks: u64,
+    pub latency: LatencyHistogram,
+}
+
+impl TrafficCounters {
+    fn add(&mut self, other: &TrafficCounters) {
+        self.urbs += other.urbs;
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        self.errors += other.errors;
+        self.unlinks += other.unlinks;
+        self.latency.add(&other.latency);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionStats {
+    pub id: u64,
+    pub peer: Option<SocketAddr>,
+    pub busid: Option<String>,
+    pub counters: TrafficCounters,
+    /// Set once the tap could no longer follow the URB stream; the counters
+    /// stop there.
+    #[serde(default)]
+    pub untracked: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatsSnapshot {
+    pub devices: HashMap<String, TrafficCounters>,
+    pub connections: Vec<ConnectionStats>,
+}
+
+impl StatsSnapshot {
+    /// Adds another process's counters to these.
+    pub fn merge(&mut self, other: StatsSnapshot) {
+        for (busid, counters) in &other.devices {
+            self.devices.entry(busid.clone()).or_default().add(counters);
+        }
+        self.connections.extend(other.connections);
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    devices: HashMap<String, TrafficCounters>,
+    connections: HashMap<u64, ConnectionStats>,
+}
+
+/// Registry of all counters. Device totals outlive the connections that
+/// produced them.
+#[derive(Default)]
+pub struct TrafficStats {
+    inner: Mutex<Inner>,
+    next_id: AtomicU64,
+}
+
+impl TrafficStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts tracking a new proxied connection.
+    pub fn open(self: &Arc<Self>, peer: Option<SocketAddr>) -> ConnectionTap {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.inner.lock().unwrap().connections.insert(
+            id,
+            ConnectionStats { id, peer, busid: None, counters: TrafficCounters::default(), untracked: false },
+        );
+        ConnectionTap {
+            id,
+            stats: self.clone(),
+            state: Mutex::new(TapState::default()),
+        }
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let inner = self.inner.lock().unwrap();
+        let mut connections: Vec<ConnectionStats> = inner.connections.values().cloned().collect();
+        connections.sort_by_key(|c| c.id);
+        StatsSnapshot { devices: inner.devices.clone(), connections }
+    }
+
+    fn update(&self, id: u64, f: impl Fn(&mut TrafficCounters)) {
+        let mut inner = self.inner.lock().unwrap();
+        let Inner { devices, connections } = &mut *inner;
+        if let Some(conn) = connections.get_mut(&id) {
+            f(&mut conn.counters);
+            if let Some(busid) = &conn.busid {
+                f(devices.entry(busid.clone()).or_default());
+            }
+        }
+    }
+
+    fn set_busid(&self, id: u64, busid: String) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.devices.entry(busid.clone()).or_default();
+        if let Some(conn) = inner.connections.get_mut(&id) {
+            conn.busid = Some(busid);
+        }
+    }
+
+    fn set_untracked(&self, id: u64) {
+        if let Some(conn) = self.inner.lock().unwrap().connections.get_mut(&id) {
+            conn.untracked = true;
+        }
+    }
+
+    fn close(&self, id: u64) {
+        self.inner.lock().unwrap().connections.remove(&id);
+    }
+}
+
+/// The process-wide registry shown in the device list.
+pub fn global() -> &'static Arc<TrafficStats> {
+    static STATS: OnceLock<Arc<TrafficStats>> = OnceLock::new();
+    STATS.get_or_init(|| Arc::new(TrafficStats::new()))
+}
+
+pub fn shared_dir() -> PathBuf {
+    config::config_dir().join(SHARED_DIR)
+}
+
+/// What a [`Publisher`] writes.
+#[derive(Serialize, Deserialize)]
+struct Published {
+    /// Milliseconds since the Unix epoch.
+    written_at: u64,
+    snapshot: StatsSnapshot,
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+fn publish_once(stats: &TrafficStats, path: &Path) -> io::Result<()> {
+    let published = Published { written_at: millis(SystemTime::now()), snapshot: stats.snapshot() };
+    // Written aside and renamed, so readers never see half a file.
+    let temp = path.with_extension("tmp");
+    fs::write(&temp, serde_json::to_string(&published).unwrap_or_default())?;
+    fs::rename(&temp, path)
+}
+
+/// Writes a registry's counters to the shared directory until dropped.
+pub struct Publisher {
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Publisher {
+    /// Publishes `stats` as `<dir>/<pid>.json` every `interval`.
+    pub fn start(stats: Arc<TrafficStats>, dir: &Path, interval: Duration) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}.json", std::process::id()));
+        publish_once(&stats, &path)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let (path, stop) = (path.clone(), stop.clone());
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let deadline = Instant::now() + interval;
+                    while !stop.load(Ordering::Relaxed) && Instant::now() < deadline {
+                        thread::sleep(STOP_POLL.min(interval));
+                    }
+                    let _ = publish_once(&stats, &path);
+                }
+            })
+        };
+        Ok(Publisher { path, stop, handle: Some(handle) })
+    }
+}
+
+impl Drop for Publisher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Adds up the counters published under `dir` within [`SHARED_MAX_AGE`] of
+/// `now`, leaving out the file of process `skip`. Files left behind by
+/// processes that were killed are removed.
+pub fn read_shared(dir: &Path, now: SystemTime, skip: Option<u32>) -> StatsSnapshot {
+    let mut total = StatsSnapshot::default();
+    let Ok(entries) = fs::read_dir(dir) else { return total };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Some(pid) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse::<u32>().ok()) else { continue };
+        if Some(pid) == skip || path.extension().is_none_or(|e| e != "json") {
+            continue;
+        }
+        let Some(published) = fs::read_to_string(&path).ok().and_then(|text| serde_json::from_str::<Published>(&text).ok())
+        else {
+            continue;
+        };
+        if millis(now).saturating_sub(published.written_at) > SHARED_MAX_AGE.as_millis() as u64 {
+            let _ = fs::remove_file(&path);
+            continue;
+        }
+        total.merge(published.snapshot);
+    }
+    total
+}
+
+/// This process's counters plus those published by every proxying process.
+pub fn current() -> StatsSnapshot {
+    let mut snapshot = global().snapshot();
+    snapshot.merge(read_shared(&shared_dir(), SystemTime::now(), Some(std::process::id())));
+    snapshot
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Bytes from the USB/IP client (`usbip attach`) towards the server.
+    ClientToServer,
+    /// Bytes from the server (usbipd) back to the client.
+    ServerToClient,
+}
+
+/// Decodes one proxied connection. Feed it every chunk in both directions.
+pub struct ConnectionTap {
+    id: u64,
+    stats: Arc<TrafficStats>,
+    state: Mutex<TapState>,
+}
+
+impl ConnectionTap {
+    pub fn feed(&self, direction: Direction, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        let mut events = Vec::new();
+        match direction {
+            Direction::ClientToServer => state.feed_client(data, &mut events),
+            Direction::ServerToClient => state.feed_server(data, &mut events),
+        }
+        drop(state);
+
+        for event in events {
+            match event {
+                Event::Imported(busid) => self.stats.set_busid(self.id, busid),
+                Event::Submitted { out_bytes } => self.stats.update(self.id, |c| {
+                    c.urbs += 1;
+                    c.bytes_out += out_bytes;
+                }),
+                Event::Completed { in_bytes, failed, latency } => self.stats.update(self.id, |c| {
+                    c.bytes_in += in_bytes;
+                    if failed {
+                        c.errors += 1;
+                    }
+                    if let Some(latency) = latency {
+                        c.latency.record(latency);
+                    }
+                }),
+                Event::Unlinked => self.stats.update(self.id, |c| c.unlinks += 1),
+                Event::Untracked => self.stats.set_untracked(self.id),
+            }
+        }
+    }
+}
+
+impl Drop for ConnectionTap {
+    fn drop(&mut self) {
+        self.stats.close(self.id);
+    }
+}
+
+enum Event {
+    Imported(String),
+    Submitted { out_bytes: u64 },
+    Completed { in_bytes: u64, failed: bool, latency: Option<Duration> },
+    Unlinked,
+    /// The URB stream stopped making sense.
+    Untracked,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// Waiting for an `OP_*` header.
+    #[default]
+    Op,
+    /// Waiting for the busid of an `OP_REQ_IMPORT`.
+    ImportBusid,
+    /// Waiting for the device record of a successful `OP_REP_IMPORT`.
+    ImportDevice,
+    /// Exchanging URB headers and payloads.
+    Urb,
+    /// Nothing we understand; let the rest through untouched.
+    Passthrough,
+}
+
+#[derive(Default)]
+struct HalfStream {
+    phase: Phase,
+    buf: Vec<u8>,
+    /// Payload bytes still to be skipped before the next header.
+    skip: usize,
+}
+
+impl HalfStream {
+    /// Returns the next `n` header bytes once they have all arrived.
+    fn take(&mut self, data: &mut &[u8], n: usize) -> Option<Vec<u8>> {
+        let skip = self.skip.min(data.len());
+        self.skip -= skip;
+        *data = &data[skip..];
+
+        let want = n - self.buf.len();
+        let got = want.min(data.len());
+        self.buf.extend_from_slice(&data[..got]);
+        *data = &data[got..];
+        if self.buf.len() == n {
+            Some(std::mem::take(&mut self.buf))
+        } else {
+            None
+        }
+    }
+
+    /// Gives up on a URB stream that stopped making sense.
+    fn lose_track(&mut self, events: &mut Vec<Event>) {
+        self.phase = Phase::Passthrough;
+        events.push(Event::Untracked);
+    }
+
+    fn header_size(&self) -> usize {
+        match self.phase {
+            Phase::Op => 8,
+            Phase::ImportBusid => BUSID_SIZE,
+            Phase::ImportDevice => DEVICE_SIZE,
+            Phase::Urb => URB_HEADER_SIZE,
+            Phase::Passthrough => 0,
+        }
+    }
+}
+
+#[derive(Default)]
+struct TapState {
+    client: HalfStream,
+    server: HalfStream,
+    /// Submitted URBs by seqnum: when they were sent and whether they are IN.
+    pending: HashMap<u32, (Instant, bool)>,
+    /// Unlink requests by their own seqnum, pointing at the URB they cancel.
+    unlinks: HashMap<u32, u32>,
+    /// A reply to a forgotten URB, which carries `.0` bytes if it was IN and
+    /// none if it was OUT. The header after it tells which.
+    unsure: Option<(usize, bool)>,
+}
+
+fn be32(b: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]])
+}
+
+/// Size of the ISO packet descriptors following a URB, if it has any, or
+/// `None` for a count usbip never sends.
+fn iso_size(number_of_packets: u32) -> Option<usize> {
+    match number_of_packets {
+        0 | 0xFFFF_FFFF => Some(0),
+        n if n <= MAX_ISO_PACKETS => Some(n as usize * ISO_DESCRIPTOR_SIZE),
+        _ => None,
+    }
+}
+
+impl TapState {
+    fn feed_client(&mut self, mut data: &[u8], events: &mut Vec<Event>) {
+        while !data.is_empty() && self.client.phase != Phase::Passthrough {
+            let size = self.client.header_size();
+            let Some(header) = self.client.take(&mut data, size) else { break };
+            match self.client.phase {
+                Phase::Op => {
+                    let op = OpHeader::from_bytes(header[..8].try_into().unwrap());
+                    self.client.phase = if op.code == OP_REQ_IMPORT { Phase::ImportBusid } else { Phase::Passthrough };
+                }
+                Phase::ImportBusid => {
+                    events.push(Event::Imported(c_string(&header)));
+                    self.client.phase = Phase::Urb;
+                }
+                Phase::Urb => match be32(&header, 0) {
+                    USBIP_CMD_SUBMIT => {
+                        let seqnum = be32(&header, 4);
+                        let is_in = be32(&header, 12) == USBIP_DIR_IN;
+                        let length = be32(&header, 24) as usize;
+                        let out_bytes = if is_in { 0 } else { length };
+                        let Some(iso) = iso_size(be32(&header, 32)) else {
+                            self.client.lose_track(events);
+                            continue;
+                        };
+                        self.client.skip = out_bytes + iso;
+                        if self.pending.len() >= MAX_PENDING
+                            && let Some(oldest) = self.pending.iter().min_by_key(|(seqnum, (sent, _))| (*sent, **seqnum)).map(|(seqnum, _)| *seqnum)
+                        {
+                            self.pending.remove(&oldest);
+                        }
+                        self.pending.insert(seqnum, (Instant::now(), is_in));
+                        events.push(Event::Submitted { out_bytes: out_bytes as u64 });
+                    }
+                    USBIP_CMD_UNLINK => {
+                        if self.unlinks.len() < MAX_PENDING {
+                            self.unlinks.insert(be32(&header, 4), be32(&header, 20));
+                        }
+                        events.push(Event::Unlinked);
+                    }
+                    _ => self.client.lose_track(events),
+                },
+                // Only the server sends a device record.
+                Phase::ImportDevice | Phase::Passthrough => self.client.phase = Phase::Passthrough,
+            }
+        }
+    }
+
+    fn feed_server(&mut self, mut data: &[u8], events: &mut Vec<Event>) {
+        while !data.is_empty() && self.server.phase != Phase::Passthrough {
+            let size = self.server.header_size();
+            let Some(header) = self.server.take(&mut data, size) else { break };
+            match self.server.phase {
+                Phase::Op => {
+                    let op = OpHeader::from_bytes(header[..8].try_into().unwrap());
+                    self.server.phase = if op.code == OP_REP_IMPORT && op.status == 0 {
+                        Phase::ImportDevice
+                    } else {
+                        Phase::Passthrough
+                    };
+                }
+                Phase::ImportDevice => self.server.phase = Phase::Urb,
+                Phase::Urb => {
+                    if let Some((in_bytes, failed)) = self.unsure.take() {
+                        if self.is_reply(&header) {
+                            events.push(Event::Completed { in_bytes: 0, failed, latency: None });
+                        } else {
+                            // Not a header, so the forgotten URB was IN and this is its payload.
+                            events.push(Event::Completed { in_bytes: in_bytes as u64, failed, latency: None });
+                            match in_bytes.checked_sub(header.len()) {
+                                Some(rest) => self.server.skip = rest,
+                                None => self.server.buf = header[in_bytes..].to_vec(),
+                            }
+                            continue;
+                        }
+                    }
+                    self.feed_server_urb(&header, events);
+                }
+                // Only the client sends a busid.
+                Phase::ImportBusid | Phase::Passthrough => self.server.phase = Phase::Passthrough,
+            }
+        }
+    }
+
+    fn feed_server_urb(&mut self, header: &[u8], events: &mut Vec<Event>) {
+        match be32(header, 0) {
+            USBIP_RET_SUBMIT => {
+                let seqnum = be32(header, 4);
+                let failed = be32(header, 20) != 0;
+                let actual = be32(header, 24) as usize;
+                let Some(iso) = iso_size(be32(header, 32)) else {
+                    self.server.lose_track(events);
+                    return;
+                };
+                match self.pending.remove(&seqnum) {
+                    Some((sent, is_in)) => {
+                        let in_bytes = if is_in { actual } else { 0 };
+                        self.server.skip = in_bytes + iso;
+                        events.push(Event::Completed { in_bytes: in_bytes as u64, failed, latency: Some(sent.elapsed()) });
+                    }
+                    None if actual == 0 && iso == 0 => events.push(Event::Completed { in_bytes: 0, failed, latency: None }),
+                    // Payload would come before the descriptors, so the next
+                    // header can't settle where ISO data ends.
+                    None if iso > 0 => self.server.lose_track(events),
+                    None => self.unsure = Some((actual, failed)),
+                }
+            }
+            USBIP_RET_UNLINK => {
+                // A non-zero status means the URB was cancelled and
+                // no RET_SUBMIT will follow for it.
+                if let Some(target) = self.unlinks.remove(&be32(header, 4))
+                    && be32(header, 20) != 0
+                {
+                    self.pending.remove(&target);
+                }
+            }
+            _ => self.server.lose_track(events),
+        }
+    }
+
+    /// Whether `header` answers a URB or unlink this tap knows about.
+    fn is_reply(&self, header: &[u8]) -> bool {
+        match be32(header, 0) {
+            USBIP_RET_SUBMIT => self.pending.contains_key(&be32(header, 4)),
+            USBIP_RET_UNLINK => self.unlinks.contains_key(&be32(header, 4)),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usbip_proto::ExportedDevice;
+
+    fn op(code: u16, status: u32) -> Vec<u8> {
+        OpHeader { version: 0x0111, code, status }.to_bytes().to_vec()
+    }
+
+    fn busid(busid: &str) -> Vec<u8> {
+        let mut buf = vec![0u8; BUSID_SIZE];
+        buf[..busid.len()].copy_from_slice(busid.as_bytes());
+        buf
+    }
+
+    fn device_record() -> Vec<u8> {
+        let mut buf = [0u8; DEVICE_SIZE];
+        buf[256..259].copy_from_slice(b"1-4");
+        ExportedDevice::from_bytes(&buf).to_bytes().to_vec()
+    }
+
+    /// A 48-byte URB header with the given words at offsets 0, 4, 12, 20, 24 and 32.
+    fn urb(command: u32, seqnum: u32, direction: u32, word20: u32, length: u32, packets: u32) -> Vec<u8> {
+        let mut buf = vec![0u8; URB_HEADER_SIZE];
+        for (at, value) in [(0, command), (4, seqnum), (12, direction), (20, word20), (24, length), (32, packets)] {
+            buf[at..at + 4].copy_from_slice(&value.to_be_bytes());
+        }
+        buf
+    }
+
+    fn submit(seqnum: u32, is_in: bool, length: u32) -> Vec<u8> {
+        let mut buf = urb(USBIP_CMD_SUBMIT, seqnum, is_in as u32, 0, length, 0);
+        if !is_in {
+            buf.extend(std::iter::repeat_n(0xAA, length as usize));
+        }
+        buf
+    }
+
+    fn ret_submit(seqnum: u32, status: u32, actual: u32, payload: usize) -> Vec<u8> {
+        let mut buf = urb(USBIP_RET_SUBMIT, seqnum, 0, status, actual, 0);
+        buf.extend(std::iter::repeat_n(0x55, payload));
+        buf
+    }
+
+    /// Feeds `data` in awkward chunk sizes so headers straddle reads.
+    fn feed(tap: &ConnectionTap, direction: Direction, data: &[u8]) {
+        for chunk in data.chunks(7) {
+            tap.feed(direction, chunk);
+        }
+    }
+
+    fn imported(stats: &Arc<TrafficStats>) -> ConnectionTap {
+        let tap = stats.open(None);
+        feed(&tap, Direction::ClientToServer, &[op(OP_REQ_IMPORT, 0), busid("1-4")].concat());
+        feed(&tap, Direction::ServerToClient, &[op(OP_REP_IMPORT, 0), device_record()].concat());
+        tap
+    }
+
+    #[test]
+    fn counts_submits_payloads_errors_and_latency() {
+        let stats = Arc::new(TrafficStats::new());
+        let tap = imported(&stats);
+        // OUT of 64 bytes, IN of up to 512 that returns 100, IN that fails.
+        feed(&tap, Direction::ClientToServer, &[submit(1, false, 64), submit(2, true, 512), submit(3, true, 8)].concat());
+        feed(&tap, Direction::ServerToClient, &[ret_submit(1, 0, 64, 0), ret_submit(2, 0, 100, 100), ret_submit(3, (-32i32) as u32, 0, 0)].concat());
+
+        let snapshot = stats.snapshot();
+        let device = &snapshot.devices["1-4"];
+        assert_eq!(device.urbs, 3);
+        assert_eq!(device.bytes_out, 64);
+        assert_eq!(device.bytes_in, 100);
+        assert_eq!(device.errors, 1);
+        assert_eq!(device.latency.count, 3);
+        assert_eq!(snapshot.connections[0].busid.as_deref(), Some("1-4"));
+        assert_eq!(snapshot.connections[0].counters, *device);
+    }
+
+    #[test]
+    fn skips_iso_descriptors() {
+        let stats = Arc::new(TrafficStats::new());
+        let tap = imported(&stats);
+        let mut iso = urb(USBIP_CMD_SUBMIT, 1, 0, 0, 32, 2);
+        iso.extend([0u8; 32 + 2 * ISO_DESCRIPTOR_SIZE]);
+        feed(&tap, Direction::ClientToServer, &[iso, submit(2, false, 4)].concat());
+        let mut reply = urb(USBIP_RET_SUBMIT, 1, 0, 0, 32, 2);
+        reply.extend([0u8; 2 * ISO_DESCRIPTOR_SIZE]);
+        feed(&tap, Direction::ServerToClient, &[reply, ret_submit(2, 0, 4, 0)].concat());
+
+        let device = &stats.snapshot().devices["1-4"];
+        assert_eq!((device.urbs, device.bytes_out, device.latency.count), (2, 36, 2));
+    }
+
+    #[test]
+    fn unlink_forgets_the_cancelled_urb() {
+        let stats = Arc::new(TrafficStats::new());
+        let tap = imported(&stats);
+        feed(&tap, Direction::ClientToServer, &[submit(1, true, 64), urb(USBIP_CMD_UNLINK, 2, 0, 1, 0, 0)].concat());
+        // -ECONNRESET: the URB was cancelled and never completes.
+        feed(&tap, Direction::ServerToClient, &urb(USBIP_RET_UNLINK, 2, 0, (-104i32) as u32, 0, 0));
+        assert!(tap.state.lock().unwrap().pending.is_empty());
+
+        // A late RET_SUBMIT for it is still followed, though without a latency.
+        feed(&tap, Direction::ClientToServer, &submit(3, false, 8));
+        feed(&tap, Direction::ServerToClient, &[ret_submit(1, 0, 64, 64), ret_submit(3, 0, 8, 0)].concat());
+        let device = &stats.snapshot().devices["1-4"];
+        assert_eq!((device.urbs, device.unlinks, device.bytes_in, device.latency.count), (2, 1, 64, 1));
+        assert_eq!(tap.state.lock().unwrap().server.phase, Phase::Urb);
+    }
+
+    #[test]
+    fn a_full_table_forgets_the_oldest_urb_and_keeps_counting() {
+        let stats = Arc::new(TrafficStats::new());
+        let tap = imported(&stats);
+        let submits: Vec<u8> = (0..=MAX_PENDING as u32).flat_map(|seqnum| submit(seqnum, true, 16)).collect();
+        tap.feed(Direction::ClientToServer, &submits);
+        assert_eq!(tap.state.lock().unwrap().pending.len(), MAX_PENDING);
+        assert!(!tap.state.lock().unwrap().pending.contains_key(&0));
+
+        // The forgotten IN reply's payload is told apart from the next header.
+        let short = ret_submit(0, 0, 20, 20);
+        feed(&tap, Direction::ServerToClient, &[short, ret_submit(1, 0, 16, 16), ret_submit(2, 0, 0, 0)].concat());
+        let snapshot = stats.snapshot();
+        assert_eq!((snapshot.devices["1-4"].bytes_in, snapshot.devices["1-4"].latency.count), (36, 2));
+        assert!(!snapshot.connections[0].untracked);
+
+        // And an OUT reply carries nothing.
+        tap.state.lock().unwrap().pending.remove(&3);
+        feed(&tap, Direction::ServerToClient, &[ret_submit(3, 0, 16, 0), ret_submit(4, 0, 16, 16)].concat());
+        let device = &stats.snapshot().devices["1-4"];
+        assert_eq!((device.bytes_in, device.latency.count), (52, 3));
+        assert_eq!(tap.state.lock().unwrap().server.phase, Phase::Urb);
+    }
+
+    #[test]
+    fn impossible_iso_counts_mark_the_connection_untracked() {
+        let stats = Arc::new(TrafficStats::new());
+        let tap = imported(&stats);
+        feed(&tap, Direction::ClientToServer, &urb(USBIP_CMD_SUBMIT, 1, 0, 0, 0, MAX_ISO_PACKETS + 1));
+        assert_eq!(tap.state.lock().unwrap().client.phase, Phase::Passthrough);
+        assert!(stats.snapshot().connections[0].untracked);
+    }
+
+    #[test]
+    fn failed_import_and_other_ops_pass_through() {
+        let stats = Arc::new(TrafficStats::new());
+        let tap = stats.open(None);
+        feed(&tap, Direction::ClientToServer, &[op(OP_REQ_IMPORT, 0), busid("2-1")].concat());
+        feed(&tap, Direction::ServerToClient, &op(OP_REP_IMPORT, 1));
+        feed(&tap, Direction::ServerToClient, &ret_submit(1, 0, 8, 8));
+        assert_eq!(stats.snapshot().devices["2-1"], TrafficCounters::default());
+
+        let devlist = stats.open(None);
+        feed(&devlist, Direction::ClientToServer, &[op(0x8005, 0), submit(1, false, 8)].concat());
+        assert_eq!(stats.snapshot().connections[1].counters, TrafficCounters::default());
+    }
+
+    #[test]
+    fn device_totals_outlive_the_connection() {
+        let stats = Arc::new(TrafficStats::new());
+        let tap = imported(&stats);
+        feed(&tap, Direction::ClientToServer, &submit(1, false, 16));
+        drop(tap);
+        let snapshot = stats.snapshot();
+        assert!(snapshot.connections.is_empty());
+        assert_eq!(snapshot.devices["1-4"].bytes_out, 16);
+    }
+
+    #[test]
+    fn histogram_buckets_and_percentiles() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.percentile_us(0.5), None);
+        for us in [50, 120, 120, 900, 400_000] {
+            histogram.record(Duration::from_micros(us));
+        }
+        assert_eq!(histogram.buckets[0], 1);
+        assert_eq!(histogram.buckets[1], 2);
+        assert_eq!(histogram.buckets[LATENCY_BUCKETS_US.len()], 1);
+        assert_eq!(histogram.percentile_us(0.5), Some(250));
+        assert_eq!(histogram.percentile_us(0.8), Some(1_000));
+        assert_eq!(histogram.percentile_us(1.0), None);
+    }
+
+    #[test]
+    fn published_counters_are_read_back_and_added_up() {
+        let dir = std::env::temp_dir().join(format!("usbip_host-stats-{}", std::process::id()));
+        let stats = Arc::new(TrafficStats::new());
+        let tap = imported(&stats);
+        feed(&tap, Direction::ClientToServer, &submit(1, false, 10));
+        let publisher = Publisher::start(stats.clone(), &dir, Duration::from_millis(50)).unwrap();
+
+        let now = SystemTime::now();
+        assert_eq!(read_shared(&dir, now, None).devices["1-4"].bytes_out, 10);
+        assert!(read_shared(&dir, now, Some(std::process::id())).devices.is_empty());
+
+        // A second process that proxied the same device.
+        let other = Published { written_at: millis(now), snapshot: stats.snapshot() };
+        fs::write(dir.join("1.json"), serde_json::to_string(&other).unwrap()).unwrap();
+        assert_eq!(read_shared(&dir, now, None).devices["1-4"].bytes_out, 20);
+
+        // One that stopped long ago is ignored and cleaned up.
+        assert_eq!(read_shared(&dir, now + Duration::from_secs(60), Some(std::process::id())).devices.len(), 0);
+        assert!(!dir.join("1.json").exists());
+
+        drop(publisher);
+        assert!(read_shared(&dir, now, None).devices.is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,191 @@
+//! Test doubles shared by the unit tests.
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crate::device_list::{DeviceState, UsbipDevice};
+use crate::runner::{CommandOutput, CommandRunner};
+use crate::usbip_proto::{self, ExportedDevice, ExportedInterface, OP_REQ_DEVLIST, OpHeader};
+use crate::version::Version;
+
+/// The usbipd release the fake runner reports unless told otherwise.
+pub const USBIPD_VERSION: &str = "4.3.0+42.Branch.master.Sha.abc";
+
+/// Answers command lines from a script and records every call.
+///
+/// A reply registered for `usbipd list` answers that command with any
+/// further arguments too; the longest matching command wins. Anything not
+/// scripted fails to start, as if the program weren't installed. Each fake
+/// caches the usbipd version it reports on its own.
+pub struct FakeRunner {
+    replies: Mutex<Vec<(String, CommandOutput)>>,
+    calls: Mutex<Vec<String>>,
+    version: RwLock<Option<Version>>,
+}
+
+impl FakeRunner {
+    /// A runner that knows only `usbipd --version`.
+    pub fn new() -> Self {
+        FakeRunner::empty().ok("usbipd --version", USBIPD_VERSION)
+    }
+
+    pub fn empty() -> Self {
+        FakeRunner { replies: Mutex::new(Vec::new()), calls: Mutex::new(Vec::new()), version: RwLock::new(None) }
+    }
+
+    pub fn ok(self, command: &str, stdout: &str) -> Self {
+        self.reply(command, 0, stdout, "")
+    }
+
+    pub fn fail(self, command: &str, code: i32, stdout: &str, stderr: &str) -> Self {
+        self.reply(command, code, stdout, stderr)
+    }
+
+    fn reply(self, command: &str, code: i32, stdout: &str, stderr: &str) -> Self {
+        self.set(command, code, stdout, stderr);
+        self
+    }
+
+    /// Replaces the reply to `command`, e.g. to change the device list mid-test.
+    pub fn set(&self, command: &str, code: i32, stdout: &str, stderr: &str) {
+        let output = CommandOutput { code: Some(code), stdout: stdout.to_string(), stderr: stderr.to_string() };
+        let mut replies = self.replies.lock().unwrap();
+        replies.retain(|(c, _)| c != command);
+        replies.push((command.to_string(), output));
+    }
+
+    /// Every command line run so far.
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// The calls that start with `prefix`.
+    pub fn calls_to(&self, prefix: &str) -> Vec<String> {
+        self.calls().into_iter().filter(|c| c.starts_with(prefix)).collect()
+    }
+}
+
+impl CommandRunner for FakeRunner {
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<CommandOutput> {
+        let line = std::iter::once(program).chain(args.iter().copied()).collect::<Vec<_>>().join(" ");
+        self.calls.lock().unwrap().push(line.clone());
+        let replies = self.replies.lock().unwrap();
+        replies
+            .iter()
+            .filter(|(command, _)| line == *command || line.starts_with(&format!("{} ", command)))
+            .max_by_key(|(command, _)| command.len())
+            .map(|(_, output)| output.clone())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, program.to_string()))
+    }
+
+    fn version_cache(&self) -> &RwLock<Option<Version>> {
+        &self.version
+    }
+}
+
+pub fn device(busid: &str, vidpid: &str, state: DeviceState) -> UsbipDevice {
+    UsbipDevice {
+        busid: busid.to_string(),
+        vidpid: vidpid.to_string(),
+        device: format!("Device {}", vidpid),
+        state,
+        persisted: state == DeviceState::Persisted,
+        guid: None,
+        instance_id: None,
+        wsl_distribution: None,
+    }
+}
+
+/// A device as a usbip server exports it, an ST-Link style `0483:<product_id>`.
+pub fn exported_device(busid: &str, product_id: u16) -> ExportedDevice {
+    ExportedDevice {
+        path: format!("/sys/devices/pci0000:00/usb1/{}", busid),
+        busid: busid.to_string(),
+        busnum: 1,
+        devnum: 2,
+        speed: 3,
+        vendor_id: 0x0483,
+        product_id,
+        bcd_device: 0x0100,
+        class: 0,
+        subclass: 0,
+        protocol: 0,
+        configuration_value: 1,
+        num_configurations: 1,
+        num_interfaces: 1,
+        interfaces: vec![ExportedInterface { class: 0xff, subclass: 0, protocol: 0 }],
+    }
+}
+
+/// A stand-in usbipd on a loopback port that answers every devlist request.
+pub fn devlist_server(devices: Vec<ExportedDevice>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            if let Ok(header) = OpHeader::read_from(&mut stream)
+                && header.code == OP_REQ_DEVLIST
+            {
+                let _ = usbip_proto::write_devlist_reply(&mut stream, &devices);
+            }
+        }
+    });
+    addr
+}
+
+/// A loopback port nothing listens on.
+pub fn closed_port() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+/// `usbipd state` output for `devices`.
+pub fn state_json(devices: &[UsbipDevice]) -> String {
+    let devices: Vec<serde_json::Value> = devices
+        .iter()
+        .map(|d| {
+            let (vid, pid) = d.vidpid.split_once(':').unwrap_or(("0000", "0000"));
+            serde_json::json!({
+                "BusId": (!d.busid.is_empty()).then_some(&d.busid),
+                "ClientIPAddress": (d.state == DeviceState::Attached).then_some("172.20.0.2"),
+                "Description": d.device,
+                "InstanceId": d.instance_id.clone().unwrap_or_else(|| format!("USB\\VID_{}&PID_{}\\{}", vid.to_uppercase(), pid.to_uppercase(), d.busid)),
+                "IsForced": d.state == DeviceState::SharedForced,
+                "PersistedGuid": d.guid.clone().or_else(|| {
+                    matches!(d.state, DeviceState::Shared | DeviceState::SharedForced | DeviceState::Attached | DeviceState::Persisted)
+                        .then(|| format!("{:0>8}-0000-0000-0000-000000000000", d.busid.replace('-', "")))
+                }),
+            })
+        })
+        .collect();
+    serde_json::json!({ "Devices": devices }).to_string()
+}
+
+/// A fresh directory under the system temp dir, removed again on drop.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Named after the test's module; a counter keeps tests running in
+    /// parallel apart.
+    pub fn new(name: &str) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let unique = NEXT.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("usbip_host-{}-{}-{}", name, std::process::id(), unique));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
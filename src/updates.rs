@@ -0,0 +1,380 @@
+//! Checks release feeds for newer versions of usbipd-win and this application.
+//!
+//! Feeds use the GitHub releases API format. Responses are cached in the
+//! config directory because unauthenticated GitHub API calls are limited to
+//! 60 an hour; a stale cache entry is still used when the feed can't be reached.
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::runner::{self, CommandError, CommandRunner};
+use crate::version::{self, Version};
+
+pub const CACHE_FILE: &str = "update_cache.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Product {
+    Usbipd,
+    App,
+}
+
+impl fmt::Display for Product {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Product::Usbipd => "usbipd-win",
+            Product::App => env!("CARGO_PKG_NAME"),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Release {
+    pub version: Version,
+    pub tag: String,
+    pub name: String,
+    /// Release notes in Markdown.
+    pub notes: String,
+    pub url: String,
+    pub prerelease: bool,
+}
+
+#[derive(Deserialize)]
+struct GithubRelease {
+    tag_name: String,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    body: Option<String>,
+    #[serde(default)]
+    html_url: String,
+    #[serde(default)]
+    prerelease: bool,
+    #[serde(default)]
+    draft: bool,
+}
+
+/// Parses a GitHub releases list. Drafts and tags that aren't versions are skipped.
+pub fn parse_releases(json: &str) -> Result<Vec<Release>, serde_json::Error> {
+    let releases: Vec<GithubRelease> = serde_json::from_str(json)?;
+    Ok(releases
+        .into_iter()
+        .filter(|r| !r.draft)
+        .filter_map(|r| {
+            Some(Release {
+                version: r.tag_name.parse().ok()?,
+                name: r.name.filter(|n| !n.is_empty()).unwrap_or_else(|| r.tag_name.clone()),
+                tag: r.tag_name,
+                notes: r.body.unwrap_or_default(),
+                url: r.html_url,
+                prerelease: r.prerelease,
+            })
+        })
+        .collect())
+}
+
+/// The newest release, leaving out pre-releases unless asked for.
+pub fn latest(releases: &[Release], include_prereleases: bool) -> Option<&Release> {
+    releases.iter().filter(|r| include_prereleases || !r.prerelease).max_by(|a, b| a.version.cmp(&b.version))
+}
+
+/// The first `max_lines` non-empty lines of the release notes.
+pub fn changelog_excerpt(notes: &str, max_lines: usize) -> String {
+    let lines: Vec<&str> = notes.lines().map(str::trim_end).filter(|l| !l.trim().is_empty()).collect();
+    let mut excerpt = lines.iter().take(max_lines).copied().collect::<Vec<_>>().join("\n");
+    if lines.len() > max_lines {
+        excerpt.push_str("\n…");
+    }
+    excerpt
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UpdateInfo {
+    pub product: Product,
+    pub current: Version,
+    pub latest: Option<Release>,
+    /// True if the answer came from a cache entry older than `cache_minutes`.
+    pub stale: bool,
+}
+
+impl UpdateInfo {
+    pub fn available(&self) -> bool {
+        self.latest.as_ref().is_some_and(|r| r.version > self.current)
+    }
+
+    /// A few lines for a confirmation dialog or the terminal.
+    pub fn summary(&self) -> String {
+        match &self.latest {
+            Some(release) if self.available() => format!(
+                "{} {} is available (installed: {}).\n\n{}\n\n{}",
+                self.product,
+                release.version,
+                self.current,
+                changelog_excerpt(&release.notes, 12),
+                release.url
+            ),
+            _ => format!("{} {} is up to date.", self.product, self.current),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum UpdateError {
+    /// No feed URL is configured for the product.
+    NoFeed(Product),
+    /// The installed usbipd-win version couldn't be determined.
+    Version(CommandError),
+    Fetch(CommandError),
+    Parse(String),
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpdateError::NoFeed(product) => write!(f, "no release feed is configured for {}", product),
+            UpdateError::Version(e) => write!(f, "could not determine the installed version: {}", e),
+            UpdateError::Fetch(e) => write!(f, "could not fetch the release feed: {}", e),
+            UpdateError::Parse(e) => write!(f, "the release feed is not in the GitHub releases format: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for UpdateError {}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Cache {
+    feeds: HashMap<String, CacheEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    /// Seconds since the Unix epoch.
+    fetched_at: u64,
+    body: String,
+}
+
+/// This application's version.
+pub fn app_version() -> Version {
+    env!("CARGO_PKG_VERSION").parse().unwrap_or(Version::new(0, 0, 0))
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+pub struct UpdateChecker<'a> {
+    runner: &'a dyn CommandRunner,
+    settings: config::UpdatesConfig,
+    cache_path: PathBuf,
+    refresh: bool,
+}
+
+impl<'a> UpdateChecker<'a> {
+    pub fn new(runner: &'a dyn CommandRunner, settings: config::UpdatesConfig, cache_path: PathBuf) -> Self {
+        UpdateChecker { runner, settings, cache_path, refresh: false }
+    }
+
+    pub fn from_config(runner: &'a dyn CommandRunner) -> Self {
+        Self::new(runner, config::current().updates, config::config_dir().join(CACHE_FILE))
+    }
+
+    /// Ignores fresh cache entries and asks the feed again.
+    pub fn refresh(mut self, refresh: bool) -> Self {
+        self.refresh = refresh;
+        self
+    }
+
+    pub fn check(&self, product: Product) -> Result<UpdateInfo, UpdateError> {
+        let (feed, current) = match product {
+            Product::Usbipd => {
+                let current = version::detect(self.runner).map_err(UpdateError::Version)?;
+                (&self.settings.usbipd_feed, current)
+            }
+            Product::App => {
+                (&self.settings.app_feed, app_version())
+            }
+        };
+        if feed.is_empty() {
+            return Err(UpdateError::NoFeed(product));
+        }
+        let (body, stale) = self.feed(feed)?;
+        let releases = parse_releases(&body).map_err(|e| UpdateError::Parse(e.to_string()))?;
+        let latest = latest(&releases, self.settings.include_prereleases).cloned();
+        Ok(UpdateInfo { product, current, latest, stale })
+    }
+
+    /// The feed body, from the cache while it's fresh.
+    fn feed(&self, url: &str) -> Result<(String, bool), UpdateError> {
+        let mut cache = load_cache(&self.cache_path);
+        let max_age = self.settings.cache_minutes.saturating_mul(60);
+        if !self.refresh
+            && let Some(entry) = cache.feeds.get(url)
+            && now().saturating_sub(entry.fetched_at) < max_age
+        {
+            return Ok((entry.body.clone(), false));
+        }
+
+        match self.fetch(url) {
+            Ok(body) => {
+                cache.feeds.insert(url.to_string(), CacheEntry { fetched_at: now(), body: body.clone() });
+                // Not being able to cache only costs another request next time.
+                let _ = save_cache(&self.cache_path, &cache);
+                Ok((body, false))
+            }
+            Err(e) => match cache.feeds.remove(url) {
+                Some(entry) => Ok((entry.body, true)),
+                None => Err(UpdateError::Fetch(e)),
+            },
+        }
+    }
+
+    fn fetch(&self, url: &str) -> Result<String, CommandError> {
+        let user_agent = format!("User-Agent: {}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        let output = runner::run_checked(
+            self.runner,
+            "curl",
+            &[
+                "--fail",
+                "--silent",
+                "--show-error",
+                "--location",
+                "--max-time",
+                "15",
+                "--header",
+                "Accept: application/vnd.github+json",
+                "--header",
+                &user_agent,
+                url,
+            ],
+        )?;
+        Ok(output.stdout)
+    }
+}
+
+fn load_cache(path: &Path) -> Cache {
+    fs::read_to_string(path).ok().and_then(|text| serde_json::from_str(&text).ok()).unwrap_or_default()
+}
+
+fn save_cache(path: &Path, cache: &Cache) -> std::io::Result<()> {
+    let text = serde_json::to_string(cache).map_err(std::io::Error::other)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Written aside and renamed, so a crash never leaves half a file.
+    let temp = path.with_extension("tmp");
+    fs::write(&temp, text)?;
+    fs::rename(&temp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakeRunner, TempDir};
+
+    const FEED: &str = "https://example.com/releases";
+
+    const RELEASES: &str = r#"[
+        {"tag_name": "v5.0.0", "name": "5.0.0", "body": "Unfinished", "html_url": "https://example.com/5.0.0", "draft": true},
+        {"tag_name": "v4.4.0-rc.1", "name": "", "body": "Release candidate", "html_url": "https://example.com/4.4.0-rc.1", "prerelease": true},
+        {"tag_name": "nightly", "name": "Nightly build", "html_url": "https://example.com/nightly"},
+        {"tag_name": "v4.3.0", "name": "usbipd-win 4.3.0", "body": "- Fixes\r\n\r\n- More fixes\r\n", "html_url": "https://example.com/4.3.0"},
+        {"tag_name": "v4.2.0", "html_url": "https://example.com/4.2.0"}
+    ]"#;
+
+
+    fn checker<'a>(runner: &'a FakeRunner, path: &Path) -> UpdateChecker<'a> {
+        let settings = config::UpdatesConfig { usbipd_feed: String::from(FEED), ..Default::default() };
+        UpdateChecker::new(runner, settings, path.to_path_buf())
+    }
+
+    fn write_cache(path: &Path, fetched_at: u64, body: &str) {
+        let mut cache = Cache::default();
+        cache.feeds.insert(String::from(FEED), CacheEntry { fetched_at, body: body.to_string() });
+        save_cache(path, &cache).unwrap();
+    }
+
+    #[test]
+    fn drafts_and_non_version_tags_are_skipped() {
+        let releases = parse_releases(RELEASES).unwrap();
+        let tags: Vec<&str> = releases.iter().map(|r| r.tag.as_str()).collect();
+        assert_eq!(tags, ["v4.4.0-rc.1", "v4.3.0", "v4.2.0"]);
+        assert!(releases[0].prerelease);
+        // An empty or missing name falls back to the tag.
+        assert_eq!((releases[0].name.as_str(), releases[2].name.as_str()), ("v4.4.0-rc.1", "v4.2.0"));
+        assert!(parse_releases(r#"{"message": "API rate limit exceeded"}"#).is_err());
+    }
+
+    #[test]
+    fn latest_leaves_out_prereleases_unless_asked() {
+        let releases = parse_releases(RELEASES).unwrap();
+        assert_eq!(latest(&releases, false).map(|r| r.version.to_string()), Some(String::from("4.3.0")));
+        assert_eq!(latest(&releases, true).map(|r| r.version.to_string()), Some(String::from("4.4.0-rc.1")));
+        assert!(latest(&[], true).is_none());
+    }
+
+    #[test]
+    fn changelog_excerpt_skips_blank_lines() {
+        assert_eq!(changelog_excerpt("- Fixes\r\n\r\n- More fixes\r\n", 5), "- Fixes\n- More fixes");
+        assert_eq!(changelog_excerpt("a\nb\nc", 2), "a\nb\n…");
+    }
+
+    #[test]
+    fn a_fresh_cache_entry_saves_the_request() {
+        let dir = TempDir::new("updates");
+        let path = dir.join(CACHE_FILE);
+        write_cache(&path, now(), RELEASES);
+        let runner = FakeRunner::new();
+        let info = checker(&runner, &path).check(Product::Usbipd).unwrap();
+        assert!(runner.calls_to("curl").is_empty());
+        assert!(!info.stale && !info.available());
+        assert_eq!(info.summary(), "usbipd-win 4.3.0 is up to date.");
+    }
+
+    #[test]
+    fn a_stale_entry_is_served_when_the_feed_is_unreachable() {
+        let dir = TempDir::new("updates");
+        let path = dir.join(CACHE_FILE);
+        write_cache(&path, 0, RELEASES);
+        let runner = FakeRunner::new().fail("curl", 6, "", "curl: (6) Could not resolve host: example.com");
+        let info = checker(&runner, &path).check(Product::Usbipd).unwrap();
+        assert_eq!(runner.calls_to("curl").len(), 1);
+        assert!(info.stale);
+        assert_eq!(info.latest.map(|r| r.tag), Some(String::from("v4.3.0")));
+
+        fs::remove_file(&path).unwrap();
+        match checker(&runner, &path).check(Product::Usbipd) {
+            Err(UpdateError::Fetch(e)) => assert_eq!(e.to_string(), "curl: (6) Could not resolve host: example.com"),
+            other => panic!("{:?}", other.map(|i| i.stale)),
+        }
+    }
+
+    #[test]
+    fn a_fetched_feed_is_cached() {
+        let dir = TempDir::new("updates");
+        let path = dir.join(CACHE_FILE);
+        write_cache(&path, now(), "[]");
+        let newer = RELEASES.replace("v4.4.0-rc.1", "v4.4.0").replace("\"prerelease\": true", "\"prerelease\": false");
+        let runner = FakeRunner::new().ok("curl", &newer);
+
+        let info = checker(&runner, &path).refresh(true).check(Product::Usbipd).unwrap();
+        assert!(info.available());
+        assert!(info.summary().starts_with("usbipd-win 4.4.0 is available (installed: 4.3.0).\n\nRelease candidate\n\nhttps://example.com/4.4.0"));
+        assert!(runner.calls_to("curl")[0].ends_with(FEED));
+        assert_eq!(load_cache(&path).feeds[FEED].body, newer);
+        assert!(!path.with_extension("tmp").exists());
+    }
+
+    #[test]
+    fn missing_feed_and_bad_json_are_errors() {
+        let runner = FakeRunner::new().ok("curl", "<html>");
+        let dir = TempDir::new("updates");
+        let path = dir.join(CACHE_FILE);
+        assert!(matches!(checker(&runner, &path).check(Product::App), Err(UpdateError::NoFeed(Product::App))));
+        assert!(matches!(checker(&runner, &path).check(Product::Usbipd), Err(UpdateError::Parse(_))));
+    }
+}
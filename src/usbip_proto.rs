@@ -0,0 +1,229 @@
+//! Wire format of the USB/IP management protocol (the `OP_*` messages).
+//!
+//! Only what this application needs is implemented: listing the devices a
+//! server exports and importing one of them.
+use std::io::{self, Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+pub const USBIP_VERSION: u16 = 0x0111;
+
+pub const OP_REQ_DEVLIST: u16 = 0x8005;
+pub const OP_REP_DEVLIST: u16 = 0x0005;
+pub const OP_REQ_IMPORT: u16 = 0x8003;
+pub const OP_REP_IMPORT: u16 = 0x0003;
+
+/// Size of `struct usbip_usb_device` on the wire.
+pub const DEVICE_SIZE: usize = 312;
+pub const BUSID_SIZE: usize = 32;
+
+/// Upper bound on the device count we accept in a reply, so a misbehaving
+/// peer can't make us allocate unbounded memory.
+const MAX_DEVICES: u32 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpHeader {
+    pub version: u16,
+    pub code: u16,
+    pub status: u32,
+}
+
+impl OpHeader {
+    pub fn new(code: u16) -> Self {
+        OpHeader { version: USBIP_VERSION, code, status: 0 }
+    }
+
+    pub fn to_bytes(self) -> [u8; 8] {
+        let mut buf = [0u8; 8];
+        buf[0..2].copy_from_slice(&self.version.to_be_bytes());
+        buf[2..4].copy_from_slice(&self.code.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.status.to_be_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8; 8]) -> Self {
+        OpHeader {
+            version: u16::from_be_bytes([buf[0], buf[1]]),
+            code: u16::from_be_bytes([buf[2], buf[3]]),
+            status: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+        }
+    }
+
+    pub fn read_from(r: &mut impl Read) -> io::Result<Self> {
+        let mut buf = [0u8; 8];
+        r.read_exact(&mut buf)?;
+        Ok(Self::from_bytes(&buf))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportedInterface {
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+}
+
+/// One entry of an `OP_REP_DEVLIST` reply.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportedDevice {
+    pub path: String,
+    pub busid: String,
+    pub busnum: u32,
+    pub devnum: u32,
+    pub speed: u32,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub bcd_device: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub configuration_value: u8,
+    pub num_configurations: u8,
+    pub num_interfaces: u8,
+    pub interfaces: Vec<ExportedInterface>,
+}
+
+impl ExportedDevice {
+    /// `vid:pid` in the same form `usbipd list` prints.
+    pub fn vidpid(&self) -> String {
+        format!("{:04x}:{:04x}", self.vendor_id, self.product_id)
+    }
+
+    /// The `devid` the vhci driver expects: bus number in the high half.
+    pub fn devid(&self) -> u32 {
+        (self.busnum << 16) | self.devnum
+    }
+
+    pub fn from_bytes(buf: &[u8; DEVICE_SIZE]) -> Self {
+        let u32_at = |i: usize| u32::from_be_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        let u16_at = |i: usize| u16::from_be_bytes([buf[i], buf[i + 1]]);
+        ExportedDevice {
+            path: c_string(&buf[0..256]),
+            busid: c_string(&buf[256..288]),
+            busnum: u32_at(288),
+            devnum: u32_at(292),
+            speed: u32_at(296),
+            vendor_id: u16_at(300),
+            product_id: u16_at(302),
+            bcd_device: u16_at(304),
+            class: buf[306],
+            subclass: buf[307],
+            protocol: buf[308],
+            configuration_value: buf[309],
+            num_configurations: buf[310],
+            num_interfaces: buf[311],
+            interfaces: Vec::new(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; DEVICE_SIZE] {
+        let mut buf = [0u8; DEVICE_SIZE];
+        put_c_string(&mut buf[0..256], &self.path);
+        put_c_string(&mut buf[256..288], &self.busid);
+        buf[288..292].copy_from_slice(&self.busnum.to_be_bytes());
+        buf[292..296].copy_from_slice(&self.devnum.to_be_bytes());
+        buf[296..300].copy_from_slice(&self.speed.to_be_bytes());
+        buf[300..302].copy_from_slice(&self.vendor_id.to_be_bytes());
+        buf[302..304].copy_from_slice(&self.product_id.to_be_bytes());
+        buf[304..306].copy_from_slice(&self.bcd_device.to_be_bytes());
+        buf[306] = self.class;
+        buf[307] = self.subclass;
+        buf[308] = self.protocol;
+        buf[309] = self.configuration_value;
+        buf[310] = self.num_configurations;
+        buf[311] = self.num_interfaces;
+        buf
+    }
+
+    pub fn read_from(r: &mut impl Read) -> io::Result<Self> {
+        let mut buf = [0u8; DEVICE_SIZE];
+        r.read_exact(&mut buf)?;
+        Ok(Self::from_bytes(&buf))
+    }
+}
+
+/// Sends `OP_REQ_DEVLIST` and reads the complete reply.
+pub fn request_devlist<S: Read + Write>(stream: &mut S) -> io::Result<Vec<ExportedDevice>> {
+    stream.write_all(&OpHeader::new(OP_REQ_DEVLIST).to_bytes())?;
+    read_devlist_reply(stream)
+}
+
+pub fn read_devlist_reply(r: &mut impl Read) -> io::Result<Vec<ExportedDevice>> {
+    let header = OpHeader::read_from(r)?;
+    expect_reply(header, OP_REP_DEVLIST)?;
+
+    let mut count = [0u8; 4];
+    r.read_exact(&mut count)?;
+    let count = u32::from_be_bytes(count);
+    if count > MAX_DEVICES {
+        return Err(invalid(format!("implausible device count {}", count)));
+    }
+
+    let mut devices = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let mut device = ExportedDevice::read_from(r)?;
+        for _ in 0..device.num_interfaces {
+            let mut iface = [0u8; 4];
+            r.read_exact(&mut iface)?;
+            device.interfaces.push(ExportedInterface {
+                class: iface[0],
+                subclass: iface[1],
+                protocol: iface[2],
+            });
+        }
+        devices.push(device);
+    }
+    Ok(devices)
+}
+
+pub fn write_devlist_reply(w: &mut impl Write, devices: &[ExportedDevice]) -> io::Result<()> {
+    let mut buf = OpHeader::new(OP_REP_DEVLIST).to_bytes().to_vec();
+    buf.extend_from_slice(&(devices.len() as u32).to_be_bytes());
+    for device in devices {
+        buf.extend_from_slice(&device.to_bytes());
+        for iface in &device.interfaces {
+            buf.extend_from_slice(&[iface.class, iface.subclass, iface.protocol, 0]);
+        }
+    }
+    w.write_all(&buf)
+}
+
+/// Sends `OP_REQ_IMPORT` for `busid` and returns the device on success.
+///
+/// After this the stream carries URB traffic for the imported device.
+pub fn request_import<S: Read + Write>(stream: &mut S, busid: &str) -> io::Result<ExportedDevice> {
+    let mut buf = OpHeader::new(OP_REQ_IMPORT).to_bytes().to_vec();
+    let mut id = [0u8; BUSID_SIZE];
+    put_c_string(&mut id, busid);
+    buf.extend_from_slice(&id);
+    stream.write_all(&buf)?;
+
+    let header = OpHeader::read_from(stream)?;
+    expect_reply(header, OP_REP_IMPORT)?;
+    ExportedDevice::read_from(stream)
+}
+
+fn expect_reply(header: OpHeader, code: u16) -> io::Result<()> {
+    if header.code != code {
+        return Err(invalid(format!("unexpected reply code {:#06x}", header.code)));
+    }
+    if header.status != 0 {
+        return Err(io::Error::other(format!("server returned status {}", header.status)));
+    }
+    Ok(())
+}
+
+pub fn c_string(buf: &[u8]) -> String {
+    let end = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..end]).into_owned()
+}
+
+fn put_c_string(buf: &mut [u8], s: &str) {
+    // Always leave room for the terminating NUL.
+    let n = s.len().min(buf.len() - 1);
+    buf[..n].copy_from_slice(&s.as_bytes()[..n]);
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,331 @@
+//! The installed usbipd-win version and what it can do.
+//!
+//! usbipd-win prints GitVersion strings such as `4.3.0+42.Branch.master.Sha.abc`.
+//! Features the app relies on appeared in different releases, so commands
+//! consult [`capabilities`] instead of finding out from a usage error.
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{OnceLock, RwLock};
+
+use serde::Serialize;
+
+use crate::runner::{self, CommandError, CommandRunner};
+use crate::{config, metrics};
+
+/// A semantic version. Build metadata is kept but ignored when comparing.
+#[derive(Debug, Clone, Eq)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    /// Dot-separated pre-release identifiers, e.g. `beta.2`.
+    pub pre: Vec<String>,
+    /// Dot-separated build metadata, e.g. `42.Branch.master.Sha.abc`.
+    pub build: Vec<String>,
+}
+
+impl Version {
+    pub const fn new(major: u64, minor: u64, patch: u64) -> Self {
+        Version { major, minor, patch, pre: Vec::new(), build: Vec::new() }
+    }
+
+    /// The version including build metadata.
+    pub fn full(&self) -> String {
+        if self.build.is_empty() { self.to_string() } else { format!("{}+{}", self, self.build.join(".")) }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseVersionError(String);
+
+impl fmt::Display for ParseVersionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "'{}' is not a version like 4.3.0", self.0)
+    }
+}
+
+impl std::error::Error for ParseVersionError {}
+
+impl FromStr for Version {
+    type Err = ParseVersionError;
+
+    /// Accepts `1.2.3`, `1.2.3-pre.1+build` and a leading `v`.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let error = || ParseVersionError(text.to_string());
+        let trimmed = text.trim();
+        let trimmed = trimmed.strip_prefix(['v', 'V']).unwrap_or(trimmed);
+        let (rest, build) = match trimmed.split_once('+') {
+            Some((rest, build)) => (rest, build.split('.').map(str::to_string).collect()),
+            None => (trimmed, Vec::new()),
+        };
+        let (core, pre) = match rest.split_once('-') {
+            Some((core, pre)) => (core, pre.split('.').map(str::to_string).collect()),
+            None => (rest, Vec::new()),
+        };
+        let numbers: Vec<u64> = core.split('.').map(|n| n.parse().map_err(|_| error())).collect::<Result<_, _>>()?;
+        let [major, minor, patch] = numbers[..] else { return Err(error()) };
+        let identifiers_ok = |ids: &Vec<String>| ids.iter().all(|i| !i.is_empty());
+        if !identifiers_ok(&pre) || !identifiers_ok(&build) {
+            return Err(error());
+        }
+        Ok(Version { major, minor, patch, pre, build })
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+        if !self.pre.is_empty() {
+            write!(f, "-{}", self.pre.join("."))?;
+        }
+        Ok(())
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.major, self.minor, self.patch)
+            .cmp(&(other.major, other.minor, other.patch))
+            .then_with(|| compare_pre(&self.pre, &other.pre))
+    }
+}
+
+/// Semver precedence: a release sorts after its pre-releases, numeric
+/// identifiers compare as numbers and sort before alphanumeric ones.
+fn compare_pre(a: &[String], b: &[String]) -> Ordering {
+    match (a.is_empty(), b.is_empty()) {
+        (true, true) => return Ordering::Equal,
+        (true, false) => return Ordering::Greater,
+        (false, true) => return Ordering::Less,
+        (false, false) => {}
+    }
+    for (x, y) in a.iter().zip(b) {
+        let ordering = match (x.parse::<u64>(), y.parse::<u64>()) {
+            (Ok(x), Ok(y)) => x.cmp(&y),
+            (Ok(_), Err(_)) => Ordering::Less,
+            (Err(_), Ok(_)) => Ordering::Greater,
+            (Err(_), Err(_)) => x.cmp(y),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+impl Serialize for Version {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.full())
+    }
+}
+
+/// usbipd-win features that not every release has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// `usbipd state`, the JSON device list.
+    StateJson,
+    /// `usbipd bind --force`.
+    BindForce,
+    /// `usbipd policy`.
+    Policy,
+    /// `usbipd attach --wsl`, which replaced `usbipd wsl attach`.
+    AttachWsl,
+    /// `usbipd unbind --guid` for persisted devices.
+    UnbindGuid,
+}
+
+impl Capability {
+    pub fn command(&self) -> &'static str {
+        match self {
+            Capability::StateJson => "usbipd state",
+            Capability::BindForce => "usbipd bind --force",
+            Capability::Policy => "usbipd policy",
+            Capability::AttachWsl => "usbipd attach --wsl",
+            Capability::UnbindGuid => "usbipd unbind --guid",
+        }
+    }
+}
+
+/// The first release with each capability.
+pub const CAPABILITIES: [(Capability, Version); 5] = [
+    (Capability::StateJson, Version::new(2, 4, 0)),
+    (Capability::BindForce, Version::new(2, 2, 0)),
+    (Capability::Policy, Version::new(4, 0, 0)),
+    (Capability::AttachWsl, Version::new(4, 0, 0)),
+    (Capability::UnbindGuid, Version::new(2, 2, 0)),
+];
+
+pub fn introduced_in(capability: Capability) -> Version {
+    CAPABILITIES.iter().find(|(c, _)| *c == capability).map(|(_, v)| v.clone()).unwrap_or(Version::new(0, 0, 0))
+}
+
+/// What the installed usbipd-win supports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// `None` if the version couldn't be determined; everything is then
+    /// assumed to work and usbipd reports what doesn't.
+    pub version: Option<Version>,
+}
+
+impl Capabilities {
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.version.as_ref().is_none_or(|v| *v >= introduced_in(capability))
+    }
+
+    /// Fails with a message naming the release that's needed.
+    pub fn require(&self, capability: Capability) -> Result<(), CommandError> {
+        if self.supports(capability) {
+            return Ok(());
+        }
+        let installed = self.version.as_ref().map(Version::to_string).unwrap_or_default();
+        Err(CommandError::Failed(format!(
+            "{} needs usbipd-win {} or later, {} is installed",
+            capability.command(),
+            introduced_in(capability),
+            installed
+        )))
+    }
+
+    pub fn supported(&self) -> Vec<Capability> {
+        CAPABILITIES.iter().map(|(c, _)| *c).filter(|c| self.supports(*c)).collect()
+    }
+}
+
+/// The version every [`runner::SystemRunner`] shares, since they all run the
+/// same usbipd.
+pub fn shared_cache() -> &'static RwLock<Option<Version>> {
+    static CACHE: OnceLock<RwLock<Option<Version>>> = OnceLock::new();
+    CACHE.get_or_init(|| RwLock::new(None))
+}
+
+/// Parses `usbipd --version` output.
+pub fn parse_usbipd_version(output: &str) -> Result<Version, ParseVersionError> {
+    output.lines().map(str::trim).find(|l| !l.is_empty()).unwrap_or("").parse()
+}
+
+/// Runs `usbipd --version` and remembers the result for [`capabilities`].
+pub fn detect(runner: &dyn CommandRunner) -> Result<Version, CommandError> {
+    let output = runner::usbipd(runner, &["--version"])?;
+    let version = parse_usbipd_version(&output.stdout).map_err(|e| CommandError::Failed(e.to_string()))?;
+    metrics::set_usbipd_version(&version.to_string());
+    *runner.version_cache().write().unwrap() = Some(version.clone());
+    Ok(version)
+}
+
+/// Forgets the version cached for `runner`, e.g. after an upgrade.
+pub fn forget(runner: &dyn CommandRunner) {
+    *runner.version_cache().write().unwrap() = None;
+}
+
+/// Capabilities of the installed usbipd, detecting the version on first use.
+pub fn capabilities(runner: &dyn CommandRunner) -> Capabilities {
+    let cached = runner.version_cache().read().unwrap().clone();
+    let version = cached.or_else(|| detect(runner).ok());
+    Capabilities { version }
+}
+
+/// A warning if `version` is older than `service.minimum_usbipd_version`.
+pub fn minimum_warning(version: &Version) -> Option<String> {
+    let minimum: Version = config::current().service.minimum_usbipd_version.parse().ok()?;
+    if *version >= minimum {
+        return None;
+    }
+    Some(format!(
+        "usbipd-win {} is installed but {} or later is required. Some features will not work until it is upgraded.",
+        version, minimum
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakeRunner;
+
+    fn v(text: &str) -> Version {
+        text.parse().unwrap()
+    }
+
+    fn installed(text: &str) -> Capabilities {
+        Capabilities { version: Some(v(text)) }
+    }
+
+    #[test]
+    fn parses_release_pre_release_and_build() {
+        let version = v(" v4.3.0-beta.2+42.Branch.master.Sha.abc ");
+        assert_eq!((version.major, version.minor, version.patch), (4, 3, 0));
+        assert_eq!(version.pre, ["beta", "2"]);
+        assert_eq!(version.build, ["42", "Branch", "master", "Sha", "abc"]);
+        assert_eq!(version.to_string(), "4.3.0-beta.2");
+        assert_eq!(version.full(), "4.3.0-beta.2+42.Branch.master.Sha.abc");
+        assert_eq!(v("V2.4.0").full(), "2.4.0");
+    }
+
+    #[test]
+    fn rejects_what_isnt_a_version() {
+        for text in ["", "4", "4.3", "4.3.0.1", "4.x.0", "-1.0.0", "4.3.0-", "4.3.0+", "4.3.0-beta..1", "usbipd 4.3.0"] {
+            let error = text.parse::<Version>().unwrap_err();
+            assert_eq!(error.to_string(), format!("'{}' is not a version like 4.3.0", text));
+        }
+    }
+
+    #[test]
+    fn orders_by_semver_precedence() {
+        let ordered = ["1.0.0-alpha", "1.0.0-alpha.1", "1.0.0-alpha.beta", "1.0.0-beta", "1.0.0-beta.2", "1.0.0-beta.11", "1.0.0-rc.1", "1.0.0", "1.0.1", "1.10.0", "2.0.0"];
+        for pair in ordered.windows(2) {
+            assert!(v(pair[0]) < v(pair[1]), "{} < {}", pair[0], pair[1]);
+        }
+        assert_eq!(v("4.3.0+1.Sha.abc"), v("4.3.0+2.Sha.def"));
+    }
+
+    #[test]
+    fn reads_the_first_line_of_usbipd_output() {
+        assert_eq!(parse_usbipd_version("\n4.3.0+42.Branch.master.Sha.abc\n").unwrap(), Version::new(4, 3, 0));
+        assert!(parse_usbipd_version("").is_err());
+    }
+
+    #[test]
+    fn capabilities_follow_the_installed_release() {
+        assert_eq!(installed("2.1.9").supported(), [] as [Capability; 0]);
+        assert_eq!(installed("2.2.0").supported(), [Capability::BindForce, Capability::UnbindGuid]);
+        assert_eq!(installed("2.4.0").supported(), [Capability::StateJson, Capability::BindForce, Capability::UnbindGuid]);
+        assert_eq!(installed("4.0.0-rc.1").supported(), installed("3.2.0").supported());
+        assert_eq!(installed("4.0.0").supported().len(), CAPABILITIES.len());
+        // Without a version nothing is held back.
+        assert_eq!(Capabilities { version: None }.supported().len(), CAPABILITIES.len());
+    }
+
+    #[test]
+    fn each_runner_remembers_its_own_version() {
+        let old = FakeRunner::empty().ok("usbipd --version", "2.3.0");
+        let current = FakeRunner::new();
+        assert!(!capabilities(&old).supports(Capability::StateJson));
+        assert!(capabilities(&current).supports(Capability::StateJson));
+        assert!(!capabilities(&old).supports(Capability::StateJson));
+        assert_eq!(old.calls_to("usbipd --version").len(), 1);
+
+        forget(&old);
+        assert!(!capabilities(&old).supports(Capability::StateJson));
+        assert_eq!(old.calls_to("usbipd --version").len(), 2);
+    }
+
+    #[test]
+    fn require_names_the_release_needed() {
+        assert!(installed("4.3.0").require(Capability::Policy).is_ok());
+        let error = installed("3.2.0").require(Capability::Policy).unwrap_err();
+        assert_eq!(error.to_string(), "usbipd policy needs usbipd-win 4.0.0 or later, 3.2.0 is installed");
+    }
+}
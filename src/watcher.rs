@@ -0,0 +1,285 @@
+//! Polls the device list and publishes what changed between snapshots.
+//!
+//! usbipd has no change notification of its own, so the watcher polls at a
+//! fixed interval and diffs consecutive [`UsbipDevice`] lists.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::device_list::{DeviceState, UsbipDevice};
+use crate::runner::CommandError;
+
+#[derive(Debug, Clone)]
+pub enum DeviceEvent {
+    Added(UsbipDevice),
+    Removed(UsbipDevice),
+    StateChanged { device: UsbipDevice, old: DeviceState },
+}
+
+impl DeviceEvent {
+    pub fn device(&self) -> &UsbipDevice {
+        match self {
+            DeviceEvent::Added(device) | DeviceEvent::Removed(device) => device,
+            DeviceEvent::StateChanged { device, .. } => device,
+        }
+    }
+}
+
+/// Identity used to match a device across snapshots.
+///
+/// A different device plugged into the same port keeps the busid but gets a
+/// new instance ID, so it shows up as a removal plus an addition.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum DeviceKey {
+    Connected { busid: String, instance_id: Option<String> },
+    Persisted { guid: String },
+}
+
+fn key(device: &UsbipDevice) -> DeviceKey {
+    match &device.guid {
+        Some(guid) if device.busid.is_empty() => DeviceKey::Persisted { guid: guid.clone() },
+        _ => DeviceKey::Connected { busid: device.busid.clone(), instance_id: device.instance_id.clone() },
+    }
+}
+
+/// Events that turn `prev` into `next`: removals first, then additions and
+/// state changes in the order of `next`.
+pub fn diff(prev: &[UsbipDevice], next: &[UsbipDevice]) -> Vec<DeviceEvent> {
+    let before: HashMap<DeviceKey, &UsbipDevice> = prev.iter().map(|d| (key(d), d)).collect();
+    let after: HashMap<DeviceKey, &UsbipDevice> = next.iter().map(|d| (key(d), d)).collect();
+
+    let mut events: Vec<DeviceEvent> = prev
+        .iter()
+        .filter(|d| !after.contains_key(&key(d)))
+        .map(|d| DeviceEvent::Removed(d.clone()))
+        .collect();
+
+    for device in next {
+        match before.get(&key(device)) {
+            None => events.push(DeviceEvent::Added(device.clone())),
+            Some(old) if old.state != device.state => events.push(DeviceEvent::StateChanged {
+                device: device.clone(),
+                old: old.state,
+            }),
+            Some(_) => {}
+        }
+    }
+    events
+}
+
+type Subscribers = Arc<Mutex<Vec<Sender<DeviceEvent>>>>;
+
+/// Background poller. Stops when dropped.
+pub struct Watcher {
+    subscribers: Subscribers,
+    latest: Arc<Mutex<Vec<UsbipDevice>>>,
+    /// Milliseconds between polls, read before every wait.
+    interval_ms: Arc<AtomicU64>,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Watcher {
+    /// Polls `source` every `interval`. The first successful poll is reported
+    /// as one `Added` event per device, to subscribers that came before it or
+    /// after it alike (see [`Watcher::subscribe`]).
+    pub fn start<F>(interval: Duration, mut source: F) -> Self
+    where
+        F: FnMut() -> Result<Vec<UsbipDevice>, CommandError> + Send + 'static,
+    {
+        let subscribers: Subscribers = Arc::new(Mutex::new(Vec::new()));
+        let latest = Arc::new(Mutex::new(Vec::new()));
+        let interval_ms = Arc::new(AtomicU64::new(millis(interval)));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let subscribers = subscribers.clone();
+            let latest = latest.clone();
+            let interval_ms = interval_ms.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    let started = Instant::now();
+                    // A failed poll keeps the previous snapshot rather than
+                    // reporting every device as removed.
+                    if let Ok(next) = source() {
+                        // Publishing under the snapshot lock keeps a new
+                        // subscriber from seeing a device both in its replay
+                        // and as a fresh event.
+                        let mut latest = latest.lock().unwrap();
+                        let events = diff(&latest, &next);
+                        *latest = next;
+                        publish(&subscribers, events);
+                    }
+                    loop {
+                        let interval = Duration::from_millis(interval_ms.load(Ordering::Relaxed));
+                        if stop.load(Ordering::Relaxed) || started.elapsed() >= interval {
+                            break;
+                        }
+                        thread::sleep(Duration::from_millis(50).min(interval));
+                    }
+                }
+            })
+        };
+
+        Watcher { subscribers, latest, interval_ms, stop, handle: Some(handle) }
+    }
+
+    /// Receives an `Added` event for every device in the latest snapshot,
+    /// then every event from now on, so subscribing after the first poll
+    /// doesn't lose the devices it found.
+    pub fn subscribe(&self) -> Receiver<DeviceEvent> {
+        let (tx, rx) = mpsc::channel();
+        let latest = self.latest.lock().unwrap();
+        for device in latest.iter() {
+            let _ = tx.send(DeviceEvent::Added(device.clone()));
+        }
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Changes the poll interval from the next wait on. Unlike starting a
+    /// new watcher, this keeps the snapshot, so nothing is reported again.
+    pub fn set_interval(&self, interval: Duration) {
+        self.interval_ms.store(millis(interval), Ordering::Relaxed);
+    }
+
+    /// The most recent successful snapshot.
+    pub fn latest(&self) -> Vec<UsbipDevice> {
+        self.latest.lock().unwrap().clone()
+    }
+}
+
+fn millis(interval: Duration) -> u64 {
+    u64::try_from(interval.as_millis()).unwrap_or(u64::MAX)
+}
+
+fn publish(subscribers: &Subscribers, events: Vec<DeviceEvent>) {
+    if events.is_empty() {
+        return;
+    }
+    let mut subscribers = subscribers.lock().unwrap();
+    // Dropped receivers are pruned on the next send.
+    subscribers.retain(|tx| events.iter().all(|e| tx.send(e.clone()).is_ok()));
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn with_instance(busid: &str, state: DeviceState, instance_id: &str) -> UsbipDevice {
+        let mut device = testing::device(busid, "0483:374b", state);
+        device.instance_id = Some(instance_id.to_string());
+        device
+    }
+
+    fn summary(events: &[DeviceEvent]) -> Vec<String> {
+        events
+            .iter()
+            .map(|e| match e {
+                DeviceEvent::Added(d) => format!("added {}", d.busid),
+                DeviceEvent::Removed(d) => format!("removed {}", d.busid),
+                DeviceEvent::StateChanged { device, old } => format!("{} {:?} -> {:?}", device.busid, old, device.state),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn first_snapshot_is_all_additions() {
+        let next = [testing::device("1-1", "0483:374b", DeviceState::Shared), testing::device("1-2", "046d:c52b", DeviceState::NotShared)];
+        assert_eq!(summary(&diff(&[], &next)), ["added 1-1", "added 1-2"]);
+        assert!(diff(&next, &next).is_empty());
+    }
+
+    #[test]
+    fn removals_come_before_additions_and_state_changes() {
+        let prev = [testing::device("1-1", "0483:374b", DeviceState::Shared), testing::device("1-2", "046d:c52b", DeviceState::NotShared)];
+        let next = [testing::device("1-3", "1366:0105", DeviceState::NotShared), testing::device("1-1", "0483:374b", DeviceState::Attached)];
+        assert_eq!(summary(&diff(&prev, &next)), ["removed 1-2", "added 1-3", "1-1 Shared -> Attached"]);
+    }
+
+    #[test]
+    fn another_device_on_the_same_port_is_a_removal_and_an_addition() {
+        let prev = [with_instance("1-1", DeviceState::Shared, "USB\\VID_0483&PID_374B\\A")];
+        let next = [with_instance("1-1", DeviceState::NotShared, "USB\\VID_0483&PID_374B\\B")];
+        let events = diff(&prev, &next);
+        assert_eq!(summary(&events), ["removed 1-1", "added 1-1"]);
+        assert_eq!(events[1].device().instance_id.as_deref(), Some("USB\\VID_0483&PID_374B\\B"));
+    }
+
+    #[test]
+    fn persisted_devices_are_matched_by_guid() {
+        let mut persisted = testing::device("", "1366:0105", DeviceState::Persisted);
+        persisted.guid = Some(String::from("5d1f1b2a-0000-0000-0000-000000000000"));
+        let mut connected = testing::device("1-4", "1366:0105", DeviceState::Shared);
+        connected.guid = persisted.guid.clone();
+
+        assert!(diff(std::slice::from_ref(&persisted), std::slice::from_ref(&persisted)).is_empty());
+        // Plugging it back in gives it a busid, so it's a new key.
+        assert_eq!(summary(&diff(&[persisted], &[connected])), ["removed ", "added 1-4"]);
+    }
+
+    fn recv_all(events: &Receiver<DeviceEvent>) -> Vec<String> {
+        let mut received = vec![events.recv_timeout(Duration::from_secs(5)).expect("no event")];
+        while let Ok(event) = events.recv_timeout(Duration::from_millis(200)) {
+            received.push(event);
+        }
+        summary(&received)
+    }
+
+    #[test]
+    fn late_subscribers_get_the_devices_already_found() {
+        let snapshots = Arc::new(Mutex::new(vec![vec![testing::device("1-1", "0483:374b", DeviceState::Shared)]]));
+        let watcher = {
+            let snapshots = snapshots.clone();
+            Watcher::start(Duration::from_millis(20), move || Ok(snapshots.lock().unwrap()[0].clone()))
+        };
+        let early = watcher.subscribe();
+        while watcher.latest().is_empty() {
+            thread::sleep(Duration::from_millis(5));
+        }
+        let late = watcher.subscribe();
+        assert_eq!(recv_all(&early), ["added 1-1"]);
+        assert_eq!(recv_all(&late), ["added 1-1"]);
+
+        snapshots.lock().unwrap()[0].push(testing::device("1-2", "046d:c52b", DeviceState::NotShared));
+        assert_eq!(recv_all(&early), ["added 1-2"]);
+        assert_eq!(recv_all(&late), ["added 1-2"]);
+    }
+
+    #[test]
+    fn a_new_interval_keeps_the_snapshot() {
+        let polls = Arc::new(AtomicU64::new(0));
+        let watcher = {
+            let polls = polls.clone();
+            Watcher::start(Duration::from_secs(3600), move || {
+                polls.fetch_add(1, Ordering::Relaxed);
+                Ok(vec![testing::device("1-1", "0483:374b", DeviceState::Shared)])
+            })
+        };
+        let events = watcher.subscribe();
+        assert_eq!(recv_all(&events), ["added 1-1"]);
+        assert_eq!(polls.load(Ordering::Relaxed), 1);
+
+        watcher.set_interval(Duration::from_millis(20));
+        let started = Instant::now();
+        while polls.load(Ordering::Relaxed) < 3 {
+            assert!(started.elapsed() < Duration::from_secs(5), "the new interval wasn't picked up");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(events.try_recv().is_err());
+    }
+}
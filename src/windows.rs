@@ -7,6 +7,7 @@ use std::ptr;
 use winapi::um::handleapi::CloseHandle;
 use winapi::um::processthreadsapi::{ GetCurrentProcess, OpenProcessToken };
 use winapi::um::securitybaseapi::GetTokenInformation;
+use winapi::um::winbase::GetUserNameW;
 use winapi::um::winnt::{HANDLE, TOKEN_ELEVATION, TOKEN_QUERY, TokenElevation};
 
 /// Returns true if the current process has admin rights, otherwise false.
@@ -14,6 +15,20 @@ pub fn is_app_elevated() -> bool {
     _is_app_elevated().unwrap_or(false)
 }
 
+/// Returns the name of the account the current process runs as.
+pub fn user_name() -> Option<String> {
+    // UNLEN + 1 for the terminating null.
+    let mut buffer = [0u16; 257];
+    let mut size = buffer.len() as u32;
+    unsafe {
+        if GetUserNameW(buffer.as_mut_ptr(), &mut size) == 0 {
+            return None;
+        }
+    }
+    // The size includes the terminating null.
+    Some(String::from_utf16_lossy(&buffer[..size.saturating_sub(1) as usize]))
+}
+
 /// On success returns a bool indicating if the current process has admin rights.
 /// Otherwise returns an OS error.
 /// 
@@ -0,0 +1,424 @@
+//! winget exit codes and output, and the package state they imply.
+//!
+//! winget exits with an HRESULT. The ones that matter for a single package
+//! are listed in [`WingetStatus`]; everything else is reported with its code
+//! and winget's own last line of output. `winget list` and `winget show`
+//! print aligned tables and `Key: value` lines, parsed here into a
+//! [`PackageState`]. [`crate::package_manager`] builds on this.
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::package_manager::{Action, PackageState};
+use crate::runner::{CommandError, CommandOutput, CommandRunner};
+use crate::version::Version;
+
+const NO_APPLICATIONS_FOUND: u32 = 0x8A15_0014;
+const MULTIPLE_APPLICATIONS_FOUND: u32 = 0x8A15_0016;
+const NO_APPLICABLE_INSTALLER: u32 = 0x8A15_0010;
+const INSTALLER_HASH_MISMATCH: u32 = 0x8A15_0011;
+const DOWNLOAD_FAILED: u32 = 0x8A15_0008;
+const UPDATE_NOT_APPLICABLE: u32 = 0x8A15_002B;
+const PACKAGE_ALREADY_INSTALLED: u32 = 0x8A15_0061;
+const INSTALL_PACKAGE_IN_USE: u32 = 0x8A15_0101;
+const INSTALL_IN_PROGRESS: u32 = 0x8A15_0102;
+const INSTALL_NO_NETWORK: u32 = 0x8A15_0107;
+const INSTALL_REBOOT_REQUIRED_TO_FINISH: u32 = 0x8A15_0109;
+const INSTALL_CANCELLED_BY_USER: u32 = 0x8A15_010C;
+const INSTALL_ALREADY_INSTALLED: u32 = 0x8A15_010D;
+const INSTALL_BLOCKED_BY_POLICY: u32 = 0x8A15_010F;
+
+/// What a winget exit code means.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WingetStatus {
+    Success,
+    RebootRequired,
+    /// `APPINSTALLER_CLI_ERROR_UPDATE_NOT_APPLICABLE`: nothing newer to upgrade to.
+    UpdateNotApplicable,
+    /// `APPINSTALLER_CLI_ERROR_NO_APPLICATIONS_FOUND`: the package isn't installed
+    /// (list, upgrade, uninstall) or doesn't exist in the sources (install).
+    NoApplicationsFound,
+    MultipleApplicationsFound,
+    AlreadyInstalled,
+    NoApplicableInstaller,
+    HashMismatch,
+    DownloadFailed,
+    NoNetwork,
+    PackageInUse,
+    InstallInProgress,
+    CancelledByUser,
+    BlockedByPolicy,
+    /// Any other non-zero code, or `None` if winget was killed.
+    Other(Option<i32>),
+}
+
+impl WingetStatus {
+    pub fn from_code(code: Option<i32>) -> Self {
+        let Some(code) = code else { return WingetStatus::Other(None) };
+        match code as u32 {
+            0 => WingetStatus::Success,
+            INSTALL_REBOOT_REQUIRED_TO_FINISH => WingetStatus::RebootRequired,
+            UPDATE_NOT_APPLICABLE => WingetStatus::UpdateNotApplicable,
+            NO_APPLICATIONS_FOUND => WingetStatus::NoApplicationsFound,
+            MULTIPLE_APPLICATIONS_FOUND => WingetStatus::MultipleApplicationsFound,
+            PACKAGE_ALREADY_INSTALLED | INSTALL_ALREADY_INSTALLED => WingetStatus::AlreadyInstalled,
+            NO_APPLICABLE_INSTALLER => WingetStatus::NoApplicableInstaller,
+            INSTALLER_HASH_MISMATCH => WingetStatus::HashMismatch,
+            DOWNLOAD_FAILED => WingetStatus::DownloadFailed,
+            INSTALL_NO_NETWORK => WingetStatus::NoNetwork,
+            INSTALL_PACKAGE_IN_USE => WingetStatus::PackageInUse,
+            INSTALL_IN_PROGRESS => WingetStatus::InstallInProgress,
+            INSTALL_CANCELLED_BY_USER => WingetStatus::CancelledByUser,
+            INSTALL_BLOCKED_BY_POLICY => WingetStatus::BlockedByPolicy,
+            _ => WingetStatus::Other(Some(code)),
+        }
+    }
+}
+
+impl fmt::Display for WingetStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WingetStatus::Success => f.write_str("success"),
+            WingetStatus::RebootRequired => f.write_str("Windows must be restarted to finish"),
+            WingetStatus::UpdateNotApplicable => f.write_str("no newer version is available"),
+            WingetStatus::NoApplicationsFound => f.write_str("no matching package was found"),
+            WingetStatus::MultipleApplicationsFound => f.write_str("more than one package matches the id"),
+            WingetStatus::AlreadyInstalled => f.write_str("the package is already installed"),
+            WingetStatus::NoApplicableInstaller => f.write_str("no installer fits this system"),
+            WingetStatus::HashMismatch => f.write_str("the downloaded installer does not match the manifest hash"),
+            WingetStatus::DownloadFailed => f.write_str("the installer could not be downloaded"),
+            WingetStatus::NoNetwork => f.write_str("no network connection"),
+            WingetStatus::PackageInUse => f.write_str("the package is in use; stop usbipd and try again"),
+            WingetStatus::InstallInProgress => f.write_str("another installation is in progress"),
+            WingetStatus::CancelledByUser => f.write_str("the installation was cancelled"),
+            WingetStatus::BlockedByPolicy => f.write_str("the installation is blocked by policy"),
+            WingetStatus::Other(Some(code)) => write!(f, "winget failed with exit code 0x{:08X}", *code as u32),
+            WingetStatus::Other(None) => f.write_str("winget was terminated"),
+        }
+    }
+}
+
+/// Turns a finished winget run into its status, or an error if `action` failed.
+///
+/// Codes that only say there was nothing to do (already installed, no
+/// update, nothing to uninstall) are not errors.
+pub fn check(action: Action, output: &CommandOutput) -> Result<WingetStatus, CommandError> {
+    let status = WingetStatus::from_code(output.code);
+    match (action, status) {
+        (_, WingetStatus::Success | WingetStatus::RebootRequired)
+        | (Action::Install, WingetStatus::AlreadyInstalled)
+        | (Action::Upgrade, WingetStatus::UpdateNotApplicable)
+        | (Action::Uninstall, WingetStatus::NoApplicationsFound) => Ok(status),
+        (Action::Upgrade, WingetStatus::NoApplicationsFound) => Err(CommandError::Failed(String::from(
+            "usbipd-win was not installed through winget, so winget can't upgrade it",
+        ))),
+        _ => {
+            let detail = last_line(output);
+            Err(CommandError::Failed(if detail.is_empty() {
+                status.to_string()
+            } else {
+                format!("{} ({})", status, detail)
+            }))
+        }
+    }
+}
+
+/// winget's last line of output, which is its own summary of what happened.
+fn last_line(output: &CommandOutput) -> String {
+    clean(&output.message()).lines().map(str::trim).rfind(|l| !l.is_empty()).unwrap_or("").to_string()
+}
+
+/// Strips the progress spinner and bars winget draws with carriage returns.
+fn clean(text: &str) -> String {
+    text.lines()
+        .map(|line| line.rsplit('\r').find(|part| !part.trim().is_empty()).unwrap_or(""))
+        .filter(|line| !matches!(line.trim(), "-" | "\\" | "|" | "/"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// An installed package as `winget list` shows it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListedPackage {
+    pub name: String,
+    pub id: String,
+    pub version: String,
+    /// Empty if no upgrade is available.
+    pub available: String,
+    pub source: String,
+}
+
+/// Finds `id` in `winget list` output.
+///
+/// Columns are located by the header's character offsets, since names may
+/// contain spaces and the Available column is blank for up-to-date packages.
+pub fn parse_list(output: &str, id: &str) -> Option<ListedPackage> {
+    let output = clean(output);
+    let lines: Vec<&str> = output.lines().collect();
+    let header_index = lines
+        .windows(2)
+        .position(|pair| pair[1].trim_start().starts_with("---") && pair[0].contains("Id") && pair[0].contains("Version"))?;
+    let header = lines[header_index];
+    let columns: Vec<(&str, usize)> = ["Name", "Id", "Version", "Available", "Source"]
+        .into_iter()
+        .filter_map(|name| column_start(header, name).map(|start| (name, start)))
+        .collect();
+
+    lines[header_index + 2..].iter().find_map(|line| {
+        let field = |name: &str| -> String {
+            let Some(position) = columns.iter().position(|(n, _)| *n == name) else { return String::new() };
+            let start = columns[position].1;
+            let end = columns.get(position + 1).map(|(_, s)| *s);
+            let chars: Vec<char> = line.chars().collect();
+            let end = end.unwrap_or(chars.len()).min(chars.len());
+            chars.get(start..end).map(|c| c.iter().collect::<String>()).unwrap_or_default().trim().to_string()
+        };
+        let package = ListedPackage {
+            name: field("Name"),
+            id: field("Id"),
+            version: field("Version"),
+            available: field("Available"),
+            source: field("Source"),
+        };
+        package.id.eq_ignore_ascii_case(id).then_some(package)
+    })
+}
+
+/// Character offset of a whole-word column title.
+fn column_start(header: &str, name: &str) -> Option<usize> {
+    let chars: Vec<char> = header.chars().collect();
+    (0..chars.len()).find(|&i| {
+        (i == 0 || chars[i - 1].is_whitespace())
+            && chars[i..].iter().take_while(|c| !c.is_whitespace()).collect::<String>() == name
+    })
+}
+
+/// The `Version:` line of `winget show`, the newest version in the sources.
+pub fn parse_show_version(output: &str) -> Option<String> {
+    clean(output)
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Version:").map(|v| v.trim().to_string()))
+        .filter(|v| !v.is_empty())
+}
+
+impl ListedPackage {
+    /// The package state this row describes, if its version is readable.
+    pub fn state(&self) -> Option<PackageState> {
+        let installed: Version = self.version.parse().ok()?;
+        Some(PackageState::installed(installed, self.available.parse().ok()))
+    }
+}
+
+/// Runs winget for one package.
+pub struct Winget<'a> {
+    runner: &'a dyn CommandRunner,
+    package_id: String,
+}
+
+impl<'a> Winget<'a> {
+    pub fn new(runner: &'a dyn CommandRunner, package_id: &str) -> Self {
+        Winget { runner, package_id: package_id.to_string() }
+    }
+
+    fn run(&self, args: &[&str]) -> Result<CommandOutput, CommandError> {
+        self.runner.run("winget", args).map_err(|e| CommandError::from_io("winget", e))
+    }
+
+    /// Reads the installed and available versions from `winget list`.
+    pub fn state(&self) -> Result<PackageState, CommandError> {
+        let output = self.run(&["list", "--id", &self.package_id, "--exact", "--accept-source-agreements", "--disable-interactivity"])?;
+        match WingetStatus::from_code(output.code) {
+            WingetStatus::NoApplicationsFound => return Ok(PackageState::NotInstalled),
+            WingetStatus::Success => {}
+            _ => return check(Action::Install, &output).map(|_| PackageState::NotInstalled),
+        }
+        let package = parse_list(&output.stdout, &self.package_id)
+            .ok_or_else(|| CommandError::Failed(format!("{} is missing from the winget list output", self.package_id)))?;
+        package
+            .state()
+            .ok_or_else(|| CommandError::Failed(format!("winget reports an unreadable version '{}'", package.version)))
+    }
+
+    /// The newest version in the winget sources.
+    pub fn available_version(&self) -> Result<Option<Version>, CommandError> {
+        let output = self.run(&["show", "--id", &self.package_id, "--exact", "--accept-source-agreements", "--disable-interactivity"])?;
+        if WingetStatus::from_code(output.code) == WingetStatus::NoApplicationsFound {
+            return Ok(None);
+        }
+        check(Action::Install, &output)?;
+        Ok(parse_show_version(&output.stdout).and_then(|v| v.parse().ok()))
+    }
+
+    /// Runs `action` on the package.
+    pub fn apply(&self, action: Action, extra_args: &[&str]) -> Result<WingetStatus, CommandError> {
+        let mut args = match action {
+            Action::Install => vec!["install", "--accept-source-agreements", "--accept-package-agreements"],
+            Action::Upgrade => vec!["upgrade", "--accept-source-agreements", "--accept-package-agreements"],
+            Action::Uninstall => vec!["uninstall"],
+        };
+        args.extend(["--silent", "--disable-interactivity", "--exact"]);
+        args.extend(extra_args);
+        args.extend(["--id", &self.package_id]);
+        check(action, &self.run(&args)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakeRunner;
+
+    const ID: &str = "dorssel.usbipd-win";
+
+    fn row(name: &str, id: &str, version: &str, available: &str, source: &str) -> String {
+        format!("{:<42}{:<33}{:<16}{:<10}{}", name, id, version, available, source)
+    }
+
+    fn list_output(rows: &[String]) -> String {
+        let header = row("Name", "Id", "Version", "Available", "Source");
+        // What winget prints before the table: a spinner and a progress bar.
+        let mut text = format!("   - \r   \\ \r   | \r\n  ██████████████████████████████  1.00 MB / 1.00 MB\r\n{}\n{}\n", header, "-".repeat(105));
+        for row in rows {
+            text.push_str(row);
+            text.push('\n');
+        }
+        text
+    }
+
+    fn output(code: u32, stdout: &str) -> CommandOutput {
+        CommandOutput { code: Some(code as i32), stdout: stdout.to_string(), stderr: String::new() }
+    }
+
+    #[test]
+    fn clean_drops_the_spinner_and_progress_redraws() {
+        let text = "   - \r   \\ \r   | \r   / \r\n  ████    10%\r  ████████  100%\r\nFound usbipd-win [dorssel.usbipd-win]\n";
+        assert_eq!(clean(text), "  ████████  100%\nFound usbipd-win [dorssel.usbipd-win]");
+    }
+
+    #[test]
+    fn parse_list_reads_columns_by_offset() {
+        let output = list_output(&[
+            row("Microsoft Visual C++ 2015-2022 Redistrib…", "Microsoft.VCRedist.2015+.x64", "14.38.33135.0", "", "winget"),
+            row("usbipd-win", ID, "4.2.0", "4.3.0", "winget"),
+        ]);
+        let package = parse_list(&output, "DORSSEL.USBIPD-WIN").unwrap();
+        assert_eq!(
+            package,
+            ListedPackage {
+                name: String::from("usbipd-win"),
+                id: String::from(ID),
+                version: String::from("4.2.0"),
+                available: String::from("4.3.0"),
+                source: String::from("winget"),
+            }
+        );
+        assert_eq!(package.state(), Some(PackageState::UpgradeAvailable { installed: Version::new(4, 2, 0), available: Version::new(4, 3, 0) }));
+
+        let truncated = parse_list(&output, "Microsoft.VCRedist.2015+.x64").unwrap();
+        assert_eq!(truncated.name, "Microsoft Visual C++ 2015-2022 Redistrib…");
+        assert_eq!((truncated.version.as_str(), truncated.available.as_str()), ("14.38.33135.0", ""));
+    }
+
+    #[test]
+    fn a_blank_available_column_means_up_to_date() {
+        let output = list_output(&[row("usbipd-win", ID, "4.3.0", "", "winget")]);
+        let package = parse_list(&output, ID).unwrap();
+        assert_eq!((package.available.as_str(), package.source.as_str()), ("", "winget"));
+        assert_eq!(package.state(), Some(PackageState::Installed { version: Version::new(4, 3, 0) }));
+
+        // Without a Source or Available column the row ends early.
+        let short = "Name       Id                  Version\n--------------------------------------\nusbipd-win dorssel.usbipd-win  4.3.0\n";
+        assert_eq!(parse_list(short, ID).unwrap().version, "4.3.0");
+        assert!(parse_list(&output, "Other.Package").is_none());
+        assert!(parse_list("No installed package found matching input criteria.", ID).is_none());
+    }
+
+    #[test]
+    fn parse_show_version_reads_the_version_line() {
+        let output = "   - \r   \\ \r\nFound usbipd-win [dorssel.usbipd-win]\nVersion: 4.3.0\nPublisher: Frans van Dorsselaer\n";
+        assert_eq!(parse_show_version(output).as_deref(), Some("4.3.0"));
+        assert_eq!(parse_show_version("Found usbipd-win\nVersion:\n"), None);
+    }
+
+    #[test]
+    fn every_mapped_code_has_a_status() {
+        let codes = [
+            (0, WingetStatus::Success),
+            (INSTALL_REBOOT_REQUIRED_TO_FINISH, WingetStatus::RebootRequired),
+            (UPDATE_NOT_APPLICABLE, WingetStatus::UpdateNotApplicable),
+            (NO_APPLICATIONS_FOUND, WingetStatus::NoApplicationsFound),
+            (MULTIPLE_APPLICATIONS_FOUND, WingetStatus::MultipleApplicationsFound),
+            (PACKAGE_ALREADY_INSTALLED, WingetStatus::AlreadyInstalled),
+            (INSTALL_ALREADY_INSTALLED, WingetStatus::AlreadyInstalled),
+            (NO_APPLICABLE_INSTALLER, WingetStatus::NoApplicableInstaller),
+            (INSTALLER_HASH_MISMATCH, WingetStatus::HashMismatch),
+            (DOWNLOAD_FAILED, WingetStatus::DownloadFailed),
+            (INSTALL_NO_NETWORK, WingetStatus::NoNetwork),
+            (INSTALL_PACKAGE_IN_USE, WingetStatus::PackageInUse),
+            (INSTALL_IN_PROGRESS, WingetStatus::InstallInProgress),
+            (INSTALL_CANCELLED_BY_USER, WingetStatus::CancelledByUser),
+            (INSTALL_BLOCKED_BY_POLICY, WingetStatus::BlockedByPolicy),
+        ];
+        for (code, status) in codes {
+            assert_eq!(WingetStatus::from_code(Some(code as i32)), status, "0x{:08X}", code);
+        }
+        assert_eq!(WingetStatus::from_code(Some(0x8A15_0001_u32 as i32)), WingetStatus::Other(Some(0x8A15_0001_u32 as i32)));
+        assert_eq!(WingetStatus::from_code(None), WingetStatus::Other(None));
+        assert_eq!(WingetStatus::Other(Some(0x8A15_0001_u32 as i32)).to_string(), "winget failed with exit code 0x8A150001");
+    }
+
+    #[test]
+    fn check_treats_nothing_to_do_as_success() {
+        let ok = [
+            (Action::Install, 0, WingetStatus::Success),
+            (Action::Upgrade, INSTALL_REBOOT_REQUIRED_TO_FINISH, WingetStatus::RebootRequired),
+            (Action::Install, PACKAGE_ALREADY_INSTALLED, WingetStatus::AlreadyInstalled),
+            (Action::Install, INSTALL_ALREADY_INSTALLED, WingetStatus::AlreadyInstalled),
+            (Action::Upgrade, UPDATE_NOT_APPLICABLE, WingetStatus::UpdateNotApplicable),
+            (Action::Uninstall, NO_APPLICATIONS_FOUND, WingetStatus::NoApplicationsFound),
+        ];
+        for (action, code, status) in ok {
+            assert_eq!(check(action, &output(code, "")).unwrap(), status);
+        }
+    }
+
+    #[test]
+    fn check_reports_failures_with_wingets_last_line() {
+        let message = check(Action::Upgrade, &output(NO_APPLICATIONS_FOUND, "")).unwrap_err().to_string();
+        assert_eq!(message, "usbipd-win was not installed through winget, so winget can't upgrade it");
+        let message = check(Action::Install, &output(NO_APPLICATIONS_FOUND, "")).unwrap_err().to_string();
+        assert_eq!(message, "no matching package was found");
+        let message = check(Action::Uninstall, &output(UPDATE_NOT_APPLICABLE, "")).unwrap_err().to_string();
+        assert_eq!(message, "no newer version is available");
+
+        let stdout = "   - \r   \\ \r\nFound usbipd-win [dorssel.usbipd-win] Version 4.3.0\nInstaller hash does not match; this cannot be overridden when running as admin\n\n";
+        let message = check(Action::Install, &output(INSTALLER_HASH_MISMATCH, stdout)).unwrap_err().to_string();
+        assert_eq!(
+            message,
+            "the downloaded installer does not match the manifest hash (Installer hash does not match; this cannot be overridden when running as admin)"
+        );
+        for code in [MULTIPLE_APPLICATIONS_FOUND, NO_APPLICABLE_INSTALLER, DOWNLOAD_FAILED, INSTALL_NO_NETWORK, INSTALL_PACKAGE_IN_USE, INSTALL_IN_PROGRESS, INSTALL_CANCELLED_BY_USER, INSTALL_BLOCKED_BY_POLICY] {
+            for action in [Action::Install, Action::Upgrade, Action::Uninstall] {
+                assert!(check(action, &output(code, "")).is_err(), "{:?} 0x{:08X}", action, code);
+            }
+        }
+    }
+
+    #[test]
+    fn state_and_apply_run_winget_for_the_package() {
+        let runner = FakeRunner::empty()
+            .ok("winget list", &list_output(&[row("usbipd-win", ID, "4.3.0", "", "winget")]))
+            .fail("winget upgrade", UPDATE_NOT_APPLICABLE as i32, "No available upgrade found.", "");
+        let winget = Winget::new(&runner, ID);
+        assert_eq!(winget.state().unwrap(), PackageState::Installed { version: Version::new(4, 3, 0) });
+        assert_eq!(winget.apply(Action::Upgrade, &["--scope", "machine"]).unwrap(), WingetStatus::UpdateNotApplicable);
+        assert_eq!(
+            runner.calls_to("winget upgrade"),
+            ["winget upgrade --accept-source-agreements --accept-package-agreements --silent --disable-interactivity --exact --scope machine --id dorssel.usbipd-win"]
+        );
+
+        let runner = FakeRunner::empty().fail("winget list", NO_APPLICATIONS_FOUND as i32, "No installed package found matching input criteria.", "");
+        assert_eq!(Winget::new(&runner, ID).state().unwrap(), PackageState::NotInstalled);
+    }
+}
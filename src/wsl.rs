@@ -0,0 +1,356 @@
+//! Attaching shared devices to WSL 2 distributions on this machine.
+//!
+//! usbipd-win 4.0 replaced `usbipd wsl attach|detach` with `usbipd attach
+//! --wsl` and `usbipd detach`; the command is built for whichever is
+//! installed. usbipd doesn't say which distribution a device went to, so
+//! attachments made here are remembered in [`Attachments`].
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::aliases::{self, DeviceAlias};
+use crate::config;
+use crate::reservations::Reservations;
+use crate::device_list::{self, DeviceState, UsbipDevice};
+use crate::runner::{self, CommandError, CommandRunner};
+use crate::version::{self, Capabilities, Capability};
+use crate::watcher::DeviceEvent;
+
+pub const ATTACHMENTS_FILE: &str = "wsl_attachments.json";
+
+/// A line of `wsl.exe --list --verbose`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Distribution {
+    pub name: String,
+    /// As printed, which depends on the Windows display language.
+    pub state: String,
+    /// WSL version, 1 or 2. Only WSL 2 distributions can attach devices.
+    pub version: u8,
+    /// Marked with `*`: the one `wsl.exe` starts without `-d`.
+    pub default: bool,
+}
+
+impl Distribution {
+    pub fn is_running(&self) -> bool {
+        self.state.eq_ignore_ascii_case("running")
+    }
+}
+
+/// Parses `wsl.exe --list --verbose`, already decoded from UTF-16.
+///
+/// The header is localized, so it is recognized by its missing version number.
+pub fn parse_distributions(output: &str) -> Vec<Distribution> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim_matches(|c: char| c.is_whitespace() || c == '\u{feff}');
+            let (default, line) = match line.strip_prefix('*') {
+                Some(rest) => (true, rest.trim_start()),
+                None => (false, line),
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [name, ref state @ .., version] = fields[..] else { return None };
+            if state.is_empty() {
+                return None;
+            }
+            Some(Distribution { name: name.to_string(), state: state.join(" "), version: version.parse().ok()?, default })
+        })
+        .collect()
+}
+
+pub fn list_distributions(runner: &dyn CommandRunner) -> Result<Vec<Distribution>, CommandError> {
+    let output = runner::run_checked(runner, "wsl.exe", &["--list", "--verbose"])?;
+    Ok(parse_distributions(&output.stdout))
+}
+
+/// WSL distribution names are limited to letters, digits, `.`, `-` and `_`.
+pub fn is_valid_distribution_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+}
+
+/// usbipd arguments attaching `busid` to `distribution`, or to the default one.
+pub fn attach_args(capabilities: &Capabilities, busid: &str, distribution: Option<&str>) -> Vec<String> {
+    let mut args: Vec<String> = Vec::new();
+    if capabilities.supports(Capability::AttachWsl) {
+        args.extend(["attach", "--wsl"].map(String::from));
+        args.extend(distribution.map(String::from));
+        args.extend(["--busid", busid].map(String::from));
+    } else {
+        args.extend(["wsl", "attach", "--busid", busid].map(String::from));
+        if let Some(distribution) = distribution {
+            args.extend(["--distribution", distribution].map(String::from));
+        }
+    }
+    args
+}
+
+pub fn detach_args(capabilities: &Capabilities, busid: &str) -> Vec<String> {
+    let command: &[&str] = if capabilities.supports(Capability::AttachWsl) { &["detach"] } else { &["wsl", "detach"] };
+    command.iter().copied().chain(["--busid", busid]).map(String::from).collect()
+}
+
+fn usbipd(runner: &dyn CommandRunner, args: &[String]) -> Result<(), CommandError> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    runner::usbipd(runner, &args)?;
+    Ok(())
+}
+
+/// Attaches a shared device. `None` picks the default distribution.
+pub fn attach(runner: &dyn CommandRunner, attachments: &Attachments, busid: &str, distribution: Option<&str>) -> Result<(), CommandError> {
+    let distributions = list_distributions(runner)?;
+    let target = match distribution {
+        Some(name) => distributions.iter().find(|d| d.name.eq_ignore_ascii_case(name)),
+        None => distributions.iter().find(|d| d.default),
+    };
+    let Some(target) = target else {
+        return Err(CommandError::Failed(match distribution {
+            Some(name) => format!("there is no WSL distribution named '{}'", name),
+            None => String::from("no WSL distribution is installed"),
+        }));
+    };
+    if target.version != 2 {
+        return Err(CommandError::Failed(format!("{} is a WSL 1 distribution; only WSL 2 can attach USB devices", target.name)));
+    }
+    usbipd(runner, &attach_args(&version::capabilities(runner), busid, distribution.map(|_| target.name.as_str())))?;
+    // Not remembering it only costs the distribution name in the device list.
+    let _ = attachments.record(busid, Some(&target.name));
+    Ok(())
+}
+
+pub fn detach(runner: &dyn CommandRunner, attachments: &Attachments, busid: &str) -> Result<(), CommandError> {
+    usbipd(runner, &detach_args(&version::capabilities(runner), busid))?;
+    let _ = attachments.record(busid, None);
+    Ok(())
+}
+
+/// Where [`Attachments::open`] keeps its file.
+pub fn default_attachments_path() -> PathBuf {
+    config::config_dir().join(ATTACHMENTS_FILE)
+}
+
+/// The distribution each device was attached to from this app, by busid.
+pub struct Attachments {
+    path: PathBuf,
+}
+
+impl Attachments {
+    pub fn at(path: PathBuf) -> Self {
+        Attachments { path }
+    }
+
+    /// The file in the config directory.
+    pub fn open() -> Self {
+        Self::at(default_attachments_path())
+    }
+
+    /// Busid to distribution. A missing or unreadable file is empty.
+    pub fn load(&self) -> BTreeMap<String, String> {
+        fs::read_to_string(&self.path).ok().and_then(|text| serde_json::from_str(&text).ok()).unwrap_or_default()
+    }
+
+    fn record(&self, busid: &str, distribution: Option<&str>) -> std::io::Result<()> {
+        let mut attachments = self.load();
+        match distribution {
+            Some(distribution) => attachments.insert(busid.to_string(), distribution.to_string()),
+            None => attachments.remove(busid),
+        };
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let text = serde_json::to_string_pretty(&attachments).map_err(std::io::Error::other)?;
+        // Written aside and renamed, so a crash never leaves half a file.
+        let temp = self.path.with_extension("tmp");
+        fs::write(&temp, text)?;
+        fs::rename(&temp, &self.path)
+    }
+
+    /// [`annotate`] with the remembered attachments.
+    pub fn annotate(&self, devices: &mut [UsbipDevice]) {
+        annotate(devices, &self.load());
+    }
+}
+
+/// Fills in [`UsbipDevice::wsl_distribution`] for attached devices.
+pub fn annotate(devices: &mut [UsbipDevice], attachments: &BTreeMap<String, String>) {
+    for device in devices.iter_mut().filter(|d| d.state == DeviceState::Attached) {
+        device.wsl_distribution = attachments.get(&device.busid).cloned();
+    }
+}
+
+/// One `[[wsl.auto_attach]]` entry in the config file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AutoAttach {
+    /// A busid or an alias from `[[aliases]]`.
+    pub device: String,
+    /// Empty means the default distribution.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub distribution: String,
+}
+
+impl AutoAttach {
+    pub fn matches(&self, device: &UsbipDevice, known: &[DeviceAlias]) -> bool {
+        if aliases::looks_like_busid(&self.device) {
+            return self.device == device.busid;
+        }
+        aliases::lookup(known, device).is_some_and(|a| a.alias.eq_ignore_ascii_case(&self.device))
+    }
+}
+
+/// Attaches a device that (re)appeared if an auto-attach entry names it,
+/// for `user`. A device reserved by someone else is left alone.
+///
+/// Devices that aren't shared yet are bound first; an auto-share rule may
+/// have beaten us to it, so a failed bind isn't an error. Returns the distribution
+/// the device was attached to, empty for the default one.
+pub fn apply_auto_attach(
+    runner: &dyn CommandRunner,
+    reservations: &Reservations,
+    attachments: &Attachments,
+    user: &str,
+    entries: &[AutoAttach],
+    known: &[DeviceAlias],
+    event: &DeviceEvent,
+) -> Result<Option<String>, CommandError> {
+    let DeviceEvent::Added(device) = event else { return Ok(None) };
+    if device.busid.is_empty() || device.state == DeviceState::Attached {
+        return Ok(None);
+    }
+    let Some(entry) = entries.iter().find(|e| e.matches(device, known)) else { return Ok(None) };
+    reservations.check(&device.busid, user).map_err(|e| CommandError::Failed(e.to_string()))?;
+    if !device.is_shared() {
+        let _ = device_list::bind_device(runner, &device.busid, false);
+    }
+    let distribution = Some(entry.distribution.as_str()).filter(|d| !d.is_empty());
+    attach(runner, attachments, &device.busid, distribution)?;
+    Ok(Some(entry.distribution.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reservations::RESERVATIONS_FILE;
+    use crate::testing::{self, FakeRunner, TempDir};
+    use crate::version::Version;
+
+    const LIST: &str = "  NAME            STATE           VERSION\r\n* Ubuntu-22.04    Running         2\r\n  Debian          Stopped         2\r\n  Legacy          Stopped         1\r\n";
+
+    /// What `wsl.exe` writes: UTF-16LE, optionally with a byte order mark.
+    fn utf16(text: &str, bom: bool) -> Vec<u8> {
+        let mut bytes = if bom { vec![0xFF, 0xFE] } else { Vec::new() };
+        bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        bytes
+    }
+
+    fn distribution(name: &str, state: &str, version: u8, default: bool) -> Distribution {
+        Distribution { name: name.to_string(), state: state.to_string(), version, default }
+    }
+
+    fn capabilities(version: Version) -> Capabilities {
+        Capabilities { version: Some(version) }
+    }
+
+    #[test]
+    fn parses_utf16_output_with_and_without_bom() {
+        let expected = [
+            distribution("Ubuntu-22.04", "Running", 2, true),
+            distribution("Debian", "Stopped", 2, false),
+            distribution("Legacy", "Stopped", 1, false),
+        ];
+        for bom in [true, false] {
+            assert_eq!(parse_distributions(&runner::decode_output(&utf16(LIST, bom))), expected);
+        }
+        assert!(expected[0].is_running() && !expected[1].is_running());
+    }
+
+    #[test]
+    fn localised_headers_and_states_are_handled() {
+        let french = "  NOM              ÉTAT                      VERSION\r\n* Ubuntu           En cours d'exécution      2\r\n  Debian           Arrêté                    2\r\n";
+        let parsed = parse_distributions(&runner::decode_output(&utf16(french, true)));
+        assert_eq!(parsed, [distribution("Ubuntu", "En cours d'exécution", 2, true), distribution("Debian", "Arrêté", 2, false)]);
+        // Without a distribution wsl.exe prints a message, not a table.
+        assert!(parse_distributions("Windows Subsystem for Linux has no installed distributions.\r\n").is_empty());
+    }
+
+    #[test]
+    fn attach_and_detach_follow_the_installed_release() {
+        let current = capabilities(Version::new(4, 3, 0));
+        let old = capabilities(Version::new(3, 2, 0));
+        assert_eq!(attach_args(&current, "1-3", Some("Debian")), ["attach", "--wsl", "Debian", "--busid", "1-3"]);
+        assert_eq!(attach_args(&current, "1-3", None), ["attach", "--wsl", "--busid", "1-3"]);
+        assert_eq!(attach_args(&old, "1-3", Some("Debian")), ["wsl", "attach", "--busid", "1-3", "--distribution", "Debian"]);
+        assert_eq!(attach_args(&old, "1-3", None), ["wsl", "attach", "--busid", "1-3"]);
+        assert_eq!(detach_args(&current, "1-3"), ["detach", "--busid", "1-3"]);
+        assert_eq!(detach_args(&old, "1-3"), ["wsl", "detach", "--busid", "1-3"]);
+    }
+
+    #[test]
+    fn attach_needs_an_existing_wsl2_distribution() {
+        let dir = TempDir::new("wsl");
+        let attachments = Attachments::at(dir.join(ATTACHMENTS_FILE));
+        let runner = FakeRunner::new().ok("wsl.exe --list --verbose", LIST);
+        let message = |distribution| attach(&runner, &attachments, "1-3", distribution).unwrap_err().to_string();
+        assert_eq!(message(Some("Arch")), "there is no WSL distribution named 'Arch'");
+        assert_eq!(message(Some("legacy")), "Legacy is a WSL 1 distribution; only WSL 2 can attach USB devices");
+        assert!(runner.calls_to("usbipd attach").is_empty());
+
+        let runner = FakeRunner::new().ok("wsl.exe --list --verbose", "");
+        assert_eq!(attach(&runner, &attachments, "1-3", None).unwrap_err().to_string(), "no WSL distribution is installed");
+    }
+
+    #[test]
+    fn names_and_annotations() {
+        assert!(is_valid_distribution_name("Ubuntu-22.04_lab"));
+        assert!(!is_valid_distribution_name("") && !is_valid_distribution_name("my distro"));
+
+        let mut devices = [testing::device("1-3", "0483:374b", DeviceState::Attached), testing::device("1-4", "046d:c52b", DeviceState::Shared)];
+        let attachments = BTreeMap::from([(String::from("1-3"), String::from("Debian")), (String::from("1-4"), String::from("Debian"))]);
+        annotate(&mut devices, &attachments);
+        assert_eq!(devices[0].wsl_distribution.as_deref(), Some("Debian"));
+        assert_eq!(devices[1].wsl_distribution, None);
+    }
+
+    #[test]
+    fn attach_and_detach_remember_the_distribution() {
+        let dir = TempDir::new("wsl");
+        let attachments = Attachments::at(dir.join(ATTACHMENTS_FILE));
+        let runner = FakeRunner::new().ok("wsl.exe --list --verbose", LIST).ok("usbipd attach", "").ok("usbipd detach", "");
+
+        attach(&runner, &attachments, "1-3", Some("debian")).unwrap();
+        attach(&runner, &attachments, "1-4", None).unwrap();
+        assert_eq!(runner.calls_to("usbipd attach"), ["usbipd attach --wsl Debian --busid 1-3", "usbipd attach --wsl --busid 1-4"]);
+        let mut devices = [testing::device("1-3", "0483:374b", DeviceState::Attached), testing::device("1-4", "046d:c52b", DeviceState::Attached)];
+        attachments.annotate(&mut devices);
+        assert_eq!(devices.map(|d| d.wsl_distribution), [Some(String::from("Debian")), Some(String::from("Ubuntu-22.04"))]);
+
+        detach(&runner, &attachments, "1-3").unwrap();
+        assert_eq!(attachments.load(), BTreeMap::from([(String::from("1-4"), String::from("Ubuntu-22.04"))]));
+        assert!(attach(&runner, &attachments, "1-5", Some("Legacy")).unwrap_err().to_string().contains("WSL 1"));
+    }
+
+    #[test]
+    fn auto_attach_only_acts_on_new_devices_it_names() {
+        let entries = [AutoAttach { device: String::from("probe"), distribution: String::new() }];
+        let known = [DeviceAlias::for_device("probe", &testing::device("1-3", "0483:374b", DeviceState::NotShared))];
+        let runner = FakeRunner::new();
+        let dir = TempDir::new("wsl");
+        let store = Reservations::at(dir.join(RESERVATIONS_FILE));
+
+        let other = testing::device("1-4", "046d:c52b", DeviceState::Shared);
+        let attached = testing::device("1-3", "0483:374b", DeviceState::Attached);
+        let shared = testing::device("1-3", "0483:374b", DeviceState::Shared);
+        let events = [
+            DeviceEvent::Added(other),
+            DeviceEvent::Added(attached),
+            DeviceEvent::StateChanged { device: shared.clone(), old: DeviceState::NotShared },
+            DeviceEvent::Removed(shared),
+        ];
+        for event in &events {
+            assert_eq!(apply_auto_attach(&runner, &store, &Attachments::at(dir.join(ATTACHMENTS_FILE)), "ana", &entries, &known, event).unwrap(), None);
+        }
+        assert!(runner.calls().is_empty());
+        assert!(entries[0].matches(&testing::device("2-1", "0483:374b", DeviceState::NotShared), &known));
+    }
+}